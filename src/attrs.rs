@@ -0,0 +1,295 @@
+use crate::cli::RunOptions;
+use crate::utils::{IdMap, Modes, Owner, Preserve};
+use std::collections::HashSet;
+use std::fs::{File, FileTimes};
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Extended attribute holding the SELinux security context.
+#[cfg(target_os = "linux")]
+const SELINUX_XATTR: &str = "security.selinux";
+
+/// Extended attribute holding the file capabilities set with `setcap`.
+#[cfg(target_os = "linux")]
+const CAPABILITY_XATTR: &str = "security.capability";
+
+/// Carries the attributes selected with `--preserve` over to the
+/// destination and applies the `--chown` and `--chmod` overrides. Problems
+/// that would repeat for every file, such as a destination without SELinux
+/// support, are only reported once.
+pub(crate) struct Attrs {
+    preserve: Preserve,
+    owner: Option<Owner>,
+    modes: Modes,
+    uid_map: Vec<IdMap>,
+    gid_map: Vec<IdMap>,
+    reported: Mutex<HashSet<&'static str>>,
+}
+
+impl Attrs {
+    pub(crate) fn new(options: &RunOptions) -> Self {
+        Attrs {
+            owner: options.chown,
+            modes: options.chmod,
+            uid_map: options.uid_map.clone(),
+            gid_map: options.gid_map.clone(),
+            ..Attrs::preserving(options.copy.preserve)
+        }
+    }
+
+    /// Copies the `preserve` attributes without any overrides.
+    pub(crate) fn preserving(preserve: Preserve) -> Self {
+        Attrs {
+            preserve,
+            owner: None,
+            modes: Modes::default(),
+            uid_map: Vec::new(),
+            gid_map: Vec::new(),
+            reported: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Whether anything is to be applied to the copies.
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.preserve != Preserve::default() || self.owner.is_some() || self.modes != Modes::default()
+    }
+
+    /// Copies the selected attributes from `from` to `to`, which may be a file
+    /// or a directory, and applies the ownership and mode overrides.
+    /// Attributes that can't be set don't fail the copy; a warning is
+    /// returned for each instead.
+    pub(crate) fn apply(&self, from: &Path, to: &Path) -> Vec<String> {
+        let mut warnings = Vec::new();
+        // Changing the owner clears setuid bits and file capabilities, so it
+        // comes before the mode and the capabilities.
+        if let Some(owner) = self.owner_for(from) {
+            self.chown(to, owner, &mut warnings);
+        }
+        let mode = if to.is_dir() { self.modes.dir } else { self.modes.file };
+        if let Some(mode) = mode {
+            self.chmod(to, mode, &mut warnings);
+        }
+        if self.preserve.context {
+            self.copy_context(from, to, &mut warnings);
+        }
+        if self.preserve.capabilities {
+            self.copy_capabilities(from, to, &mut warnings);
+        }
+        // Times go last: setting the other attributes doesn't touch the
+        // modification time, but may change the access time.
+        if self.preserve.timestamps
+            && let Err(e) = copy_times(from, to)
+        {
+            warnings.push(format!("cannot preserve times of {}: {}", to.display(), e));
+        }
+        warnings
+    }
+
+    #[cfg(target_os = "linux")]
+    fn copy_context(&self, from: &Path, to: &Path, warnings: &mut Vec<String>) {
+        match copy_xattr(from, to, SELINUX_XATTR) {
+            Ok(()) => {}
+            Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+                if self.first_report("context") {
+                    warnings.push(format!(
+                        "The destination doesn't support SELinux contexts; copied files get its default labels ({})",
+                        to.display()
+                    ));
+                }
+            }
+            Err(e) => warnings.push(format!("cannot preserve the SELinux context of {}: {}", to.display(), e)),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn copy_context(&self, _from: &Path, _to: &Path, warnings: &mut Vec<String>) {
+        if self.first_report("context") {
+            warnings.push("SELinux contexts are only preserved on Linux".to_string());
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn copy_capabilities(&self, from: &Path, to: &Path, warnings: &mut Vec<String>) {
+        match copy_xattr(from, to, CAPABILITY_XATTR) {
+            Ok(()) => {}
+            Err(e) if e.raw_os_error() == Some(libc::EPERM) => {
+                if self.first_report("capabilities") {
+                    warnings.push(format!(
+                        "File capabilities can only be preserved as root (CAP_SETFCAP); {} and any others lose theirs",
+                        to.display()
+                    ));
+                }
+            }
+            Err(e) => warnings.push(format!("cannot preserve the file capabilities of {}: {}", to.display(), e)),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn copy_capabilities(&self, _from: &Path, _to: &Path, _warnings: &mut Vec<String>) {
+        // File capabilities only exist on Linux.
+    }
+
+    /// The owner to give the copy of `from`: the source's, translated by the
+    /// id maps, when ownership is preserved, with `--chown` taking precedence.
+    #[cfg(unix)]
+    fn owner_for(&self, from: &Path) -> Option<Owner> {
+        use std::os::unix::fs::MetadataExt;
+        let mut owner = Owner::default();
+        if self.preserve.ownership
+            && let Ok(meta) = std::fs::metadata(from)
+        {
+            owner.uid = Some(crate::utils::map_id(&self.uid_map, meta.uid()));
+            owner.gid = Some(crate::utils::map_id(&self.gid_map, meta.gid()));
+        }
+        if let Some(chown) = self.owner {
+            owner.uid = chown.uid.or(owner.uid);
+            owner.gid = chown.gid.or(owner.gid);
+        }
+        (owner != Owner::default()).then_some(owner)
+    }
+
+    /// Ownership only exists on Unix; asking for it gets a warning.
+    #[cfg(not(unix))]
+    fn owner_for(&self, _from: &Path) -> Option<Owner> {
+        self.owner.or(self.preserve.ownership.then(Owner::default))
+    }
+
+    #[cfg(unix)]
+    fn chown(&self, to: &Path, owner: Owner, warnings: &mut Vec<String>) {
+        match std::os::unix::fs::chown(to, owner.uid, owner.gid) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                if self.first_report("chown") {
+                    warnings.push(format!("Changing the owner needs root; cannot chown {}: {}", to.display(), e));
+                }
+            }
+            Err(e) => warnings.push(format!("cannot chown {}: {}", to.display(), e)),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn chown(&self, _to: &Path, _owner: Owner, warnings: &mut Vec<String>) {
+        if self.first_report("chown") {
+            warnings.push("Ownership can only be changed on Unix".to_string());
+        }
+    }
+
+    #[cfg(unix)]
+    fn chmod(&self, to: &Path, mode: u32, warnings: &mut Vec<String>) {
+        use std::os::unix::fs::PermissionsExt;
+        if let Err(e) = std::fs::set_permissions(to, std::fs::Permissions::from_mode(mode)) {
+            warnings.push(format!("cannot chmod {}: {}", to.display(), e));
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn chmod(&self, _to: &Path, _mode: u32, warnings: &mut Vec<String>) {
+        if self.first_report("chmod") {
+            warnings.push("--chmod is only supported on Unix".to_string());
+        }
+    }
+
+    /// Whether the problem `key` is reported for the first time.
+    fn first_report(&self, key: &'static str) -> bool {
+        self.reported.lock().unwrap().insert(key)
+    }
+}
+
+/// Sets the access and modification times of `to` to those of `from`.
+fn copy_times(from: &Path, to: &Path) -> io::Result<()> {
+    let meta = std::fs::metadata(from)?;
+    let times = FileTimes::new()
+        .set_accessed(meta.accessed()?)
+        .set_modified(meta.modified()?);
+    open_for_times(to)?.set_times(times)
+}
+
+/// Sets the modification time of the file or directory `path`.
+pub(crate) fn set_modified(path: &Path, modified: std::time::SystemTime) -> io::Result<()> {
+    open_for_times(path)?.set_times(FileTimes::new().set_modified(modified))
+}
+
+/// Opens a file or directory so its times can be set.
+#[cfg(not(windows))]
+fn open_for_times(path: &Path) -> io::Result<File> {
+    File::open(path)
+}
+
+/// Opens a file or directory so its times can be set. Directories can only be
+/// opened with `FILE_FLAG_BACKUP_SEMANTICS`, and setting times needs
+/// `FILE_WRITE_ATTRIBUTES`.
+#[cfg(windows)]
+fn open_for_times(path: &Path) -> io::Result<File> {
+    use std::os::windows::fs::OpenOptionsExt;
+    const FILE_WRITE_ATTRIBUTES: u32 = 0x100;
+    const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x0200_0000;
+    std::fs::OpenOptions::new()
+        .access_mode(FILE_WRITE_ATTRIBUTES)
+        .custom_flags(FILE_FLAG_BACKUP_SEMANTICS)
+        .open(path)
+}
+
+/// Copies the extended attribute `name` from `from` to `to`. Nothing happens
+/// when the source doesn't have it, or its filesystem has no extended
+/// attributes at all.
+#[cfg(target_os = "linux")]
+fn copy_xattr(from: &Path, to: &Path, name: &str) -> io::Result<()> {
+    match get_xattr(from, name) {
+        Ok(Some(value)) => set_xattr(to, name, &value),
+        Ok(None) => Ok(()),
+        Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn c_path(path: &Path) -> io::Result<std::ffi::CString> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(std::ffi::CString::new(path.as_os_str().as_bytes())?)
+}
+
+/// Reads the extended attribute `name` of `path`, or `None` if it isn't set.
+#[cfg(target_os = "linux")]
+fn get_xattr(path: &Path, name: &str) -> io::Result<Option<Vec<u8>>> {
+    let path = c_path(path)?;
+    let name = std::ffi::CString::new(name)?;
+    loop {
+        // SAFETY: `path` and `name` are valid C strings; a null buffer of size
+        // 0 asks for the value's length only.
+        let len = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+        if len < 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(libc::ENODATA) => Ok(None),
+                _ => Err(err),
+            };
+        }
+        let mut value = vec![0u8; len as usize];
+        // SAFETY: `value` is writable for `value.len()` bytes.
+        let read = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), value.as_mut_ptr().cast(), value.len()) };
+        if read >= 0 {
+            value.truncate(read as usize);
+            return Ok(Some(value));
+        }
+        // The value grew between the two calls; ask for its length again.
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(err);
+        }
+    }
+}
+
+/// Sets the extended attribute `name` of `path` to `value`.
+#[cfg(target_os = "linux")]
+fn set_xattr(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+    let path = c_path(path)?;
+    let name = std::ffi::CString::new(name)?;
+    // SAFETY: `path` and `name` are valid C strings and `value` is readable
+    // for `value.len()` bytes.
+    let result = unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), value.as_ptr().cast(), value.len(), 0) };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
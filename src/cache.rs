@@ -0,0 +1,149 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::fs;
+
+/// Whether large copies drop what they read and wrote from the page cache;
+/// `--drop-cache never` turns it off.
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Bytes of a file copied before its pages start being dropped, so small
+/// and medium files stay cached as usual.
+const DROP_AFTER: u64 = 64 * 1024 * 1024;
+
+/// Bytes copied between two rounds of hints.
+const WINDOW: u64 = 16 * 1024 * 1024;
+
+/// Keeps every copy's pages in the page cache, for `--drop-cache never`.
+pub(crate) fn disable() {
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+/// Tells the kernel how a file is being copied so that a copy much larger
+/// than the memory doesn't push every other program's data out of the page
+/// cache: the source is read sequentially, and once a file has passed
+/// [`DROP_AFTER`] the ranges already copied are written back and dropped
+/// from the cache as the copy moves on. Elsewhere than on Linux and
+/// Android, which lack the calls, it does nothing.
+pub(crate) struct Dropper {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    source: std::os::fd::RawFd,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    dest: std::os::fd::RawFd,
+    source_offset: u64,
+    dest_offset: u64,
+    /// Bytes copied so far.
+    copied: u64,
+    /// Bytes whose writeback has been started.
+    flushed: u64,
+    /// Bytes dropped from the cache.
+    dropped: u64,
+}
+
+impl Dropper {
+    /// Starts hinting for a copy from `source_offset` in `source` to
+    /// `dest_offset` in `dest`. Both files must outlive the dropper.
+    pub(crate) fn new(source: &fs::File, source_offset: u64, dest: &fs::File, dest_offset: u64) -> Self {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let dropper = {
+            use std::os::fd::AsRawFd;
+            let source = source.as_raw_fd();
+            if ENABLED.load(Ordering::Relaxed) {
+                // SAFETY: the caller keeps `source` open; the call only
+                // advises the kernel.
+                unsafe { libc::posix_fadvise(source, source_offset as libc::off_t, 0, libc::POSIX_FADV_SEQUENTIAL) };
+            }
+            Dropper {
+                source,
+                dest: dest.as_raw_fd(),
+                source_offset,
+                dest_offset,
+                copied: 0,
+                flushed: 0,
+                dropped: 0,
+            }
+        };
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        let dropper = {
+            let _ = (source, dest);
+            Dropper {
+                source_offset,
+                dest_offset,
+                copied: 0,
+                flushed: 0,
+                dropped: 0,
+            }
+        };
+        dropper
+    }
+
+    /// Counts `bytes` more as copied. Every [`WINDOW`], the writeback of the
+    /// last window is started and the one before it, which has had a window's
+    /// time to reach the disk, is dropped.
+    pub(crate) fn copied(&mut self, bytes: u64) {
+        self.copied += bytes;
+        if self.copied - self.flushed < WINDOW || !self.dropping() {
+            return;
+        }
+        self.start_writeback();
+        self.drop_range(self.flushed);
+        self.flushed = self.copied;
+    }
+
+    /// Drops the whole copy once it is complete, including ranges that were
+    /// still being written back when they were first dropped. Destination
+    /// pages that haven't reached the disk yet, because the copy wasn't
+    /// synced, stay cached until they do.
+    pub(crate) fn finish(&mut self) {
+        if !self.dropping() {
+            return;
+        }
+        self.start_writeback();
+        self.flushed = self.copied;
+        self.dropped = 0;
+        self.drop_range(self.copied);
+    }
+
+    fn dropping(&self) -> bool {
+        self.copied >= DROP_AFTER && ENABLED.load(Ordering::Relaxed)
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn start_writeback(&self) {
+        let len = self.copied - self.flushed;
+        // SAFETY: the caller keeps `dest` open; the call only starts
+        // writing back pages that are already in the cache.
+        unsafe {
+            libc::sync_file_range(
+                self.dest,
+                (self.dest_offset + self.flushed) as libc::off64_t,
+                len as libc::off64_t,
+                libc::SYNC_FILE_RANGE_WRITE,
+            )
+        };
+    }
+
+    /// Drops everything copied up to `end` that wasn't dropped yet.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn drop_range(&mut self, end: u64) {
+        // A length of 0 would mean up to the end of the file.
+        if end == self.dropped {
+            return;
+        }
+        let len = (end - self.dropped) as libc::off_t;
+        // SAFETY: the caller keeps both files open; the calls only advise
+        // the kernel.
+        unsafe {
+            libc::posix_fadvise(self.source, (self.source_offset + self.dropped) as libc::off_t, len, libc::POSIX_FADV_DONTNEED);
+            libc::posix_fadvise(self.dest, (self.dest_offset + self.dropped) as libc::off_t, len, libc::POSIX_FADV_DONTNEED);
+        }
+        self.dropped = end;
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn start_writeback(&self) {}
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn drop_range(&mut self, end: u64) {
+        let _ = (self.source_offset, self.dest_offset);
+        self.dropped = end;
+    }
+}
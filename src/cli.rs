@@ -1,37 +1,357 @@
-use crate::{cmd_config, cmd_local, cmd_s3};
+use crate::compress::{parse_compression, Compression};
+use crate::encrypt::{parse_decryption, parse_encryption, Decryption, Encryption};
+use crate::diff::DiffOptions;
+use crate::engine::CopyOptions;
+use crate::hash::HashAlgorithm;
+use crate::job::JobPlan;
+use crate::manifest::ManifestFormat;
+use crate::progress::{self, ProgressMode, Theme};
+use crate::utils::{
+    is_portable_name_char, parse_duration, parse_id_map, parse_locked_policy, parse_modes, parse_owner, parse_preserve,
+    parse_size, IdMap, LockedPolicy, Modes, NormalForm, Owner, Preserve,
+};
+use crate::config::{self, Config};
+use crate::sftp::SftpLocation;
+use crate::{cmd_archive, cmd_bench, cmd_config, cmd_daemon, cmd_dedupe, cmd_diff, cmd_du, cmd_hash, cmd_local, cmd_remote, cmd_resume, cmd_s3, cmd_undo, cmd_verify, cmd_watch, exit, failures, http, tar, webdav};
 use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
 use clap_verbosity_flag::Verbosity;
+use colored::Colorize;
+use std::io::IsTerminal;
 use std::thread;
 
 // ─── CLI arguments ────────────────────────────────────────────────────────────
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, after_help = exit::HELP)]
 #[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
     #[command(subcommand)]
     command: Option<Command>,
 
-    /// Source files or directories
+    /// Source files or directories; these may also be on a single SFTP or WebDAV server, or http(s):// URLs to download
     #[arg(required = true)]
     source: Vec<String>,
 
-    /// Destination directory (local path or remote:bucket/prefix)
+    /// Destination directory (local path, remote:bucket/prefix, name:path for an SFTP or WebDAV remote in the config file, an SFTP server as [user@]host:/path or sftp://[user@]host[:port]/path, or a WebDAV server as dav[s]://[user@]host/path)
     #[arg(required = true)]
     destination: Option<String>,
 
+    /// Also write every copied file to DIR, laid out as in the destination, reading each source file only once for all of them; may be repeated (local copies only)
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["extract", "to_archive", "transactional", "undo", "delta", "checksum", "skip_identical", "compress", "encrypt", "decrypt", "write_hashes", "verify", "manifest", "split_oversize", "direct_io"])]
+    also_to: Vec<std::path::PathBuf>,
+
     /// Enable recursive copying for directories
     #[arg(short, long, default_value_t = false)]
     recursive: bool,
 
+    /// Read the sources from a point-in-time snapshot of their filesystem (a btrfs subvolume or LVM volume on Linux, a VSS shadow copy on Windows), so files that change during the copy are copied as they were when it started; needs root (local copies only)
+    #[arg(long, default_value_t = false)]
+    snapshot: bool,
+
+    /// Follow symbolic links in the sources and copy the files and directories they point to
+    #[arg(short = 'L', long, default_value_t = false)]
+    dereference: bool,
+
+    /// Whether recursive copies take hidden files and directories: dotfiles, and on Windows those with the hidden or system attribute; sources named on the command line are always copied
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t = HiddenChoice::Include)]
+    hidden: HiddenChoice,
+
+    /// Carry attributes of the sources over to the destination: timestamps (of files and directories), ownership, context (SELinux labels), capabilities (setcap file capabilities) or all; a bare --preserve means timestamps (local copies only)
+    #[arg(long, value_name = "ATTRS", value_parser = parse_preserve, num_args = 0..=1, require_equals = true, default_missing_value = "timestamps")]
+    preserve: Option<Preserve>,
+
+    /// Set the owner of every copied file and directory, e.g. www-data:www-data or 1000 (local copies only)
+    #[arg(long, value_name = "USER[:GROUP]", value_parser = parse_owner)]
+    chown: Option<Owner>,
+
+    /// Set the permissions of every copied file and directory: an octal MODE, or separate modes like D755,F644 (local copies only)
+    #[arg(long, value_name = "MODE", value_parser = parse_modes)]
+    chmod: Option<Modes>,
+
+    /// Translate source user ids when preserving ownership, e.g. 1000:100000 or 0:100000:65536 for a range; may be repeated and implies --preserve=ownership (local copies only)
+    #[arg(long, value_name = "FROM:TO[:COUNT]", value_parser = parse_id_map)]
+    uid_map: Vec<IdMap>,
+
+    /// Translate source group ids like --uid-map does for users (local copies only)
+    #[arg(long, value_name = "FROM:TO[:COUNT]", value_parser = parse_id_map)]
+    gid_map: Vec<IdMap>,
+
+    /// Recreate FIFOs and sockets at the destination instead of skipping them (local copies only)
+    #[arg(long, default_value_t = false)]
+    special: bool,
+
+    /// Recreate character and block device nodes at the destination instead of skipping them; needs root (local copies only)
+    #[arg(long, default_value_t = false)]
+    devices: bool,
+
     /// Parallel level (number of concurrent copy operations)
-    #[arg(short, long, default_value_t = 4, value_parser = parse_parallel)]
+    #[arg(short, long, env = "CP2_PARALLEL", default_value_t = 4, value_parser = parse_parallel)]
     parallel: usize,
 
+    /// Size of the read/write buffer used for each large file (e.g. 1M, 64M) (local copies only)
+    #[arg(long, value_name = "SIZE", env = "CP2_BUFFER_SIZE", default_value = "8M", value_parser = parse_buffer_size)]
+    buffer_size: usize,
+
     /// Sync each file to disk after copying (slower, but crash-safe; local copies only)
     #[arg(short = 'S', long, default_value_t = false)]
     sync: bool,
 
+    /// Limit the combined transfer rate to RATE per second (e.g. 20M); adjust it live with +/- and pause with p/r while bars are shown
+    #[arg(long, value_name = "RATE", env = "CP2_BWLIMIT", value_parser = parse_size)]
+    bwlimit: Option<u64>,
+
+    /// Retry a file up to N times after a transient error (e.g. EIO or a timeout on a network share)
+    #[arg(long, value_name = "N", env = "CP2_RETRIES", default_value_t = 0)]
+    retries: u32,
+
+    /// Delay before the first retry (e.g. 500ms, 2s); doubled for every further retry
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "1s")]
+    retry_delay: std::time::Duration,
+
+    /// Stop the whole run at the first failed file, aborting the copies in flight
+    #[arg(long, default_value_t = false, overrides_with = "continue_on_error")]
+    fail_fast: bool,
+
+    /// Keep going after a failed file and report all failures at the end (default)
+    #[arg(long, default_value_t = false, overrides_with = "fail_fast")]
+    continue_on_error: bool,
+
+    /// What to do with files another process holds open exclusively (Windows): skip them with a warning, wait for them to be released (default timeout 30s), or fail
+    #[arg(long, value_name = "POLICY", value_parser = parse_locked_policy, default_value = "fail")]
+    locked: LockedPolicy,
+
+    /// Keep partially written files when a copy fails or is interrupted instead of deleting them (local copies only)
+    #[arg(long, default_value_t = false)]
+    keep_partial: bool,
+
+    /// Write files under temporary names and rename them into place only once every file was copied; if any fails, or the copy is interrupted, remove everything written so the destination is left as it was (local copies only)
+    #[arg(long, default_value_t = false, conflicts_with_all = ["keep_partial", "delta", "split_oversize", "hash_sidecars"])]
+    transactional: bool,
+
+    /// Record the copy for cp2 undo, moving the files it overwrites into a .cp2-undo directory in the destination until the next --undo copy (local copies only)
+    #[arg(long, default_value_t = false)]
+    undo: bool,
+
+    /// Unpack tar archive sources (.tar, .tar.gz, .tgz, .tar.zst, .tar.xz, .tar.bz2) into the destination directory; compressed ones need gzip, zstd, xz or bzip2 installed
+    #[arg(long, default_value_t = false, conflicts_with = "to_archive")]
+    extract: bool,
+
+    /// Write the sources into a tar archive at the destination instead of copying them, as when it ends in .tar
+    #[arg(long, default_value_t = false)]
+    to_archive: bool,
+
+    /// Update files that already exist at the destination in place, writing only the blocks that changed, like rsync (local copies only)
+    #[arg(long, default_value_t = false)]
+    delta: bool,
+
+    /// Skip files whose destination already has the same size and SHA-256 digest, like rsync -c, for destinations whose timestamps can't be trusted (local copies only)
+    #[arg(short = 'c', long, default_value_t = false, conflicts_with_all = ["compress", "encrypt", "decrypt"])]
+    checksum: bool,
+
+    /// Skip files whose destination already has the same size and is at least as new, so re-running an interrupted copy only copies what is missing or changed (local copies only)
+    #[arg(long, default_value_t = false, conflicts_with_all = ["compress", "encrypt", "decrypt"])]
+    skip_identical: bool,
+
+    /// Compress each copied file with zstd (level 1-19, default 3) or gzip (level 1-9, default 6), adding a .zst or .gz suffix to its name; needs zstd or gzip installed (local copies only)
+    #[arg(long, value_name = "zstd[:LEVEL]|gzip[:LEVEL]", value_parser = parse_compression, conflicts_with = "delta")]
+    compress: Option<Compression>,
+
+    /// Encrypt each copied file with age for a recipient (an age or SSH public key, or a file of them), or with gpg and the passphrase in FILE, adding a .age or .gpg suffix to its name (local copies only)
+    #[arg(long, value_name = "age:RECIPIENT|passphrase:FILE", value_parser = parse_encryption, conflicts_with = "delta")]
+    encrypt: Option<Encryption>,
+
+    /// Decrypt each copied file encrypted with --encrypt, given the age identity file or the passphrase file, removing its .age or .gpg suffix (local copies only)
+    #[arg(long, value_name = "age:IDENTITY_FILE|passphrase:FILE", value_parser = parse_decryption, conflicts_with = "delta")]
+    decrypt: Option<Decryption>,
+
+    /// Compute each file's digest while copying it and list them in SHA256SUMS (or B3SUMS) at the destination, for checking later with sha256sum -c or b3sum -c (local copies only)
+    #[arg(long, value_enum, value_name = "ALGO", num_args = 0..=1, require_equals = true, default_missing_value = "sha256", conflicts_with_all = ["delta", "compress", "encrypt", "decrypt", "split_oversize"])]
+    write_hashes: Option<HashAlgorithm>,
+
+    /// With --write-hashes, write each digest next to its file as <file>.sha256 (or .b3) instead of one list at the destination
+    #[arg(long, default_value_t = false, requires = "write_hashes")]
+    hash_sidecars: bool,
+
+    /// Read each copied file back and compare its digest with the one computed from the source while copying it, failing files that differ; digests are SHA-256 unless --write-hashes picks another (local copies only)
+    #[arg(long, default_value_t = false, conflicts_with_all = ["delta", "compress", "encrypt", "decrypt", "split_oversize"])]
+    verify: bool,
+
+    /// Write the relative path, size, modification time and digest of every copied file to FILE as JSON at the end of the run, for checking later with verify --manifest; digests are SHA-256 unless --write-hashes picks another (local copies only)
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["delta", "compress", "encrypt", "decrypt", "split_oversize"])]
+    manifest: Option<std::path::PathBuf>,
+
+    /// Format of the --manifest: cp2's JSON, hashdeep's CSV (for hashdeep -a -k; SHA-256 only), BSD-style "SHA256 (path) = digest" lines or GNU-style sha256sum lines
+    #[arg(long, value_enum, value_name = "FORMAT", default_value_t = ManifestFormat::Json, requires = "manifest")]
+    manifest_format: ManifestFormat,
+
+    /// Don't lock the destination directory against other cp2 runs writing into it at the same time (local copies only)
+    #[arg(long, default_value_t = false)]
+    no_lock: bool,
+
+    /// Wait for another cp2 run writing into the destination to finish instead of failing (local copies only)
+    #[arg(long, default_value_t = false, conflicts_with = "no_lock")]
+    wait_lock: bool,
+
+    /// Don't ask for confirmation when the preflight check finds unreadable sources or files that will be overwritten
+    #[arg(short, long, default_value_t = false)]
+    yes: bool,
+
+    /// What to do with files whose destination names differ only in letter case when the destination filesystem is case-insensitive (local copies only)
+    #[arg(long, value_enum, value_name = "POLICY", default_value_t = CaseConflict::Error)]
+    case_conflict: CaseConflict,
+
+    /// Replace characters that NTFS and FAT reject in file names (\:*?"<>|, control characters, trailing dots and spaces) and rename reserved names like CON; renames are listed in --report (local copies only)
+    #[arg(long, default_value_t = false)]
+    sanitize_names: bool,
+
+    /// Replacement used by --sanitize-names for each invalid character
+    #[arg(long, value_name = "STR", default_value = "_", value_parser = parse_replacement)]
+    sanitize_replacement: String,
+
+    /// Convert destination file names to a Unicode normalization form, e.g. NFC so names copied from macOS match those created on Linux (local copies only)
+    #[arg(long, value_enum, value_name = "FORM", default_value_t = NormalizeChoice::None)]
+    normalize: NormalizeChoice,
+
+    /// Write files too large for the destination filesystem (e.g. over 4 GiB on FAT32) as numbered chunks plus a manifest for reassembly, instead of refusing to copy (local copies only)
+    #[arg(long, default_value_t = false)]
+    split_oversize: bool,
+
+    /// Treat files larger than SIZE as too large for the destination, for filesystems whose limit isn't detected (local copies only)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_file_size: Option<u64>,
+
+    /// Leave out files larger than SIZE (e.g. 2G), listing them and counting them as skipped in the summary (local copies only)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    skip_larger_than: Option<u64>,
+
+    /// Start copying even if the destination doesn't seem to have enough free space (local copies only)
+    #[arg(long, default_value_t = false)]
+    ignore_space_check: bool,
+
+    /// Split files at least this large (e.g. 4G) into ranges copied in parallel (local copies only)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    split_threshold: Option<u64>,
+
+    /// Read and write files with O_DIRECT, bypassing the page cache so that copying files much larger than RAM doesn't evict everything else cached; Linux only, and filesystems that refuse it are copied as usual (local copies only)
+    #[arg(long, default_value_t = false, conflicts_with_all = ["delta", "compress", "encrypt", "decrypt", "split_oversize", "write_hashes", "verify", "manifest"])]
+    direct_io: bool,
+
+    /// Drop what large files leave in the page cache as they are copied, so that copying hundreds of gigabytes doesn't evict every other program's cached data; auto does so for files past their first 64M, never keeps everything cached (local copies only)
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t = DropCache::Auto)]
+    drop_cache: DropCache,
+
+    /// How files under 64K are copied: async hands every open, read and write to tokio's thread pool; threads copies whole batches of them with blocking calls on threads of their own, updating progress once per file (local copies only)
+    #[arg(long, value_enum, value_name = "ENGINE", default_value_t = Engine::Async)]
+    engine: Engine,
+
+    /// Tune for SMB/CIFS shares: a 4M buffer unless --buffer-size is given, and at most 2 directories scanned at once; auto applies it when a source or the destination is on one (local copies only)
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t = SmbTuning::Auto)]
+    smb_tuning: SmbTuning,
+
+    /// Download HTTP(S) sources of at least 16M in up to N ranges fetched in parallel, when the server supports range requests
+    #[arg(long, value_name = "N", default_value_t = 4, value_parser = parse_parallel)]
+    segments: usize,
+
+    /// Command used to reach SFTP servers, with any options of its own (e.g. "ssh -i ~/.ssh/deploy"); cp2 appends the host and asks for the sftp subsystem
+    #[arg(long, value_name = "CMD", env = "CP2_SSH_COMMAND", default_value = "ssh")]
+    ssh_command: String,
+
+    /// Compress file contents in flight with zstd on SFTP transfers, trading CPU for bandwidth on slow links; needs zstd on both ends, and transfers stay uncompressed otherwise
+    #[arg(long, default_value_t = false)]
+    compress_transfer: bool,
+
+    /// When to color messages and progress bars; auto colors them when stderr is a terminal and NO_COLOR isn't set
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+
+    /// Display sizes in binary units (KiB, MiB, GiB; powers of 1024) or SI units (kB, MB, GB; powers of 1000), as shown by Finder and `du --si`
+    #[arg(long, value_enum, default_value_t = UnitsChoice::Binary)]
+    units: UnitsChoice,
+
+    /// Built-in look of the progress bars; ascii avoids Unicode characters for terminals and logs that mangle them
+    #[arg(long, value_enum, env = "CP2_THEME", default_value_t = Theme::Classic)]
+    theme: Theme,
+
+    /// Template of the overall progress bar in indicatif syntax, e.g. "{wide_bar} {percent}% {bytes}/{total_bytes} ({smooth_eta})"; also offers {files}, {smooth_eta} and {net}
+    #[arg(long, value_name = "TEMPLATE", env = "CP2_BAR_TEMPLATE", value_parser = progress::parse_template)]
+    bar_template: Option<String>,
+
+    /// Template of the per-file progress bars in indicatif syntax, e.g. "  {msg:<30} {percent:>3}%"
+    #[arg(long, value_name = "TEMPLATE", env = "CP2_FILE_BAR_TEMPLATE", value_parser = progress::parse_template)]
+    file_bar_template: Option<String>,
+
+    /// Show at most N per-file bars at once; further files are counted on one "… and N more files" line and get a bar as others finish
+    #[arg(long, value_name = "N", default_value_t = 10)]
+    max_bars: usize,
+
+    /// How to display progress: animated bars, or periodic plain-text lines for logs; given explicitly, progress is shown even in quiet mode
+    #[arg(long, value_enum, env = "CP2_PROGRESS", default_value_t = ProgressChoice::Auto)]
+    progress: ProgressChoice,
+
+    /// Don't display progress, while still logging at the chosen verbosity; wins over --progress and CP2_PROGRESS
+    #[arg(long, default_value_t = false)]
+    no_progress: bool,
+
+    /// Show a full-screen dashboard instead of bars: overall figures, a throughput graph, the files being copied and the latest errors; needs stderr to be a terminal
+    #[arg(long, default_value_t = false, conflicts_with_all = ["no_progress", "json"])]
+    tui: bool,
+
+    /// Mirror overall progress to the terminal tab/taskbar with OSC 9;4 sequences
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t = TermProgressChoice::Auto)]
+    term_progress: TermProgressChoice,
+
+    /// How to print errors on stderr: colored text, or one JSON object per line with the path, operation, kind, OS error code and message, for scripts
+    #[arg(long, value_enum, value_name = "FORMAT", default_value_t = ErrorFormat::Text)]
+    errors: ErrorFormat,
+
+    /// Emit machine-readable progress events as JSON Lines on stdout
+    #[arg(long, default_value_t = false)]
+    json: bool,
+
+    /// Publish the JSON Lines event stream to clients of a Unix socket (named pipe on Windows) at PATH
+    #[arg(long, value_name = "PATH")]
+    progress_socket: Option<std::path::PathBuf>,
+
+    /// Print the end-of-run summary even in quiet mode
+    #[arg(long, default_value_t = false, conflicts_with = "no_summary")]
+    summary: bool,
+
+    /// Don't print the end-of-run summary
+    #[arg(long, default_value_t = false)]
+    no_summary: bool,
+
+    /// Record how long each file spent opening, reading, writing, syncing and setting metadata, and print a breakdown of where the time went at the end (local copies only)
+    #[arg(long, default_value_t = false)]
+    timings: bool,
+
+    /// Show a desktop notification with the totals when the run finishes
+    #[arg(long, default_value_t = false)]
+    notify: bool,
+
+    /// Run CMD through the shell before each file is copied and skip the file if it exits non-zero; {src} and {dest} expand to the quoted paths
+    #[arg(long, value_name = "CMD")]
+    pre_file_cmd: Option<String>,
+
+    /// Run CMD through the shell after each file is copied; {src} and {dest} expand to the quoted paths
+    #[arg(long, value_name = "CMD")]
+    post_file_cmd: Option<String>,
+
+    /// POST the run summary as JSON to URL when the run finishes
+    #[arg(long, value_name = "URL")]
+    on_complete_url: Option<reqwest::Url>,
+
+    /// Run CMD through the shell when the run finishes, with the summary JSON on stdin
+    #[arg(long, value_name = "CMD")]
+    on_complete_cmd: Option<String>,
+
+    /// Append a timestamped record for every file to PATH
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<std::path::PathBuf>,
+
+    /// Write a per-file report of the run to PATH (CSV if it ends in .csv, JSON otherwise)
+    #[arg(long, value_name = "PATH")]
+    report: Option<std::path::PathBuf>,
+
     #[command(flatten)]
     verbosity: Verbosity,
 }
@@ -43,6 +363,129 @@ enum Command {
         #[command(subcommand)]
         action: ConfigAction,
     },
+    /// Check files against the checksums recorded for them (e.g. by --write-hashes), or compare a source tree with its copy
+    Verify {
+        /// DEST to check against its checksums, or SOURCE DEST to compare the two trees
+        #[arg(value_name = "PATH", num_args = 1..=2, required = true)]
+        paths: Vec<std::path::PathBuf>,
+        /// Checksum file to check DEST against, in sha256sum/b3sum format; by default SHA256SUMS or B3SUMS in DEST, or else the .sha256/.b3 files next to each file
+        #[arg(long, value_name = "FILE")]
+        sums: Option<std::path::PathBuf>,
+        /// Manifest written by --manifest to check DEST against, by size and digest
+        #[arg(long, value_name = "FILE", conflicts_with = "sums")]
+        manifest: Option<std::path::PathBuf>,
+        /// Compare file contents by digest when comparing trees; with checksums, the algorithm they use if the file name doesn't tell (B3SUMS and *.b3 are BLAKE3, anything else SHA-256)
+        #[arg(long, value_enum, value_name = "ALGO", num_args = 0..=1, require_equals = true, default_missing_value = "sha256")]
+        hash: Option<HashAlgorithm>,
+        /// When comparing trees, only compare sizes, not modification times
+        #[arg(long, default_value_t = false, conflicts_with = "hash")]
+        size_only: bool,
+    },
+    /// List the files added, removed or modified in DEST compared with SOURCE; exits with 1 if there are any
+    Diff {
+        /// Tree (or file) to compare against
+        source: std::path::PathBuf,
+        /// Tree (or file) compared with SOURCE
+        destination: std::path::PathBuf,
+        /// Print each change as a JSON object on its own line, e.g. {"change":"modified","path":"a.txt","reasons":["size"],"detail":"3 vs 4 bytes"}
+        #[arg(long, default_value_t = false)]
+        json: bool,
+        /// Compare file contents by digest instead of modification times
+        #[arg(long, value_enum, value_name = "ALGO", num_args = 0..=1, require_equals = true, default_missing_value = "sha256")]
+        hash: Option<HashAlgorithm>,
+        /// Only compare sizes, not modification times
+        #[arg(long, default_value_t = false, conflicts_with = "hash")]
+        size_only: bool,
+    },
+    /// Print the digest of every file at or below PATH in sha256sum/b3sum format, for checking later with verify
+    Hash {
+        /// Files or directories to hash; directories are hashed recursively, leaving out their SHA256SUMS/B3SUMS and .sha256/.b3 files
+        #[arg(value_name = "PATH", required = true)]
+        paths: Vec<std::path::PathBuf>,
+        /// Digest to compute
+        #[arg(long, value_enum, value_name = "ALGO", default_value = "sha256")]
+        hash: HashAlgorithm,
+        /// Number of files hashed at once
+        #[arg(short, long, env = "CP2_PARALLEL", default_value_t = 4, value_parser = parse_parallel)]
+        parallel: usize,
+    },
+    /// Print how many files and bytes each PATH holds, i.e. what copying it would take
+    Du {
+        /// Files or directories to measure; directories are walked recursively
+        #[arg(value_name = "PATH", required = true)]
+        paths: Vec<std::path::PathBuf>,
+        /// Print each path as a JSON object on its own line, e.g. {"path":"photos","files":1204,"dirs":12,"bytes":5368709120}
+        #[arg(long, default_value_t = false)]
+        json: bool,
+        /// Follow symbolic links, as copies with -L do
+        #[arg(short = 'L', long, default_value_t = false)]
+        dereference: bool,
+        /// Number of directories read at once
+        #[arg(short, long, env = "CP2_PARALLEL", default_value_t = 4, value_parser = parse_parallel)]
+        parallel: usize,
+    },
+    /// Time copies of SRC (or of a generated workload) into DEST with various buffer sizes, parallel levels and engines, and recommend the fastest settings for the config file
+    Bench {
+        /// SRC DEST to copy SRC into DEST, or just DEST to copy a generated workload
+        #[arg(value_name = "PATH", num_args = 1..=2, required = true)]
+        paths: Vec<std::path::PathBuf>,
+        /// Size of the generated workload, half in one large file and half in 64K files
+        #[arg(long, value_name = "SIZE", default_value = "256M", value_parser = parse_size)]
+        size: u64,
+    },
+    /// Continue an interrupted copy where it stopped, or list the copies that can be continued
+    Resume {
+        /// ID of the copy, as printed when it was interrupted, or the path of its job file
+        job: Option<String>,
+    },
+    /// Reverse the last local copy: remove the files and directories it created and put back the files it overwrote
+    Undo {
+        /// Only list what would be removed and restored
+        #[arg(short = 'n', long, default_value_t = false)]
+        dry_run: bool,
+    },
+    /// Run copy jobs submitted as JSON lines on a local socket, one at a time and highest priority first
+    Daemon {
+        /// Socket to listen on [default: $XDG_RUNTIME_DIR/cp2.sock]
+        #[arg(long, value_name = "PATH")]
+        socket: Option<std::path::PathBuf>,
+        /// Number of files of a job copied at once
+        #[arg(short, long, env = "CP2_PARALLEL", default_value_t = 4, value_parser = parse_parallel)]
+        parallel: usize,
+        /// Limit the combined transfer rate of all jobs to RATE per second (e.g. 20M)
+        #[arg(long, value_name = "RATE", env = "CP2_BWLIMIT", value_parser = parse_size)]
+        bwlimit: Option<u64>,
+    },
+    /// Copy SRC into DEST like `cp2 -r`, then keep copying the files that are created or changed below SRC until Ctrl-C
+    Watch {
+        /// Directory to watch
+        source: std::path::PathBuf,
+        /// Directory to copy it into
+        destination: std::path::PathBuf,
+        /// Wait until no change arrived for this long before copying, so files written in several goes are copied once
+        #[arg(long, value_name = "DURATION", value_parser = parse_duration, default_value = "500ms")]
+        debounce: std::time::Duration,
+        /// Rescan SRC this often instead of relying on filesystem notifications, which don't report changes made by other machines on network filesystems
+        #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+        poll: Option<std::time::Duration>,
+        /// Number of files copied at once
+        #[arg(short, long, env = "CP2_PARALLEL", default_value_t = 4, value_parser = parse_parallel)]
+        parallel: usize,
+    },
+    /// Find files with the same contents below DIR and replace all but one of each with hard links
+    Dedupe {
+        /// Directory to deduplicate
+        dir: std::path::PathBuf,
+        /// Replace duplicates with reflinks (copy-on-write clones on Btrfs, XFS or APFS) instead of hard links, so they stay independent files
+        #[arg(long, default_value_t = false)]
+        reflink: bool,
+        /// Only list the duplicates and the space linking them would save
+        #[arg(short = 'n', long, default_value_t = false)]
+        dry_run: bool,
+        /// Number of files hashed at once
+        #[arg(short, long, env = "CP2_PARALLEL", default_value_t = 4, value_parser = parse_parallel)]
+        parallel: usize,
+    },
 }
 
 #[derive(Subcommand, Debug)]
@@ -67,6 +510,233 @@ pub(crate) enum ConfigAction {
     },
 }
 
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ProgressChoice {
+    /// Bars when stderr is a terminal, plain lines otherwise
+    Auto,
+    /// Animated progress bars
+    Bars,
+    /// Periodic single-line status updates
+    Plain,
+}
+
+/// How to handle destination names that collide once letter case is ignored.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CaseConflict {
+    /// Report the later files as errors and don't copy them
+    Error,
+    /// Copy the later files under a numbered name, e.g. `README (1).md`
+    Rename,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum HiddenChoice {
+    /// Copy hidden files like any other
+    Include,
+    /// Leave hidden files and directories out
+    Exclude,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum NormalizeChoice {
+    /// Composed form, used by most Linux and Windows tools
+    Nfc,
+    /// Decomposed form, used by macOS
+    Nfd,
+    /// Keep names as they are
+    None,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ColorChoice {
+    /// When stderr is a terminal and NO_COLOR is not set
+    Auto,
+    /// Always, even when redirected
+    Always,
+    /// Never
+    Never,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum UnitsChoice {
+    /// KiB, MiB, GiB
+    Binary,
+    /// kB, MB, GB
+    Si,
+}
+
+/// How errors are printed on stderr.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ErrorFormat {
+    /// Colored messages, and a report grouped by kind at the end
+    Text,
+    /// One JSON object per error
+    Json,
+}
+
+/// When to tune a local copy for SMB/CIFS shares.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum SmbTuning {
+    /// When a source or the destination is on an SMB share
+    Auto,
+    /// Always, e.g. for shares that aren't detected
+    On,
+    /// Never
+    Off,
+}
+
+/// When a local copy drops the files it copies from the page cache.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum DropCache {
+    /// Once a file is larger than 64M
+    Auto,
+    /// Never
+    Never,
+}
+
+/// How a local copy copies its small files.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Engine {
+    /// One async task per batch, with tokio's file I/O
+    Async,
+    /// One blocking thread per batch, with synchronous file I/O
+    Threads,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum TermProgressChoice {
+    /// When progress is displayed on a terminal
+    Auto,
+    /// Never
+    Off,
+}
+
+/// Settings shared by the local, S3 and SFTP copy commands.
+pub(crate) struct RunOptions {
+    /// Settings shared with library users of [`crate::copy`].
+    pub(crate) copy: CopyOptions,
+    /// Owner set on every copy with `--chown`.
+    pub(crate) chown: Option<Owner>,
+    /// Permissions set on every copy with `--chmod`.
+    pub(crate) chmod: Modes,
+    /// Translations of source user ids when preserving ownership.
+    pub(crate) uid_map: Vec<IdMap>,
+    /// Translations of source group ids when preserving ownership.
+    pub(crate) gid_map: Vec<IdMap>,
+    /// Read the sources from snapshots of their filesystems.
+    pub(crate) snapshot: bool,
+    /// Recreate FIFOs and sockets instead of skipping them.
+    pub(crate) special: bool,
+    /// Recreate device nodes instead of skipping them.
+    pub(crate) devices: bool,
+    pub(crate) split_threshold: Option<u64>,
+    /// Copy file contents with O_DIRECT.
+    pub(crate) direct_io: bool,
+    /// Whether large files are dropped from the page cache as they are copied.
+    pub(crate) drop_cache: DropCache,
+    /// How files small enough to be batched are copied.
+    pub(crate) engine: Engine,
+    /// Initial bandwidth limit in bytes per second.
+    pub(crate) bwlimit: Option<u64>,
+    /// Retries per file after a transient error.
+    pub(crate) retries: u32,
+    /// Base delay of the exponential retry backoff.
+    pub(crate) retry_delay: std::time::Duration,
+    /// Cancel the run at the first failed file instead of carrying on.
+    pub(crate) fail_fast: bool,
+    /// What to do with sources locked by another process.
+    pub(crate) locked: LockedPolicy,
+    /// Ask before copying when the preflight check finds problems.
+    pub(crate) interactive: bool,
+    /// Lock the destination against other runs writing into it.
+    pub(crate) lock: bool,
+    /// Wait for a run holding the destination lock instead of failing.
+    pub(crate) wait_lock: bool,
+    /// How to handle destinations that collide on a case-insensitive filesystem.
+    pub(crate) case_conflict: CaseConflict,
+    /// Replacement for invalid characters when `--sanitize-names` is given.
+    pub(crate) sanitize_names: Option<String>,
+    /// Normalization form applied to destination names.
+    pub(crate) normalize: Option<NormalForm>,
+    /// Write files over the destination's size limit as chunks instead of refusing.
+    pub(crate) split_oversize: bool,
+    /// File size limit of the destination given with `--max-file-size`.
+    pub(crate) max_file_size: Option<u64>,
+    /// Leave out files larger than this many bytes.
+    pub(crate) skip_larger_than: Option<u64>,
+    /// Only warn when the destination lacks the free space for the copy.
+    pub(crate) ignore_space_check: bool,
+    /// Leave partial destinations behind on failure or interruption.
+    pub(crate) keep_partial: bool,
+    /// Rename copies into place only once all succeeded, or else roll back.
+    pub(crate) transactional: bool,
+    /// Record the changes of the copy for `cp2 undo`.
+    pub(crate) undo: bool,
+    /// More directories every file is written to, from the same read.
+    pub(crate) also_to: Vec<std::path::PathBuf>,
+    /// Write only the changed blocks of existing destinations.
+    pub(crate) delta: bool,
+    /// Skip files whose destination has identical contents.
+    pub(crate) checksum: bool,
+    /// Leave out files whose destination has the same size and isn't older.
+    pub(crate) skip_identical: bool,
+    /// Compress destination files as they are written.
+    pub(crate) compress: Option<Compression>,
+    /// Encrypt destination files as they are written.
+    pub(crate) encrypt: Option<Encryption>,
+    /// Decrypt source files as they are copied.
+    pub(crate) decrypt: Option<Decryption>,
+    /// Digest copied files while copying them.
+    pub(crate) write_hashes: Option<HashAlgorithm>,
+    /// Write digests next to each file rather than in one list.
+    pub(crate) hash_sidecars: bool,
+    /// Read copied files back and check their digests.
+    pub(crate) verify: bool,
+    /// Where to write the manifest of the copied files.
+    pub(crate) manifest: Option<std::path::PathBuf>,
+    pub(crate) manifest_format: ManifestFormat,
+    pub(crate) progress: ProgressMode,
+    /// Mirror progress to the terminal tab/taskbar (OSC 9;4).
+    pub(crate) term_progress: bool,
+    /// Emit JSON Lines events on stdout.
+    pub(crate) json: bool,
+    /// `-q` was given: nothing but errors is printed.
+    pub(crate) quiet: bool,
+    /// Where to publish the event stream for other processes, if requested.
+    pub(crate) progress_socket: Option<std::path::PathBuf>,
+    /// Print the human-readable summary once the run finishes.
+    pub(crate) summary: bool,
+    /// Print how long copying spent in each phase once the run finishes.
+    pub(crate) timings: bool,
+    /// Where to write the per-file report, if requested.
+    pub(crate) report: Option<std::path::PathBuf>,
+    /// Where to append per-file log records, if requested.
+    pub(crate) log_file: Option<std::path::PathBuf>,
+    /// Show a desktop notification when the run finishes.
+    pub(crate) notify: bool,
+    /// Shell command template whose non-zero exit skips a file.
+    pub(crate) pre_file_cmd: Option<String>,
+    /// Shell command template run after each successfully copied file.
+    pub(crate) post_file_cmd: Option<String>,
+    /// Webhook that receives the summary JSON once the run finishes.
+    pub(crate) on_complete_url: Option<reqwest::Url>,
+    /// Shell command that receives the summary JSON on stdin once the run finishes.
+    pub(crate) on_complete_cmd: Option<String>,
+    /// Program and leading arguments used to reach SFTP servers.
+    pub(crate) ssh_command: Vec<String>,
+    /// Compress SFTP transfers with zstd when both ends have it.
+    pub(crate) compress_transfer: bool,
+    /// Most ranges an HTTP(S) download is split into.
+    pub(crate) segments: usize,
+    /// When to tune for SMB shares.
+    pub(crate) smb_tuning: SmbTuning,
+    /// `--buffer-size` was left at its default, so SMB tuning may change it.
+    pub(crate) default_buffer_size: bool,
+    /// The interrupted copy `cp2 resume` continues, whose files are copied
+    /// instead of scanning the sources again.
+    pub(crate) resume: Option<JobPlan>,
+}
+
 impl Args {
     fn command_with_dynamic_parallel() -> clap::Command {
         let max = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
@@ -75,6 +745,33 @@ impl Args {
     }
 }
 
+/// Takes the settings the command line and the environment leave at their
+/// defaults from the `[defaults]` section of the config file.
+fn apply_defaults(args: &mut Args, matches: &clap::ArgMatches, defaults: &config::Defaults) {
+    let unset = |id| matches!(matches.value_source(id), None | Some(clap::parser::ValueSource::DefaultValue));
+    if unset("parallel")
+        && let Some(parallel) = defaults.parallel
+    {
+        args.parallel = parallel;
+    }
+    if unset("buffer_size")
+        && let Some(ref size) = defaults.buffer_size
+    {
+        match parse_buffer_size(size) {
+            Ok(size) => args.buffer_size = size,
+            Err(e) => log::warn!("Ignoring buffer_size in the config file: {}", e),
+        }
+    }
+    if unset("split_threshold")
+        && let Some(ref size) = defaults.split_threshold
+    {
+        match parse_size(size) {
+            Ok(size) => args.split_threshold = Some(size),
+            Err(e) => log::warn!("Ignoring split_threshold in the config file: {}", e),
+        }
+    }
+}
+
 fn parse_parallel(s: &str) -> Result<usize, String> {
     let n: usize = s
         .parse()
@@ -85,6 +782,20 @@ fn parse_parallel(s: &str) -> Result<usize, String> {
     Ok(n)
 }
 
+fn parse_buffer_size(s: &str) -> Result<usize, String> {
+    match parse_size(s)? {
+        0 => Err("buffer size must be at least 1 byte".to_string()),
+        n => usize::try_from(n).map_err(|_| format!("`{s}` is too large")),
+    }
+}
+
+fn parse_replacement(s: &str) -> Result<String, String> {
+    if !s.chars().all(|c| c != '/' && is_portable_name_char(c)) {
+        return Err(format!("`{s}` is not valid in file names itself"));
+    }
+    Ok(s.to_string())
+}
+
 // ─── Destination type ─────────────────────────────────────────────────────────
 
 #[derive(Debug, PartialEq, Eq)]
@@ -98,11 +809,23 @@ pub enum Destination {
         /// Key prefix within the bucket (may be empty).
         prefix: String,
     },
+    /// A directory on an SFTP server: `[user@]host:/path` or
+    /// `sftp://[user@]host[:port]/path`.
+    Sftp(SftpLocation),
+    /// A collection on a WebDAV server, given as `dav://` or `davs://` and
+    /// held as the `http://` or `https://` URL it stands for.
+    WebDav(reqwest::Url),
 }
 
-/// Parses a destination string, distinguishing `remote:bucket/prefix` from a
-/// plain local path.
+/// Parses a destination string, distinguishing WebDAV and SFTP locations and
+/// `remote:bucket/prefix` from a plain local path.
 pub fn parse_destination(dest: &str) -> Destination {
+    if let Some(url) = webdav::parse_url(dest) {
+        return Destination::WebDav(url);
+    }
+    if let Some(location) = SftpLocation::parse(dest) {
+        return Destination::Sftp(location);
+    }
     if let Some(colon_pos) = dest.find(':') {
         let name = &dest[..colon_pos];
         // A remote name must be at least 2 characters and must not contain
@@ -127,61 +850,389 @@ pub fn parse_destination(dest: &str) -> Destination {
     Destination::Local(std::path::PathBuf::from(dest))
 }
 
+/// The sources `parse` accepts, if it accepts any; a mix of these and other
+/// sources is a usage error.
+fn remote_sources<T>(sources: &[String], parse: impl Fn(&str) -> Option<T>) -> Option<Vec<T>> {
+    let parsed: Vec<T> = sources.iter().filter_map(|s| parse(s)).collect();
+    if parsed.is_empty() {
+        return None;
+    }
+    if parsed.len() != sources.len() {
+        eprintln!("{}", "Remote sources can't be mixed with local sources or other kinds of remotes.".red());
+        std::process::exit(exit::USAGE);
+    }
+    Some(parsed)
+}
+
+/// The remotes in the config file, if any of `args` could name one. A config
+/// file that can't be read only matters to arguments that do, so it is
+/// reported as a warning.
+fn configured_remotes<'a>(mut args: impl Iterator<Item = &'a String>) -> Config {
+    if !args.any(|s| s.split_once(':').is_some_and(|(name, _)| name.len() >= 2 && !name.contains(['/', '\\']))) {
+        return Config::new();
+    }
+    config::load_config().unwrap_or_else(|e| {
+        log::warn!("Failed to load configuration: {}", e);
+        Config::new()
+    })
+}
+
+/// The SFTP or WebDAV remote `s` names, as `name:path`.
+fn configured_remote<'a>(remotes: &'a Config, s: &str) -> Option<(&'a str, &'a config::RemoteConfig)> {
+    let (name, _) = s.split_once(':')?;
+    let (name, remote) = remotes.get_key_value(name)?;
+    matches!(remote.remote_type.as_str(), "sftp" | "webdav").then_some((name.as_str(), remote))
+}
+
+/// Where `name:path` points when `name` is an SFTP or WebDAV remote in the
+/// config file. A remote missing its host or URL is a usage error.
+fn configured_location(remotes: &Config, s: &str) -> Option<Destination> {
+    let (name, remote) = configured_remote(remotes, s)?;
+    let path = &s[name.len() + 1..];
+    let location = match remote.remote_type.as_str() {
+        "sftp" => remote.sftp_location(path).map(Destination::Sftp),
+        _ => remote.webdav_url(path).map(Destination::WebDav),
+    };
+    match location {
+        Ok(location) => Some(location),
+        Err(e) => {
+            eprintln!("{} {}", format!("Remote \"{}\" is misconfigured:", name).red(), e);
+            std::process::exit(exit::USAGE);
+        }
+    }
+}
+
+/// The destination of a download, which must be a local directory.
+fn local_destination(destination: &str) -> std::path::PathBuf {
+    match parse_destination(destination) {
+        Destination::Local(path) => path,
+        _ => {
+            eprintln!("{}", "Remote sources can only be copied to a local destination.".red());
+            std::process::exit(exit::USAGE);
+        }
+    }
+}
+
 // ─── Entry point ──────────────────────────────────────────────────────────────
 
 pub async fn run() {
     let max = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
     log::debug!("Max parallel level (number of CPU cores): {}", max);
-    let matches = Args::command_with_dynamic_parallel().get_matches();
-    let args = Args::from_arg_matches(&matches).expect("parse args");
+    // Usage errors exit with 3 rather than clap's 2, which means a partial
+    // failure here.
+    let mut matches = match Args::command_with_dynamic_parallel().try_get_matches() {
+        Ok(matches) => matches,
+        Err(e) if e.use_stderr() => {
+            let _ = e.print();
+            std::process::exit(exit::USAGE);
+        }
+        Err(e) => e.exit(),
+    };
+    let mut args = Args::from_arg_matches(&matches).expect("parse args");
+
+    // `cp2 resume` carries on with the command line of the interrupted copy.
+    let mut resume = None;
+    if let Some(Command::Resume { ref job }) = args.command {
+        let Some(job) = job else {
+            cmd_resume::list();
+            return;
+        };
+        let plan = cmd_resume::load(job);
+        matches = match Args::command_with_dynamic_parallel().try_get_matches_from(&plan.args) {
+            Ok(matches) => matches,
+            Err(e) => {
+                let _ = e.print();
+                std::process::exit(exit::USAGE);
+            }
+        };
+        args = Args::from_arg_matches(&matches).expect("parse args");
+        resume = Some(plan);
+    }
     log::debug!("Parsed args: {:#?}", args);
 
     env_logger::Builder::new()
         .filter_level(args.verbosity.into())
         .init();
 
-    if let Some(Command::Config { action }) = args.command {
-        cmd_config::run(action);
-        return;
+    // Messages and bars both go to stderr, so that is what decides; `colored`
+    // would otherwise look at stdout and `console` at each stream separately.
+    let color = match args.color {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()) && std::io::stderr().is_terminal()
+        }
+    };
+    colored::control::set_override(color);
+    if args.errors == ErrorFormat::Json {
+        failures::set_json();
+    }
+    progress::set_si_units(args.units == UnitsChoice::Si);
+    progress::set_max_file_bars(args.max_bars);
+    progress::set_bar_look(progress::BarLook {
+        theme: args.theme,
+        main_template: args.bar_template.take(),
+        file_template: args.file_bar_template.take(),
+    });
+    console::set_colors_enabled(color);
+    console::set_colors_enabled_stderr(color);
+
+    match args.command {
+        Some(Command::Config { action }) => {
+            cmd_config::run(action);
+            return;
+        }
+        Some(Command::Verify { paths, sums, manifest, hash, size_only }) => {
+            match <[_; 2]>::try_from(paths) {
+                Ok([source, destination]) if sums.is_none() && manifest.is_none() => {
+                    cmd_verify::compare(source, destination, hash, size_only).await
+                }
+                Ok(_) => {
+                    eprintln!("{}", "--sums and --manifest check a single destination, not two trees".red());
+                    std::process::exit(exit::USAGE);
+                }
+                Err(mut paths) => cmd_verify::run(paths.remove(0), sums, manifest, hash).await,
+            }
+            return;
+        }
+        Some(Command::Diff { source, destination, json, hash, size_only }) => {
+            cmd_diff::run(source, destination, DiffOptions { hash, size_only }, json).await;
+            return;
+        }
+        Some(Command::Hash { paths, hash, parallel }) => {
+            cmd_hash::run(paths, hash, parallel.clamp(1, max)).await;
+            return;
+        }
+        Some(Command::Du { paths, json, dereference, parallel }) => {
+            cmd_du::run(paths, json, parallel.clamp(1, max), dereference).await;
+            return;
+        }
+        Some(Command::Dedupe { dir, reflink, dry_run, parallel }) => {
+            cmd_dedupe::run(dir, reflink, dry_run, parallel.clamp(1, max)).await;
+            return;
+        }
+        Some(Command::Bench { mut paths, size }) => {
+            let destination = paths.pop().expect("clap requires a path");
+            cmd_bench::run(paths.pop(), destination, size, max).await;
+            return;
+        }
+        Some(Command::Undo { dry_run }) => {
+            cmd_undo::run(dry_run);
+            return;
+        }
+        Some(Command::Daemon { socket, parallel, bwlimit }) => {
+            cmd_daemon::run(socket, parallel.clamp(1, max), bwlimit).await;
+            return;
+        }
+        Some(Command::Watch { source, destination, debounce, poll, parallel }) => {
+            cmd_watch::run(source, destination, debounce, poll, parallel.clamp(1, max)).await;
+            return;
+        }
+        Some(Command::Resume { .. }) => unreachable!("handled before the arguments are used"),
+        None => {}
+    }
+
+    let defaults = config::load_defaults().unwrap_or_else(|e| {
+        log::warn!("Failed to load configuration: {}", e);
+        config::Defaults::default()
+    });
+    apply_defaults(&mut args, &matches, &defaults);
+
+    if args.manifest_format == ManifestFormat::Hashdeep && args.write_hashes == Some(HashAlgorithm::Blake3) {
+        eprintln!("{}", "hashdeep has no BLAKE3; use --manifest-format json, bsd or gnu for BLAKE3 manifests".red());
+        std::process::exit(exit::USAGE);
     }
 
     let parallel = args.parallel.clamp(1, max);
     log::debug!("Using parallel level: {}", parallel);
 
-    let is_quiet = args.verbosity.is_silent();
+    // Bars redraw themselves with control sequences: indicatif draws nothing
+    // at all to a file or pipe, and a dumb terminal shows the sequences raw.
+    let interactive = std::io::stderr().is_terminal() && std::env::var_os("TERM").is_none_or(|term| term != "dumb");
+
+    // Quiet mode silences the logs and, unless progress was asked for with
+    // --progress or CP2_PROGRESS, the progress display with them.
+    let progress_chosen = !matches!(matches.value_source("progress"), None | Some(clap::parser::ValueSource::DefaultValue));
+
+    // JSON consumers drive their own UI, so bars are replaced by progress events.
+    let progress = if args.json {
+        ProgressMode::Json
+    } else if args.no_progress || (args.verbosity.is_silent() && !progress_chosen) {
+        ProgressMode::Hidden
+    } else if args.tui && interactive {
+        ProgressMode::Tui
+    } else {
+        match args.progress {
+            ProgressChoice::Plain => ProgressMode::Plain,
+            ProgressChoice::Bars | ProgressChoice::Auto if interactive => ProgressMode::Bars,
+            ProgressChoice::Bars => {
+                log::debug!("stderr is not an interactive terminal, showing plain progress instead of bars");
+                ProgressMode::Plain
+            }
+            ProgressChoice::Auto => ProgressMode::Plain,
+        }
+    };
+
+    let preserve = args.preserve.unwrap_or_default();
+    let mut options = RunOptions {
+        copy: CopyOptions::new()
+            .recursive(args.recursive)
+            .dereference(args.dereference)
+            .skip_hidden(args.hidden == HiddenChoice::Exclude)
+            .preserve(Preserve {
+                ownership: preserve.ownership || !args.uid_map.is_empty() || !args.gid_map.is_empty(),
+                ..preserve
+            })
+            .buffer_size(args.buffer_size)
+            .parallel(parallel)
+            .sync(args.sync),
+        chown: args.chown,
+        chmod: args.chmod.unwrap_or_default(),
+        uid_map: args.uid_map,
+        gid_map: args.gid_map,
+        snapshot: args.snapshot,
+        special: args.special,
+        devices: args.devices,
+        split_threshold: args.split_threshold,
+        direct_io: args.direct_io,
+        drop_cache: args.drop_cache,
+        engine: args.engine,
+        bwlimit: args.bwlimit,
+        retries: args.retries,
+        retry_delay: args.retry_delay,
+        fail_fast: args.fail_fast && !args.continue_on_error,
+        locked: args.locked,
+        interactive: !args.yes
+            && !args.json
+            && std::io::stdin().is_terminal()
+            && std::io::stderr().is_terminal(),
+        lock: !args.no_lock,
+        wait_lock: args.wait_lock,
+        case_conflict: args.case_conflict,
+        sanitize_names: args.sanitize_names.then_some(args.sanitize_replacement),
+        normalize: match args.normalize {
+            NormalizeChoice::Nfc => Some(NormalForm::Nfc),
+            NormalizeChoice::Nfd => Some(NormalForm::Nfd),
+            NormalizeChoice::None => None,
+        },
+        split_oversize: args.split_oversize,
+        max_file_size: args.max_file_size,
+        skip_larger_than: args.skip_larger_than,
+        ignore_space_check: args.ignore_space_check,
+        keep_partial: args.keep_partial,
+        transactional: args.transactional,
+        undo: args.undo,
+        also_to: args.also_to,
+        delta: args.delta,
+        checksum: args.checksum,
+        skip_identical: args.skip_identical,
+        compress: args.compress,
+        encrypt: args.encrypt,
+        decrypt: args.decrypt,
+        write_hashes: args.write_hashes,
+        hash_sidecars: args.hash_sidecars,
+        verify: args.verify,
+        manifest: args.manifest,
+        manifest_format: args.manifest_format,
+        progress,
+        term_progress: args.term_progress == TermProgressChoice::Auto
+            && matches!(progress, ProgressMode::Bars | ProgressMode::Plain | ProgressMode::Tui)
+            && interactive,
+        json: args.json,
+        quiet: args.verbosity.is_silent(),
+        progress_socket: args.progress_socket,
+        // JSON consumers get the `summary` event instead.
+        summary: args.summary
+            || (!args.no_summary && !args.verbosity.is_silent() && progress != ProgressMode::Json),
+        timings: args.timings,
+        report: args.report,
+        log_file: args.log_file,
+        notify: args.notify,
+        pre_file_cmd: args.pre_file_cmd,
+        post_file_cmd: args.post_file_cmd,
+        on_complete_url: args.on_complete_url,
+        on_complete_cmd: args.on_complete_cmd,
+        ssh_command: args.ssh_command.split_whitespace().map(String::from).collect(),
+        compress_transfer: args.compress_transfer,
+        segments: args.segments,
+        smb_tuning: args.smb_tuning,
+        default_buffer_size: matches.value_source("buffer_size") == Some(clap::parser::ValueSource::DefaultValue)
+            && defaults.buffer_size.is_none(),
+        resume,
+    };
 
     // Required by clap when no subcommand is used.
     let destination = args
         .destination
         .expect("clap guarantees destination is set when no subcommand is used");
 
-    match parse_destination(&destination) {
+    // `name:path` stands for a location on an SFTP or WebDAV remote from
+    // the config file, which also supplies its credentials.
+    let remotes = configured_remotes(args.source.iter().chain([&destination]));
+    for s in args.source.iter().chain([&destination]) {
+        if let Some((name, remote)) = configured_remote(&remotes, s)
+            && remote.remote_type == "sftp"
+        {
+            match remote.ssh_options(name) {
+                Ok(ssh_options) => options.ssh_command.extend(ssh_options),
+                Err(e) => {
+                    eprintln!("{} {}", format!("Cannot use remote \"{}\":", name).red(), e);
+                    std::process::exit(exit::USAGE);
+                }
+            }
+            break;
+        }
+    }
+    let location = |s: &str| configured_location(&remotes, s);
+
+    if args.extract {
+        cmd_archive::extract(args.source, local_destination(&destination), &options).await;
+        return;
+    }
+
+    // Remote sources are downloaded into a local directory.
+    if let Some(urls) = remote_sources(&args.source, |s| http::is_url(s).then(|| s.to_string())) {
+        cmd_remote::download_http(urls, local_destination(&destination), &options).await;
+        return;
+    }
+    let sftp_source = |s: &str| match location(s) {
+        Some(Destination::Sftp(location)) => Some(location),
+        Some(_) => None,
+        None => SftpLocation::parse(s),
+    };
+    if let Some(locations) = remote_sources(&args.source, sftp_source) {
+        cmd_remote::download_sftp(locations, local_destination(&destination), &options).await;
+        return;
+    }
+    let webdav_source = |s: &str| match location(s) {
+        Some(Destination::WebDav(url)) => Some(url),
+        Some(_) => None,
+        None => webdav::parse_url(s),
+    };
+    if let Some(urls) = remote_sources(&args.source, webdav_source) {
+        cmd_remote::download_webdav(urls, local_destination(&destination), &options).await;
+        return;
+    }
+
+    match location(&destination).unwrap_or_else(|| parse_destination(&destination)) {
+        Destination::Local(dest_path) if args.to_archive || tar::is_tar_path(&dest_path) => {
+            cmd_archive::create_tar(args.source, dest_path, &options).await;
+        }
         Destination::Local(dest_path) => {
-            cmd_local::run(
-                args.source,
-                dest_path,
-                args.recursive,
-                args.sync,
-                parallel,
-                is_quiet,
-            )
-            .await;
+            cmd_local::run(args.source, dest_path, &options).await;
         }
         Destination::S3 {
             remote_name,
             bucket,
             prefix,
         } => {
-            cmd_s3::run(
-                args.source,
-                remote_name,
-                bucket,
-                prefix,
-                args.recursive,
-                parallel,
-                is_quiet,
-            )
-            .await;
+            cmd_s3::run(args.source, remote_name, bucket, prefix, &options).await;
+        }
+        Destination::Sftp(location) => {
+            cmd_remote::upload_sftp(args.source, location, &options).await;
+        }
+        Destination::WebDav(url) => {
+            cmd_remote::upload_webdav(args.source, url, &options).await;
         }
     }
 }
@@ -0,0 +1,857 @@
+use crate::cli::RunOptions;
+use crate::cmd_remote::{check_source, open_outputs};
+use crate::copy::CopyError;
+use crate::engine::Overwrite;
+use crate::events::{CopyEvent, EventSink};
+use crate::exit;
+use crate::failures::{self, Failures, COMMAND_FAILED};
+use crate::hooks;
+use crate::interrupt;
+use crate::keys::{self, KeyListener};
+use crate::logfile::FileLog;
+use crate::notify;
+use crate::progress::{self, FileBars, RunProgress, RunStats};
+use crate::report::Recorder;
+use crate::tar::{EntryKind, Header, TarReader, TarWriter};
+use crate::throttle::Throttle;
+use crate::utils::{map_id, trim_filename, walk_dir};
+use colored::Colorize;
+use indicatif::ProgressBar;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tokio::io::{AsyncRead, BufReader, BufWriter};
+use tokio::process::{Child, Command};
+use tokio_util::sync::CancellationToken;
+
+/// Compressed tar archives by file name suffix, with the program that
+/// decompresses them.
+const COMPRESSED_TARS: &[(&str, &str)] = &[
+    (".tar.gz", "gzip"),
+    (".tgz", "gzip"),
+    (".tar.zst", "zstd"),
+    (".tzst", "zstd"),
+    (".tar.xz", "xz"),
+    (".txz", "xz"),
+    (".tar.bz2", "bzip2"),
+    (".tbz2", "bzip2"),
+];
+
+/// Something to add to the archive: the local `path` and its name inside
+/// the archive.
+struct Member {
+    path: PathBuf,
+    name: String,
+    is_dir: bool,
+    size: u64,
+}
+
+/// Progress UI and outcome bookkeeping of an archive run, shared by
+/// [`create_tar`] and [`extract`]. Unlike copies, archive members are handled
+/// one after the other, as a tar archive can only be read or written front
+/// to back.
+struct ArchiveRun<'a> {
+    options: &'a RunOptions,
+    /// `Archive` or `Extract`, for messages.
+    noun: &'static str,
+    /// `Archived` or `Extracted`, for the summary.
+    past: &'static str,
+    events: Arc<EventSink>,
+    file_log: Option<Arc<FileLog>>,
+    interrupt: CancellationToken,
+    cancel: CancellationToken,
+    stats: Arc<RunStats>,
+    run_progress: Option<RunProgress>,
+    key_listener: Option<KeyListener>,
+    throttle: Throttle,
+    recorder: Option<Recorder>,
+    failures: Failures,
+    failed: bool,
+    total_files: u64,
+}
+
+impl<'a> ArchiveRun<'a> {
+    #[allow(clippy::too_many_arguments)]
+    fn start(
+        options: &'a RunOptions,
+        noun: &'static str,
+        past: &'static str,
+        events: Arc<EventSink>,
+        file_log: Option<Arc<FileLog>>,
+        total_size: u64,
+        total_files: u64,
+        has_errors: bool,
+    ) -> Self {
+        events.emit(&CopyEvent::ScanFinished {
+            files: total_files,
+            bytes: total_size,
+        });
+        if let Some(ref log) = file_log {
+            log.note(&format!(
+                "{} started: {} files, {} bytes",
+                noun.to_lowercase(),
+                total_files,
+                total_size
+            ));
+        }
+        let interrupt = interrupt::install();
+        let cancel = interrupt.child_token();
+        let stats = Arc::new(RunStats::new());
+        let run_progress = RunProgress::start(
+            options.progress,
+            options.term_progress,
+            total_size,
+            total_files,
+            Arc::clone(&stats),
+            Arc::clone(&events),
+        );
+        let throttle = Throttle::new(options.bwlimit);
+        let key_listener = match run_progress {
+            Some(ref run_progress) if run_progress.multi.is_some() => {
+                keys::listen(throttle.clone(), Arc::clone(&run_progress.main))
+            }
+            _ => None,
+        };
+        Self {
+            options,
+            noun,
+            past,
+            events,
+            file_log,
+            interrupt,
+            cancel,
+            stats,
+            run_progress,
+            key_listener,
+            throttle,
+            recorder: options.report.as_ref().map(|_| Recorder::new()),
+            failures: Failures::new(),
+            failed: has_errors,
+            total_files,
+        }
+    }
+
+    /// Whether no further member should be handled.
+    fn is_stopped(&self) -> bool {
+        self.cancel.is_cancelled() || (self.failed && self.options.fail_fast)
+    }
+
+    fn is_interrupted(&self) -> bool {
+        self.interrupt.is_cancelled()
+    }
+
+    /// Runs `--pre-file-cmd` for a file; returns whether it is to be handled.
+    async fn pre_file_check(&mut self, source: &Path, destination: &str, size: u64) -> bool {
+        let Some(ref cmd) = self.options.pre_file_cmd else {
+            return true;
+        };
+        match hooks::pre_file_check(cmd, source, destination).await {
+            Ok(true) => true,
+            Ok(false) => {
+                self.skip(source, destination, size, "rejected by --pre-file-cmd");
+                false
+            }
+            Err(e) => {
+                self.failures.record(source, "pre-file command", COMMAND_FAILED.to_string(), e.clone());
+                self.stats.files_failed.fetch_add(1, Ordering::Relaxed);
+                self.events.error(Some(source), e);
+                self.failed = true;
+                false
+            }
+        }
+    }
+
+    /// Records a file that won't be handled and takes its size out of the
+    /// overall progress so the bar still reaches 100%.
+    fn skip(&self, source: &Path, destination: &str, size: u64, reason: &str) {
+        log::info!("Skipping {}: {}", source.display(), reason);
+        self.stats.files_skipped.fetch_add(1, Ordering::Relaxed);
+        if let Some(ref run_progress) = self.run_progress {
+            run_progress.main.dec_length(size);
+        }
+        if let Some(ref log) = self.file_log {
+            log.note(&format!("{} -> {} skipped: {}", source.display(), destination, reason));
+        }
+        if let Some(ref recorder) = self.recorder {
+            recorder.skipped(source, destination.to_string(), reason.to_string());
+        }
+        self.events.emit(&CopyEvent::FileSkipped {
+            source: source.display().to_string(),
+            destination: destination.to_string(),
+            reason: reason.to_string(),
+        });
+    }
+
+    /// Announces a file and returns its bar, if bars are shown.
+    fn file_started(&self, source: &Path, destination: &str, size: u64, name: &str) -> Option<ProgressBar> {
+        self.events.emit(&CopyEvent::FileStarted {
+            source: source.display().to_string(),
+            destination: destination.to_string(),
+            bytes: size,
+        });
+        let multi = self.run_progress.as_ref()?.multi.as_ref()?;
+        let pb = multi.add(ProgressBar::new(size));
+        pb.set_style(progress::file_bar_style());
+        let verb = format!("{}ing", self.noun.trim_end_matches('e'));
+        let file_name = name.rsplit('/').next().unwrap_or(name);
+        pb.set_message(format!("{} {}", verb, trim_filename(file_name, 35 - verb.len())));
+        Some(pb)
+    }
+
+    fn bars<'b>(&'b self, file_pb: Option<&'b ProgressBar>) -> FileBars<'b> {
+        FileBars {
+            file: file_pb,
+            source: None,
+            main: self.run_progress.as_ref().map(|p| p.main.as_ref()),
+            network: None,
+        }
+    }
+
+    /// Records the outcome of a file that was started.
+    async fn file_finished(
+        &mut self,
+        source: &Path,
+        destination: &str,
+        started: Instant,
+        started_at: SystemTime,
+        result: &Result<u64, CopyError>,
+        operation: &'static str,
+    ) {
+        let (bytes, error) = match result {
+            Ok(bytes) => (*bytes, None),
+            Err(e) => (0, Some(e.to_string())),
+        };
+        if let Some(ref log) = self.file_log {
+            log.file(source, destination, started_at, bytes, error.as_deref());
+        }
+        if let Some(ref recorder) = self.recorder {
+            recorder.record(source, destination.to_string(), bytes, started.elapsed(), error);
+        }
+        match result {
+            Ok(bytes) => {
+                self.stats.files_done.fetch_add(1, Ordering::Relaxed);
+                self.stats.bytes_copied.fetch_add(*bytes, Ordering::Relaxed);
+                self.events.emit(&CopyEvent::FileDone {
+                    source: source.display().to_string(),
+                    destination: destination.to_string(),
+                    bytes: *bytes,
+                });
+                if let Some(ref cmd) = self.options.post_file_cmd
+                    && let Err(e) = hooks::file_cmd(cmd, source, destination).await
+                {
+                    self.failures.record(source, "post-file command", COMMAND_FAILED.to_string(), e.clone());
+                    self.events.error(Some(source), e);
+                    self.failed = true;
+                }
+            }
+            Err(e) => {
+                self.stats.files_failed.fetch_add(1, Ordering::Relaxed);
+                self.events.error(Some(source), e.to_string());
+                self.failures.record(source, operation, failures::kind_of(e), e.to_string());
+                self.failed = true;
+            }
+        }
+    }
+
+    /// Records a failure that isn't about one file, such as an archive that
+    /// can't be read.
+    fn fail(&mut self, path: &Path, operation: &'static str, e: &CopyError) {
+        self.events.error(Some(path), e.to_string());
+        self.failures.record(path, operation, failures::kind_of(e), e.to_string());
+        self.failed = true;
+    }
+
+    /// Shows the summary, writes the report, runs the completion hooks and
+    /// exits with the run's status if it didn't succeed. `stopped` is set
+    /// when the run ended before handling every member.
+    async fn finish(mut self, stopped: bool) {
+        let interrupted = self.is_interrupted();
+        let aborted = !interrupted && stopped;
+        let noun = self.noun;
+        if let Some(key_listener) = self.key_listener.take() {
+            key_listener.stop();
+        }
+        if let Some(run_progress) = self.run_progress.take() {
+            let headline = match (interrupted, aborted) {
+                (true, _) => format!("{} interrupted!", noun),
+                (false, true) => format!("{} aborted!", noun),
+                (false, false) => format!("{} complete!", noun),
+            };
+            run_progress.finish(&headline).await;
+        }
+        self.failures.print_report();
+        if self.options.summary {
+            self.stats.print_summary(self.past);
+        }
+        let not_done = format!("not {}", self.past.to_lowercase());
+        if interrupted || aborted {
+            let reason = if interrupted { "Interrupted" } else { "Aborted after an error" };
+            let missing = progress::files(self.stats.not_finished(self.total_files));
+            eprintln!("{}", format!("{}: {} {}", reason, missing, not_done).yellow());
+            self.events.error(None, format!("{}: {} {}", reason.to_lowercase(), missing, not_done));
+        }
+        self.events.emit(&self.stats.summary_event());
+        self.events.close().await;
+        if let Some(ref log) = self.file_log {
+            log.note(&format!(
+                "{} finished: {} copied, {} failed, {} bytes",
+                noun.to_lowercase(),
+                self.stats.files_done.load(Ordering::Relaxed),
+                self.stats.files_failed.load(Ordering::Relaxed),
+                self.stats.bytes_copied.load(Ordering::Relaxed)
+            ));
+        }
+
+        if let (Some(path), Some(recorder)) = (&self.options.report, &self.recorder)
+            && let Err(e) = recorder.write(path, self.stats.started.elapsed())
+        {
+            eprintln!("{} {}", "Failed to write report:".red(), e);
+            self.failed = true;
+        }
+        if self.options.on_complete_url.is_some() || self.options.on_complete_cmd.is_some() {
+            hooks::on_complete(
+                self.options.on_complete_url.as_ref(),
+                self.options.on_complete_cmd.as_deref(),
+                &self.stats.summary_event(),
+            )
+            .await;
+        }
+        if self.options.notify {
+            let summary = match (interrupted, self.failed) {
+                (true, _) => format!("{} interrupted", noun),
+                (false, true) if aborted => format!("{} aborted after an error", noun),
+                (false, true) => format!("{} finished with errors", noun),
+                (false, false) => format!("{} complete", noun),
+            };
+            notify::run_finished(summary, &self.stats).await;
+        }
+        if interrupted {
+            std::process::exit(exit::INTERRUPTED);
+        }
+        if self.failed {
+            let status = exit::for_failed_run(&self.stats);
+            self.failures.print_exit_message(status);
+            std::process::exit(status);
+        }
+    }
+}
+
+/// Writes the sources into the tar archive `archive`, with the progress UI,
+/// hooks and reporting of a local copy.
+pub(crate) async fn create_tar(sources: Vec<String>, archive: PathBuf, options: &RunOptions) {
+    let (events, file_log) = open_outputs(options);
+    if archive.is_dir() {
+        let message = "Archive path is a directory:";
+        eprintln!("{} {}", message.red(), archive.display().to_string().red());
+        std::process::exit(exit::USAGE);
+    }
+    if archive.exists() && options.copy.overwrite == Overwrite::Never {
+        let message = "Archive already exists:";
+        eprintln!("{} {}", message.red(), archive.display().to_string().red());
+        std::process::exit(exit::USAGE);
+    }
+
+    events.emit(&CopyEvent::ScanStarted {
+        sources: sources.clone(),
+    });
+    let mut members = Vec::new();
+    let mut has_errors = false;
+    for source_str in &sources {
+        let source = Path::new(source_str);
+        if !check_source(&events, source_str, source.exists(), source.is_dir(), options) {
+            has_errors = true;
+            continue;
+        }
+        // `.` and `..` have no name of their own.
+        let name = match source.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => std::fs::canonicalize(source)
+                .ok()
+                .and_then(|p| p.file_name().map(|n| n.to_string_lossy().into_owned()))
+                .unwrap_or_default(),
+        };
+        if !source.is_dir() {
+            members.push(Member {
+                path: source.to_path_buf(),
+                name,
+                is_dir: false,
+                size: source.metadata().map(|m| m.len()).unwrap_or(0),
+            });
+            continue;
+        }
+        match walk_dir(source, options.copy.parallel, options.copy.dereference, options.copy.skip_hidden, None).await {
+            Ok((files, subdirs, specials, _)) => {
+                let name_of = |path: &Path| {
+                    let relative = path.strip_prefix(source).unwrap_or(path);
+                    relative
+                        .components()
+                        .fold(name.clone(), |dir, c| format!("{}/{}", dir, c.as_os_str().to_string_lossy()))
+                };
+                // Sorted so that directories come before their contents and
+                // the same tree always gives the same archive.
+                let mut tree = vec![Member {
+                    path: source.to_path_buf(),
+                    name: name.clone(),
+                    is_dir: true,
+                    size: 0,
+                }];
+                tree.extend(subdirs.into_iter().map(|dir| Member {
+                    name: name_of(&dir),
+                    path: dir,
+                    is_dir: true,
+                    size: 0,
+                }));
+                tree.extend(files.into_iter().map(|(path, size)| Member {
+                    name: name_of(&path),
+                    path,
+                    is_dir: false,
+                    size,
+                }));
+                tree.sort_by(|a, b| a.path.cmp(&b.path));
+                members.extend(tree);
+                for (path, _) in specials {
+                    log::warn!("skipping special file: {}", path.display());
+                }
+            }
+            Err(e) => {
+                eprintln!("{} {}", "Error:".red(), e.to_string().red());
+                events.error(Some(source), e.to_string());
+                has_errors = true;
+            }
+        }
+    }
+    if members.is_empty() {
+        if !has_errors {
+            eprintln!("Nothing to archive.");
+        }
+        std::process::exit(exit::USAGE);
+    }
+
+    let file = match fs::File::create(&archive).await {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("{} {}: {}", "Cannot create".red(), archive.display().to_string().red(), e);
+            std::process::exit(exit::USAGE);
+        }
+    };
+    let mut writer = TarWriter::new(BufWriter::with_capacity(options.copy.buffer_size, file));
+
+    let total_size = members.iter().map(|m| m.size).sum();
+    let total_files = members.iter().filter(|m| !m.is_dir).count() as u64;
+    let mut run = ArchiveRun::start(options, "Archive", "Archived", events, file_log, total_size, total_files, has_errors);
+    // A failure writing the archive itself ends the run.
+    let mut broken: Option<CopyError> = None;
+
+    for member in &members {
+        if run.is_stopped() {
+            break;
+        }
+        let destination = format!("{}:{}", archive.display(), member.name);
+        let metadata = match fs::metadata(&member.path).await {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                let result = Err(CopyError::io("stat", &member.path)(e));
+                run.file_finished(&member.path, &destination, Instant::now(), SystemTime::now(), &result, "archive")
+                    .await;
+                continue;
+            }
+        };
+        let header = Header::from_metadata(member.name.clone(), &metadata);
+        if member.is_dir {
+            if let Err(e) = writer.append(&header).await {
+                broken = Some(CopyError::io("write", &archive)(e));
+                break;
+            }
+            continue;
+        }
+        if !run.pre_file_check(&member.path, &destination, member.size).await {
+            continue;
+        }
+
+        let file_pb = run.file_started(&member.path, &destination, header.size, &member.name);
+        let started = Instant::now();
+        let started_at = SystemTime::now();
+        let result = match fs::File::open(&member.path).await {
+            Ok(mut data) => {
+                let bars = run.bars(file_pb.as_ref());
+                writer
+                    .append_file(
+                        &header,
+                        &member.path,
+                        &archive,
+                        &mut data,
+                        Some(&bars),
+                        Some(&run.throttle),
+                        Some(&run.cancel),
+                    )
+                    .await
+            }
+            Err(e) => Err(CopyError::io("open", &member.path)(e)),
+        };
+        if let Some(pb) = file_pb {
+            pb.finish_and_clear();
+        }
+        match result {
+            Err(_) if run.cancel.is_cancelled() => break,
+            Err(e) if is_about(&e, &archive) => {
+                broken = Some(e);
+                break;
+            }
+            result => {
+                run.file_finished(&member.path, &destination, started, started_at, &result, "archive")
+                    .await
+            }
+        }
+    }
+
+    let stopped = run.is_stopped() || broken.is_some();
+    if !run.is_interrupted() && broken.is_none() {
+        let finished = match writer.finish().await {
+            Ok(file) if options.copy.sync => file.into_inner().sync_all().await,
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = finished {
+            broken = Some(CopyError::io("write", &archive)(e));
+        }
+    }
+    if let Some(ref e) = broken {
+        run.fail(&archive, "write archive", e);
+    }
+    // An archive cut short can't be told apart from a complete one.
+    if (run.is_interrupted() || broken.is_some())
+        && !options.keep_partial
+        && let Err(e) = fs::remove_file(&archive).await
+    {
+        log::warn!("Cannot remove partial archive {}: {}", archive.display(), e);
+    }
+    run.finish(stopped).await;
+}
+
+/// Whether `name` is that of a tar archive cp2 can extract, compressed or
+/// not.
+pub(crate) fn is_archive(name: &str) -> bool {
+    crate::tar::is_tar_path(Path::new(name)) || decompressor(Path::new(name)).is_some()
+}
+
+/// The program that decompresses `archive`, judging by its name, or `None`
+/// for plain tar archives.
+fn decompressor(archive: &Path) -> Option<&'static str> {
+    let name = archive.file_name()?.to_string_lossy().to_lowercase();
+    COMPRESSED_TARS
+        .iter()
+        .find(|(suffix, _)| name.ends_with(suffix))
+        .map(|&(_, program)| program)
+}
+
+/// Opens `archive` for reading, through its decompressor when it is
+/// compressed. The decompressor is killed when the returned child is
+/// dropped.
+fn open_archive(archive: &Path) -> io::Result<(Box<dyn AsyncRead + Unpin + Send>, Option<Child>)> {
+    let Some(program) = decompressor(archive) else {
+        let file = std::fs::File::open(archive)?;
+        return Ok((Box::new(BufReader::new(fs::File::from_std(file))), None));
+    };
+    let mut child = Command::new(program)
+        .arg("-dc")
+        .arg(archive)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => io::Error::new(e.kind(), format!("`{program}` is needed to decompress it")),
+            _ => e,
+        })?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+    Ok((Box::new(BufReader::new(stdout)), Some(child)))
+}
+
+/// The headers of every entry of `archive`.
+async fn list_archive(archive: &Path) -> io::Result<Vec<Header>> {
+    let (reader, _child) = open_archive(archive)?;
+    let mut reader = TarReader::new(reader);
+    let mut headers = Vec::new();
+    while let Some(header) = reader.next().await? {
+        headers.push(header);
+    }
+    Ok(headers)
+}
+
+/// Where the entry `name` goes below `destination`, or `None` when it would
+/// end up outside of it. Leading slashes are dropped, as `tar` does.
+fn entry_path(destination: &Path, name: &str) -> Option<PathBuf> {
+    let mut path = destination.to_path_buf();
+    for component in Path::new(name.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    (path != destination).then_some(path)
+}
+
+/// Whether a directory between `destination` and `path` is a symbolic
+/// link, which writing `path` would follow, possibly out of `destination`.
+fn behind_symlink(destination: &Path, path: &Path) -> bool {
+    path.ancestors()
+        .skip(1)
+        .take_while(|dir| *dir != destination)
+        .any(|dir| dir.symlink_metadata().is_ok_and(|m| m.file_type().is_symlink()))
+}
+
+/// Unpacks the tar archives `sources` into the directory `destination`,
+/// with the progress UI, overwrite policy, hooks and reporting of a local
+/// copy. Every archive is read twice: once to count its files, and once to
+/// extract them.
+pub(crate) async fn extract(sources: Vec<String>, destination: PathBuf, options: &RunOptions) {
+    let (events, file_log) = open_outputs(options);
+    if !destination.is_dir() {
+        let message = match destination.exists() {
+            true => "Destination path is not a directory:",
+            false => "Destination path does not exist:",
+        };
+        eprintln!("{} {}", message.red(), destination.display().to_string().red());
+        std::process::exit(exit::USAGE);
+    }
+    if let Some(other) = sources.iter().find(|s| !is_archive(s)) {
+        eprintln!("{} {}", "Not a tar archive:".red(), other.red());
+        std::process::exit(exit::USAGE);
+    }
+
+    events.emit(&CopyEvent::ScanStarted {
+        sources: sources.clone(),
+    });
+    let mut archives = Vec::new();
+    let mut has_errors = false;
+    for source in &sources {
+        let archive = Path::new(source);
+        if !check_source(&events, source, archive.exists(), archive.is_dir(), options) {
+            has_errors = true;
+            continue;
+        }
+        match list_archive(archive).await {
+            Ok(headers) => archives.push((archive.to_path_buf(), headers)),
+            Err(e) => {
+                eprintln!("{} {}: {}", "Cannot read".red(), source.red(), e);
+                events.error(Some(archive), e.to_string());
+                has_errors = true;
+            }
+        }
+    }
+    let files = archives
+        .iter()
+        .flat_map(|(_, headers)| headers.iter().filter(|h| h.kind == EntryKind::File));
+    let total_size = files.clone().map(|h| h.size).sum();
+    let total_files = files.count() as u64;
+    if archives.iter().all(|(_, headers)| headers.is_empty()) {
+        if !has_errors {
+            eprintln!("Nothing to extract.");
+        }
+        std::process::exit(exit::USAGE);
+    }
+
+    let mut run = ArchiveRun::start(options, "Extract", "Extracted", events, file_log, total_size, total_files, has_errors);
+    for (archive, _) in &archives {
+        if run.is_stopped() {
+            break;
+        }
+        if let Err(e) = extract_archive(&mut run, archive, &destination).await {
+            run.fail(archive, "read archive", &e);
+        }
+    }
+    let stopped = run.is_stopped();
+    run.finish(stopped).await;
+}
+
+/// Extracts the entries of one archive. Failures of single entries are
+/// recorded in `run`; the error returned is for an archive that can't be
+/// read any further.
+async fn extract_archive(run: &mut ArchiveRun<'_>, archive: &Path, destination: &Path) -> Result<(), CopyError> {
+    let (reader, _child) = open_archive(archive).map_err(CopyError::io("open", archive))?;
+    let mut reader = TarReader::new(reader);
+    // Directory times are set last, as extracting into them changes them.
+    let mut dirs = Vec::new();
+    while let Some(header) = reader.next().await.map_err(CopyError::io("read", archive))? {
+        if run.is_stopped() {
+            break;
+        }
+        let source = PathBuf::from(format!("{}:{}", archive.display(), header.path));
+        let size = if header.kind == EntryKind::File { header.size } else { 0 };
+        let Some(to) = entry_path(destination, &header.path).filter(|to| !behind_symlink(destination, to)) else {
+            run.skip(&source, &header.path, size, "path leads outside the destination");
+            continue;
+        };
+        let target = to.display().to_string();
+        if header.kind != EntryKind::Dir
+            && let Some(reason) = overwrite_skip_reason(run.options.copy.overwrite, &header, &to)
+        {
+            run.skip(&source, &target, size, reason);
+            continue;
+        }
+        match header.kind {
+            EntryKind::Dir => match fs::create_dir_all(&to).await {
+                Ok(()) => dirs.push((to, header)),
+                Err(e) => run.fail(&to, "create directory", &CopyError::io("create", &to)(e)),
+            },
+            EntryKind::File => {
+                if !run.pre_file_check(&source, &target, header.size).await {
+                    continue;
+                }
+                let file_pb = run.file_started(&source, &target, header.size, &header.path);
+                let started = Instant::now();
+                let started_at = SystemTime::now();
+                let result = extract_file(run, &mut reader, &header, archive, &to, file_pb.as_ref()).await;
+                if let Some(pb) = file_pb {
+                    pb.finish_and_clear();
+                }
+                if let Err(ref e) = result
+                    && e.is_partial()
+                    && !run.options.keep_partial
+                    && let Err(e) = fs::remove_file(&to).await
+                {
+                    log::warn!("Cannot remove partial file {}: {}", to.display(), e);
+                }
+                match result {
+                    Err(_) if run.cancel.is_cancelled() => break,
+                    Err(e) if is_about(&e, archive) => return Err(e),
+                    result => run.file_finished(&source, &target, started, started_at, &result, "extract").await,
+                }
+            }
+            EntryKind::Symlink | EntryKind::HardLink => {
+                if let Err(e) = extract_link(&header, destination, &to).await {
+                    run.fail(&to, "create link", &CopyError::io("create link", &to)(e));
+                }
+            }
+            EntryKind::Other(flag) => {
+                log::warn!("skipping {} (unsupported tar entry type {:?})", header.path, flag as char);
+            }
+        }
+    }
+    for (dir, header) in dirs.iter().rev() {
+        for warning in apply_header(run.options, header, dir) {
+            log::warn!("{}", warning);
+        }
+    }
+    Ok(())
+}
+
+/// Why the entry `header` mustn't replace `to` under `overwrite`, if it
+/// mustn't.
+fn overwrite_skip_reason(overwrite: Overwrite, header: &Header, to: &Path) -> Option<&'static str> {
+    let existing = to.symlink_metadata().ok()?;
+    if existing.is_dir() {
+        return Some("destination is a directory");
+    }
+    match overwrite {
+        Overwrite::Always => None,
+        Overwrite::Never => Some("destination exists"),
+        Overwrite::IfNewer => {
+            let modified = existing.modified().ok()?;
+            (UNIX_EPOCH + Duration::from_secs(header.mtime) <= modified)
+                .then_some("destination is not older than the source")
+        }
+    }
+}
+
+async fn extract_file<R: AsyncRead + Unpin>(
+    run: &ArchiveRun<'_>,
+    reader: &mut TarReader<R>,
+    header: &Header,
+    archive: &Path,
+    to: &Path,
+    file_pb: Option<&ProgressBar>,
+) -> Result<u64, CopyError> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent).await.map_err(CopyError::io("create", parent))?;
+    }
+    // Whatever is in the way is replaced rather than written through, as a
+    // symbolic or hard link would carry the data outside the destination.
+    if to.symlink_metadata().is_ok_and(|m| !m.is_dir()) {
+        fs::remove_file(to).await.map_err(CopyError::io("remove", to))?;
+    }
+    let file = fs::File::create(to).await.map_err(CopyError::io("create", to))?;
+    let mut out = BufWriter::with_capacity(run.options.copy.buffer_size, file);
+    let bars = run.bars(file_pb);
+    let copied = reader
+        .copy_data(header, archive, to, &mut out, Some(&bars), Some(&run.throttle), Some(&run.cancel))
+        .await
+        .map_err(CopyError::partial)?;
+    if run.options.copy.sync {
+        out.into_inner()
+            .sync_all()
+            .await
+            .map_err(|e| CopyError::io("sync", to)(e).partial())?;
+    }
+    for warning in apply_header(run.options, header, to) {
+        log::warn!("{}", warning);
+    }
+    Ok(copied)
+}
+
+async fn extract_link(header: &Header, destination: &Path, to: &Path) -> io::Result<()> {
+    let link = header.link.as_deref().unwrap_or_default();
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    if to.symlink_metadata().is_ok() {
+        fs::remove_file(to).await?;
+    }
+    if header.kind == EntryKind::HardLink {
+        let target = entry_path(destination, link)
+            .filter(|target| !behind_symlink(destination, target))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "link leads outside the destination"))?;
+        return fs::hard_link(target, to).await;
+    }
+    #[cfg(unix)]
+    return fs::symlink(link, to).await;
+    #[cfg(not(unix))]
+    Err(io::Error::new(io::ErrorKind::Unsupported, "symbolic links are only extracted on Unix"))
+}
+
+/// Gives the extracted `to` the modification time and permissions stored in
+/// `header`, as `tar` does, with `--chmod` taking precedence. The owner is
+/// set with `--preserve ownership` or `--chown`. Returns a warning for each
+/// attribute that can't be set.
+fn apply_header(options: &RunOptions, header: &Header, to: &Path) -> Vec<String> {
+    let mut warnings = Vec::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let (uid, gid) = match (options.copy.preserve.ownership, options.chown) {
+            (_, Some(owner)) => (owner.uid, owner.gid),
+            (true, None) => (
+                Some(map_id(&options.uid_map, header.uid as u32)),
+                Some(map_id(&options.gid_map, header.gid as u32)),
+            ),
+            (false, None) => (None, None),
+        };
+        if (uid.is_some() || gid.is_some())
+            && let Err(e) = std::os::unix::fs::chown(to, uid, gid)
+        {
+            warnings.push(format!("cannot chown {}: {}", to.display(), e));
+        }
+        let chmod = if header.kind == EntryKind::Dir { options.chmod.dir } else { options.chmod.file };
+        // Set-user and set-group bits are only kept along with the owner.
+        let mode = chmod.unwrap_or(header.mode & if uid.is_some() { 0o7777 } else { 0o1777 });
+        if let Err(e) = std::fs::set_permissions(to, std::fs::Permissions::from_mode(mode)) {
+            warnings.push(format!("cannot chmod {}: {}", to.display(), e));
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (options, map_id);
+    if let Err(e) = crate::attrs::set_modified(to, UNIX_EPOCH + Duration::from_secs(header.mtime)) {
+        warnings.push(format!("cannot set the modification time of {}: {}", to.display(), e));
+    }
+    warnings
+}
+
+/// Whether `e` concerns `path` (the archive) rather than a file read into
+/// or out of it.
+fn is_about(e: &CopyError, path: &Path) -> bool {
+    matches!(e.cause(), CopyError::Io { path: p, .. } if p == path)
+}
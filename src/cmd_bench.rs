@@ -0,0 +1,231 @@
+use colored::Colorize;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+
+use crate::config;
+use crate::copy::{copy_file_chunked, copy_file_with_buffer};
+use crate::exit;
+use crate::progress;
+use crate::utils::{parse_size, walk_dir};
+
+/// Buffer sizes tried, as they would be written in the config file.
+const BUFFER_SIZES: [&str; 3] = ["128K", "1M", "8M"];
+
+/// Files at least this large are split across the workers by the chunked
+/// engine; recommended as `split_threshold` when it wins.
+const CHUNKED_THRESHOLD: &str = "64M";
+
+/// Size of each of the small files of the generated workload.
+const SMALL_FILE_SIZE: u64 = 64 * 1024;
+
+/// How the files of a trial are copied.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Engine {
+    /// Each file read and written sequentially by one task, as by default.
+    Buffered,
+    /// Files of at least [`CHUNKED_THRESHOLD`] split into ranges copied by
+    /// all workers at once, as with `--split-threshold`.
+    Chunked,
+}
+
+impl Engine {
+    fn name(self) -> &'static str {
+        match self {
+            Engine::Buffered => "buffered",
+            Engine::Chunked => "chunked",
+        }
+    }
+}
+
+/// One combination of settings and how fast it copied the workload.
+struct Trial {
+    engine: Engine,
+    buffer_size: &'static str,
+    parallel: usize,
+    elapsed: Duration,
+}
+
+/// `cp2 bench`: copies the files below `source`, or a generated workload of
+/// about `size` bytes when there is none, into a scratch directory in
+/// `destination` once for every combination of buffer size, parallel level
+/// and engine. Prints how fast each one was and the `[defaults]` for the
+/// config file that make copies use the fastest.
+pub(crate) async fn run(source: Option<PathBuf>, destination: PathBuf, size: u64, max_parallel: usize) {
+    if !destination.is_dir() {
+        eprintln!("{} {}", "Destination is not a directory:".red(), destination.display());
+        std::process::exit(exit::USAGE);
+    }
+    let scratch = destination.join(format!(".cp2-bench-{}", std::process::id()));
+    let result = bench(source.as_deref(), &scratch, size, max_parallel).await;
+    if let Err(e) = std::fs::remove_dir_all(&scratch) {
+        log::warn!("Cannot remove {}: {}", scratch.display(), e);
+    }
+    let trials = match result {
+        Ok(trials) => trials,
+        Err(e) => {
+            eprintln!("{} {}", "Benchmark failed:".red(), e);
+            std::process::exit(exit::FAILURE);
+        }
+    };
+
+    let Some(best) = trials.iter().min_by_key(|trial| trial.elapsed) else {
+        return;
+    };
+    let config = config::config_path().map_or_else(|_| "the config file".to_string(), |path| path.display().to_string());
+    println!();
+    println!("Recommended settings for {}:", config);
+    println!();
+    println!("[defaults]");
+    println!("parallel = {}", best.parallel);
+    println!("buffer_size = \"{}\"", best.buffer_size);
+    if best.engine == Engine::Chunked {
+        println!("split_threshold = \"{}\"", CHUNKED_THRESHOLD);
+    }
+}
+
+/// Runs every trial against `source`, or a workload generated in `scratch`,
+/// and prints a row for each as it finishes.
+async fn bench(source: Option<&Path>, scratch: &Path, size: u64, max_parallel: usize) -> std::io::Result<Vec<Trial>> {
+    std::fs::create_dir_all(scratch)?;
+    let (root, files) = match source {
+        Some(source) if source.is_dir() => {
+            let (files, _, _, _) = walk_dir(source, max_parallel, false, false, None).await?;
+            (source.to_path_buf(), files)
+        }
+        Some(source) => {
+            let size = std::fs::metadata(source)?.len();
+            (source.parent().unwrap_or(Path::new("")).to_path_buf(), vec![(source.to_path_buf(), size)])
+        }
+        None => {
+            let root = scratch.join("source");
+            eprintln!("Generating a workload of {} in {}", progress::format_bytes(size), root.display());
+            (root.clone(), generate(&root, size)?)
+        }
+    };
+    let total: u64 = files.iter().map(|(_, size)| size).sum();
+    eprintln!("Copying {} ({}) once per trial", progress::files(files.len() as u64), progress::format_bytes(total));
+    let threshold = parse_size(CHUNKED_THRESHOLD).expect("valid size");
+    let has_large = files.iter().any(|&(_, size)| size >= threshold);
+    let mut parallels = vec![1, 4, max_parallel.max(1)];
+    parallels.sort_unstable();
+    parallels.dedup();
+    parallels.retain(|&n| n <= max_parallel.max(1));
+
+    println!("{:<10} {:>8} {:>8} {:>10} {:>14}", "ENGINE", "BUFFER", "PARALLEL", "TIME", "THROUGHPUT");
+    let mut trials = Vec::new();
+    for engine in [Engine::Buffered, Engine::Chunked] {
+        for &parallel in &parallels {
+            // Splitting files only pays off with several workers and a
+            // file big enough to split.
+            if engine == Engine::Chunked && (parallel < 2 || !has_large) {
+                continue;
+            }
+            for buffer_size in BUFFER_SIZES {
+                let target = scratch.join("run");
+                let buffer = parse_size(buffer_size).expect("valid size") as usize;
+                let started = Instant::now();
+                copy_all(&files, &root, &target, engine, buffer, parallel, threshold).await?;
+                let elapsed = started.elapsed();
+                std::fs::remove_dir_all(&target)?;
+                let rate = (total as f64 / elapsed.as_secs_f64().max(f64::EPSILON)) as u64;
+                println!(
+                    "{:<10} {:>8} {:>8} {:>10} {:>14}",
+                    engine.name(),
+                    buffer_size,
+                    parallel,
+                    progress::format_elapsed(elapsed),
+                    format!("{}/s", progress::format_bytes(rate))
+                );
+                let _ = std::io::stdout().flush();
+                trials.push(Trial {
+                    engine,
+                    buffer_size,
+                    parallel,
+                    elapsed,
+                });
+            }
+        }
+    }
+    Ok(trials)
+}
+
+/// Copies `files`, found below `root`, to the same places below `target`
+/// with up to `parallel` files at once, syncing each so that the time
+/// includes writing it out.
+async fn copy_all(
+    files: &[(PathBuf, u64)],
+    root: &Path,
+    target: &Path,
+    engine: Engine,
+    buffer_size: usize,
+    parallel: usize,
+    threshold: u64,
+) -> std::io::Result<()> {
+    let semaphore = Arc::new(Semaphore::new(parallel));
+    let mut tasks = Vec::with_capacity(files.len());
+    for (from, size) in files.iter().cloned() {
+        let to = target.join(from.strip_prefix(root).unwrap_or(&from));
+        if let Some(parent) = to.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let permit = Arc::clone(&semaphore).acquire_owned().await.expect("semaphore is never closed");
+        tasks.push(tokio::spawn(async move {
+            let _permit = permit;
+            let copied = match engine {
+                Engine::Chunked if size >= threshold => {
+                    copy_file_chunked(&from, &to, size, parallel, None, buffer_size, true, None, None).await
+                }
+                _ => {
+                    let mut buffer = vec![0u8; buffer_size];
+                    copy_file_with_buffer(&from, &to, &mut buffer, None, true, None, None).await
+                }
+            };
+            copied.map(|_| ()).map_err(|e| std::io::Error::other(e.to_string()))
+        }));
+    }
+    for task in tasks {
+        task.await.map_err(std::io::Error::other)??;
+    }
+    Ok(())
+}
+
+/// Writes a workload of about `size` bytes below `root`: half of it one large
+/// file, the other half small files in directories of a hundred. The data
+/// is pseudo-random, so compressing or deduplicating filesystems can't skip
+/// writing it.
+fn generate(root: &Path, size: u64) -> std::io::Result<Vec<(PathBuf, u64)>> {
+    let mut files = Vec::new();
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let mut write = |path: PathBuf, len: u64| -> std::io::Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = std::io::BufWriter::new(std::fs::File::create(&path)?);
+        let mut left = len;
+        while left > 0 {
+            // xorshift64
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let bytes = state.to_le_bytes();
+            let n = left.min(8) as usize;
+            file.write_all(&bytes[..n])?;
+            left -= n as u64;
+        }
+        file.flush()?;
+        files.push((path, len));
+        Ok(())
+    };
+    let large = size / 2;
+    if large > 0 {
+        write(root.join("large.bin"), large)?;
+    }
+    let small = (size - large).div_ceil(SMALL_FILE_SIZE);
+    for i in 0..small {
+        write(root.join(format!("small/{:03}/{:05}.bin", i / 100, i)), SMALL_FILE_SIZE)?;
+    }
+    Ok(files)
+}
@@ -70,7 +70,7 @@ fn create(name: &str, force: bool) {
     }
 
     println!("Creating remote \"{}\"", name);
-    println!("Only S3-compatible remotes are supported.\n");
+    println!("Only S3-compatible remotes can be created here; add SFTP and WebDAV remotes to the [remotes] section of the config file.\n");
 
     let provider = prompt("Provider (e.g. AWS, Minio, DigitalOcean)", "AWS");
     let access_key_id = prompt_required("Access key ID");
@@ -103,6 +103,7 @@ fn create(name: &str, force: bool) {
             } else {
                 Some(endpoint)
             },
+            ..RemoteConfig::default()
         },
     );
 
@@ -139,14 +140,25 @@ fn list() {
     let name_width = names.iter().map(|n| n.len()).max().unwrap_or(0);
     for name in names {
         let r = &cfg[name];
-        let provider = r.provider.as_deref().unwrap_or("unknown");
-        let region = r.region.as_deref().unwrap_or("unknown");
+        let details = match r.remote_type.as_str() {
+            "sftp" => format!(
+                "host={}",
+                r.sftp_location("")
+                    .map(|l| l.target())
+                    .unwrap_or_else(|_| "unknown".to_string())
+            ),
+            "webdav" => format!("url={}", r.url.as_deref().unwrap_or("unknown")),
+            _ => format!(
+                "provider={} region={}",
+                r.provider.as_deref().unwrap_or("unknown"),
+                r.region.as_deref().unwrap_or("unknown")
+            ),
+        };
         println!(
-            "{:<width$} type={} provider={} region={}",
+            "{:<width$} type={} {}",
             name,
             r.remote_type,
-            provider,
-            region,
+            details,
             width = name_width,
         );
     }
@@ -0,0 +1,387 @@
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use crate::engine::{self, CopyOptions, Overwrite};
+use crate::exit;
+use crate::sink::ProgressSink;
+use crate::throttle::Throttle;
+
+/// One request line sent to the daemon, such as
+/// `{"op":"submit","sources":["photos"],"destination":"/mnt/backup","recursive":true}`.
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Request {
+    /// Queues a copy of `sources` into `destination`. Jobs with a higher
+    /// priority run first, and jobs of the same priority in order.
+    Submit {
+        sources: Vec<PathBuf>,
+        destination: PathBuf,
+        #[serde(default)]
+        recursive: bool,
+        #[serde(default)]
+        overwrite: Overwrite,
+        #[serde(default)]
+        priority: i32,
+    },
+    /// The job `id`, or every job the daemon knows about.
+    Status { id: Option<u64> },
+    /// Stops the job `id`, whether it is running or still queued.
+    Cancel { id: u64 },
+}
+
+/// The line answering each request: `{"ok":true,"id":1}` for a submitted
+/// job, `{"ok":true,"jobs":[...]}` for a status request, or
+/// `{"ok":false,"error":"..."}`.
+#[derive(Default, Serialize)]
+struct Response {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    jobs: Option<Vec<JobStatus>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Response {
+    fn error(error: impl Into<String>) -> Self {
+        Self {
+            error: Some(error.into()),
+            ..Self::default()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum JobState {
+    Queued,
+    Running,
+    /// Every file was copied or deliberately skipped.
+    Done,
+    /// Some files couldn't be copied, or the copy couldn't start.
+    Failed,
+    Cancelled,
+}
+
+/// A job as reported by a status request.
+#[derive(Serialize)]
+struct JobStatus {
+    id: u64,
+    state: JobState,
+    priority: i32,
+    sources: Vec<PathBuf>,
+    destination: PathBuf,
+    /// Known once the sources were scanned.
+    files_total: u64,
+    bytes_total: u64,
+    files_done: u64,
+    files_skipped: u64,
+    files_failed: u64,
+    bytes_done: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Counts the progress of a running job.
+#[derive(Default)]
+struct JobProgress {
+    files_total: AtomicU64,
+    bytes_total: AtomicU64,
+    files_done: AtomicU64,
+    files_skipped: AtomicU64,
+    files_failed: AtomicU64,
+    bytes_done: AtomicU64,
+}
+
+impl ProgressSink for JobProgress {
+    fn on_scan_finished(&self, files: u64, bytes: u64) {
+        self.files_total.store(files, Ordering::Relaxed);
+        self.bytes_total.store(bytes, Ordering::Relaxed);
+    }
+
+    fn on_bytes(&self, _from: &Path, bytes: u64) {
+        self.bytes_done.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn on_file_done(&self, _from: &Path, _to: &Path, _bytes: u64) {
+        self.files_done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_file_skipped(&self, _from: &Path, _to: &Path, _reason: &str) {
+        self.files_skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_error(&self, _path: &Path, _message: &str) {
+        self.files_failed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+struct Job {
+    state: JobState,
+    priority: i32,
+    sources: Vec<PathBuf>,
+    destination: PathBuf,
+    recursive: bool,
+    overwrite: Overwrite,
+    progress: Arc<JobProgress>,
+    cancel: CancellationToken,
+    error: Option<String>,
+}
+
+impl Job {
+    fn status(&self, id: u64) -> JobStatus {
+        let progress = &self.progress;
+        JobStatus {
+            id,
+            state: self.state,
+            priority: self.priority,
+            sources: self.sources.clone(),
+            destination: self.destination.clone(),
+            files_total: progress.files_total.load(Ordering::Relaxed),
+            bytes_total: progress.bytes_total.load(Ordering::Relaxed),
+            files_done: progress.files_done.load(Ordering::Relaxed),
+            files_skipped: progress.files_skipped.load(Ordering::Relaxed),
+            files_failed: progress.files_failed.load(Ordering::Relaxed),
+            bytes_done: progress.bytes_done.load(Ordering::Relaxed),
+            error: self.error.clone(),
+        }
+    }
+}
+
+/// The job queue shared by the connections and the runner.
+struct Daemon {
+    jobs: Mutex<BTreeMap<u64, Job>>,
+    next_id: AtomicU64,
+    /// Wakes the runner when a job is queued.
+    queued: Notify,
+    /// Cancelled on Ctrl-C, which cancels every job with it.
+    shutdown: CancellationToken,
+    parallel: usize,
+    throttle: Throttle,
+}
+
+impl Daemon {
+    fn handle(&self, request: Request) -> Response {
+        let mut jobs = self.jobs.lock().unwrap();
+        match request {
+            Request::Submit { sources, destination, recursive, overwrite, priority } => {
+                if sources.is_empty() {
+                    return Response::error("no sources given");
+                }
+                let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+                jobs.insert(
+                    id,
+                    Job {
+                        state: JobState::Queued,
+                        priority,
+                        sources,
+                        destination,
+                        recursive,
+                        overwrite,
+                        progress: Arc::default(),
+                        cancel: self.shutdown.child_token(),
+                        error: None,
+                    },
+                );
+                self.queued.notify_one();
+                Response {
+                    ok: true,
+                    id: Some(id),
+                    ..Response::default()
+                }
+            }
+            Request::Status { id: Some(id) } => match jobs.get(&id) {
+                Some(job) => Response {
+                    ok: true,
+                    jobs: Some(vec![job.status(id)]),
+                    ..Response::default()
+                },
+                None => Response::error(format!("no job {id}")),
+            },
+            Request::Status { id: None } => Response {
+                ok: true,
+                jobs: Some(jobs.iter().map(|(id, job)| job.status(*id)).collect()),
+                ..Response::default()
+            },
+            Request::Cancel { id } => match jobs.get_mut(&id) {
+                Some(job) if matches!(job.state, JobState::Queued | JobState::Running) => {
+                    job.cancel.cancel();
+                    if job.state == JobState::Queued {
+                        job.state = JobState::Cancelled;
+                    }
+                    Response {
+                        ok: true,
+                        id: Some(id),
+                        ..Response::default()
+                    }
+                }
+                Some(_) => Response::error(format!("job {id} already finished")),
+                None => Response::error(format!("no job {id}")),
+            },
+        }
+    }
+
+    /// Marks the queued job of the highest priority as running and returns
+    /// the options to copy it with.
+    fn start_next(&self) -> Option<(u64, Vec<PathBuf>, PathBuf, CopyOptions)> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let (&id, job) = jobs
+            .iter_mut()
+            .filter(|(_, job)| job.state == JobState::Queued)
+            // The earliest of the highest priority.
+            .max_by_key(|(id, job)| (job.priority, std::cmp::Reverse(**id)))?;
+        job.state = JobState::Running;
+        let options = CopyOptions::new()
+            .recursive(job.recursive)
+            .overwrite(job.overwrite)
+            .parallel(self.parallel)
+            .throttle(self.throttle.clone())
+            .progress(Arc::clone(&job.progress) as Arc<dyn ProgressSink>)
+            .cancel(job.cancel.clone());
+        Some((id, job.sources.clone(), job.destination.clone(), options))
+    }
+
+    /// Runs the queued jobs one at a time, so they share one pipeline of
+    /// `parallel` file copies instead of competing.
+    async fn run_jobs(&self) {
+        while !self.shutdown.is_cancelled() {
+            let Some((id, sources, destination, options)) = self.start_next() else {
+                tokio::select! {
+                    _ = self.queued.notified() => {}
+                    _ = self.shutdown.cancelled() => {}
+                }
+                continue;
+            };
+            log::info!("Starting job {}", id);
+            let result = engine::copy(&sources, &destination, &options).await;
+            let mut jobs = self.jobs.lock().unwrap();
+            let job = jobs.get_mut(&id).expect("jobs are never removed");
+            (job.state, job.error) = match result {
+                Ok(report) if report.cancelled => (JobState::Cancelled, None),
+                Ok(report) if report.totals.files_failed > 0 => (
+                    JobState::Failed,
+                    Some(format!("{} of the files could not be copied", report.totals.files_failed)),
+                ),
+                Ok(_) => (JobState::Done, None),
+                Err(e) => (JobState::Failed, Some(e.to_string())),
+            };
+            log::info!("Job {} finished: {:?}", id, job.state);
+        }
+    }
+}
+
+/// Where the daemon listens unless `--socket` says otherwise: `cp2.sock` in
+/// `$XDG_RUNTIME_DIR`, or a per-user socket in the temporary directory.
+#[cfg(unix)]
+fn default_socket() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("cp2.sock"),
+        // SAFETY: getuid has no preconditions.
+        _ => std::env::temp_dir().join(format!("cp2-{}.sock", unsafe { libc::getuid() })),
+    }
+}
+
+/// `cp2 daemon`: accepts copy jobs as JSON lines on a Unix socket and runs
+/// them one at a time, highest priority first, with `parallel` files at
+/// once and at most `bwlimit` bytes per second between them.
+#[cfg(unix)]
+pub(crate) async fn run(socket: Option<PathBuf>, parallel: usize, bwlimit: Option<u64>) {
+    let socket = socket.unwrap_or_else(default_socket);
+    if std::os::unix::net::UnixStream::connect(&socket).is_ok() {
+        eprintln!("{} {}", "A daemon is already listening on".red(), socket.display().to_string().red());
+        std::process::exit(exit::USAGE);
+    }
+    // Jobs run as this user, so nobody else may connect: the socket is
+    // made private, and connections from other users are refused in case
+    // they got in before that.
+    let listener = match crate::events::bind_unix_socket(&socket).and_then(|listener| {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&socket, std::fs::Permissions::from_mode(0o600)).map(|()| listener)
+    }) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("{} {}: {}", "Cannot listen on".red(), socket.display().to_string().red(), e);
+            let _ = std::fs::remove_file(&socket);
+            std::process::exit(exit::FAILURE);
+        }
+    };
+    // SAFETY: getuid has no preconditions.
+    let uid = unsafe { libc::getuid() };
+    let daemon = Arc::new(Daemon {
+        jobs: Mutex::new(BTreeMap::new()),
+        next_id: AtomicU64::new(1),
+        queued: Notify::new(),
+        shutdown: crate::interrupt::install(),
+        parallel,
+        throttle: Throttle::new(bwlimit),
+    });
+    let runner = {
+        let daemon = Arc::clone(&daemon);
+        tokio::spawn(async move { daemon.run_jobs().await })
+    };
+    eprintln!("{}", format!("Listening for jobs on {}", socket.display()).bold());
+    loop {
+        let stream = tokio::select! {
+            _ = daemon.shutdown.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    log::warn!("Cannot accept a connection: {}", e);
+                    continue;
+                }
+            },
+        };
+        match stream.peer_cred() {
+            Ok(cred) if cred.uid() == uid => {}
+            Ok(cred) => {
+                log::warn!("Refusing a connection from user {}", cred.uid());
+                continue;
+            }
+            Err(e) => {
+                log::warn!("Cannot check who connected: {}", e);
+                continue;
+            }
+        }
+        tokio::spawn(serve(stream, Arc::clone(&daemon)));
+    }
+    // Let the running job clean up its partial files.
+    let _ = runner.await;
+    let _ = std::fs::remove_file(&socket);
+    std::process::exit(exit::INTERRUPTED);
+}
+
+#[cfg(not(unix))]
+pub(crate) async fn run(_socket: Option<PathBuf>, _parallel: usize, _bwlimit: Option<u64>) {
+    eprintln!("{}", "cp2 daemon needs Unix sockets".red());
+    std::process::exit(exit::USAGE);
+}
+
+/// Answers the requests of one connection, a line for a line.
+#[cfg(unix)]
+async fn serve(stream: tokio::net::UnixStream, daemon: Arc<Daemon>) {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str(&line) {
+            Ok(request) => daemon.handle(request),
+            Err(e) => Response::error(format!("invalid request: {e}")),
+        };
+        let mut json = serde_json::to_string(&response).expect("responses serialize");
+        json.push('\n');
+        if writer.write_all(json.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
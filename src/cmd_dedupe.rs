@@ -0,0 +1,154 @@
+use colored::Colorize;
+use indicatif::ProgressBar;
+use std::collections::{BTreeMap, HashSet};
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+use crate::copy::reflink;
+use crate::exit;
+use crate::hash::{self, HashAlgorithm};
+use crate::progress;
+use crate::resume::with_suffix;
+use crate::utils::{trim_filename, walk_dir};
+
+/// Suffix of the link created next to a duplicate before it replaces it.
+const TEMP_SUFFIX: &str = ".cp2-dedupe";
+
+/// `cp2 dedupe`: finds files below `dir` with the same size and SHA-256
+/// digest and replaces every copy but the first (by path) with a hard link
+/// to it, or a reflink with `use_reflink`. With `dry_run`, only reports what
+/// would be linked.
+pub(crate) async fn run(dir: PathBuf, use_reflink: bool, dry_run: bool, parallel: usize) {
+    if !dir.is_dir() {
+        eprintln!("{} {}", "Not a directory:".red(), dir.display().to_string().red());
+        std::process::exit(exit::USAGE);
+    }
+    let files = match walk_dir(&dir, parallel, false, false, None).await {
+        Ok((files, _, _, _)) => files,
+        Err(e) => {
+            eprintln!("{} {}: {}", "Cannot read".red(), dir.display().to_string().red(), e);
+            std::process::exit(exit::FAILURE);
+        }
+    };
+
+    // Only files sharing their size with another can be duplicates, and
+    // files that are already hard links of each other count once. Empty
+    // files have nothing to save.
+    let mut by_size: BTreeMap<u64, Vec<PathBuf>> = BTreeMap::new();
+    let mut seen = HashSet::new();
+    let mut files = files;
+    files.sort();
+    for (path, size) in files {
+        if size > 0 && file_key(&path).is_none_or(|key| seen.insert(key)) {
+            by_size.entry(size).or_default().push(path);
+        }
+    }
+    let candidates: Vec<(PathBuf, u64)> = by_size
+        .into_iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        .flat_map(|(size, paths)| paths.into_iter().map(move |path| (path, size)))
+        .collect();
+
+    let total: u64 = candidates.iter().map(|(_, size)| size).sum();
+    let pb = match std::io::stderr().is_terminal() {
+        true => Arc::new(ProgressBar::new(total).with_style(progress::file_bar_style())),
+        false => Arc::new(ProgressBar::hidden()),
+    };
+    let semaphore = Arc::new(Semaphore::new(parallel));
+    let mut tasks = Vec::new();
+    for (path, size) in candidates {
+        let sem = Arc::clone(&semaphore);
+        let pb = Arc::clone(&pb);
+        tasks.push(tokio::spawn(async move {
+            let _permit = sem
+                .acquire()
+                .await
+                .expect("failed to acquire semaphore permit");
+
+            let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+            pb.set_message(format!("Hashing {}", trim_filename(&name, 28)));
+            let digest = hash::hash_file(&path, HashAlgorithm::Sha256, |n| pb.inc(n)).await;
+            (path, size, digest)
+        }));
+    }
+    let mut groups: BTreeMap<(u64, String), Vec<PathBuf>> = BTreeMap::new();
+    let mut failed = 0u64;
+    for task in tasks {
+        match task.await {
+            Ok((path, size, Ok(digest))) => groups.entry((size, digest)).or_default().push(path),
+            Ok((path, _, Err(e))) => {
+                pb.suspend(|| eprintln!("{} {}: {}", "Cannot read".red(), path.display().to_string().red(), e));
+                failed += 1;
+            }
+            Err(_) => failed += 1,
+        }
+    }
+    pb.finish_and_clear();
+
+    let verb = match (dry_run, use_reflink) {
+        (true, false) => "Would link",
+        (true, true) => "Would reflink",
+        (false, false) => "Linked",
+        (false, true) => "Reflinked",
+    };
+    let (mut duplicates, mut saved) = (0u64, 0u64);
+    for ((size, _), paths) in groups.iter().filter(|(_, paths)| paths.len() > 1) {
+        let original = &paths[0];
+        for duplicate in &paths[1..] {
+            if !dry_run && let Err(e) = replace(original, duplicate, use_reflink) {
+                eprintln!("{} {}: {}", "Failed to link".red(), duplicate.display().to_string().red(), e);
+                failed += 1;
+                continue;
+            }
+            println!("{} {} -> {}", verb.green(), duplicate.display(), original.display());
+            duplicates += 1;
+            saved += size;
+        }
+    }
+
+    let summary = match dry_run {
+        true => format!("{} found, {} could be saved", duplicates_noun(duplicates), progress::format_bytes(saved)),
+        false => format!("{} replaced, {} saved", duplicates_noun(duplicates), progress::format_bytes(saved)),
+    };
+    eprintln!("{}", summary);
+    if failed > 0 {
+        std::process::exit(if duplicates > 0 { exit::PARTIAL } else { exit::FAILURE });
+    }
+}
+
+fn duplicates_noun(n: u64) -> String {
+    match n {
+        1 => "1 duplicate".to_string(),
+        n => format!("{n} duplicates"),
+    }
+}
+
+/// Replaces `duplicate` with a link to `original`. The link is made under a
+/// temporary name and renamed over the duplicate, so the duplicate is never
+/// missing, even if linking fails.
+fn replace(original: &Path, duplicate: &Path, use_reflink: bool) -> std::io::Result<()> {
+    let temp = with_suffix(duplicate, TEMP_SUFFIX);
+    let linked = match use_reflink {
+        true => reflink(original, &temp),
+        false => std::fs::hard_link(original, &temp),
+    };
+    linked.and_then(|()| std::fs::rename(&temp, duplicate)).inspect_err(|_| {
+        let _ = std::fs::remove_file(&temp);
+    })
+}
+
+/// Identifies the file at `path` regardless of the name it was reached
+/// through, so existing hard links are recognised.
+#[cfg(unix)]
+fn file_key(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    let meta = std::fs::metadata(path).ok()?;
+    Some((meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_key(_path: &Path) -> Option<(u64, u64)> {
+    None
+}
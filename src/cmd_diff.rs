@@ -0,0 +1,58 @@
+use colored::Colorize;
+use indicatif::ProgressBar;
+use std::io::{IsTerminal, Write};
+use std::path::PathBuf;
+
+use crate::cmd_verify::{check_exists, tree_size};
+use crate::diff::{diff_trees, ChangeKind, DiffOptions};
+use crate::exit;
+use crate::progress;
+use crate::utils::trim_filename;
+
+/// `cp2 diff SOURCE DEST`: lists what was added to, removed from or changed
+/// in `destination` relative to `source`, as text or, with `json`, as one
+/// JSON object per change on stdout.
+pub(crate) async fn run(source: PathBuf, destination: PathBuf, options: DiffOptions, json: bool) {
+    check_exists(&source, &destination);
+    let pb = match options.hash.is_some() && std::io::stderr().is_terminal() {
+        true => ProgressBar::new(tree_size(&source)).with_style(progress::file_bar_style()),
+        false => ProgressBar::hidden(),
+    };
+    let diff = diff_trees(&source, &destination, &options, &|name, n| match n {
+        0 => pb.set_message(format!("Comparing {}", trim_filename(name, 28))),
+        n => pb.inc(n),
+    })
+    .await;
+    pb.finish_and_clear();
+
+    let mut stdout = std::io::stdout().lock();
+    for change in &diff.changes {
+        let line = match json {
+            true => serde_json::to_string(change).expect("changes serialize"),
+            false => match change.change {
+                ChangeKind::Added => format!("{} {}", "A".green(), change.path),
+                ChangeKind::Removed => format!("{} {}", "D".red(), change.path),
+                ChangeKind::Modified => format!("{} {} ({})", "M".yellow(), change.path, change.describe()),
+            },
+        };
+        let _ = writeln!(stdout, "{}", line);
+    }
+    let _ = stdout.flush();
+
+    let count = |kind| diff.changes.iter().filter(|c| c.change == kind).count();
+    if !json {
+        match diff.changes.is_empty() {
+            true => eprintln!("No differences ({} compared)", progress::files(diff.same_files)),
+            false => eprintln!(
+                "{} added, {} removed, {} modified, {} unchanged",
+                count(ChangeKind::Added),
+                count(ChangeKind::Removed),
+                count(ChangeKind::Modified),
+                progress::files(diff.same_files)
+            ),
+        }
+    }
+    if !diff.changes.is_empty() {
+        std::process::exit(exit::DIFFERENT);
+    }
+}
@@ -0,0 +1,91 @@
+use colored::Colorize;
+use indicatif::ProgressBar;
+use serde::Serialize;
+use std::io::IsTerminal;
+use std::path::PathBuf;
+
+use crate::exit;
+use crate::progress;
+use crate::utils::walk_dir;
+
+/// What `cp2 du` found at or below one path.
+#[derive(Serialize)]
+struct Usage {
+    path: String,
+    files: u64,
+    dirs: u64,
+    bytes: u64,
+}
+
+/// `cp2 du`: prints how many files and bytes a copy of each of `paths`
+/// would take, walking directories the way copies do.
+pub(crate) async fn run(paths: Vec<PathBuf>, json: bool, parallel: usize, follow_symlinks: bool) {
+    let scan_pb = match std::io::stderr().is_terminal() {
+        true => {
+            let pb = ProgressBar::new(0);
+            pb.set_style(progress::scan_spinner_style());
+            pb.enable_steady_tick(std::time::Duration::from_millis(100));
+            Some(pb)
+        }
+        false => None,
+    };
+
+    let mut usages = Vec::new();
+    let mut failed = false;
+    for path in &paths {
+        let metadata = match follow_symlinks {
+            true => std::fs::metadata(path),
+            false => std::fs::symlink_metadata(path),
+        };
+        let usage = match metadata {
+            Ok(metadata) if metadata.is_dir() => walk_dir(path, parallel, follow_symlinks, false, scan_pb.as_ref())
+                .await
+                .map(|(files, dirs, _, _)| Usage {
+                    path: path.display().to_string(),
+                    files: files.len() as u64,
+                    // The directory itself is created too.
+                    dirs: dirs.len() as u64 + 1,
+                    bytes: files.iter().map(|(_, size)| size).sum(),
+                }),
+            Ok(metadata) => Ok(Usage {
+                path: path.display().to_string(),
+                files: metadata.is_file() as u64,
+                dirs: 0,
+                bytes: if metadata.is_file() { metadata.len() } else { 0 },
+            }),
+            Err(e) => Err(e),
+        };
+        match usage {
+            Ok(usage) => usages.push(usage),
+            Err(e) => {
+                let print = || eprintln!("{} {}: {}", "Cannot read".red(), path.display().to_string().red(), e);
+                match &scan_pb {
+                    Some(pb) => pb.suspend(print),
+                    None => print(),
+                }
+                failed = true;
+            }
+        }
+    }
+    if let Some(pb) = scan_pb {
+        pb.finish_and_clear();
+    }
+
+    if json {
+        for usage in &usages {
+            println!("{}", serde_json::to_string(usage).expect("usage serializes"));
+        }
+    } else {
+        for usage in &usages {
+            println!("{:>10}  {:>12}  {}", progress::format_bytes(usage.bytes), progress::files(usage.files), usage.path);
+        }
+        if usages.len() > 1 {
+            let files = usages.iter().map(|usage| usage.files).sum();
+            let bytes = usages.iter().map(|usage| usage.bytes).sum();
+            println!("{:>10}  {:>12}  {}", progress::format_bytes(bytes), progress::files(files), "total".bold());
+        }
+    }
+    if failed {
+        std::process::exit(if usages.is_empty() { exit::FAILURE } else { exit::PARTIAL });
+    }
+}
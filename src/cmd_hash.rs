@@ -0,0 +1,157 @@
+use colored::Colorize;
+use indicatif::ProgressBar;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use tokio::sync::Semaphore;
+
+use crate::events::EventSink;
+use crate::exit;
+use crate::hash::{self, HashAlgorithm};
+use crate::progress::{self, ProgressMode, RunProgress, RunStats};
+use crate::utils::trim_filename;
+
+/// A file to hash, under the name it is listed with.
+struct Entry {
+    name: String,
+    path: PathBuf,
+    size: u64,
+}
+
+/// `cp2 hash`: prints the digest of every file at or below `paths` to
+/// stdout, in the format `sha256sum` and `b3sum` (and `cp2 verify`) read,
+/// hashing up to `parallel` files at once.
+pub(crate) async fn run(paths: Vec<PathBuf>, algorithm: HashAlgorithm, parallel: usize) {
+    let stats = Arc::new(RunStats::new());
+    let mut entries = Vec::new();
+    for path in &paths {
+        match std::fs::metadata(path) {
+            Ok(metadata) if metadata.is_dir() => collect(path, algorithm, &mut entries),
+            Ok(metadata) => entries.push(Entry {
+                name: path.display().to_string(),
+                path: path.clone(),
+                size: metadata.len(),
+            }),
+            Err(e) => {
+                eprintln!("{} {}: {}", "Cannot read".red(), path.display().to_string().red(), e);
+                stats.files_failed.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    let total_size = entries.iter().map(|entry| entry.size).sum();
+    let mode = match std::io::stderr().is_terminal() {
+        true => ProgressMode::Bars,
+        false => ProgressMode::Hidden,
+    };
+    let run_progress = RunProgress::start(
+        mode,
+        false,
+        total_size,
+        entries.len() as u64,
+        Arc::clone(&stats),
+        Arc::new(EventSink::new(false)),
+    );
+    let multi = run_progress.as_ref().and_then(|p| p.multi.clone());
+    let main_pb = run_progress.as_ref().map(|p| Arc::clone(&p.main));
+
+    let semaphore = Arc::new(Semaphore::new(parallel));
+    let mut tasks = Vec::new();
+    for entry in entries {
+        let sem = Arc::clone(&semaphore);
+        let multi = multi.clone();
+        let main_pb = main_pb.clone();
+        tasks.push(tokio::spawn(async move {
+            let _permit = sem
+                .acquire()
+                .await
+                .expect("failed to acquire semaphore permit");
+
+            let file_pb = multi.map(|multi| {
+                let pb = multi.add(ProgressBar::new(entry.size));
+                pb.set_style(progress::file_bar_style());
+                let file_name = entry.path.file_name().unwrap_or_default().to_string_lossy();
+                pb.set_message(format!("Hashing {}", trim_filename(&file_name, 28)));
+                pb
+            });
+            let digest = hash::hash_file(&entry.path, algorithm, |n| {
+                if let Some(pb) = &file_pb {
+                    pb.inc(n);
+                }
+                if let Some(pb) = &main_pb {
+                    pb.inc(n);
+                }
+            })
+            .await;
+            if let Some(pb) = file_pb {
+                pb.finish_and_clear();
+            }
+            (entry, digest)
+        }));
+    }
+
+    // Digests are printed in the order the files were listed, whichever
+    // finishes first.
+    for task in tasks {
+        let Ok((entry, digest)) = task.await else {
+            stats.files_failed.fetch_add(1, Ordering::Relaxed);
+            continue;
+        };
+        let print = || match &digest {
+            Ok(digest) => print!("{}", hash::sums_line(digest, &entry.name)),
+            Err(e) => eprintln!("{} {}: {}", "Cannot read".red(), entry.name.red(), e),
+        };
+        match &multi {
+            Some(multi) => multi.suspend(print),
+            None => print(),
+        }
+        match digest {
+            Ok(_) => {
+                stats.files_done.fetch_add(1, Ordering::Relaxed);
+                stats.bytes_copied.fetch_add(entry.size, Ordering::Relaxed);
+            }
+            Err(_) => {
+                stats.files_failed.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+    let _ = std::io::stdout().flush();
+
+    if let Some(run_progress) = run_progress {
+        run_progress.finish("Hashing complete!").await;
+    }
+    stats.print_summary("Hashed");
+    if stats.files_failed.load(Ordering::Relaxed) > 0 {
+        std::process::exit(exit::for_failed_run(&stats));
+    }
+}
+
+/// Adds the files below `dir`, sorted by name, leaving out checksum files
+/// for `algorithm` so a listing can be written into the tree it describes.
+fn collect(dir: &Path, algorithm: HashAlgorithm, entries: &mut Vec<Entry>) {
+    let mut children: Vec<_> = match std::fs::read_dir(dir) {
+        Ok(children) => children.flatten().collect(),
+        Err(e) => {
+            log::warn!("Cannot read {}: {}", dir.display(), e);
+            return;
+        }
+    };
+    children.sort_by_key(|child| child.file_name());
+    for child in children {
+        let path = child.path();
+        let Ok(metadata) = std::fs::metadata(&path) else {
+            continue;
+        };
+        let name = child.file_name().to_string_lossy().into_owned();
+        if metadata.is_dir() {
+            collect(&path, algorithm, entries);
+        } else if metadata.is_file() && name != algorithm.sums_file() && !name.ends_with(algorithm.suffix()) {
+            entries.push(Entry {
+                name: path.display().to_string(),
+                path,
+                size: metadata.len(),
+            });
+        }
+    }
+}
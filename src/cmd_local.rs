@@ -1,41 +1,115 @@
-use crate::copy::copy_file_with_dual_progress;
-use crate::utils::{collect_copy_entries, trim_filename, CopyEntry};
+use crate::copy::{
+    CopyError, copy_file_blocking, copy_file_chunked, copy_file_direct, copy_file_hashed, copy_file_kernel, copy_file_split, copy_file_to_many, copy_file_with_buffer, is_locked, is_transient,
+    pace, recreate_special, retry_delay, same_file, split_chunk_path, split_manifest_path,
+};
+use crate::attrs::Attrs;
+use crate::cache;
+use crate::cmd_remote;
+use crate::delta::copy_file_delta;
+use crate::compress::Compression;
+use crate::dashboard;
+use crate::encrypt::{Decryption, Encryption};
+use crate::filter::{copy_file_filtered, Filter};
+use crate::hash::{self, HashAlgorithm};
+use crate::manifest::{Manifest, ManifestEntry};
+use crate::cli::{CaseConflict, DropCache, Engine, RunOptions, SmbTuning};
+use crate::events::{CopyEvent, EventSink};
+use crate::exit;
+use crate::failures::{self, Failures, COMMAND_FAILED};
+use crate::progress::{self, BarSlots, FileBars, ProgressMode, RunProgress, RunStats};
+use crate::hooks;
+use crate::interrupt;
+use crate::job::JobLog;
+use crate::keys;
+use crate::lock::{DestinationLock, LockError};
+use crate::logfile::FileLog;
+use crate::notify;
+use crate::preflight;
+use crate::resume;
+use crate::snapshot;
+use crate::report::Recorder;
+use crate::throttle::Throttle;
+use crate::timings::{self, Phase};
+use crate::transaction::Transaction;
+use crate::undo::UndoLog;
+use crate::utils::{
+    case_collisions, case_fold, case_unique_path, collect_copy_entries, normalize_path, sanitize_path, trim_filename, CopyEntry, LockedPolicy,
+    SpecialEntry,
+};
 use colored::Colorize;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget};
+use std::borrow::Cow;
 use std::collections::HashSet;
 use std::path::Path;
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+
+/// Files smaller than this are grouped into batches instead of getting their
+/// own task and progress bar.
+const SMALL_FILE_THRESHOLD: u64 = 64 * 1024; // 64 KiB
+
+/// Maximum number of small files handled by a single batch task.
+const BATCH_MAX_FILES: usize = 256;
+
+/// Buffer used on SMB shares; the Linux client's default write size, so each
+/// write maps to a single request.
+const SMB_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+/// Most directories scanned at once on SMB shares.
+const SMB_SCAN_PARALLEL: usize = 2;
+
+/// How often a locked source is tried again with `--locked wait`.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(500);
 
 /// Runs a local filesystem copy for the given sources.
-pub(crate) async fn run(
-    sources: Vec<String>,
-    destination: std::path::PathBuf,
-    recursive: bool,
-    sync: bool,
-    parallel: usize,
-    is_quiet: bool,
-) {
+pub(crate) async fn run(sources: Vec<String>, destination: std::path::PathBuf, options: &RunOptions) {
+    let recursive = options.copy.recursive;
+    let parallel = options.copy.parallel;
+    if options.timings {
+        timings::enable();
+    }
+    if options.drop_cache == DropCache::Never {
+        cache::disable();
+    }
+    let (events, file_log) = cmd_remote::open_outputs(options);
+
     if !destination.exists() {
         log::debug!("Destination path does not exist: {}", destination.display());
-        println!(
-            "{} {}",
-            "Destination path does not exist: ".red(),
-            destination.display().to_string().red()
-        );
-        std::process::exit(1);
+        failures::print_error(Some(&destination), "scan", std::io::ErrorKind::NotFound.to_string().into(), "destination path does not exist", || {
+            eprintln!(
+                "{} {}",
+                "Destination path does not exist: ".red(),
+                destination.display().to_string().red()
+            )
+        });
+        std::process::exit(exit::USAGE);
     }
     if !destination.is_dir() {
         log::debug!(
             "Destination path is not a directory: {}",
             destination.display()
         );
-        println!(
-            "{} {}",
-            "Destination path is not a directory: ".red(),
-            destination.display().to_string().red()
-        );
-        std::process::exit(1);
+        failures::print_error(Some(&destination), "scan", std::io::ErrorKind::NotADirectory.to_string().into(), "destination path is not a directory", || {
+            eprintln!(
+                "{} {}",
+                "Destination path is not a directory: ".red(),
+                destination.display().to_string().red()
+            )
+        });
+        std::process::exit(exit::USAGE);
+    }
+    if let Some(dir) = options.also_to.iter().find(|dir| !dir.is_dir()) {
+        let kind = match dir.exists() {
+            true => std::io::ErrorKind::NotADirectory,
+            false => std::io::ErrorKind::NotFound,
+        };
+        failures::print_error(Some(dir), "scan", kind.to_string().into(), "--also-to path is not a directory", || {
+            eprintln!("{} {}", "--also-to path is not a directory:".red(), dir.display().to_string().red())
+        });
+        std::process::exit(exit::USAGE);
     }
 
     // Validate sources.
@@ -45,19 +119,28 @@ pub(crate) async fn run(
     for source_str in &sources {
         let source = Path::new(source_str);
         if !source.exists() {
-            eprintln!(
-                "{} {}",
-                "Source path does not exist:".red(),
-                source_str.red()
-            );
+            failures::print_error(Some(source), "scan", std::io::ErrorKind::NotFound.to_string().into(), "source path does not exist", || {
+                eprintln!(
+                    "{} {}",
+                    "Source path does not exist:".red(),
+                    source_str.red()
+                )
+            });
+            events.error(Some(source), "source path does not exist");
             has_errors = true;
             continue;
         }
         if source.is_dir() && !recursive {
-            eprintln!(
-                "{} {}",
-                "Source path is a directory, but recursive flag is not set:".red(),
-                source_str.red()
+            failures::print_error(Some(source), "scan", std::io::ErrorKind::IsADirectory.to_string().into(), "source path is a directory, but recursive flag is not set", || {
+                eprintln!(
+                    "{} {}",
+                    "Source path is a directory, but recursive flag is not set:".red(),
+                    source_str.red()
+                )
+            });
+            events.error(
+                Some(source),
+                "source path is a directory, but recursive flag is not set",
             );
             has_errors = true;
             continue;
@@ -66,28 +149,167 @@ pub(crate) async fn run(
     }
 
     if valid_sources.is_empty() {
-        std::process::exit(1);
+        std::process::exit(exit::USAGE);
+    }
+
+    // From here on the run ends with `quit`, which removes the snapshots. A
+    // resumed copy takes them again, as those of the first run are gone.
+    if options.snapshot {
+        for (source, e) in snapshot::read_from_snapshots(&mut valid_sources) {
+            eprintln!("{} {}: {}", "Cannot snapshot, reading live:".yellow(), source, e);
+        }
+    }
+
+    // SMB shares answer each metadata request with a network round trip and
+    // favour fewer, larger writes, so scans are kept narrow and the buffer
+    // grows to match the client's default write size.
+    let smb_paths: Vec<&str> = match options.smb_tuning {
+        SmbTuning::Off => Vec::new(),
+        SmbTuning::On => vec!["forced with --smb-tuning on"],
+        SmbTuning::Auto => {
+            let mut paths: Vec<&str> = valid_sources
+                .iter()
+                .filter(|s| preflight::is_smb(Path::new(s)))
+                .map(String::as_str)
+                .collect();
+            if preflight::is_smb(&destination) {
+                paths.push(destination.to_str().unwrap_or("destination"));
+            }
+            paths
+        }
+    };
+    let mut buffer_size = options.copy.buffer_size;
+    let mut scan_parallel = parallel;
+    let mut tuning_notes = Vec::new();
+    if !smb_paths.is_empty() {
+        tuning_notes.push(format!("SMB share detected: {}", smb_paths.join(", ")));
+        if options.default_buffer_size {
+            buffer_size = SMB_BUFFER_SIZE;
+            tuning_notes.push(format!("SMB tuning: buffer size set to {}", progress::format_bytes(SMB_BUFFER_SIZE as u64)));
+        }
+        if parallel > SMB_SCAN_PARALLEL {
+            scan_parallel = SMB_SCAN_PARALLEL;
+            tuning_notes.push(format!("SMB tuning: at most {SMB_SCAN_PARALLEL} directories scanned at once"));
+        }
+        for note in &tuning_notes {
+            log::info!("{}", note);
+        }
     }
 
+    events.emit(&CopyEvent::ScanStarted {
+        sources: valid_sources.clone(),
+    });
+
+    // While the sources are walked, a spinner shows how much has been found
+    // so far; its position counts files and its length sums their sizes.
+    let scan_pb = if matches!(options.progress, ProgressMode::Bars | ProgressMode::Tui) && options.resume.is_none() {
+        let pb = ProgressBar::new(0);
+        pb.set_style(progress::scan_spinner_style());
+        pb.enable_steady_tick(std::time::Duration::from_millis(100));
+        Some(pb)
+    } else {
+        None
+    };
+
     // Collect all copy entries.
     let mut all_entries: Vec<CopyEntry> = Vec::new();
-    let mut all_dirs: Vec<std::path::PathBuf> = Vec::new();
+    let mut all_dirs: Vec<(std::path::PathBuf, std::path::PathBuf)> = Vec::new();
     let mut total_size: u64 = 0;
     let mut dest_paths: HashSet<std::path::PathBuf> = HashSet::new();
+    // Sources whose permission bits suggest they can't be read, for the
+    // preflight check.
+    let mut maybe_unreadable: Vec<std::path::PathBuf> = Vec::new();
 
-    for source_str in &valid_sources {
+    let path_limits = preflight::PathLimits::of(&destination);
+    // Programs each file is piped through for --decrypt, --compress and --encrypt.
+    let filters: Vec<Filter> = options
+        .decrypt
+        .iter()
+        .map(Decryption::filter)
+        .chain(options.compress.map(Compression::filter))
+        .chain(options.encrypt.iter().map(Encryption::filter))
+        .collect();
+    // Destinations changed by --normalize, --sanitize-names or --case-conflict rename, as
+    // (new, original) pairs.
+    let mut renames: Vec<(std::path::PathBuf, std::path::PathBuf)> = Vec::new();
+    // Special files to recreate with --special/--devices, and those skipped.
+    let mut all_specials: Vec<SpecialEntry> = Vec::new();
+    let mut skipped_specials: Vec<SpecialEntry> = Vec::new();
+    // A resumed copy already knows its files.
+    let scanned_sources = match options.resume {
+        Some(_) => &[][..],
+        None => &valid_sources[..],
+    };
+    for source_str in scanned_sources {
         let source = Path::new(source_str);
-        match collect_copy_entries(source, &destination).await {
-            Ok((entries, dirs, _count, size)) => {
-                let mut source_has_dup = false;
-                for entry in &entries {
-                    if dest_paths.contains(&entry.to) {
+        match collect_copy_entries(source, &destination, scan_parallel, options.copy.dereference, options.copy.skip_hidden, scan_pb.as_ref()).await {
+            Ok((mut entries, mut dirs, _count, mut size, mut specials, unreadable)) => {
+                if options.normalize.is_some() || options.sanitize_names.is_some() {
+                    let targets = entries.iter_mut().map(|e| &mut e.to);
+                    for to in targets.chain(dirs.iter_mut().map(|(_, to)| to)).chain(specials.iter_mut().map(|s| &mut s.to)) {
+                        let normalized = options.normalize.and_then(|form| normalize_path(&destination, to, form));
+                        let current = normalized.as_deref().unwrap_or(to);
+                        let sanitized = options
+                            .sanitize_names
+                            .as_deref()
+                            .and_then(|replacement| sanitize_path(&destination, current, replacement));
+                        if let Some(renamed) = sanitized.or(normalized) {
+                            renames.push((renamed.clone(), std::mem::replace(to, renamed)));
+                        }
+                    }
+                }
+                if !filters.is_empty() {
+                    for entry in entries.iter_mut() {
+                        entry.to = filtered_path(&entry.to, options);
+                    }
+                }
+                entries.retain_mut(|entry| {
+                    let problem = if same_file(&entry.from, &entry.to) {
+                        "source and destination are the same file".to_string()
+                    } else {
+                        match path_limits.check(&mut entry.to) {
+                            Ok(()) => return true,
+                            Err(e) => format!("destination {}", e),
+                        }
+                    };
+                    failures::print_error(Some(&entry.from), "scan", "invalid destination".to_string().into(), &problem, || {
                         eprintln!(
                             "{} {} -> {}",
-                            "Duplicate destination path:".red(),
+                            format!("Cannot copy ({}):", problem).red(),
                             entry.from.display().to_string().red(),
                             entry.to.display().to_string().red()
-                        );
+                        )
+                    });
+                    events.error(Some(&entry.from), problem);
+                    has_errors = true;
+                    size -= entry.size;
+                    false
+                });
+                dirs.retain_mut(|(_, dir)| match path_limits.check(dir) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        let message = format!("destination {}", e);
+                        failures::print_error(Some(dir), "scan", "invalid destination".to_string().into(), &message, || {
+                            eprintln!("{} {}", format!("Cannot create directory ({}):", e).red(), dir.display().to_string().red())
+                        });
+                        events.error(Some(dir), message);
+                        has_errors = true;
+                        false
+                    }
+                });
+                let mut source_has_dup = false;
+                for entry in &entries {
+                    if dest_paths.contains(&entry.to) {
+                        let kind = "duplicate destination path";
+                        failures::print_error(Some(&entry.from), "scan", kind.to_string().into(), kind, || {
+                            eprintln!(
+                                "{} {} -> {}",
+                                "Duplicate destination path:".red(),
+                                entry.from.display().to_string().red(),
+                                entry.to.display().to_string().red()
+                            )
+                        });
+                        events.error(Some(&entry.from), "duplicate destination path");
                         has_errors = true;
                         source_has_dup = true;
                     }
@@ -100,17 +322,143 @@ pub(crate) async fn run(
                 }
                 all_entries.extend(entries);
                 all_dirs.extend(dirs);
+                maybe_unreadable.extend(unreadable);
+                for special in specials {
+                    if if special.kind.is_device() { options.devices } else { options.special } {
+                        all_specials.push(special);
+                    } else {
+                        skipped_specials.push(special);
+                    }
+                }
                 total_size += size;
             }
             Err(e) => {
-                eprintln!("{} {}", "Error:".red(), e.to_string().red());
+                failures::print_error(Some(source), "scan", failures::kind_of(e.as_ref()), &e.to_string(), || {
+                    eprintln!("{} {}", "Error:".red(), e.to_string().red())
+                });
+                events.error(Some(source), e.to_string());
                 has_errors = true;
             }
         }
     }
 
-    if all_entries.is_empty() && all_dirs.is_empty() {
-        std::process::exit(1);
+    if let Some(pb) = scan_pb {
+        pb.finish_and_clear();
+    }
+    if let Some(ref plan) = options.resume {
+        eprintln!(
+            "{}",
+            format!("Resuming: {} already copied, {} left", progress::files(plan.done as u64), progress::files(plan.pending.len() as u64))
+                .bold()
+        );
+        all_entries = plan.pending.clone();
+        all_dirs = plan.dirs.clone();
+        if options.snapshot {
+            for entry in &mut all_entries {
+                entry.from = snapshot::in_snapshot(&entry.from);
+            }
+            for (from, _) in &mut all_dirs {
+                *from = snapshot::in_snapshot(from);
+            }
+        }
+        total_size = all_entries.iter().map(|entry| entry.size).sum();
+    }
+
+    if !skipped_specials.is_empty() {
+        for special in &skipped_specials {
+            log::warn!("skipping {}: {}", special.kind, special.from.display());
+        }
+        let noun = if skipped_specials.len() == 1 { "special file" } else { "special files" };
+        eprintln!(
+            "{}",
+            format!(
+                "Skipped {} {} (FIFOs, sockets or devices); use --special or --devices to recreate them",
+                skipped_specials.len(),
+                noun
+            )
+            .yellow()
+        );
+    }
+
+    if all_entries.is_empty() && all_dirs.is_empty() && all_specials.is_empty() {
+        quit(exit::USAGE);
+    }
+
+    if preflight::is_case_insensitive(&destination) {
+        let collisions = case_collisions(&all_entries);
+        match options.case_conflict {
+            CaseConflict::Error => {
+                for &i in collisions.iter().rev() {
+                    let entry = all_entries.remove(i);
+                    let message = "destination differs only in case from another file";
+                    failures::print_error(Some(&entry.from), "scan", "case conflict".to_string().into(), message, || {
+                        eprintln!(
+                            "{} {} -> {}",
+                            "Destination differs only in case from another file:".red(),
+                            entry.from.display().to_string().red(),
+                            entry.to.display().to_string().red()
+                        )
+                    });
+                    events.error(Some(&entry.from), "destination differs only in case from another file");
+                    has_errors = true;
+                    total_size -= entry.size;
+                }
+            }
+            CaseConflict::Rename => {
+                let mut taken: HashSet<String> = all_entries.iter().map(|e| case_fold(&e.to)).collect();
+                for &i in &collisions {
+                    let entry = &mut all_entries[i];
+                    let renamed = case_unique_path(&entry.to, &taken);
+                    eprintln!(
+                        "{} {} -> {}",
+                        "Renaming to avoid a case conflict:".yellow(),
+                        entry.from.display(),
+                        renamed.display()
+                    );
+                    taken.insert(case_fold(&renamed));
+                    renames.push((renamed.clone(), std::mem::replace(&mut entry.to, renamed)));
+                }
+            }
+        }
+        if all_entries.is_empty() && all_dirs.is_empty() {
+            quit(exit::USAGE);
+        }
+    }
+
+    // Files an earlier run already copied are left out before anything is
+    // counted, so the totals and the overwrite warning only cover the rest.
+    let mut unchanged = Vec::new();
+    if options.skip_identical {
+        let mut remaining = Vec::with_capacity(all_entries.len());
+        for entry in all_entries {
+            match is_unchanged(&entry).await {
+                true => {
+                    total_size -= entry.size;
+                    unchanged.push(entry);
+                }
+                false => remaining.push(entry),
+            }
+        }
+        all_entries = remaining;
+    }
+
+    // Files over --skip-larger-than are left out the same way, and listed so
+    // that a copy onto a small stick doesn't lose them silently.
+    let mut too_large = Vec::new();
+    if let Some(max) = options.skip_larger_than {
+        let (large, remaining): (Vec<CopyEntry>, Vec<CopyEntry>) = all_entries.into_iter().partition(|e| e.size > max);
+        all_entries = remaining;
+        if !large.is_empty() {
+            eprintln!(
+                "{}",
+                format!("Skipping {} larger than {}:", progress::files(large.len() as u64), progress::format_bytes(max)).yellow()
+            );
+            for entry in &large {
+                total_size -= entry.size;
+                eprintln!("  {} ({})", entry.from.display(), progress::format_bytes(entry.size));
+            }
+            too_large = large;
+        }
     }
 
     log::info!(
@@ -118,44 +466,321 @@ pub(crate) async fn run(
         all_entries.len(),
         total_size
     );
+    events.emit(&CopyEvent::ScanFinished {
+        files: all_entries.len() as u64,
+        bytes: total_size,
+    });
+    if let Some(ref log) = file_log {
+        log.note(&format!(
+            "copy started: {} files, {} bytes",
+            all_entries.len(),
+            total_size
+        ));
+    }
 
-    // Create destination directories upfront.
-    for dir in &all_dirs {
-        if let Err(e) = tokio::fs::create_dir_all(dir).await {
-            eprintln!(
-                "{} {}",
-                "Error creating directory:".red(),
-                e.to_string().red()
-            );
-            std::process::exit(1);
+    // Files too large for the destination (FAT32 stops at 4 GiB) are refused
+    // up front, or written as chunks with --split-oversize.
+    let mut split_oversize = None;
+    if let Some(max) = options.max_file_size.or_else(|| preflight::max_file_size(&destination)) {
+        let oversize: Vec<&CopyEntry> = all_entries.iter().filter(|e| e.size > max).collect();
+        if !oversize.is_empty() {
+            if options.split_oversize {
+                log::info!("Splitting {} files larger than {} bytes into chunks", oversize.len(), max);
+                split_oversize = Some(max);
+            } else {
+                if !failures::is_json() {
+                    eprintln!(
+                        "{}",
+                        format!(
+                            "{} too large for the destination filesystem (max {}):",
+                            progress::files(oversize.len() as u64),
+                            progress::format_bytes(max)
+                        )
+                        .red()
+                    );
+                }
+                for entry in &oversize {
+                    let message = format!(
+                        "file too large for the destination filesystem ({}, max {})",
+                        progress::format_bytes(entry.size),
+                        progress::format_bytes(max)
+                    );
+                    failures::print_error(Some(&entry.from), "scan", "file too large".to_string().into(), &message, || {
+                        eprintln!("  {} ({})", entry.from.display(), progress::format_bytes(entry.size))
+                    });
+                    events.error(Some(&entry.from), "file too large for the destination filesystem");
+                }
+                if !failures::is_json() {
+                    eprintln!("Use --split-oversize to write them as numbered chunks.");
+                }
+                quit(exit::USAGE);
+            }
         }
     }
 
-    let (multi_progress, main_pb) = if !is_quiet {
-        let multi = MultiProgress::new();
-        let main_pb = multi.add(ProgressBar::new(total_size));
-        main_pb.set_style(
-            ProgressStyle::default_bar()
-                .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-                .unwrap()
-                .progress_chars("=>-"),
+    if !renames.is_empty() {
+        for (to, original) in &renames {
+            log::info!("Renaming {} to {}", original.display(), to.display());
+            if let Some(ref log) = file_log {
+                log.note(&format!("{} renamed to {}", original.display(), to.display()));
+            }
+        }
+        let noun = if renames.len() == 1 { "path" } else { "paths" };
+        eprintln!(
+            "{}",
+            format!("Renamed {} {} to fit the destination filesystem", renames.len(), noun).yellow()
         );
-        main_pb.set_message("Overall progress");
-        main_pb.enable_steady_tick(std::time::Duration::from_millis(100));
-        (Some(Arc::new(multi)), Some(Arc::new(main_pb)))
-    } else {
-        (None, None)
+    }
+
+    let report = preflight::Report::check(&destination, &all_entries, &maybe_unreadable);
+    report.emit(&destination, &events);
+    // The report is prose, kept out of quiet runs and machine-readable output.
+    let print_report = !options.quiet && !options.json && !failures::is_json();
+    if print_report {
+        report.print(&destination);
+    }
+    if report.is_fatal() {
+        if !print_report {
+            report.print_fatal(&destination);
+        }
+        quit(exit::USAGE);
+    }
+    if !report.is_clean() && options.interactive && !preflight::confirm("Continue with the copy?") {
+        eprintln!("Aborted.");
+        quit(exit::USAGE);
+    }
+
+    // Files the copy overwrites keep their space while they are kept for
+    // `cp2 undo` or until a transactional copy commits.
+    // The --also-to directories are checked for the whole copy, as the
+    // files found at the destination say nothing about what is in them.
+    let keeps_overwritten = options.undo || options.transactional;
+    let checked = std::iter::once((&destination, keeps_overwritten)).chain(options.also_to.iter().map(|dir| (dir, true)));
+    for (dir, keeps_overwritten) in checked {
+        let Err(e) = preflight::check_free_space(dir, &all_entries, keeps_overwritten) else {
+            continue;
+        };
+        if options.ignore_space_check {
+            eprintln!("{} {}", "Warning: not enough free space:".yellow(), e);
+        } else {
+            failures::print_error(Some(dir), "space check", "not enough free space".to_string().into(), &e, || {
+                eprintln!("{} {}", "Not enough free space:".red(), e.red());
+                eprintln!("Use --ignore-space-check to copy anyway.");
+            });
+            events.error(Some(dir), format!("not enough free space: {}", e));
+            quit(exit::USAGE);
+        }
+    }
+
+    let lock = match options.lock {
+        true => lock_destination(&destination, options.wait_lock, &events).await,
+        false => None,
+    };
+
+    // With --undo every change is recorded for `cp2 undo`, replacing the
+    // record of the previous copy.
+    let undo = match options.undo {
+        true => UndoLog::start(&destination)
+            .inspect_err(|e| log::warn!("Cannot record the copy for cp2 undo: {}", e))
+            .ok(),
+        false => None,
+    };
+    // A transactional copy removes the directories it created when it is
+    // rolled back.
+    let transaction = options.transactional.then(Transaction::new);
+    for (_, dir) in &all_dirs {
+        if !dir.exists() {
+            if let Some(ref undo) = undo {
+                undo.created(dir);
+            }
+            if let Some(ref transaction) = transaction {
+                transaction.created(dir);
+            }
+        }
+    }
+
+    // Create destination directories upfront, in every --also-to directory
+    // too.
+    let also_dirs = all_dirs.iter().flat_map(|(_, dir)| also_paths(&destination, &options.also_to, dir));
+    for dir in all_dirs.iter().map(|(_, dir)| dir.clone()).chain(also_dirs) {
+        let dir = &dir;
+        if let Err(e) = tokio::fs::create_dir_all(dir).await {
+            failures::print_error(Some(dir), "create directory", failures::kind_of(&e), &e.to_string(), || {
+                eprintln!(
+                    "{} {}",
+                    "Error creating directory:".red(),
+                    e.to_string().red()
+                )
+            });
+            events.error(Some(dir), format!("error creating directory: {}", e));
+            if let Some(ref transaction) = transaction {
+                transaction.rollback().await;
+            }
+            drop(lock);
+            quit(exit::FAILURE);
+        }
+    }
+
+    // The plan is kept until the copy has finished, for `cp2 resume`. A
+    // transactional copy that didn't finish leaves nothing to resume.
+    let job = match options.resume {
+        _ if options.transactional => None,
+        Some(ref plan) => Some(JobLog::reopen(plan)),
+        None => Some(JobLog::create(&all_dirs, &all_entries)),
+    };
+    let job = job
+        .transpose()
+        .inspect_err(|e| log::warn!("Cannot record the copy for cp2 resume: {}", e))
+        .ok()
+        .flatten();
+
+    let interrupt = interrupt::install();
+    // Ctrl-C cancels the run through its parent; --fail-fast cancels it
+    // directly.
+    let cancel = interrupt.child_token();
+    if has_errors && options.fail_fast {
+        cancel.cancel();
+    }
+    let total_files = all_entries.len() as u64;
+    let stats = Arc::new(RunStats::new());
+    let run_progress = RunProgress::start(
+        options.progress,
+        options.term_progress,
+        total_size,
+        total_files,
+        Arc::clone(&stats),
+        Arc::clone(&events),
+    );
+    let throttle = Throttle::new(options.bwlimit);
+    let key_listener = match run_progress {
+        Some(ref run_progress) if run_progress.multi.is_some() => {
+            keys::listen(throttle.clone(), Arc::clone(&run_progress.main))
+        }
+        _ => None,
     };
 
+    // With several sources, every directory among them gets a bar of its
+    // own below the overall one, sized from what the scan found in it.
+    let source_bars = match run_progress.as_ref().and_then(|p| p.multi.as_ref()) {
+        Some(multi) if valid_sources.len() > 1 => valid_sources
+            .iter()
+            .map(Path::new)
+            .filter(|source| source.is_dir())
+            .map(|source| {
+                let size = all_entries.iter().filter(|e| e.from.starts_with(source)).map(|e| e.size).sum();
+                let name = source
+                    .file_name()
+                    .map_or_else(|| source.display().to_string(), |name| name.to_string_lossy().into_owned());
+                let pb = multi.add(ProgressBar::new(size));
+                pb.set_style(progress::file_bar_style());
+                pb.set_message(format!("{}/", trim_filename(&name, 27)));
+                (source.to_path_buf(), pb)
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    let ctx = Arc::new(CopyContext {
+        sync: options.copy.sync,
+        split_threshold: options.split_threshold,
+        direct_io: options.direct_io,
+        engine: options.engine,
+        parallel,
+        multi: run_progress.as_ref().and_then(|p| p.multi.clone()),
+        file_bars: run_progress.as_ref().and_then(|p| p.file_bars.clone()),
+        main_pb: run_progress.as_ref().map(|p| Arc::clone(&p.main)),
+        source_bars,
+        stats: Arc::clone(&stats),
+        events: Arc::clone(&events),
+        recorder: options.report.as_ref().map(|_| Recorder::new()),
+        file_log: file_log.clone(),
+        pre_file_cmd: options.pre_file_cmd.clone(),
+        post_file_cmd: options.post_file_cmd.clone(),
+        throttle,
+        retries: options.retries,
+        retry_delay: options.retry_delay,
+        fail_fast: options.fail_fast,
+        locked: options.locked,
+        max_file_size: split_oversize,
+        keep_partial: options.keep_partial,
+        delta: options.delta,
+        checksum: options.checksum,
+        filters,
+        hashing: options
+            .write_hashes
+            .or((options.manifest.is_some() || options.verify).then(HashAlgorithm::default)),
+        verify: options.verify,
+        write_hashes: options.write_hashes.is_some(),
+        hash_sidecars: options.hash_sidecars,
+        hash_root: match destination.is_dir() {
+            true => destination.clone(),
+            false => destination.parent().map(Path::to_path_buf).unwrap_or_default(),
+        },
+        digests: Mutex::new(Vec::new()),
+        manifest: options.manifest.as_ref().map(|_| Mutex::new(Vec::new())),
+        buffer_size,
+        attrs: Attrs::new(options),
+        cancel: cancel.clone(),
+        has_failed: Mutex::new(has_errors),
+        failures: Failures::new(),
+        job,
+        transaction,
+        undo,
+        destination: destination.clone(),
+        also_to: options.also_to.clone(),
+    });
+
+    if let Some(ref recorder) = ctx.recorder {
+        for note in tuning_notes {
+            recorder.note(note);
+        }
+        for (to, original) in renames {
+            recorder.renamed(to.display().to_string(), original.display().to_string());
+        }
+    }
+
+    for entry in &unchanged {
+        record_skip(&ctx, entry, "unchanged at destination");
+    }
+    for entry in &too_large {
+        record_skip(&ctx, entry, "larger than --skip-larger-than");
+    }
+
+    for special in &all_specials {
+        if let Some(ref undo) = ctx.undo {
+            undo.before_write(&special.to, true);
+        }
+        for to in std::iter::once(special.to.clone()).chain(ctx.also_targets(&special.to)) {
+            match recreate_special(&special.from, &to) {
+                Ok(()) => {
+                    log::info!("Created {} {}", special.kind, to.display());
+                    if let Some(ref transaction) = ctx.transaction {
+                        transaction.created(&to);
+                    }
+                }
+                Err(e) => {
+                    let message = format!("cannot create {}: {}", special.kind, e);
+                    ctx.events.error(Some(&special.from), message.clone());
+                    ctx.failures.record(&special.from, "create special file", failures::kind_of(&e), message);
+                    ctx.fail();
+                }
+            }
+        }
+    }
+
     let semaphore = Arc::new(Semaphore::new(parallel));
-    let has_failed = Arc::new(Mutex::new(has_errors));
     let mut tasks = Vec::new();
 
-    for entry in all_entries {
+    // Tiny files are dominated by per-task and per-bar overhead, so they are
+    // copied in batches by a single worker sharing one buffer.
+    let (small_entries, large_entries): (Vec<CopyEntry>, Vec<CopyEntry>) = all_entries
+        .into_iter()
+        .partition(|e| e.size < SMALL_FILE_THRESHOLD);
+    let mut small_entries = small_entries.into_iter().peekable();
+    while small_entries.peek().is_some() {
+        let batch: Vec<CopyEntry> = small_entries.by_ref().take(BATCH_MAX_FILES).collect();
         let sem = Arc::clone(&semaphore);
-        let multi_clone = multi_progress.as_ref().map(Arc::clone);
-        let main_pb_clone = main_pb.as_ref().map(Arc::clone);
-        let has_failed_clone = Arc::clone(&has_failed);
+        let ctx = Arc::clone(&ctx);
 
         tasks.push(tokio::spawn(async move {
             let _permit = sem
@@ -163,65 +788,1029 @@ pub(crate) async fn run(
                 .await
                 .expect("failed to acquire semaphore permit");
 
-            let file_pb = if let Some(ref multi) = multi_clone {
-                let file_name = entry
-                    .from
-                    .file_name()
-                    .and_then(|n| n.to_str())
-                    .unwrap_or("unknown");
-                let pb = multi.add(ProgressBar::new(entry.size));
-                pb.set_style(
-                    ProgressStyle::default_bar()
-                        .template(
-                            "  {spinner:.green} {msg:<30} [{wide_bar:.yellow/blue}] {bytes}/{total_bytes}",
-                        )
-                        .unwrap()
-                        .progress_chars("=>-"),
-                );
-                let display_name = trim_filename(file_name, 28);
-                pb.set_message(format!("Copying {}", display_name));
-                Some(pb)
-            } else {
-                None
-            };
-
-            match copy_file_with_dual_progress(
-                &entry.from,
-                &entry.to,
-                file_pb.as_ref(),
-                main_pb_clone.as_deref(),
-                sync,
-            )
-            .await
-            {
-                Ok(_) => {
-                    if let Some(ref pb) = file_pb {
-                        pb.finish_and_clear();
+            if ctx.engine == Engine::Threads {
+                // The batch gets a thread of its own, where the files are
+                // copied with blocking calls instead of one trip to tokio's
+                // thread pool per operation.
+                let runtime = tokio::runtime::Handle::current();
+                let copied = tokio::task::spawn_blocking(move || {
+                    let mut buffer = vec![0u8; SMALL_FILE_THRESHOLD as usize];
+                    for entry in batch {
+                        runtime.block_on(copy_entry(&ctx, &entry, Some(&mut buffer)));
                     }
-                }
-                Err(e) => {
-                    if let Some(ref pb) = file_pb {
-                        pb.finish_and_clear();
-                    }
-                    eprintln!("Error copying file: {}", e);
-                    *has_failed_clone.lock().unwrap() = true;
-                }
+                });
+                copied.await.expect("copy thread panicked");
+                return;
+            }
+            let mut buffer = vec![0u8; SMALL_FILE_THRESHOLD as usize];
+            for entry in batch {
+                copy_entry(&ctx, &entry, Some(&mut buffer)).await;
             }
         }));
     }
 
+    for entry in large_entries {
+        let sem = Arc::clone(&semaphore);
+        let ctx = Arc::clone(&ctx);
+
+        tasks.push(tokio::spawn(async move {
+            let _permit = sem
+                .acquire()
+                .await
+                .expect("failed to acquire semaphore permit");
+
+            copy_entry(&ctx, &entry, None).await;
+        }));
+    }
+
     for task in tasks {
         if let Err(e) = task.await {
-            eprintln!("Copy task failed: {}", e);
-            *has_failed.lock().unwrap() = true;
+            ctx.failures.record(&destination, "copy task", "task failed".to_string(), e.to_string());
+            ctx.fail();
+        }
+    }
+
+    // Once every file is copied, a transactional copy renames them into
+    // place; after a failure or Ctrl-C, it removes them again.
+    let mut rolled_back = false;
+    if let Some(ref transaction) = ctx.transaction {
+        if *ctx.has_failed.lock().unwrap() || cancel.is_cancelled() {
+            transaction.rollback().await;
+            rolled_back = true;
+        } else if let Err(e) = transaction.commit(ctx.undo.as_ref()).await {
+            failures::print_error(Some(&destination), "commit", failures::kind_of(&e), &e.to_string(), || {
+                eprintln!("{} {}", "Failed to commit the copy:".red(), e)
+            });
+            ctx.failures.record(&destination, "commit", failures::kind_of(&e), e.to_string());
+            ctx.fail();
+            rolled_back = true;
+        }
+    }
+
+    if let Some(algorithm) = ctx.hashing
+        && ctx.write_hashes
+        && !ctx.hash_sidecars
+        && !rolled_back
+    {
+        let digests = std::mem::take(&mut *ctx.digests.lock().unwrap());
+        let sums = ctx.hash_root.join(algorithm.sums_file());
+        if !digests.is_empty()
+            && let Err(e) = hash::update_sums_file(&sums, digests).await
+        {
+            failures::print_error(Some(&sums), "write checksums", failures::kind_of(&e), &e.to_string(), || {
+                eprintln!("{} {}: {}", "Failed to write".red(), sums.display().to_string().red(), e)
+            });
+            ctx.fail();
+        }
+    }
+    if let (Some(path), Some(entries), Some(algorithm)) = (&options.manifest, &ctx.manifest, ctx.hashing)
+        && !rolled_back
+    {
+        let manifest = Manifest::new(algorithm, std::mem::take(&mut *entries.lock().unwrap()));
+        if let Err(e) = manifest.write(path, options.manifest_format, &ctx.hash_root).await {
+            failures::print_error(Some(path), "write manifest", failures::kind_of(&e), &e.to_string(), || {
+                eprintln!("{} {}: {}", "Failed to write".red(), path.display().to_string().red(), e)
+            });
+            ctx.fail();
+        }
+    }
+
+    // Creating files bumps the times of their directories, so directory
+    // attributes are applied last, deepest directories first.
+    if ctx.attrs.is_enabled() && !interrupt.is_cancelled() && !rolled_back {
+        all_dirs.sort_by_key(|(_, to)| std::cmp::Reverse(to.components().count()));
+        for (from, to) in &all_dirs {
+            for to in std::iter::once(to.clone()).chain(ctx.also_targets(to)) {
+                for warning in ctx.attrs.apply(from, &to) {
+                    warn(&ctx, &warning);
+                }
+            }
+        }
+    }
+
+    snapshot::release_all();
+
+    let interrupted = interrupt.is_cancelled();
+    let aborted = !interrupted && cancel.is_cancelled();
+    if let Some(key_listener) = key_listener {
+        key_listener.stop();
+    }
+    for (_, pb) in &ctx.source_bars {
+        pb.finish();
+    }
+    if let Some(run_progress) = run_progress {
+        let headline = match (interrupted, aborted) {
+            (true, _) => "Copy interrupted!",
+            (false, true) => "Copy aborted!",
+            (false, false) => "Copy complete!",
+        };
+        run_progress.finish(headline).await;
+    }
+    ctx.failures.print_report();
+    if options.summary {
+        stats.print_summary("Copied");
+    }
+    if options.timings {
+        timings::print_breakdown();
+    }
+    if interrupted || aborted {
+        let reason = if interrupted { "Interrupted" } else { "Aborted after the first error" };
+        let not_copied = stats.not_finished(total_files);
+        eprintln!("{}", format!("{}: {} not copied", reason, progress::files(not_copied)).yellow());
+        events.error(
+            None,
+            format!("{}: {} not copied", reason.to_lowercase(), progress::files(not_copied)),
+        );
+    }
+    if rolled_back {
+        eprintln!("{}", "Rolled back: the destination is left as it was before the copy".yellow());
+        events.error(Some(&destination), "copy rolled back");
+    }
+    events.emit(&stats.summary_event());
+    events.close().await;
+    if let Some(ref log) = file_log {
+        log.note(&format!(
+            "copy finished: {} copied, {} failed, {} bytes",
+            stats.files_done.load(Ordering::Relaxed),
+            stats.files_failed.load(Ordering::Relaxed),
+            stats.bytes_copied.load(Ordering::Relaxed)
+        ));
+    }
+
+    if let (Some(path), Some(recorder)) = (&options.report, &ctx.recorder)
+        && let Err(e) = recorder.write(path, stats.started.elapsed())
+    {
+        failures::print_error(Some(path), "write report", failures::kind_of(e.as_ref()), &e.to_string(), || {
+            eprintln!("{} {}", "Failed to write report:".red(), e)
+        });
+        *ctx.has_failed.lock().unwrap() = true;
+    }
+
+    if options.on_complete_url.is_some() || options.on_complete_cmd.is_some() {
+        hooks::on_complete(
+            options.on_complete_url.as_ref(),
+            options.on_complete_cmd.as_deref(),
+            &stats.summary_event(),
+        )
+        .await;
+    }
+    let failed = *ctx.has_failed.lock().unwrap();
+    if let Some(ref job) = ctx.job {
+        match interrupted || failed {
+            true => eprintln!("{}", format!("Continue with: cp2 resume {}", job.id).yellow()),
+            false => job.remove(),
+        }
+    }
+    // `process::exit` skips destructors, and the lock file should go.
+    drop(lock);
+    if options.notify {
+        let summary = match (interrupted, failed) {
+            (true, _) => "Copy interrupted",
+            (false, true) if aborted => "Copy aborted after an error",
+            (false, true) => "Copy finished with errors",
+            (false, false) => "Copy complete",
+        };
+        notify::run_finished(summary.to_string(), &stats).await;
+    }
+    if interrupted {
+        quit(exit::INTERRUPTED);
+    }
+    if failed {
+        // Nothing is left of a rolled-back copy.
+        let status = match rolled_back {
+            true => exit::FAILURE,
+            false => exit::for_failed_run(&stats),
+        };
+        ctx.failures.print_exit_message(status);
+        quit(status);
+    }
+}
+
+/// Exits with `status`, removing the snapshots the sources were read from
+/// first.
+fn quit(status: i32) -> ! {
+    snapshot::release_all();
+    std::process::exit(status)
+}
+
+/// Locks `destination` against other runs, failing if one holds it unless
+/// `wait` is set. Destinations that can't be locked, e.g. on filesystems
+/// without lock support, are copied into unlocked with a warning.
+async fn lock_destination(destination: &Path, wait: bool, events: &EventSink) -> Option<DestinationLock> {
+    let held = match DestinationLock::acquire(destination, false) {
+        Ok(lock) => return Some(lock),
+        Err(LockError::Held(pid)) => pid,
+        Err(LockError::Io(e)) => {
+            log::warn!("Cannot lock {}: {}", destination.display(), e);
+            return None;
+        }
+    };
+    let holder = match held {
+        Some(pid) => format!("Another cp2 run (PID {}) is copying into", pid),
+        None => "Another cp2 run is copying into".to_string(),
+    };
+    if !wait {
+        let message = format!("{}: {}", holder, destination.display());
+        failures::print_error(Some(destination), "lock", "locked".to_string().into(), &message, || {
+            eprintln!("{} {}", format!("{}:", holder).red(), destination.display().to_string().red());
+            eprintln!("Use --wait-lock to wait for it to finish, or --no-lock to copy anyway.");
+        });
+        events.error(Some(destination), "destination is locked by another run");
+        quit(exit::USAGE);
+    }
+    eprintln!("{} {}", format!("{}, waiting for it to finish:", holder).yellow(), destination.display());
+    let dir = destination.to_path_buf();
+    match tokio::task::spawn_blocking(move || DestinationLock::acquire(&dir, true)).await {
+        Ok(Ok(lock)) => Some(lock),
+        Ok(Err(LockError::Io(e))) => {
+            log::warn!("Cannot lock {}: {}", destination.display(), e);
+            None
+        }
+        Ok(Err(LockError::Held(_))) => unreachable!("waits for the lock"),
+        Err(e) => {
+            log::warn!("Cannot lock {}: {}", destination.display(), e);
+            None
+        }
+    }
+}
+
+/// State shared by every copy task of a run.
+struct CopyContext {
+    sync: bool,
+    split_threshold: Option<u64>,
+    /// Copy file contents with O_DIRECT, bypassing the page cache.
+    direct_io: bool,
+    /// How batched small files are copied.
+    engine: Engine,
+    parallel: usize,
+    /// Container for per-file bars; `None` when they aren't displayed.
+    multi: Option<Arc<MultiProgress>>,
+    /// Hands out the per-file bars in `multi`.
+    file_bars: Option<Arc<BarSlots>>,
+    main_pb: Option<Arc<ProgressBar>>,
+    /// Bars of the directory sources, with the source each one belongs to.
+    source_bars: Vec<(std::path::PathBuf, ProgressBar)>,
+    stats: Arc<RunStats>,
+    events: Arc<EventSink>,
+    /// Collects per-file outcomes when `--report` is given.
+    recorder: Option<Recorder>,
+    /// Per-file log when `--log-file` is given.
+    file_log: Option<Arc<FileLog>>,
+    /// `--pre-file-cmd` template; a non-zero exit skips the file.
+    pre_file_cmd: Option<String>,
+    /// `--post-file-cmd` template run after each successful copy.
+    post_file_cmd: Option<String>,
+    /// Pause switch and bandwidth limit, adjustable from the keyboard.
+    throttle: Throttle,
+    /// How often a file is retried after a transient error.
+    retries: u32,
+    /// Delay before the first retry; doubled for every further one.
+    retry_delay: Duration,
+    /// Cancel the run at the first failure (`--fail-fast`).
+    fail_fast: bool,
+    /// What to do with sources locked by another process.
+    locked: LockedPolicy,
+    /// Largest file the destination can hold, when it has a limit and
+    /// `--split-oversize` asked for larger files to be split into chunks.
+    max_file_size: Option<u64>,
+    /// Leave partially written destinations in place on failure or Ctrl-C.
+    keep_partial: bool,
+    /// Update existing destinations in place, writing only changed blocks.
+    delta: bool,
+    /// Skip files whose destination already has identical contents.
+    checksum: bool,
+    /// Programs that decrypt, compress or encrypt each file on its way to
+    /// the destination, in that order.
+    filters: Vec<Filter>,
+    /// Digest each file while copying it, for `--write-hashes`,
+    /// `--manifest` or `--verify`.
+    hashing: Option<HashAlgorithm>,
+    /// Read each copied file back and compare its digest with the one
+    /// computed while copying it.
+    verify: bool,
+    /// List the digests in a checksum file, or with `hash_sidecars` next to
+    /// each file.
+    write_hashes: bool,
+    /// Write each digest next to its file instead of into the list at
+    /// `hash_root`.
+    hash_sidecars: bool,
+    /// Directory holding the list of digests; the paths in it are relative
+    /// to it.
+    hash_root: std::path::PathBuf,
+    /// Digests of the files copied so far, by path relative to `hash_root`.
+    digests: Mutex<Vec<(String, String)>>,
+    /// Entries of the `--manifest` of the files copied so far.
+    manifest: Option<Mutex<Vec<ManifestEntry>>>,
+    /// Size of the read/write buffer of each large-file copy.
+    buffer_size: usize,
+    /// Attributes to copy from each source to its destination.
+    attrs: Attrs,
+    /// Cancelled on Ctrl-C or, with `--fail-fast`, on the first failure: no
+    /// new files start and in-flight copies abort.
+    cancel: CancellationToken,
+    has_failed: Mutex<bool>,
+    /// Per-file failures for the report printed at the end.
+    failures: Failures,
+    /// Job file the copied files are ticked off in.
+    job: Option<JobLog>,
+    /// Files and directories written by a `--transactional` copy.
+    transaction: Option<Transaction>,
+    /// Journal of the changes for `cp2 undo`.
+    undo: Option<UndoLog>,
+    /// Destination directory of the copy.
+    destination: std::path::PathBuf,
+    /// Directories every file is also written to with `--also-to`.
+    also_to: Vec<std::path::PathBuf>,
+}
+
+impl CopyContext {
+    /// Whether `entry` is written as chunks because it is too large for the
+    /// destination.
+    fn is_oversize(&self, entry: &CopyEntry) -> bool {
+        self.max_file_size.is_some_and(|max| entry.size > max)
+    }
+
+    /// Whether `entry` takes the --delta path, updating its destination in
+    /// place; `batched` tells whether it is copied through a batch's shared
+    /// buffer, which rewrites it instead, like the other ways of copying.
+    fn updates_in_place(&self, entry: &CopyEntry, batched: bool) -> bool {
+        self.delta
+            && !batched
+            && self.filters.is_empty()
+            && self.hashing.is_none()
+            && !self.is_oversize(entry)
+            && !self.direct_io
+            && entry.to.is_file()
+    }
+
+    /// Bar of the directory source `entry` was found in, if it has one.
+    fn source_bar(&self, entry: &CopyEntry) -> Option<&ProgressBar> {
+        self.source_bars
+            .iter()
+            .find(|(source, _)| entry.from.starts_with(source))
+            .map(|(_, pb)| pb)
+    }
+
+    /// Where the file for `entry` is written: its destination, or with
+    /// `--transactional` a temporary name next to it.
+    fn target<'a>(&self, entry: &'a CopyEntry) -> Cow<'a, Path> {
+        match self.transaction {
+            Some(_) => Cow::Owned(Transaction::staged_path(&entry.to)),
+            None => Cow::Borrowed(&entry.to),
+        }
+    }
+
+    /// Where `path`, below the destination, is also written with
+    /// `--also-to`.
+    fn also_targets(&self, path: &Path) -> Vec<std::path::PathBuf> {
+        also_paths(&self.destination, &self.also_to, path).collect()
+    }
+
+    /// Marks the run as failed and, with `--fail-fast`, stops it.
+    fn fail(&self) {
+        *self.has_failed.lock().unwrap() = true;
+        if self.fail_fast {
+            self.cancel.cancel();
+        }
+    }
+}
+
+/// Copies a single entry and records the outcome. Files copied as part of a
+/// batch pass the batch's shared `buffer` and get no bar of their own.
+async fn copy_entry(ctx: &CopyContext, entry: &CopyEntry, buffer: Option<&mut [u8]>) {
+    if ctx.cancel.is_cancelled() {
+        return;
+    }
+    if let Some(ref cmd) = ctx.pre_file_cmd {
+        match hooks::pre_file_check(cmd, &entry.from, &entry.to.display().to_string()).await {
+            Ok(true) => {}
+            Ok(false) => {
+                skip_entry(ctx, entry, "rejected by --pre-file-cmd");
+                return;
+            }
+            Err(e) => {
+                ctx.failures.record(&entry.from, "pre-file command", COMMAND_FAILED.to_string(), e.clone());
+                ctx.stats.files_failed.fetch_add(1, Ordering::Relaxed);
+                if let Some(ref recorder) = ctx.recorder {
+                    recorder.record(&entry.from, entry.to.display().to_string(), 0, Duration::ZERO, Some(e.clone()));
+                }
+                ctx.events.error(Some(&entry.from), e);
+                ctx.fail();
+                return;
+            }
+        }
+    }
+
+    if ctx.checksum && hash::same_contents(&entry.from, &entry.to).await {
+        skip_entry(ctx, entry, "identical contents at destination");
+        return;
+    }
+
+    ctx.events.emit(&CopyEvent::FileStarted {
+        source: entry.from.display().to_string(),
+        destination: entry.to.display().to_string(),
+        bytes: entry.size,
+    });
+
+    // A file about to be overwritten is moved aside for `cp2 undo`, unless
+    // --delta updates it in place; a transactional copy only replaces it
+    // once committed.
+    let backup = match (&ctx.undo, &ctx.transaction) {
+        (Some(undo), None) => undo.before_write(&entry.to, !ctx.updates_in_place(entry, buffer.is_some())),
+        _ => None,
+    };
+
+    let file_pb = match (&ctx.file_bars, &buffer) {
+        (Some(slots), None) => {
+            let file_name = entry
+                .from
+                .file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or("unknown");
+            let pb = slots.add(entry.size);
+            pb.set_style(progress::file_bar_style());
+            let display_name = trim_filename(file_name, 28);
+            pb.set_message(format!("Copying {}", display_name));
+            Some(pb)
+        }
+        _ => None,
+    };
+    let main_pb = ctx.main_pb.as_deref();
+    let started = Instant::now();
+    let started_at = SystemTime::now();
+
+    // With retries enabled every attempt needs to know how many bytes it
+    // added, so they can be taken off the overall bar before trying again.
+    let attempt_pb = match (&file_pb, ctx.retries) {
+        (Some(pb), _) => Some(ProgressBar::clone(pb)),
+        (None, 0) => None,
+        (None, _) => Some(ProgressBar::with_draw_target(Some(entry.size), ProgressDrawTarget::hidden())),
+    };
+    let bars = FileBars {
+        file: attempt_pb.as_ref(),
+        source: ctx.source_bar(entry),
+        main: main_pb,
+        network: None,
+    };
+    let to = ctx.target(entry);
+    // With no progress display, or for a file whose bytes barely move the
+    // bars, the kernel copies it without going through a buffer.
+    let kernel_copy = (main_pb.is_none() || entry.size < SMALL_FILE_THRESHOLD)
+        && !ctx.direct_io
+        && !ctx.delta
+        && ctx.split_threshold.is_none_or(|threshold| entry.size < threshold || ctx.parallel < 2);
+    let mut buffer = buffer;
+    let mut attempt = 0;
+    let mut locked_since = None;
+    let mut digest = None;
+    let result = loop {
+        // The error is boiled down to what the retries and the report need.
+        let copy = async {
+            match (buffer.as_deref_mut(), ctx.split_threshold) {
+                // Every destination is written from the same read of the
+                // source.
+                (buffer, _) if !ctx.also_to.is_empty() => {
+                    let mut own_buffer = Vec::new();
+                    let buffer = match buffer {
+                        Some(buffer) => buffer,
+                        None => {
+                            own_buffer.resize(ctx.buffer_size, 0);
+                            &mut own_buffer[..]
+                        }
+                    };
+                    let mut targets = vec![to.to_path_buf()];
+                    targets.extend(ctx.also_targets(&entry.to));
+                    copy_file_to_many(
+                        &entry.from,
+                        &targets,
+                        buffer,
+                        Some(&bars),
+                        ctx.sync,
+                        Some(&ctx.throttle),
+                        Some(&ctx.cancel),
+                    )
+                    .await
+                }
+                (_, _) if !ctx.filters.is_empty() => {
+                    copy_file_filtered(
+                        &entry.from,
+                        &to,
+                        &ctx.filters,
+                        Some(&bars),
+                        ctx.sync,
+                        Some(&ctx.throttle),
+                        Some(&ctx.cancel),
+                    )
+                    .await
+                }
+                // The digest needs the data in order, so files aren't
+                // copied in chunks.
+                (buffer, _) if let Some(algorithm) = ctx.hashing => {
+                    let mut own_buffer = Vec::new();
+                    let buffer = match buffer {
+                        Some(buffer) => buffer,
+                        None => {
+                            own_buffer.resize(ctx.buffer_size, 0);
+                            &mut own_buffer[..]
+                        }
+                    };
+                    let mut hasher = algorithm.hasher();
+                    let copied = copy_file_hashed(
+                        &entry.from,
+                        &to,
+                        buffer,
+                        &mut hasher,
+                        Some(&bars),
+                        ctx.sync,
+                        Some(&ctx.throttle),
+                        Some(&ctx.cancel),
+                    )
+                    .await;
+                    match copied {
+                        Ok(bytes) => {
+                            let source_digest = hasher.finish();
+                            let checked = match ctx.verify {
+                                true => check_digest(&entry.from, &to, algorithm, &source_digest).await,
+                                false => Ok(()),
+                            };
+                            checked.map(|()| {
+                                digest = Some(source_digest);
+                                bytes
+                            })
+                        }
+                        Err(e) => Err(e),
+                    }
+                }
+                (_, _) if ctx.is_oversize(entry) => {
+                    copy_file_split(
+                        &entry.from,
+                        &to,
+                        ctx.max_file_size.unwrap_or(u64::MAX),
+                        Some(&bars),
+                        ctx.buffer_size,
+                        ctx.sync,
+                        Some(&ctx.throttle),
+                        Some(&ctx.cancel),
+                    )
+                    .await
+                }
+                // On a batch's own thread, blocking calls don't hold up
+                // other tasks.
+                (Some(_), _) if ctx.engine == Engine::Threads => {
+                    match copy_file_blocking(&entry.from, &to, Some(&bars), ctx.sync) {
+                        Ok(bytes) => pace(Some(&ctx.throttle), bytes, Some(&ctx.cancel)).await.map(|()| bytes),
+                        Err(e) => Err(e),
+                    }
+                }
+                (_, _) if kernel_copy => {
+                    copy_file_kernel(
+                        &entry.from,
+                        &to,
+                        Some(&bars),
+                        ctx.sync,
+                        Some(&ctx.throttle),
+                        Some(&ctx.cancel),
+                    )
+                    .await
+                }
+                // Small files are batched through a shared buffer and left to
+                // the page cache.
+                (Some(buffer), _) => {
+                    copy_file_with_buffer(
+                        &entry.from,
+                        &to,
+                        buffer,
+                        Some(&bars),
+                        ctx.sync,
+                        Some(&ctx.throttle),
+                        Some(&ctx.cancel),
+                    )
+                    .await
+                }
+                (None, _) if ctx.direct_io => {
+                    copy_file_direct(
+                        &entry.from,
+                        &to,
+                        Some(&bars),
+                        ctx.buffer_size,
+                        ctx.sync,
+                        Some(&ctx.throttle),
+                        Some(&ctx.cancel),
+                    )
+                    .await
+                }
+                (None, _) if ctx.updates_in_place(entry, false) => {
+                    copy_file_delta(&entry.from, &to, Some(&bars), ctx.sync, Some(&ctx.throttle), Some(&ctx.cancel))
+                        .await
+                        .map(|stats| {
+                            log::info!(
+                                "Wrote {} of {} bytes to {}",
+                                stats.written,
+                                stats.bytes,
+                                entry.to.display()
+                            );
+                            stats.bytes
+                        })
+                }
+                (None, Some(threshold)) if entry.size >= threshold && ctx.parallel > 1 => {
+                    copy_file_chunked(
+                        &entry.from,
+                        &to,
+                        entry.size,
+                        ctx.parallel,
+                        Some(&bars),
+                        ctx.buffer_size,
+                        ctx.sync,
+                        Some(&ctx.throttle),
+                        Some(&ctx.cancel),
+                    )
+                    .await
+                }
+                (None, _) => {
+                    let mut buffer = vec![0u8; ctx.buffer_size];
+                    copy_file_with_buffer(
+                        &entry.from,
+                        &to,
+                        &mut buffer,
+                        Some(&bars),
+                        ctx.sync,
+                        Some(&ctx.throttle),
+                        Some(&ctx.cancel),
+                    )
+                    .await
+                }
+            }
+            .map_err(|e| CopyFailure {
+                partial: e.is_partial(),
+                transient: is_transient(&e),
+                locked: is_locked(&e),
+                kind: failures::kind_of(&e),
+                message: e.to_string(),
+            })
+        };
+        // The copy stops between two buffers once the run is cancelled; it
+        // has closed its handles by the time the partial file is cleaned up.
+        let result = match copy.await {
+            Err(_) if ctx.cancel.is_cancelled() => None,
+            result => Some(result),
+        };
+
+        match result {
+            Some(Err(failure)) if failure.locked && ctx.locked != LockedPolicy::Fail => {
+                let LockedPolicy::Wait(timeout) = ctx.locked else {
+                    break Some(Err(failure));
+                };
+                if locked_since.get_or_insert_with(Instant::now).elapsed() >= timeout {
+                    break Some(Err(CopyFailure {
+                        message: format!("still locked after {:?}: {}", timeout, failure.message),
+                        ..failure
+                    }));
+                }
+                if let Some(ref pb) = file_pb {
+                    pb.set_message(format!(
+                        "Locked {}",
+                        trim_filename(&entry.from.file_name().unwrap_or_default().to_string_lossy(), 22)
+                    ));
+                }
+                tokio::select! {
+                    biased;
+                    _ = ctx.cancel.cancelled() => break None,
+                    _ = tokio::time::sleep(LOCK_POLL_INTERVAL) => {}
+                }
+            }
+            Some(Err(failure)) if failure.transient && attempt < ctx.retries => {
+                attempt += 1;
+                let delay = retry_delay(ctx.retry_delay, attempt);
+                log::warn!(
+                    "Retrying {} in {:?} (attempt {}/{}): {}",
+                    entry.from.display(),
+                    delay,
+                    attempt,
+                    ctx.retries,
+                    failure.message
+                );
+                if let Some(ref pb) = attempt_pb {
+                    if let Some(main_pb) = main_pb {
+                        main_pb.dec(pb.position());
+                    }
+                    if let Some(source_pb) = ctx.source_bar(entry) {
+                        source_pb.dec(pb.position());
+                    }
+                    pb.set_position(0);
+                }
+                if let Some(ref pb) = file_pb {
+                    pb.set_message(format!(
+                        "Retry {}/{} {}",
+                        attempt,
+                        ctx.retries,
+                        trim_filename(&entry.from.file_name().unwrap_or_default().to_string_lossy(), 22)
+                    ));
+                }
+                tokio::select! {
+                    biased;
+                    _ = ctx.cancel.cancelled() => break None,
+                    _ = tokio::time::sleep(delay) => {}
+                }
+            }
+            result => break result,
+        }
+    };
+
+    // Clears the bar and hands its slot to a file waiting for one.
+    drop(file_pb);
+    let Some(result) = result else {
+        abandon_entry(ctx, entry).await;
+        put_back(ctx, entry, backup);
+        return;
+    };
+    if let Err(ref failure) = result
+        && failure.locked
+        && ctx.locked == LockedPolicy::Skip
+    {
+        warn(ctx, &format!("Skipping {}: locked by another process", entry.from.display()));
+        skip_entry(ctx, entry, "locked by another process");
+        put_back(ctx, entry, backup);
+        return;
+    }
+
+    let (bytes, error) = match result {
+        Ok(bytes) => (bytes, None),
+        Err(failure) => {
+            if failure.partial {
+                remove_partial(ctx, entry).await;
+            }
+            put_back(ctx, entry, backup);
+            (0, Some(failure))
+        }
+    };
+    let message = error.as_ref().map(|failure| failure.message.clone());
+    if let Some(ref log) = ctx.file_log {
+        log.file(
+            &entry.from,
+            &entry.to.display().to_string(),
+            started_at,
+            bytes,
+            message.as_deref(),
+        );
+    }
+    if let Some(ref recorder) = ctx.recorder {
+        recorder.record(
+            &entry.from,
+            entry.to.display().to_string(),
+            bytes,
+            started.elapsed(),
+            message,
+        );
+    }
+
+    match error {
+        None => {
+            if let Some(digest) = digest {
+                record_digest(ctx, entry, digest).await;
+            }
+            if !ctx.is_oversize(entry) {
+                let applying = Instant::now();
+                let mut warnings = ctx.attrs.apply(&entry.from, &to);
+                for also in ctx.also_targets(&entry.to) {
+                    warnings.extend(ctx.attrs.apply(&entry.from, &also));
+                }
+                timings::record(&entry.from, Phase::Metadata, applying.elapsed());
+                for warning in warnings {
+                    warn(ctx, &warning);
+                }
+            }
+            if let Some(ref transaction) = ctx.transaction {
+                transaction.staged(&entry.to);
+            }
+            ctx.stats.files_done.fetch_add(1, Ordering::Relaxed);
+            ctx.stats.bytes_copied.fetch_add(bytes, Ordering::Relaxed);
+            if let Some(ref job) = ctx.job {
+                job.done(&entry.to);
+            }
+            ctx.events.emit(&CopyEvent::FileDone {
+                source: entry.from.display().to_string(),
+                destination: entry.to.display().to_string(),
+                bytes,
+            });
+            // A transactional copy isn't committed yet, so the hook gets the
+            // staged file, which is what ends up at the destination.
+            if let Some(ref cmd) = ctx.post_file_cmd
+                && let Err(e) = hooks::file_cmd(cmd, &entry.from, &to.display().to_string()).await
+            {
+                ctx.failures.record(&entry.from, "post-file command", COMMAND_FAILED.to_string(), e.clone());
+                ctx.events.error(Some(&entry.from), e);
+                ctx.fail();
+            }
+        }
+        Some(failure) => {
+            ctx.stats.files_failed.fetch_add(1, Ordering::Relaxed);
+            ctx.events.error(Some(&entry.from), failure.message.clone());
+            ctx.failures.record(&entry.from, "copy", failure.kind, failure.message);
+            ctx.fail();
+        }
+    }
+}
+
+/// Why a single copy attempt failed.
+struct CopyFailure {
+    /// The destination was created or truncated before the error.
+    partial: bool,
+    /// The error may go away when retried.
+    transient: bool,
+    /// The source is held open exclusively by another process.
+    locked: bool,
+    /// Group of the error in the final report.
+    kind: failures::Kind,
+    message: String,
+}
+
+/// Reads `to` back for `--verify` and compares its digest with `expected`,
+/// the digest of `from` computed while copying it, so that only the
+/// destination is read a second time.
+async fn check_digest(from: &Path, to: &Path, algorithm: HashAlgorithm, expected: &str) -> Result<(), CopyError> {
+    let written = hash::hash_file(to, algorithm, |_| {})
+        .await
+        .map_err(|e| CopyError::io("verify", to)(e).partial())?;
+    match written == expected {
+        true => Ok(()),
+        false => Err(CopyError::DigestMismatch { path: from.to_path_buf() }.partial()),
+    }
+}
+
+/// Keeps the digest of the copied `entry` for the list and the manifest
+/// written at the end, or writes it next to the file with `--hash-sidecars`.
+async fn record_digest(ctx: &CopyContext, entry: &CopyEntry, digest: String) {
+    let Some(algorithm) = ctx.hashing else {
+        return;
+    };
+    let relative = entry.to.strip_prefix(&ctx.hash_root).unwrap_or(&entry.to);
+    let relative = relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    if let Some(ref manifest) = ctx.manifest {
+        let mtime = tokio::fs::metadata(&entry.from)
+            .await
+            .and_then(|meta| meta.modified())
+            .ok()
+            .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|since| since.as_secs());
+        manifest.lock().unwrap().push(ManifestEntry {
+            path: relative.clone(),
+            size: entry.size,
+            mtime,
+            hash: digest.clone(),
+        });
+    }
+    if !ctx.write_hashes {
+        return;
+    }
+    if ctx.hash_sidecars {
+        let name = entry.to.file_name().unwrap_or_default().to_string_lossy();
+        let sidecar = resume::with_suffix(&entry.to, algorithm.suffix());
+        if let Err(e) = tokio::fs::write(&sidecar, hash::sums_line(&digest, &name)).await {
+            warn(ctx, &format!("Cannot write {}: {}", sidecar.display(), e));
         }
+        return;
     }
+    ctx.digests.lock().unwrap().push((relative, digest));
+}
 
-    if let Some(pb) = main_pb {
-        pb.finish_with_message("Copy complete!");
+/// Name of the destination `to` once filtered: without the suffix of the
+/// files `--decrypt` decrypts, and with those of the files `--compress` and
+/// `--encrypt` write.
+fn filtered_path(to: &Path, options: &RunOptions) -> std::path::PathBuf {
+    let mut to = to.to_path_buf();
+    if let Some(decryption) = &options.decrypt
+        && let Some(name) = to.file_name().and_then(|n| n.to_str())
+        && let Some(stem) = name.strip_suffix(decryption.suffix())
+        && !stem.is_empty()
+    {
+        to = to.with_file_name(stem);
     }
+    if let Some(compression) = options.compress {
+        to = resume::with_suffix(&to, compression.suffix());
+    }
+    if let Some(encryption) = &options.encrypt {
+        to = resume::with_suffix(&to, encryption.suffix());
+    }
+    to
+}
+
+/// Whether the destination of `entry` looks like a finished copy of it: the
+/// same size, and modified no earlier than the source (copies made without
+/// `--preserve` are newer, those made with it equally old).
+async fn is_unchanged(entry: &CopyEntry) -> bool {
+    let (Ok(from), Ok(to)) = (tokio::fs::metadata(&entry.from).await, tokio::fs::metadata(&entry.to).await) else {
+        return false;
+    };
+    if !to.is_file() || to.len() != from.len() {
+        return false;
+    }
+    match (from.modified(), to.modified()) {
+        // Some filesystems only keep whole (or even two) seconds.
+        (Ok(from), Ok(to)) => to + Duration::from_secs(2) > from,
+        _ => false,
+    }
+}
+
+/// Cleans up after a copy aborted by Ctrl-C or `--fail-fast`.
+async fn abandon_entry(ctx: &CopyContext, entry: &CopyEntry) {
+    log::info!("Cancelled while copying {}", entry.from.display());
+    remove_partial(ctx, entry).await;
+    if let Some(ref log) = ctx.file_log {
+        log.note(&format!("{} -> {} interrupted", entry.from.display(), entry.to.display()));
+    }
+}
 
-    if *has_failed.lock().unwrap() {
-        std::process::exit(1);
+/// Puts the file moved aside for `cp2 undo` back after the copy meant to
+/// replace it didn't happen, unless a partial copy was kept in its place.
+fn put_back(ctx: &CopyContext, entry: &CopyEntry, backup: Option<std::path::PathBuf>) {
+    if let (Some(undo), Some(backup)) = (&ctx.undo, backup)
+        && std::fs::symlink_metadata(&entry.to).is_err()
+    {
+        undo.put_back(&backup, &entry.to);
+    }
+}
+
+/// Prints a warning above the progress bars, or to stderr when there are none.
+/// The `--tui` dashboard lists it with the recent errors instead.
+fn warn(ctx: &CopyContext, message: &str) {
+    if dashboard::show_message(message.to_string()) {
+        return;
+    }
+    match ctx.multi {
+        Some(ref multi) => {
+            let _ = multi.println(message.yellow().to_string());
+        }
+        None => eprintln!("{}", message.yellow()),
+    }
+}
+
+/// Removes a partially written destination (or the chunks of a split one) so
+/// it can't be mistaken for a complete file, unless `--keep-partial` asked
+/// for it to stay.
+async fn remove_partial(ctx: &CopyContext, entry: &CopyEntry) {
+    if ctx.keep_partial {
+        log::info!("Keeping partial file {}", entry.to.display());
+        return;
+    }
+    let paths = match ctx.max_file_size {
+        Some(chunk_size) if ctx.is_oversize(entry) => {
+            let count = entry.size.div_ceil(chunk_size) as usize;
+            let mut paths: Vec<_> = (0..count).map(|i| split_chunk_path(&entry.to, i, count)).collect();
+            paths.push(split_manifest_path(&entry.to));
+            paths
+        }
+        _ => std::iter::once(ctx.target(entry).into_owned())
+            .chain(ctx.also_targets(&entry.to))
+            .collect(),
+    };
+    for path in paths {
+        if let Err(e) = tokio::fs::remove_file(&path).await
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            failures::print_error(Some(&path), "remove partial file", failures::kind_of(&e), &e.to_string(), || {
+                eprintln!("{} {}: {}", "Failed to remove partial file".red(), path.display(), e)
+            });
+        }
+    }
+}
+
+/// Where `path`, below `destination`, goes in each of the `--also-to`
+/// directories `also_to`.
+fn also_paths<'a>(destination: &Path, also_to: &'a [std::path::PathBuf], path: &Path) -> impl Iterator<Item = std::path::PathBuf> + 'a {
+    let relative = path.strip_prefix(destination).ok().map(Path::to_path_buf);
+    also_to.iter().filter_map(move |dir| Some(dir.join(relative.as_ref()?)))
+}
+
+/// Records a file that won't be copied and takes its size out of the overall
+/// progress so the bar still reaches 100%.
+fn skip_entry(ctx: &CopyContext, entry: &CopyEntry, reason: &str) {
+    if let Some(ref pb) = ctx.main_pb {
+        pb.dec_length(entry.size);
+    }
+    if let Some(pb) = ctx.source_bar(entry) {
+        pb.dec_length(entry.size);
+    }
+    record_skip(ctx, entry, reason);
+}
+
+/// Records a file that won't be copied and was never part of the totals.
+fn record_skip(ctx: &CopyContext, entry: &CopyEntry, reason: &str) {
+    log::info!("Skipping {}: {}", entry.from.display(), reason);
+    ctx.stats.files_skipped.fetch_add(1, Ordering::Relaxed);
+    let destination = entry.to.display().to_string();
+    if let Some(ref log) = ctx.file_log {
+        log.note(&format!("{} -> {} skipped: {}", entry.from.display(), destination, reason));
+    }
+    if let Some(ref recorder) = ctx.recorder {
+        recorder.skipped(&entry.from, destination.clone(), reason.to_string());
     }
+    ctx.events.emit(&CopyEvent::FileSkipped {
+        source: entry.from.display().to_string(),
+        destination,
+        reason: reason.to_string(),
+    });
 }
@@ -0,0 +1,744 @@
+use crate::cli::RunOptions;
+use crate::copy::{is_transient, retry_delay};
+use crate::events::{CopyEvent, EventSink};
+use crate::exit;
+use crate::failures::{self, Failures, COMMAND_FAILED};
+use crate::hooks;
+use crate::http::HttpTransport;
+use crate::interrupt;
+use crate::keys;
+use crate::logfile::FileLog;
+use crate::notify;
+use crate::progress::{self, BarSlots, FileBars, RunProgress, RunStats};
+use crate::report::Recorder;
+use crate::sftp::{SftpLocation, SftpSession};
+use crate::throttle::Throttle;
+use crate::transport::{join_remote, walk_remote, Transport};
+use crate::utils::{trim_filename, walk_dir};
+use crate::webdav::{self, WebDavTransport};
+use colored::Colorize;
+use indicatif::{ProgressBar, ProgressDrawTarget};
+use reqwest::Url;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+
+/// Which way files move between this machine and the server.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Upload,
+    Download,
+}
+
+impl Direction {
+    fn noun(self) -> &'static str {
+        match self {
+            Direction::Upload => "Upload",
+            Direction::Download => "Download",
+        }
+    }
+
+    fn past(self) -> &'static str {
+        match self {
+            Direction::Upload => "Uploaded",
+            Direction::Download => "Downloaded",
+        }
+    }
+}
+
+/// A file to move: `local` on this machine, `remote` on the server.
+struct Transfer {
+    local: PathBuf,
+    remote: String,
+    size: u64,
+}
+
+/// Uploads local sources into the directory `destination` on an SFTP server.
+pub(crate) async fn upload_sftp(sources: Vec<String>, destination: SftpLocation, options: &RunOptions) {
+    let session = connect(&destination, options).await;
+    upload(session, sources, destination.path, options).await;
+}
+
+/// Downloads sources from a single SFTP server into the local directory
+/// `destination`.
+pub(crate) async fn download_sftp(sources: Vec<SftpLocation>, destination: PathBuf, options: &RunOptions) {
+    if let Some(other) = sources.iter().find(|s| !s.same_server(&sources[0])) {
+        eprintln!(
+            "{} {}",
+            "All remote sources must be on the same server:".red(),
+            other.to_string().red()
+        );
+        std::process::exit(exit::USAGE);
+    }
+    let session = connect(&sources[0], options).await;
+    let paths = sources.into_iter().map(|s| s.path).collect();
+    download(session, paths, destination, options).await;
+}
+
+/// Downloads `http://` and `https://` URLs into the local directory
+/// `destination`.
+pub(crate) async fn download_http(urls: Vec<String>, destination: PathBuf, options: &RunOptions) {
+    download(HttpTransport::new(options.segments), urls, destination, options).await;
+}
+
+/// Uploads local sources into the collection `destination` on a WebDAV
+/// server.
+pub(crate) async fn upload_webdav(sources: Vec<String>, destination: Url, options: &RunOptions) {
+    let transport = WebDavTransport::new(&destination);
+    upload(transport, sources, webdav::url_path(&destination), options).await;
+}
+
+/// Downloads sources from a single WebDAV server into the local directory
+/// `destination`.
+pub(crate) async fn download_webdav(sources: Vec<Url>, destination: PathBuf, options: &RunOptions) {
+    if let Some(other) = sources.iter().find(|s| !webdav::same_server(s, &sources[0])) {
+        eprintln!(
+            "{} {}",
+            "All remote sources must be on the same server:".red(),
+            other.to_string().red()
+        );
+        std::process::exit(exit::USAGE);
+    }
+    let transport = WebDavTransport::new(&sources[0]);
+    let paths = sources.iter().map(webdav::url_path).collect();
+    download(transport, paths, destination, options).await;
+}
+
+async fn connect(location: &SftpLocation, options: &RunOptions) -> SftpSession {
+    match SftpSession::connect(location, &options.ssh_command).await {
+        Ok(mut session) => {
+            if options.compress_transfer {
+                if session.enable_compression().await {
+                    log::info!("Compressing transfers with {} using zstd", location.target());
+                } else {
+                    log::warn!("zstd isn't available on both ends; transferring to {} uncompressed", location.target());
+                }
+            }
+            session
+        }
+        Err(e) => {
+            eprintln!("{} {}: {}", "Failed to connect to".red(), location.target().red(), e);
+            std::process::exit(exit::USAGE);
+        }
+    }
+}
+
+async fn upload<T: Transport>(transport: T, sources: Vec<String>, destination: String, options: &RunOptions) {
+    let (events, file_log) = open_outputs(options);
+    match transport.stat(&destination).await {
+        Ok(Some(stat)) if stat.is_dir => {}
+        Ok(Some(_)) => {
+            let message = "Destination path is not a directory:";
+            eprintln!("{} {}", message.red(), transport.display(&destination).red());
+            std::process::exit(exit::USAGE);
+        }
+        Ok(None) => {
+            let message = "Destination path does not exist:";
+            eprintln!("{} {}", message.red(), transport.display(&destination).red());
+            std::process::exit(exit::USAGE);
+        }
+        Err(e) => {
+            eprintln!("{} {}: {}", "Cannot access".red(), transport.display(&destination).red(), e);
+            std::process::exit(exit::USAGE);
+        }
+    }
+
+    events.emit(&CopyEvent::ScanStarted {
+        sources: sources.clone(),
+    });
+    let mut transfers = Vec::new();
+    let mut dirs = Vec::new();
+    let mut has_errors = false;
+    for source_str in &sources {
+        let source = Path::new(source_str);
+        if !check_source(&events, source_str, source.exists(), source.is_dir(), options) {
+            has_errors = true;
+            continue;
+        }
+        // `.` and `..` have no name of their own.
+        let name = match source.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => std::fs::canonicalize(source)
+                .ok()
+                .and_then(|p| p.file_name().map(|n| n.to_string_lossy().into_owned()))
+                .unwrap_or_default(),
+        };
+        let base = join_remote(&destination, &name);
+        if !source.is_dir() {
+            let size = source.metadata().map(|m| m.len()).unwrap_or(0);
+            transfers.push(Transfer {
+                local: source.to_path_buf(),
+                remote: base,
+                size,
+            });
+            continue;
+        }
+        match walk_dir(source, options.copy.parallel, options.copy.dereference, options.copy.skip_hidden, None).await {
+            Ok((files, subdirs, specials, _)) => {
+                let remote_of = |path: &Path| {
+                    let relative = path.strip_prefix(source).unwrap_or(path);
+                    relative
+                        .components()
+                        .fold(base.clone(), |dir, c| join_remote(&dir, &c.as_os_str().to_string_lossy()))
+                };
+                dirs.push(base.clone());
+                dirs.extend(subdirs.iter().map(|dir| remote_of(dir)));
+                for (path, size) in files {
+                    transfers.push(Transfer {
+                        remote: remote_of(&path),
+                        local: path,
+                        size,
+                    });
+                }
+                for (path, _) in specials {
+                    log::warn!("skipping special file: {}", path.display());
+                }
+            }
+            Err(e) => {
+                eprintln!("{} {}", "Error:".red(), e.to_string().red());
+                events.error(Some(source), e.to_string());
+                has_errors = true;
+            }
+        }
+    }
+
+    let transport = Arc::new(transport);
+    let mut failed_dirs = Vec::new();
+    for dir in &dirs {
+        if let Err(e) = transport.create_dir(dir).await {
+            failed_dirs.push((PathBuf::from(transport.display(dir)), e));
+        }
+    }
+    run(Direction::Upload, transport, transfers, failed_dirs, has_errors, events, file_log, options).await;
+}
+
+async fn download<T: Transport>(transport: T, sources: Vec<String>, destination: PathBuf, options: &RunOptions) {
+    let (events, file_log) = open_outputs(options);
+    if !destination.is_dir() {
+        let message = match destination.exists() {
+            true => "Destination path is not a directory:",
+            false => "Destination path does not exist:",
+        };
+        eprintln!("{} {}", message.red(), destination.display().to_string().red());
+        std::process::exit(exit::USAGE);
+    }
+
+    events.emit(&CopyEvent::ScanStarted {
+        sources: sources.iter().map(|s| transport.display(s)).collect(),
+    });
+    let mut transfers = Vec::new();
+    let mut dirs = Vec::new();
+    let mut has_errors = false;
+    for source in &sources {
+        let display = transport.display(source);
+        let stat = match transport.stat(source).await {
+            Ok(stat) => stat,
+            Err(e) => {
+                eprintln!("{} {}", "Error:".red(), e.to_string().red());
+                events.error(Some(Path::new(&display)), e.to_string());
+                has_errors = true;
+                continue;
+            }
+        };
+        let is_dir = stat.is_some_and(|s| s.is_dir);
+        if !check_source(&events, &display, stat.is_some(), is_dir, options) {
+            has_errors = true;
+            continue;
+        }
+        let base = destination.join(transport.file_name(source));
+        if !is_dir {
+            transfers.push(Transfer {
+                local: base,
+                remote: source.clone(),
+                size: stat.map_or(0, |s| s.size),
+            });
+            continue;
+        }
+        match walk_remote(&transport, source, options.copy.skip_hidden).await {
+            Ok((files, subdirs)) => {
+                dirs.push(base.clone());
+                dirs.extend(subdirs.iter().map(|dir| base.join(dir)));
+                for (path, size) in files {
+                    transfers.push(Transfer {
+                        local: base.join(&path),
+                        remote: join_remote(source, &path),
+                        size,
+                    });
+                }
+            }
+            Err(e) => {
+                eprintln!("{} {}", "Error:".red(), e.to_string().red());
+                events.error(Some(Path::new(&display)), e.to_string());
+                has_errors = true;
+            }
+        }
+    }
+
+    let mut failed_dirs = Vec::new();
+    for dir in dirs {
+        if let Err(e) = tokio::fs::create_dir_all(&dir).await {
+            failed_dirs.push((dir, e));
+        }
+    }
+    let transport = Arc::new(transport);
+    run(Direction::Download, transport, transfers, failed_dirs, has_errors, events, file_log, options).await;
+}
+
+/// Opens the event sink and the `--log-file`, exiting if either fails.
+pub(crate) fn open_outputs(options: &RunOptions) -> (Arc<EventSink>, Option<Arc<FileLog>>) {
+    let mut events = EventSink::new(options.json);
+    if let Some(ref path) = options.progress_socket
+        && let Err(e) = events.listen(path)
+    {
+        failures::print_error(Some(path), "open progress socket", failures::kind_of(&e), &e.to_string(), || {
+            eprintln!("{} {}", "Failed to open progress socket:".red(), e)
+        });
+        std::process::exit(exit::USAGE);
+    }
+    let file_log = match options.log_file.as_deref().map(FileLog::open).transpose() {
+        Ok(log) => log.map(Arc::new),
+        Err(e) => {
+            failures::print_error(options.log_file.as_deref(), "open log file", failures::kind_of(&e), &e.to_string(), || {
+                eprintln!("{} {}", "Failed to open log file:".red(), e)
+            });
+            std::process::exit(exit::USAGE);
+        }
+    };
+    (Arc::new(events), file_log)
+}
+
+/// Reports a source that is missing, or a directory without `-r`; returns
+/// whether it can be copied.
+pub(crate) fn check_source(events: &EventSink, display: &str, exists: bool, is_dir: bool, options: &RunOptions) -> bool {
+    let problem = if !exists {
+        "Source path does not exist:"
+    } else if is_dir && !options.copy.recursive {
+        "Source path is a directory, but recursive flag is not set:"
+    } else {
+        return true;
+    };
+    let message = problem.trim_end_matches(':').to_lowercase();
+    let kind = match exists {
+        false => std::io::ErrorKind::NotFound,
+        true => std::io::ErrorKind::IsADirectory,
+    };
+    failures::print_error(Some(Path::new(display)), "scan", kind.to_string().into(), &message, || {
+        eprintln!("{} {}", problem.red(), display.red())
+    });
+    events.error(Some(Path::new(display)), message);
+    false
+}
+
+/// State shared by every transfer task of a run.
+struct TransferContext<T> {
+    direction: Direction,
+    transport: Arc<T>,
+    /// Hands out per-file bars; `None` when they aren't displayed.
+    file_bars: Option<Arc<BarSlots>>,
+    main_pb: Option<Arc<ProgressBar>>,
+    /// Counts the bytes that cross the network.
+    network_pb: Option<ProgressBar>,
+    stats: Arc<RunStats>,
+    events: Arc<EventSink>,
+    recorder: Option<Recorder>,
+    file_log: Option<Arc<FileLog>>,
+    pre_file_cmd: Option<String>,
+    post_file_cmd: Option<String>,
+    throttle: Throttle,
+    retries: u32,
+    retry_delay: Duration,
+    fail_fast: bool,
+    keep_partial: bool,
+    cancel: CancellationToken,
+    has_failed: Mutex<bool>,
+    failures: Failures,
+}
+
+impl<T: Transport> TransferContext<T> {
+    /// Where `transfer` comes from and goes to, as shown to the user.
+    fn ends(&self, transfer: &Transfer) -> (PathBuf, String) {
+        match self.direction {
+            Direction::Upload => (transfer.local.clone(), self.transport.display(&transfer.remote)),
+            Direction::Download => (
+                PathBuf::from(self.transport.display(&transfer.remote)),
+                transfer.local.display().to_string(),
+            ),
+        }
+    }
+
+    /// Marks the run as failed and, with `--fail-fast`, stops it.
+    fn fail(&self) {
+        *self.has_failed.lock().unwrap() = true;
+        if self.fail_fast {
+            self.cancel.cancel();
+        }
+    }
+}
+
+/// Transfers the scanned files with the progress UI, hooks and reporting of
+/// a local copy, then exits with the run's status if it didn't succeed.
+#[allow(clippy::too_many_arguments)]
+async fn run<T: Transport>(
+    direction: Direction,
+    transport: Arc<T>,
+    transfers: Vec<Transfer>,
+    failed_dirs: Vec<(PathBuf, std::io::Error)>,
+    has_errors: bool,
+    events: Arc<EventSink>,
+    file_log: Option<Arc<FileLog>>,
+    options: &RunOptions,
+) {
+    let noun = direction.noun();
+    if transfers.is_empty() && failed_dirs.is_empty() {
+        if !has_errors {
+            eprintln!("Nothing to {}.", noun.to_lowercase());
+        }
+        std::process::exit(exit::USAGE);
+    }
+    let total_size: u64 = transfers.iter().map(|t| t.size).sum();
+    let total_files = transfers.len() as u64;
+    events.emit(&CopyEvent::ScanFinished {
+        files: total_files,
+        bytes: total_size,
+    });
+    if let Some(ref log) = file_log {
+        log.note(&format!(
+            "{} started: {} files, {} bytes",
+            noun.to_lowercase(),
+            total_files,
+            total_size
+        ));
+    }
+
+    let interrupt = interrupt::install();
+    let cancel = interrupt.child_token();
+    let stats = Arc::new(RunStats::new());
+    let run_progress = RunProgress::start(
+        options.progress,
+        options.term_progress,
+        total_size,
+        total_files,
+        Arc::clone(&stats),
+        Arc::clone(&events),
+    );
+    let throttle = Throttle::new(options.bwlimit);
+    let key_listener = match run_progress {
+        Some(ref run_progress) if run_progress.multi.is_some() => {
+            keys::listen(throttle.clone(), Arc::clone(&run_progress.main))
+        }
+        _ => None,
+    };
+
+    let ctx = Arc::new(TransferContext {
+        direction,
+        transport,
+        file_bars: run_progress.as_ref().and_then(|p| p.file_bars.clone()),
+        main_pb: run_progress.as_ref().map(|p| Arc::clone(&p.main)),
+        network_pb: run_progress.as_ref().map(|p| p.network.bar.clone()),
+        stats: Arc::clone(&stats),
+        events: Arc::clone(&events),
+        recorder: options.report.as_ref().map(|_| Recorder::new()),
+        file_log: file_log.clone(),
+        pre_file_cmd: options.pre_file_cmd.clone(),
+        post_file_cmd: options.post_file_cmd.clone(),
+        throttle,
+        retries: options.retries,
+        retry_delay: options.retry_delay,
+        fail_fast: options.fail_fast,
+        keep_partial: options.keep_partial,
+        cancel: cancel.clone(),
+        has_failed: Mutex::new(has_errors),
+        failures: Failures::new(),
+    });
+    for (dir, e) in failed_dirs {
+        let message = e.to_string();
+        ctx.events.error(Some(&dir), message.clone());
+        ctx.failures.record(&dir, "create directory", failures::kind_of(&e), message);
+        ctx.fail();
+    }
+    if has_errors && options.fail_fast {
+        cancel.cancel();
+    }
+
+    let semaphore = Arc::new(Semaphore::new(options.copy.parallel));
+    let mut tasks = Vec::new();
+    for transfer in transfers {
+        let ctx = Arc::clone(&ctx);
+        let semaphore = Arc::clone(&semaphore);
+        tasks.push(tokio::spawn(async move {
+            let _permit = semaphore
+                .acquire()
+                .await
+                .expect("failed to acquire semaphore permit");
+            transfer_file(&ctx, &transfer).await;
+        }));
+    }
+    for task in tasks {
+        if let Err(e) = task.await {
+            let target = PathBuf::from(noun.to_lowercase());
+            ctx.failures.record(&target, "transfer task", "task failed".to_string(), e.to_string());
+            ctx.fail();
+        }
+    }
+
+    let interrupted = interrupt.is_cancelled();
+    let aborted = !interrupted && cancel.is_cancelled();
+    if let Some(key_listener) = key_listener {
+        key_listener.stop();
+    }
+    if let Some(run_progress) = run_progress {
+        let headline = match (interrupted, aborted) {
+            (true, _) => format!("{} interrupted!", noun),
+            (false, true) => format!("{} aborted!", noun),
+            (false, false) => format!("{} complete!", noun),
+        };
+        run_progress.finish(&headline).await;
+    }
+    ctx.failures.print_report();
+    if options.summary {
+        stats.print_summary(direction.past());
+    }
+    let not_done = format!("not {}", direction.past().to_lowercase());
+    if interrupted || aborted {
+        let reason = if interrupted { "Interrupted" } else { "Aborted after the first error" };
+        let missing = progress::files(stats.not_finished(total_files));
+        eprintln!("{}", format!("{}: {} {}", reason, missing, not_done).yellow());
+        events.error(None, format!("{}: {} {}", reason.to_lowercase(), missing, not_done));
+    }
+    events.emit(&stats.summary_event());
+    events.close().await;
+    if let Some(ref log) = file_log {
+        log.note(&format!(
+            "{} finished: {} copied, {} failed, {} bytes",
+            noun.to_lowercase(),
+            stats.files_done.load(Ordering::Relaxed),
+            stats.files_failed.load(Ordering::Relaxed),
+            stats.bytes_copied.load(Ordering::Relaxed)
+        ));
+    }
+
+    if let (Some(path), Some(recorder)) = (&options.report, &ctx.recorder)
+        && let Err(e) = recorder.write(path, stats.started.elapsed())
+    {
+        eprintln!("{} {}", "Failed to write report:".red(), e);
+        *ctx.has_failed.lock().unwrap() = true;
+    }
+
+    if options.on_complete_url.is_some() || options.on_complete_cmd.is_some() {
+        hooks::on_complete(
+            options.on_complete_url.as_ref(),
+            options.on_complete_cmd.as_deref(),
+            &stats.summary_event(),
+        )
+        .await;
+    }
+    let failed = *ctx.has_failed.lock().unwrap();
+    if options.notify {
+        let summary = match (interrupted, failed) {
+            (true, _) => format!("{} interrupted", noun),
+            (false, true) if aborted => format!("{} aborted after an error", noun),
+            (false, true) => format!("{} finished with errors", noun),
+            (false, false) => format!("{} complete", noun),
+        };
+        notify::run_finished(summary, &stats).await;
+    }
+    if interrupted {
+        std::process::exit(exit::INTERRUPTED);
+    }
+    if failed {
+        let status = exit::for_failed_run(&stats);
+        ctx.failures.print_exit_message(status);
+        std::process::exit(status);
+    }
+}
+
+/// Transfers a single file, retrying transient errors, and records the
+/// outcome.
+async fn transfer_file<T: Transport>(ctx: &TransferContext<T>, transfer: &Transfer) {
+    if ctx.cancel.is_cancelled() {
+        return;
+    }
+    let (source, destination) = ctx.ends(transfer);
+    if let Some(ref cmd) = ctx.pre_file_cmd {
+        match hooks::pre_file_check(cmd, &source, &destination).await {
+            Ok(true) => {}
+            Ok(false) => {
+                skip_transfer(ctx, transfer, "rejected by --pre-file-cmd");
+                return;
+            }
+            Err(e) => {
+                ctx.failures.record(&source, "pre-file command", COMMAND_FAILED.to_string(), e.clone());
+                ctx.stats.files_failed.fetch_add(1, Ordering::Relaxed);
+                if let Some(ref recorder) = ctx.recorder {
+                    recorder.record(&source, destination, 0, Duration::ZERO, Some(e.clone()));
+                }
+                ctx.events.error(Some(&source), e);
+                ctx.fail();
+                return;
+            }
+        }
+    }
+
+    ctx.events.emit(&CopyEvent::FileStarted {
+        source: source.display().to_string(),
+        destination: destination.clone(),
+        bytes: transfer.size,
+    });
+    let file_name = source.file_name().unwrap_or_default().to_string_lossy().into_owned();
+    let file_pb = ctx.file_bars.as_ref().map(|slots| {
+        let pb = slots.add(transfer.size);
+        pb.set_style(progress::remote_file_bar_style());
+        pb.set_message(format!("{}ing {}", ctx.direction.noun(), trim_filename(&file_name, 26)));
+        pb
+    });
+    // Transfers that are retried need to know how many bytes each attempt
+    // added to the overall bar.
+    let attempt_pb = match (&file_pb, ctx.retries) {
+        (Some(pb), _) => Some(ProgressBar::clone(pb)),
+        (None, 0) => None,
+        (None, _) => Some(ProgressBar::with_draw_target(Some(transfer.size), ProgressDrawTarget::hidden())),
+    };
+
+    let started = Instant::now();
+    let started_at = SystemTime::now();
+    let mut attempt = 0;
+    let result = loop {
+        let sink = FileBars {
+            file: attempt_pb.as_ref(),
+            source: None,
+            main: ctx.main_pb.as_deref(),
+            network: ctx.network_pb.as_ref(),
+        };
+        let result = match ctx.direction {
+            Direction::Upload => {
+                ctx.transport
+                    .upload(&transfer.local, &transfer.remote, Some(&sink), Some(&ctx.throttle), Some(&ctx.cancel))
+                    .await
+            }
+            Direction::Download => {
+                ctx.transport
+                    .download(&transfer.remote, &transfer.local, Some(&sink), Some(&ctx.throttle), Some(&ctx.cancel))
+                    .await
+            }
+        };
+        match result {
+            Err(e) if attempt < ctx.retries && !ctx.cancel.is_cancelled() && is_transient(&e) => {
+                attempt += 1;
+                let delay = retry_delay(ctx.retry_delay, attempt);
+                log::warn!(
+                    "Retrying {} in {:?} (attempt {}/{}): {}",
+                    source.display(),
+                    delay,
+                    attempt,
+                    ctx.retries,
+                    e
+                );
+                if let Some(ref pb) = attempt_pb {
+                    if let Some(ref main_pb) = ctx.main_pb {
+                        main_pb.dec(pb.position());
+                    }
+                    pb.set_position(0);
+                }
+                if let Some(ref pb) = file_pb {
+                    pb.set_message(format!("Retry {}/{} {}", attempt, ctx.retries, trim_filename(&file_name, 22)));
+                }
+                tokio::select! {
+                    _ = ctx.cancel.cancelled() => {}
+                    _ = tokio::time::sleep(delay) => {}
+                }
+                if ctx.cancel.is_cancelled() {
+                    break Err(e);
+                }
+            }
+            result => break result,
+        }
+    };
+    // Clears the bar and hands its slot to a file waiting for one.
+    drop(file_pb);
+    if let Err(ref e) = result
+        && e.is_partial()
+        && !ctx.keep_partial
+    {
+        let removed = match ctx.direction {
+            Direction::Upload => ctx.transport.remove_file(&transfer.remote).await,
+            Direction::Download => tokio::fs::remove_file(&transfer.local).await,
+        };
+        if let Err(e) = removed {
+            log::warn!("Cannot remove partial file {}: {}", destination, e);
+        }
+    }
+
+    // Transfers cancelled by Ctrl-C or another file's failure under
+    // --fail-fast aren't failures of their own.
+    if result.is_err() && ctx.cancel.is_cancelled() {
+        log::info!("Interrupted while transferring {}", source.display());
+        if let Some(ref log) = ctx.file_log {
+            log.note(&format!("{} -> {} interrupted", source.display(), destination));
+        }
+        return;
+    }
+
+    let (bytes, error) = match &result {
+        Ok(bytes) => (*bytes, None),
+        Err(e) => (0, Some(e.to_string())),
+    };
+    if let Some(ref log) = ctx.file_log {
+        log.file(&source, &destination, started_at, bytes, error.as_deref());
+    }
+    if let Some(ref recorder) = ctx.recorder {
+        recorder.record(&source, destination.clone(), bytes, started.elapsed(), error);
+    }
+    match result {
+        Ok(bytes) => {
+            ctx.stats.files_done.fetch_add(1, Ordering::Relaxed);
+            ctx.stats.bytes_copied.fetch_add(bytes, Ordering::Relaxed);
+            ctx.events.emit(&CopyEvent::FileDone {
+                source: source.display().to_string(),
+                destination: destination.clone(),
+                bytes,
+            });
+            if let Some(ref cmd) = ctx.post_file_cmd
+                && let Err(e) = hooks::file_cmd(cmd, &source, &destination).await
+            {
+                ctx.failures.record(&source, "post-file command", COMMAND_FAILED.to_string(), e.clone());
+                ctx.events.error(Some(&source), e);
+                ctx.fail();
+            }
+        }
+        Err(e) => {
+            ctx.stats.files_failed.fetch_add(1, Ordering::Relaxed);
+            ctx.events.error(Some(&source), e.to_string());
+            let operation = match ctx.direction {
+                Direction::Upload => "upload",
+                Direction::Download => "download",
+            };
+            ctx.failures.record(&source, operation, failures::kind_of(&e), e.to_string());
+            ctx.fail();
+        }
+    }
+}
+
+/// Records a file that won't be transferred and takes its size out of the
+/// overall progress so the bar still reaches 100%.
+fn skip_transfer<T: Transport>(ctx: &TransferContext<T>, transfer: &Transfer, reason: &str) {
+    let (source, destination) = ctx.ends(transfer);
+    log::info!("Skipping {}: {}", source.display(), reason);
+    ctx.stats.files_skipped.fetch_add(1, Ordering::Relaxed);
+    if let Some(ref pb) = ctx.main_pb {
+        pb.dec_length(transfer.size);
+    }
+    if let Some(ref log) = ctx.file_log {
+        log.note(&format!("{} -> {} skipped: {}", source.display(), destination, reason));
+    }
+    if let Some(ref recorder) = ctx.recorder {
+        recorder.skipped(&source, destination.clone(), reason.to_string());
+    }
+    ctx.events.emit(&CopyEvent::FileSkipped {
+        source: source.display().to_string(),
+        destination,
+        reason: reason.to_string(),
+    });
+}
@@ -0,0 +1,43 @@
+use colored::Colorize;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::exit;
+use crate::job::JobPlan;
+use crate::logfile::format_utc;
+
+/// `cp2 resume JOB`: reads the job file of an interrupted copy and moves to
+/// the directory it was started in, so its command line means the same
+/// again. Exits with a usage error when there is no such job.
+pub(crate) fn load(job: &str) -> JobPlan {
+    let plan = match JobPlan::load(job) {
+        Ok(plan) => plan,
+        Err(e) => {
+            eprintln!("{} {}", "Cannot resume:".red(), e);
+            std::process::exit(exit::USAGE);
+        }
+    };
+    if let Err(e) = std::env::set_current_dir(&plan.cwd) {
+        eprintln!("{} {}: {}", "Cannot resume in".red(), plan.cwd.display().to_string().red(), e);
+        std::process::exit(exit::USAGE);
+    }
+    plan
+}
+
+/// `cp2 resume` without a job: lists the copies that can be resumed.
+pub(crate) fn list() {
+    let jobs = JobPlan::list();
+    if jobs.is_empty() {
+        eprintln!("No interrupted copies to resume");
+        return;
+    }
+    for job in jobs {
+        let started = format_utc(UNIX_EPOCH + Duration::from_secs(job.started));
+        println!(
+            "{}  {}  {} left  {}",
+            job.id.bold(),
+            started,
+            crate::progress::files(job.pending.len() as u64),
+            job.args.join(" ")
+        );
+    }
+}
@@ -1,12 +1,31 @@
+use crate::cli::RunOptions;
 use crate::config;
+use crate::copy::{is_locked, is_transient, retry_delay};
+use crate::events::{CopyEvent, EventSink};
+use crate::exit;
+use crate::failures::{self, Failures, COMMAND_FAILED};
+use crate::progress::{self, FileBars, RunProgress, RunStats};
+use crate::hooks;
+use crate::interrupt;
+use crate::keys;
+use crate::logfile::FileLog;
+use crate::notify;
+use crate::report::Recorder;
+use crate::throttle::Throttle;
 use crate::s3::{self, S3UploadEntry};
-use crate::utils::trim_filename;
+use crate::utils::{trim_filename, LockedPolicy};
 use colored::Colorize;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use indicatif::{ProgressBar, ProgressDrawTarget};
 use std::collections::HashSet;
 use std::path::Path;
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+
+/// How often a locked source is tried again with `--locked wait`.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(500);
 
 /// Runs an S3 upload for the given sources.
 pub(crate) async fn run(
@@ -14,16 +33,32 @@ pub(crate) async fn run(
     remote_name: String,
     bucket: String,
     prefix: String,
-    recursive: bool,
-    parallel: usize,
-    is_quiet: bool,
+    options: &RunOptions,
 ) {
+    let recursive = options.copy.recursive;
+    let parallel = options.copy.parallel;
+    let mut events = EventSink::new(options.json);
+    if let Some(ref path) = options.progress_socket
+        && let Err(e) = events.listen(path)
+    {
+        eprintln!("{} {}", "Failed to open progress socket:".red(), e);
+        std::process::exit(exit::USAGE);
+    }
+    let events = Arc::new(events);
+    let file_log = match options.log_file.as_deref().map(FileLog::open).transpose() {
+        Ok(log) => log.map(Arc::new),
+        Err(e) => {
+            eprintln!("{} {}", "Failed to open log file:".red(), e);
+            std::process::exit(exit::USAGE);
+        }
+    };
+
     // Load and look up the remote config.
     let cfg = match config::load_config() {
         Ok(c) => c,
         Err(e) => {
             eprintln!("{} {}", "Failed to load configuration:".red(), e);
-            std::process::exit(1);
+            std::process::exit(exit::USAGE);
         }
     };
 
@@ -36,7 +71,7 @@ pub(crate) async fn run(
                 remote_name.red(),
                 " not found. Run `cp2 config create <name>` to add it.".red()
             );
-            std::process::exit(1);
+            std::process::exit(exit::USAGE);
         }
     };
 
@@ -45,10 +80,14 @@ pub(crate) async fn run(
         Ok(c) => Arc::new(c),
         Err(e) => {
             eprintln!("{} {}", "Failed to create S3 client:".red(), e);
-            std::process::exit(1);
+            std::process::exit(exit::USAGE);
         }
     };
 
+    events.emit(&CopyEvent::ScanStarted {
+        sources: sources.clone(),
+    });
+
     // Validate sources and collect upload entries.
     let mut all_entries: Vec<S3UploadEntry> = Vec::new();
     let mut total_size: u64 = 0;
@@ -63,6 +102,7 @@ pub(crate) async fn run(
                 "Source path does not exist:".red(),
                 source_str.red()
             );
+            events.error(Some(source), "source path does not exist");
             has_errors = true;
             continue;
         }
@@ -72,11 +112,15 @@ pub(crate) async fn run(
                 "Source path is a directory, but recursive flag is not set:".red(),
                 source_str.red()
             );
+            events.error(
+                Some(source),
+                "source path is a directory, but recursive flag is not set",
+            );
             has_errors = true;
             continue;
         }
 
-        match s3::collect_s3_upload_entries(source, &prefix).await {
+        match s3::collect_s3_upload_entries(source, &prefix, options.copy.dereference, options.copy.skip_hidden).await {
             Ok((entries, _count, _size)) => {
                 for entry in entries {
                     if !seen_keys.insert(entry.key.clone()) {
@@ -86,6 +130,7 @@ pub(crate) async fn run(
                             entry.from.display().to_string().red(),
                             entry.key.red(),
                         );
+                        events.error(Some(&entry.from), "duplicate destination key");
                         has_errors = true;
                         continue;
                     }
@@ -95,6 +140,7 @@ pub(crate) async fn run(
             }
             Err(e) => {
                 eprintln!("{} {}", "Error:".red(), e.to_string().red());
+                events.error(Some(source), e.to_string());
                 has_errors = true;
             }
         }
@@ -102,10 +148,10 @@ pub(crate) async fn run(
 
     if all_entries.is_empty() {
         if has_errors {
-            std::process::exit(1);
+            std::process::exit(exit::USAGE);
         }
         eprintln!("Nothing to upload.");
-        std::process::exit(1);
+        std::process::exit(exit::USAGE);
     }
 
     log::info!(
@@ -113,25 +159,50 @@ pub(crate) async fn run(
         all_entries.len(),
         total_size
     );
+    events.emit(&CopyEvent::ScanFinished {
+        files: all_entries.len() as u64,
+        bytes: total_size,
+    });
+    if let Some(ref log) = file_log {
+        log.note(&format!(
+            "upload started: {} files, {} bytes",
+            all_entries.len(),
+            total_size
+        ));
+    }
 
-    let (multi_progress, main_pb) = if !is_quiet {
-        let multi = MultiProgress::new();
-        let main_pb = multi.add(ProgressBar::new(total_size));
-        main_pb.set_style(
-            ProgressStyle::default_bar()
-                .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-                .unwrap()
-                .progress_chars("=>-"),
-        );
-        main_pb.set_message("Overall progress");
-        main_pb.enable_steady_tick(std::time::Duration::from_millis(100));
-        (Some(Arc::new(multi)), Some(Arc::new(main_pb)))
-    } else {
-        (None, None)
+    let interrupt = interrupt::install();
+    // Ctrl-C cancels the run through its parent; --fail-fast cancels it
+    // directly.
+    let cancel = interrupt.child_token();
+    if has_errors && options.fail_fast {
+        cancel.cancel();
+    }
+    let total_files = all_entries.len() as u64;
+    let stats = Arc::new(RunStats::new());
+    let run_progress = RunProgress::start(
+        options.progress,
+        options.term_progress,
+        total_size,
+        total_files,
+        Arc::clone(&stats),
+        Arc::clone(&events),
+    );
+    let throttle = Throttle::new(options.bwlimit);
+    let key_listener = match run_progress {
+        Some(ref run_progress) if run_progress.multi.is_some() => {
+            keys::listen(throttle.clone(), Arc::clone(&run_progress.main))
+        }
+        _ => None,
     };
+    let multi_progress = run_progress.as_ref().and_then(|p| p.multi.clone());
+    let main_pb = run_progress.as_ref().map(|p| Arc::clone(&p.main));
+    let network_pb = run_progress.as_ref().map(|p| p.network.bar.clone());
 
+    let recorder = options.report.as_ref().map(|_| Arc::new(Recorder::new()));
     let semaphore = Arc::new(Semaphore::new(parallel));
     let has_failed = Arc::new(Mutex::new(has_errors));
+    let failures = Arc::new(Failures::new());
     let mut tasks = Vec::new();
 
     for entry in all_entries {
@@ -140,13 +211,65 @@ pub(crate) async fn run(
         let bucket_clone = bucket.clone();
         let multi_clone = multi_progress.as_ref().map(Arc::clone);
         let main_pb_clone = main_pb.as_ref().map(Arc::clone);
+        let network_pb_clone = network_pb.clone();
         let has_failed_clone = Arc::clone(&has_failed);
+        let failures_clone = Arc::clone(&failures);
+        let stats_clone = Arc::clone(&stats);
+        let events_clone = Arc::clone(&events);
+        let recorder_clone = recorder.as_ref().map(Arc::clone);
+        let file_log_clone = file_log.as_ref().map(Arc::clone);
+        let pre_file_cmd = options.pre_file_cmd.clone();
+        let cancel_clone = cancel.clone();
+        let throttle_clone = throttle.clone();
+        let post_file_cmd = options.post_file_cmd.clone();
+        let (retries, retry_base) = (options.retries, options.retry_delay);
+        let fail_fast = options.fail_fast;
+        let locked = options.locked;
+        let destination = format!("{}:{}/{}", remote_name, bucket, entry.key);
 
         tasks.push(tokio::spawn(async move {
             let _permit = sem
                 .acquire()
                 .await
                 .expect("failed to acquire semaphore permit");
+            if cancel_clone.is_cancelled() {
+                return;
+            }
+
+            if let Some(ref cmd) = pre_file_cmd {
+                match hooks::pre_file_check(cmd, &entry.from, &destination).await {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        skip_upload(
+                            &entry,
+                            destination,
+                            "rejected by --pre-file-cmd",
+                            &stats_clone,
+                            main_pb_clone.as_deref(),
+                            file_log_clone.as_deref(),
+                            recorder_clone.as_deref(),
+                            &events_clone,
+                        );
+                        return;
+                    }
+                    Err(e) => {
+                        failures_clone.record(&entry.from, "pre-file command", COMMAND_FAILED.to_string(), e.clone());
+                        stats_clone.files_failed.fetch_add(1, Ordering::Relaxed);
+                        if let Some(ref recorder) = recorder_clone {
+                            recorder.record(&entry.from, destination, 0, Duration::ZERO, Some(e.clone()));
+                        }
+                        events_clone.error(Some(&entry.from), e);
+                        mark_failed(&has_failed_clone, &cancel_clone, fail_fast);
+                        return;
+                    }
+                }
+            }
+
+            events_clone.emit(&CopyEvent::FileStarted {
+                source: entry.from.display().to_string(),
+                destination: destination.clone(),
+                bytes: entry.size,
+            });
 
             let file_pb = if let Some(ref multi) = multi_clone {
                 let file_name = entry
@@ -155,14 +278,7 @@ pub(crate) async fn run(
                     .and_then(|n| n.to_str())
                     .unwrap_or("unknown");
                 let pb = multi.add(ProgressBar::new(entry.size));
-                pb.set_style(
-                    ProgressStyle::default_bar()
-                        .template(
-                            "  {spinner:.green} {msg:<30} [{wide_bar:.yellow/blue}] {bytes}/{total_bytes}",
-                        )
-                        .unwrap()
-                        .progress_chars("=>-"),
-                );
+                pb.set_style(progress::file_bar_style());
                 let display_name = trim_filename(file_name, 28);
                 pb.set_message(format!("Uploading {}", display_name));
                 Some(pb)
@@ -170,27 +286,162 @@ pub(crate) async fn run(
                 None
             };
 
-            match s3::upload_file(
-                &client_clone,
-                &entry.from,
-                &bucket_clone,
-                &entry.key,
-                file_pb.as_ref(),
-                main_pb_clone.as_deref(),
-            )
-            .await
+            let started = Instant::now();
+            let started_at = SystemTime::now();
+            // Uploads that are retried need to know how many bytes each
+            // attempt added to the overall bar.
+            let attempt_pb = match (&file_pb, retries) {
+                (Some(pb), _) => Some(pb.clone()),
+                (None, 0) => None,
+                (None, _) => Some(ProgressBar::with_draw_target(Some(entry.size), ProgressDrawTarget::hidden())),
+            };
+            let mut attempt = 0;
+            let mut locked_since = None;
+            let result = loop {
+                let result = s3::upload_file(
+                    &client_clone,
+                    &entry.from,
+                    entry.size,
+                    &bucket_clone,
+                    &entry.key,
+                    Some(&FileBars {
+                        file: attempt_pb.as_ref(),
+                        source: None,
+                        main: main_pb_clone.as_deref(),
+                        network: network_pb_clone.as_ref(),
+                    }),
+                    Some(&throttle_clone),
+                    &cancel_clone,
+                )
+                .await;
+                match result {
+                    Err(e) if is_locked(e.as_ref()) && locked != LockedPolicy::Fail => {
+                        let LockedPolicy::Wait(timeout) = locked else {
+                            break Err(e);
+                        };
+                        if locked_since.get_or_insert_with(Instant::now).elapsed() >= timeout {
+                            break Err(format!("still locked after {:?}: {}", timeout, e).into());
+                        }
+                        if let Some(ref pb) = file_pb {
+                            let file_name = entry.from.file_name().unwrap_or_default().to_string_lossy();
+                            pb.set_message(format!("Locked {}", trim_filename(&file_name, 22)));
+                        }
+                        tokio::select! {
+                            _ = cancel_clone.cancelled() => break Err(e),
+                            _ = tokio::time::sleep(LOCK_POLL_INTERVAL) => {}
+                        }
+                    }
+                    Err(e) if attempt < retries && !cancel_clone.is_cancelled() && is_transient(e.as_ref()) => {
+                        attempt += 1;
+                        let delay = retry_delay(retry_base, attempt);
+                        log::warn!(
+                            "Retrying {} in {:?} (attempt {}/{}): {}",
+                            entry.from.display(),
+                            delay,
+                            attempt,
+                            retries,
+                            e
+                        );
+                        if let Some(ref pb) = attempt_pb {
+                            if let Some(ref main_pb) = main_pb_clone {
+                                main_pb.dec(pb.position());
+                            }
+                            pb.set_position(0);
+                        }
+                        if let Some(ref pb) = file_pb {
+                            let file_name = entry.from.file_name().unwrap_or_default().to_string_lossy();
+                            pb.set_message(format!("Retry {}/{} {}", attempt, retries, trim_filename(&file_name, 22)));
+                        }
+                        tokio::select! {
+                            _ = cancel_clone.cancelled() => {}
+                            _ = tokio::time::sleep(delay) => {}
+                        }
+                        if cancel_clone.is_cancelled() {
+                            break Err(e);
+                        }
+                    }
+                    result => break result,
+                }
+            };
+
+            // Uploads cancelled by Ctrl-C or another file's failure under
+            // --fail-fast aren't failures of their own.
+            if result.is_err() && cancel_clone.is_cancelled() {
+                if let Some(ref pb) = file_pb {
+                    pb.finish_and_clear();
+                }
+                log::info!("Interrupted while uploading {}", entry.from.display());
+                if let Some(ref log) = file_log_clone {
+                    log.note(&format!("{} -> {} interrupted", entry.from.display(), destination));
+                }
+                return;
+            }
+            if let Err(ref e) = result
+                && locked == LockedPolicy::Skip
+                && is_locked(e.as_ref())
             {
+                if let Some(ref pb) = file_pb {
+                    pb.finish_and_clear();
+                }
+                let warning = format!("Skipping {}: locked by another process", entry.from.display());
+                match multi_clone {
+                    Some(ref multi) => {
+                        let _ = multi.println(warning.yellow().to_string());
+                    }
+                    None => eprintln!("{}", warning.yellow()),
+                }
+                skip_upload(
+                    &entry,
+                    destination,
+                    "locked by another process",
+                    &stats_clone,
+                    main_pb_clone.as_deref(),
+                    file_log_clone.as_deref(),
+                    recorder_clone.as_deref(),
+                    &events_clone,
+                );
+                return;
+            }
+
+            let (bytes, error) = match &result {
+                Ok(()) => (entry.size, None),
+                Err(e) => (0, Some(e.to_string())),
+            };
+            if let Some(ref log) = file_log_clone {
+                log.file(&entry.from, &destination, started_at, bytes, error.as_deref());
+            }
+            if let Some(ref recorder) = recorder_clone {
+                recorder.record(&entry.from, destination.clone(), bytes, started.elapsed(), error);
+            }
+
+            match result {
                 Ok(_) => {
+                    stats_clone.files_done.fetch_add(1, Ordering::Relaxed);
+                    stats_clone.bytes_copied.fetch_add(entry.size, Ordering::Relaxed);
                     if let Some(ref pb) = file_pb {
                         pb.finish_and_clear();
                     }
+                    events_clone.emit(&CopyEvent::FileDone {
+                        source: entry.from.display().to_string(),
+                        destination: destination.clone(),
+                        bytes: entry.size,
+                    });
+                    if let Some(ref cmd) = post_file_cmd
+                        && let Err(e) = hooks::file_cmd(cmd, &entry.from, &destination).await
+                    {
+                        failures_clone.record(&entry.from, "post-file command", COMMAND_FAILED.to_string(), e.clone());
+                        events_clone.error(Some(&entry.from), e);
+                        mark_failed(&has_failed_clone, &cancel_clone, fail_fast);
+                    }
                 }
                 Err(e) => {
                     if let Some(ref pb) = file_pb {
                         pb.finish_and_clear();
                     }
-                    eprintln!("Error uploading file: {}", e);
-                    *has_failed_clone.lock().unwrap() = true;
+                    stats_clone.files_failed.fetch_add(1, Ordering::Relaxed);
+                    events_clone.error(Some(&entry.from), e.to_string());
+                    failures_clone.record(&entry.from, "upload", failures::kind_of(e.as_ref()), e.to_string());
+                    mark_failed(&has_failed_clone, &cancel_clone, fail_fast);
                 }
             }
         }));
@@ -198,16 +449,119 @@ pub(crate) async fn run(
 
     for task in tasks {
         if let Err(e) = task.await {
-            eprintln!("Upload task failed: {}", e);
-            *has_failed.lock().unwrap() = true;
+            let target = format!("{}:{}", remote_name, bucket);
+            failures.record(Path::new(&target), "upload task", "task failed".to_string(), e.to_string());
+            mark_failed(&has_failed, &cancel, options.fail_fast);
         }
     }
 
+    let interrupted = interrupt.is_cancelled();
+    let aborted = !interrupted && cancel.is_cancelled();
+    if let Some(key_listener) = key_listener {
+        key_listener.stop();
+    }
+    if let Some(run_progress) = run_progress {
+        let headline = match (interrupted, aborted) {
+            (true, _) => "Upload interrupted!",
+            (false, true) => "Upload aborted!",
+            (false, false) => "Upload complete!",
+        };
+        run_progress.finish(headline).await;
+    }
+    failures.print_report();
+    if options.summary {
+        stats.print_summary("Uploaded");
+    }
+    if interrupted || aborted {
+        let reason = if interrupted { "Interrupted" } else { "Aborted after the first error" };
+        let not_uploaded = stats.not_finished(total_files);
+        eprintln!("{}", format!("{}: {} not uploaded", reason, progress::files(not_uploaded)).yellow());
+        events.error(
+            None,
+            format!("{}: {} not uploaded", reason.to_lowercase(), progress::files(not_uploaded)),
+        );
+    }
+    events.emit(&stats.summary_event());
+    events.close().await;
+    if let Some(ref log) = file_log {
+        log.note(&format!(
+            "upload finished: {} copied, {} failed, {} bytes",
+            stats.files_done.load(Ordering::Relaxed),
+            stats.files_failed.load(Ordering::Relaxed),
+            stats.bytes_copied.load(Ordering::Relaxed)
+        ));
+    }
+
+    if let (Some(path), Some(recorder)) = (&options.report, &recorder)
+        && let Err(e) = recorder.write(path, stats.started.elapsed())
+    {
+        eprintln!("{} {}", "Failed to write report:".red(), e);
+        *has_failed.lock().unwrap() = true;
+    }
+
+    if options.on_complete_url.is_some() || options.on_complete_cmd.is_some() {
+        hooks::on_complete(
+            options.on_complete_url.as_ref(),
+            options.on_complete_cmd.as_deref(),
+            &stats.summary_event(),
+        )
+        .await;
+    }
+    let failed = *has_failed.lock().unwrap();
+    if options.notify {
+        let summary = match (interrupted, failed) {
+            (true, _) => "Upload interrupted",
+            (false, true) if aborted => "Upload aborted after an error",
+            (false, true) => "Upload finished with errors",
+            (false, false) => "Upload complete",
+        };
+        notify::run_finished(summary.to_string(), &stats).await;
+    }
+    if interrupted {
+        std::process::exit(exit::INTERRUPTED);
+    }
+    if failed {
+        let status = exit::for_failed_run(&stats);
+        failures.print_exit_message(status);
+        std::process::exit(status);
+    }
+}
+
+/// Records a file that won't be uploaded and takes its size out of the
+/// overall progress so the bar still reaches 100%.
+#[allow(clippy::too_many_arguments)]
+fn skip_upload(
+    entry: &S3UploadEntry,
+    destination: String,
+    reason: &str,
+    stats: &RunStats,
+    main_pb: Option<&ProgressBar>,
+    file_log: Option<&FileLog>,
+    recorder: Option<&Recorder>,
+    events: &EventSink,
+) {
+    log::info!("Skipping {}: {}", entry.from.display(), reason);
+    stats.files_skipped.fetch_add(1, Ordering::Relaxed);
     if let Some(pb) = main_pb {
-        pb.finish_with_message("Upload complete!");
+        pb.dec_length(entry.size);
     }
+    if let Some(log) = file_log {
+        log.note(&format!("{} -> {} skipped: {}", entry.from.display(), destination, reason));
+    }
+    if let Some(recorder) = recorder {
+        recorder.skipped(&entry.from, destination.clone(), reason.to_string());
+    }
+    events.emit(&CopyEvent::FileSkipped {
+        source: entry.from.display().to_string(),
+        destination,
+        reason: reason.to_string(),
+    });
+}
 
-    if *has_failed.lock().unwrap() {
-        std::process::exit(1);
+/// Marks the run as failed and, with `--fail-fast`, stops it.
+fn mark_failed(has_failed: &Mutex<bool>, cancel: &CancellationToken, fail_fast: bool) {
+    *has_failed.lock().unwrap() = true;
+    if fail_fast {
+        cancel.cancel();
     }
 }
@@ -0,0 +1,111 @@
+use colored::Colorize;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::exit;
+use crate::logfile::format_utc;
+use crate::progress;
+use crate::undo::{Change, UndoPlan};
+
+/// `cp2 undo`: reverses the last local copy, newest change first, removing
+/// the files and directories it created and putting back the files it
+/// overwrote. With `dry_run`, only lists what would be done. The journal is
+/// kept when something can't be undone, so that it can be tried again.
+pub(crate) fn run(dry_run: bool) {
+    let plan = match UndoPlan::last() {
+        Ok(Some(plan)) => plan,
+        Ok(None) => {
+            eprintln!("{}", "No copy to undo (only copies made with --undo are recorded)".red());
+            std::process::exit(exit::USAGE);
+        }
+        Err(e) => {
+            eprintln!("{} {}", "Cannot undo:".red(), e);
+            std::process::exit(exit::USAGE);
+        }
+    };
+    let started = format_utc(UNIX_EPOCH + Duration::from_secs(plan.started));
+    eprintln!("{}", format!("Undoing {}  {}", started, plan.args.join(" ")).bold());
+
+    let (mut removed, mut restored, mut failed) = (0, 0, 0);
+    for change in plan.changes.iter().rev() {
+        let (action, path, result) = match change {
+            Change::Created(path) => (
+                "remove",
+                path,
+                match dry_run {
+                    true => Ok(std::fs::symlink_metadata(path).is_ok()),
+                    false => remove(path),
+                },
+            ),
+            Change::Overwritten { path, backup } => (
+                "restore",
+                path,
+                match dry_run {
+                    true => Ok(backup.exists()),
+                    false => restore(backup, path),
+                },
+            ),
+        };
+        match result {
+            Ok(false) => {}
+            Ok(true) => {
+                if dry_run {
+                    println!("{} {}", action, path.display());
+                }
+                match change {
+                    Change::Created(_) => removed += 1,
+                    Change::Overwritten { .. } => restored += 1,
+                }
+            }
+            Err(e) => {
+                eprintln!("{} {}: {}", format!("Cannot {}", action).red(), path.display().to_string().red(), e);
+                failed += 1;
+            }
+        }
+    }
+    let (removed, restored) = (progress::files(removed), progress::files(restored));
+    if dry_run {
+        eprintln!("Would remove {removed} and restore {restored}");
+        return;
+    }
+    eprintln!("Removed {removed}, restored {restored}");
+    if failed > 0 {
+        eprintln!("{}", format!("{} could not be undone", progress::files(failed)).red());
+        std::process::exit(exit::FAILURE);
+    }
+    if let Err(e) = plan.discard() {
+        log::warn!("Cannot remove the undo journal: {}", e);
+    }
+}
+
+/// Removes a file or (empty) directory the copy created; `Ok(false)` if it
+/// is gone already. Directories that hold files added since are left alone.
+fn remove(path: &Path) -> io::Result<bool> {
+    let result = match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => match std::fs::remove_dir(path) {
+            Err(e) if e.kind() == io::ErrorKind::DirectoryNotEmpty => {
+                log::warn!("Leaving {}: not empty", path.display());
+                return Ok(false);
+            }
+            result => result,
+        },
+        Ok(_) => std::fs::remove_file(path),
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Moves the file the copy overwrote back from `backup` to `path`;
+/// `Ok(false)` if it was put back already.
+fn restore(backup: &Path, path: &Path) -> io::Result<bool> {
+    match std::fs::rename(backup, path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,251 @@
+use colored::Colorize;
+use indicatif::ProgressBar;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+
+use crate::diff::{diff_trees, ChangeKind, DiffOptions};
+use crate::exit;
+use crate::hash::{self, HashAlgorithm};
+use crate::manifest::Manifest;
+use crate::progress::{self, RunStats};
+use crate::utils::trim_filename;
+
+/// A file to check against the digest recorded for it.
+struct Check {
+    /// The file as the checksum file names it.
+    name: String,
+    path: PathBuf,
+    digest: String,
+    algorithm: HashAlgorithm,
+    /// Size recorded in a manifest, checked before reading the file.
+    size: Option<u64>,
+}
+
+/// `cp2 verify`: re-reads the files under `destination` and compares them
+/// with the sizes and digests in the `--manifest` of a copy, the digests in
+/// `sums` or, without either, those in the `SHA256SUMS` or `B3SUMS` at
+/// `destination` or the `.sha256` and `.b3` sidecars below it.
+pub(crate) async fn run(
+    destination: PathBuf,
+    sums: Option<PathBuf>,
+    manifest: Option<PathBuf>,
+    algorithm: Option<HashAlgorithm>,
+) {
+    if !destination.exists() {
+        eprintln!("{} {}", "Destination path does not exist:".red(), destination.display().to_string().red());
+        std::process::exit(exit::USAGE);
+    }
+    let mut checks = Vec::new();
+    if let Some(manifest) = manifest {
+        let manifest = match Manifest::read(&manifest).await {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                eprintln!("{} {}: {}", "Cannot read".red(), manifest.display().to_string().red(), e);
+                std::process::exit(exit::USAGE);
+            }
+        };
+        checks.extend(manifest.files.into_iter().map(|file| Check {
+            path: destination.join(&file.path),
+            name: file.path,
+            digest: file.hash,
+            algorithm: manifest.algorithm,
+            size: Some(file.size),
+        }));
+        return check_all(checks).await;
+    }
+    let sums_files = match sums {
+        Some(sums) => vec![sums],
+        None => [HashAlgorithm::Sha256, HashAlgorithm::Blake3]
+            .iter()
+            .map(|algorithm| destination.join(algorithm.sums_file()))
+            .filter(|path| path.is_file())
+            .collect(),
+    };
+    for sums in &sums_files {
+        let contents = match tokio::fs::read_to_string(sums).await {
+            Ok(contents) => contents,
+            Err(e) => {
+                eprintln!("{} {}: {}", "Cannot read".red(), sums.display().to_string().red(), e);
+                std::process::exit(exit::USAGE);
+            }
+        };
+        let algorithm = algorithm.unwrap_or_else(|| HashAlgorithm::for_sums_file(sums));
+        checks.extend(hash::parse_sums(&contents).into_iter().map(|(name, digest)| Check {
+            path: destination.join(&name),
+            name,
+            digest,
+            algorithm,
+            size: None,
+        }));
+    }
+    if sums_files.is_empty() {
+        sidecar_checks(&destination, algorithm, &mut checks);
+    }
+    if checks.is_empty() {
+        eprintln!("{} {}", "No checksums found for".red(), destination.display().to_string().red());
+        std::process::exit(exit::USAGE);
+    }
+    check_all(checks).await;
+}
+
+/// Reads every file of `checks` and reports those that don't match.
+async fn check_all(checks: Vec<Check>) {
+
+    let total_bytes: u64 = checks
+        .iter()
+        .filter_map(|check| std::fs::metadata(&check.path).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    // Per-file lines from -vv would be torn up by a bar.
+    let pb = match std::io::stderr().is_terminal() && !log::log_enabled!(log::Level::Info) {
+        true => ProgressBar::new(total_bytes).with_style(progress::file_bar_style()),
+        false => ProgressBar::hidden(),
+    };
+    let stats = RunStats::new();
+    for check in &checks {
+        pb.set_message(format!("Verifying {}", trim_filename(&check.name, 28)));
+        let actual_size = std::fs::metadata(&check.path).map(|metadata| metadata.len()).ok();
+        let problem = match (check.size, actual_size) {
+            (Some(size), Some(actual)) if size != actual => {
+                pb.inc(actual);
+                Some(format!("size mismatch: {} vs {} bytes", size, actual))
+            }
+            _ => match hash::hash_file(&check.path, check.algorithm, |n| {
+                pb.inc(n);
+                stats.bytes_copied.fetch_add(n, Ordering::Relaxed);
+            })
+            .await
+            {
+                Ok(digest) if digest == check.digest => None,
+                Ok(_) => Some("checksum mismatch".to_string()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Some("missing".to_string()),
+                Err(e) => Some(format!("cannot read: {}", e)),
+            },
+        };
+        match problem {
+            None => {
+                log::info!("OK {}", check.name);
+                stats.files_done.fetch_add(1, Ordering::Relaxed);
+            }
+            Some(problem) => {
+                pb.suspend(|| eprintln!("{} {} ({})", "FAILED".red(), check.name, problem));
+                stats.files_failed.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+    pb.finish_and_clear();
+    stats.print_summary("Verified");
+    if stats.files_failed.load(Ordering::Relaxed) > 0 {
+        std::process::exit(exit::for_failed_run(&stats));
+    }
+}
+
+/// Adds a check for every `<file>.sha256` or `<file>.b3` sidecar below
+/// `dir`, as written by `--hash-sidecars`.
+fn sidecar_checks(dir: &Path, algorithm: Option<HashAlgorithm>, checks: &mut Vec<Check>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    let mut entries: Vec<_> = entries.flatten().collect();
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        if entry.file_type().is_ok_and(|t| t.is_dir()) {
+            sidecar_checks(&path, algorithm, checks);
+            continue;
+        }
+        let sidecar_algorithm = [HashAlgorithm::Sha256, HashAlgorithm::Blake3]
+            .into_iter()
+            .find(|a| path.to_string_lossy().ends_with(a.suffix()));
+        let Some(found) = sidecar_algorithm else {
+            continue;
+        };
+        let Ok(contents) = std::fs::read_to_string(&path) else {
+            continue;
+        };
+        for (name, digest) in hash::parse_sums(&contents) {
+            let file = dir.join(&name);
+            checks.push(Check {
+                name: file.display().to_string(),
+                path: file,
+                digest,
+                algorithm: algorithm.unwrap_or(found),
+                size: None,
+            });
+        }
+    }
+}
+
+/// `cp2 verify SOURCE DEST`: compares the tree at `source` with its copy at
+/// `destination` and reports missing, differing and extra files.
+pub(crate) async fn compare(source: PathBuf, destination: PathBuf, hash: Option<HashAlgorithm>, size_only: bool) {
+    check_exists(&source, &destination);
+    let options = DiffOptions { hash, size_only };
+    let compared_bytes = match hash {
+        Some(_) => tree_size(&source),
+        None => 0,
+    };
+    let pb = match hash.is_some() && std::io::stderr().is_terminal() && !log::log_enabled!(log::Level::Info) {
+        true => ProgressBar::new(compared_bytes).with_style(progress::file_bar_style()),
+        false => ProgressBar::hidden(),
+    };
+    let diff = diff_trees(&source, &destination, &options, &|name, n| match n {
+        0 => pb.set_message(format!("Comparing {}", trim_filename(name, 28))),
+        n => pb.inc(n),
+    })
+    .await;
+    pb.finish_and_clear();
+
+    let stats = RunStats::new();
+    stats.files_done.store(diff.same_files, Ordering::Relaxed);
+    stats.bytes_copied.store(diff.same_bytes, Ordering::Relaxed);
+    let mut failed_dirs = 0;
+    let mut extra = 0;
+    for change in &diff.changes {
+        match change.change {
+            ChangeKind::Added => {
+                eprintln!("{} {}", "Extra".yellow(), change.path);
+                extra += 1;
+            }
+            ChangeKind::Removed => eprintln!("{} {} (missing)", "FAILED".red(), change.path),
+            ChangeKind::Modified => eprintln!("{} {} ({})", "FAILED".red(), change.path, change.describe()),
+        }
+        match change.change {
+            ChangeKind::Added => {}
+            _ if change.path.ends_with('/') => failed_dirs += 1,
+            _ => {
+                stats.files_failed.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+    stats.print_summary("Matched");
+    if extra > 0 {
+        let noun = if extra == 1 { "path" } else { "paths" };
+        eprintln!("{}", format!("  {} {} only at the destination", extra, noun).yellow());
+    }
+    if stats.files_failed.load(Ordering::Relaxed) > 0 || failed_dirs > 0 {
+        std::process::exit(exit::for_failed_run(&stats));
+    }
+}
+
+/// Exits with a usage error unless both trees exist.
+pub(crate) fn check_exists(source: &Path, destination: &Path) {
+    for (path, what) in [(source, "Source"), (destination, "Destination")] {
+        if !path.exists() {
+            eprintln!("{} {}", format!("{what} path does not exist:").red(), path.display().to_string().red());
+            std::process::exit(exit::USAGE);
+        }
+    }
+}
+
+/// Total size of the files at or below `path`, for sizing a progress bar.
+pub(crate) fn tree_size(path: &Path) -> u64 {
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.is_dir() => std::fs::read_dir(path)
+            .map(|entries| entries.flatten().map(|entry| tree_size(&entry.path())).sum())
+            .unwrap_or(0),
+        Ok(metadata) => metadata.len(),
+        Err(_) => 0,
+    }
+}
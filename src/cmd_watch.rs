@@ -0,0 +1,150 @@
+use colored::Colorize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::cmd_verify::check_exists;
+use crate::engine::{self, CopyOptions, Overwrite};
+use crate::exit;
+use crate::interrupt;
+use crate::progress::{files, format_bytes};
+use crate::report::FileStatus;
+use crate::utils::Preserve;
+use crate::watch;
+
+/// `cp2 watch`: copies `source` into `destination` like `cp2 -r`, then keeps
+/// copying the files below it that are created or changed until Ctrl-C.
+/// Changes are gathered until none arrived for `debounce`, so a file
+/// written in several goes is copied once. Deletions aren't mirrored.
+pub(crate) async fn run(source: PathBuf, destination: PathBuf, debounce: Duration, poll: Option<Duration>, parallel: usize) {
+    check_exists(&source, &destination);
+    if !source.is_dir() || !destination.is_dir() {
+        eprintln!("{}", "cp2 watch copies a directory into a directory".red());
+        std::process::exit(exit::USAGE);
+    }
+    let cancel = interrupt::install();
+    // Watch before the first sync so nothing changed during it is missed.
+    let mut changes = match watch::watch(&source, poll) {
+        Ok(changes) => changes,
+        Err(e) => {
+            eprintln!("{} {}: {}", "Cannot watch".red(), source.display().to_string().red(), e);
+            std::process::exit(exit::FAILURE);
+        }
+    };
+    // Times are kept so that files not changed since are recognised on the
+    // next start.
+    let options = CopyOptions::new()
+        .recursive(true)
+        .parallel(parallel)
+        .preserve(Preserve {
+            timestamps: true,
+            ..Preserve::default()
+        })
+        .cancel(cancel.clone());
+    let mirror = destination.join(source.file_name().unwrap_or_default());
+
+    sync(&source, &destination, &options).await;
+    eprintln!(
+        "{}",
+        format!("Watching {} for changes (press Ctrl-C to stop)", source.display()).bold()
+    );
+    'watch: loop {
+        let first = tokio::select! {
+            _ = cancel.cancelled() => break,
+            changed = changes.recv() => match changed {
+                Some(path) => path,
+                None => break,
+            },
+        };
+        let mut batch = BTreeSet::from([first]);
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break 'watch,
+                changed = tokio::time::timeout(debounce, changes.recv()) => match changed {
+                    Ok(Some(path)) => {
+                        batch.insert(path);
+                    }
+                    _ => break,
+                },
+            }
+        }
+        match batch.contains(&source) {
+            // Changes were missed; catch up with everything.
+            true => sync(&source, &destination, &options).await,
+            false => copy_changes(&source, &mirror, batch, &options).await,
+        }
+    }
+    std::process::exit(exit::INTERRUPTED);
+}
+
+/// Copies what changed in `source` since it was last copied to `destination`.
+async fn sync(source: &Path, destination: &Path, options: &CopyOptions) {
+    let options = options.clone().overwrite(Overwrite::IfNewer);
+    match engine::copy(&[source], destination, &options).await {
+        Ok(report) => {
+            print_failures(&report);
+            eprintln!(
+                "{}",
+                format!(
+                    "Synced {} ({}), {} up to date",
+                    files(report.totals.files_copied),
+                    format_bytes(report.totals.bytes_copied),
+                    report.totals.files_skipped
+                )
+                .green()
+            );
+        }
+        Err(e) => eprintln!("{} {}", "Sync failed:".red(), e),
+    }
+}
+
+/// Copies the changed files and directories of `batch` from `source` to the
+/// same places below `mirror`.
+async fn copy_changes(source: &Path, mirror: &Path, batch: BTreeSet<PathBuf>, options: &CopyOptions) {
+    // Paths below a changed directory are copied along with it.
+    let mut parents: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    let mut last_dir: Option<PathBuf> = None;
+    for path in batch {
+        if last_dir.as_ref().is_some_and(|dir| path.starts_with(dir)) {
+            continue;
+        }
+        let Ok(relative) = path.strip_prefix(source) else {
+            continue;
+        };
+        // Gone again by now, such as an editor's temporary file.
+        let Ok(metadata) = std::fs::metadata(&path) else {
+            continue;
+        };
+        if metadata.is_dir() {
+            last_dir = Some(path.clone());
+        }
+        let parent = mirror.join(relative).parent().unwrap_or(mirror).to_path_buf();
+        parents.entry(parent).or_default().push(path);
+    }
+    for (parent, paths) in parents {
+        if let Err(e) = tokio::fs::create_dir_all(&parent).await {
+            eprintln!("{} {}: {}", "Cannot create".red(), parent.display().to_string().red(), e);
+            continue;
+        }
+        match engine::copy(&paths, &parent, options).await {
+            Ok(report) => {
+                for record in report.files.iter().filter(|r| r.status == FileStatus::Copied) {
+                    eprintln!("{} {}", "Copied".green(), record.destination);
+                }
+                print_failures(&report);
+            }
+            Err(e) => eprintln!("{} {}", "Copy failed:".red(), e),
+        }
+    }
+}
+
+fn print_failures(report: &engine::CopyReport) {
+    for record in report.files.iter().filter(|r| r.status == FileStatus::Failed) {
+        eprintln!(
+            "{} {}: {}",
+            "Failed".red(),
+            record.source,
+            record.error.as_deref().unwrap_or("unknown error")
+        );
+    }
+}
@@ -0,0 +1,51 @@
+use crate::filter::Filter;
+
+/// How destination files are compressed with `--compress`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// zstd at a level from 1 to 19.
+    Zstd(u32),
+    /// gzip at a level from 1 to 9.
+    Gzip(u32),
+}
+
+impl Compression {
+    /// Suffix appended to the names of compressed files.
+    pub fn suffix(self) -> &'static str {
+        match self {
+            Compression::Zstd(_) => ".zst",
+            Compression::Gzip(_) => ".gz",
+        }
+    }
+
+    /// The program that compresses stdin to stdout.
+    pub fn filter(self) -> Filter {
+        match self {
+            Compression::Zstd(level) => Filter::new("zstd", &["-q", "-c"], "compress").arg(format!("-{level}")),
+            Compression::Gzip(level) => Filter::new("gzip", &["-c"], "compress").arg(format!("-{level}")),
+        }
+    }
+}
+
+/// Parses a `--compress` value: `zstd` or `gzip`, optionally followed by a
+/// level as in `zstd:19`.
+pub fn parse_compression(s: &str) -> Result<Compression, String> {
+    let (name, level) = match s.split_once(':') {
+        Some((name, level)) => {
+            let level = level.parse::<u32>().map_err(|_| format!("`{level}` is not a compression level"))?;
+            (name, Some(level))
+        }
+        None => (s, None),
+    };
+    match name {
+        "zstd" => match level.unwrap_or(3) {
+            level @ 1..=19 => Ok(Compression::Zstd(level)),
+            level => Err(format!("zstd level {level} is out of range (1-19)")),
+        },
+        "gzip" => match level.unwrap_or(6) {
+            level @ 1..=9 => Ok(Compression::Gzip(level)),
+            level => Err(format!("gzip level {level} is out of range (1-9)")),
+        },
+        other => Err(format!("`{other}` is not a supported compression (use zstd[:LEVEL] or gzip[:LEVEL])")),
+    }
+}
@@ -2,14 +2,18 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::path::PathBuf;
 
-/// Configuration for an S3-compatible remote.
+use crate::sftp::SftpLocation;
+use crate::transport::join_remote;
+
+/// Configuration for a named remote: an S3-compatible service, an SFTP
+/// server or a WebDAV server.
 ///
 /// Intentionally does not derive `Debug`: the struct holds plaintext
 /// credentials and we don't want them landing in log lines or panic
 /// messages.
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub struct RemoteConfig {
-    /// Remote type – currently only "s3" is supported.
+    /// Remote type: "s3", "sftp" or "webdav".
     #[serde(rename = "type")]
     pub remote_type: String,
     /// Provider hint (e.g. "AWS", "Minio", "DigitalOcean").
@@ -22,11 +26,143 @@ pub struct RemoteConfig {
     pub region: Option<String>,
     /// Custom endpoint URL for S3-compatible services (leave empty for AWS).
     pub endpoint: Option<String>,
+    /// SFTP server host name or address.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// SFTP server port, if not 22.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    /// User to log in as on an SFTP or WebDAV server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// WebDAV password; `CP2_DAV_PASSWORD` is used when it isn't set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// WebDAV base URL, `dav://`, `davs://`, `http://` or `https://`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// Directory on an SFTP server that relative paths start from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Private key for SFTP logins, instead of ssh's defaults.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity_file: Option<String>,
+    /// Public keys the SFTP server may present, as in `known_hosts` but
+    /// without the host name (e.g. `ssh-ed25519 AAAA...`). When set, any
+    /// other key is refused.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub host_keys: Vec<String>,
+}
+
+impl RemoteConfig {
+    /// `path` on this SFTP remote. Relative paths start in the remote's
+    /// `path`, or the login directory if it has none.
+    pub fn sftp_location(&self, path: &str) -> Result<SftpLocation, String> {
+        let host = self.host.clone().ok_or("host is not set")?;
+        let path = match self.path.as_deref() {
+            Some(base) if !path.starts_with('/') => join_remote(base, path),
+            _ if path.is_empty() => ".".to_string(),
+            _ => path.to_string(),
+        };
+        Ok(SftpLocation {
+            user: self.user.clone(),
+            host,
+            port: self.port,
+            path,
+        })
+    }
+
+    /// `path` below this WebDAV remote's URL, with its credentials.
+    pub fn webdav_url(&self, path: &str) -> Result<reqwest::Url, String> {
+        let base = self.url.as_deref().ok_or("url is not set")?;
+        let mut url = crate::webdav::parse_url(base)
+            .or_else(|| reqwest::Url::parse(base).ok().filter(|u| matches!(u.scheme(), "http" | "https")))
+            .ok_or_else(|| format!("url {base} is not a dav://, davs://, http:// or https:// URL"))?;
+        let joined = format!("{}/{}", url.path().trim_end_matches('/'), path.trim_start_matches('/'));
+        url.set_path(&joined);
+        if let Some(ref user) = self.user {
+            let _ = url.set_username(user);
+        }
+        if let Some(ref password) = self.password {
+            let _ = url.set_password(Some(password));
+        }
+        Ok(url)
+    }
+
+    /// Options added to the ssh command for this SFTP remote: its identity
+    /// file, and its host keys written to `known_hosts.<name>` next to the
+    /// configuration file.
+    pub fn ssh_options(&self, name: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let mut options = Vec::new();
+        if let Some(ref identity) = self.identity_file {
+            let identity = match (identity.strip_prefix("~/"), dirs::home_dir()) {
+                (Some(rest), Some(home)) => home.join(rest).display().to_string(),
+                _ => identity.clone(),
+            };
+            options.extend(["-i".to_string(), identity]);
+        }
+        if !self.host_keys.is_empty() {
+            let host = self.host.as_deref().unwrap_or_default();
+            let pattern = match self.port {
+                Some(port) => format!("[{host}]:{port}"),
+                None => host.to_string(),
+            };
+            let known_hosts = config_path()?.with_file_name(format!("known_hosts.{name}"));
+            let lines: String = self.host_keys.iter().map(|key| format!("{pattern} {key}\n")).collect();
+            if let Some(parent) = known_hosts.parent() {
+                create_secure_dir(parent)?;
+            }
+            write_secure(&known_hosts, lines.as_bytes())?;
+            options.extend([
+                "-o".to_string(),
+                format!("UserKnownHostsFile={}", known_hosts.display()),
+                "-o".to_string(),
+                "StrictHostKeyChecking=yes".to_string(),
+            ]);
+        }
+        Ok(options)
+    }
 }
 
 /// In-memory representation of the whole config file.
 pub type Config = HashMap<String, RemoteConfig>;
 
+/// Settings of local copies taken from the `[defaults]` section of the
+/// config file when neither the command line nor the environment sets them,
+/// e.g. the ones `cp2 bench` recommends. Sizes are written as on the command
+/// line (`"1M"`).
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Defaults {
+    /// `--parallel`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parallel: Option<usize>,
+    /// `--buffer-size`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub buffer_size: Option<String>,
+    /// `--split-threshold`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub split_threshold: Option<String>,
+}
+
+impl Defaults {
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Layout of the config file: remotes live in a `[remotes]` section
+/// (`[remotes.nas]`) and default settings in `[defaults]`. Top-level tables,
+/// which older versions wrote, are still read as remotes.
+#[derive(Default, Serialize, Deserialize)]
+struct ConfigFile {
+    #[serde(default, skip_serializing_if = "Defaults::is_empty")]
+    defaults: Defaults,
+    #[serde(default)]
+    remotes: Config,
+    #[serde(flatten, skip_serializing)]
+    legacy: Config,
+}
+
 /// Environment variable that overrides the default config path. Primarily
 /// used by tests so they don't clobber the user's real configuration.
 const CONFIG_PATH_ENV: &str = "CP2_CONFIG";
@@ -49,9 +185,22 @@ pub fn config_path() -> Result<PathBuf, Box<dyn std::error::Error>> {
 /// Loads the configuration file from disk.  Returns an empty map if the file
 /// does not exist yet.
 pub fn load_config() -> Result<Config, Box<dyn std::error::Error>> {
+    let file = load_file()?;
+    let mut config = file.legacy;
+    config.extend(file.remotes);
+    Ok(config)
+}
+
+/// Loads the `[defaults]` section of the configuration file; empty if the
+/// file does not exist yet.
+pub fn load_defaults() -> Result<Defaults, Box<dyn std::error::Error>> {
+    Ok(load_file()?.defaults)
+}
+
+fn load_file() -> Result<ConfigFile, Box<dyn std::error::Error>> {
     let path = config_path()?;
     if !path.exists() {
-        return Ok(Config::new());
+        return Ok(ConfigFile::default());
     }
     let content = std::fs::read_to_string(&path)?;
     Ok(toml::from_str(&content)?)
@@ -65,7 +214,16 @@ pub fn save_config(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
     if let Some(parent) = path.parent() {
         create_secure_dir(parent)?;
     }
-    let serialized = toml::to_string_pretty(config)?;
+    // The defaults are edited by hand; keep them as they are.
+    let defaults = match path.exists() {
+        true => load_file()?.defaults,
+        false => Defaults::default(),
+    };
+    let serialized = toml::to_string_pretty(&ConfigFile {
+        defaults,
+        remotes: config.clone(),
+        legacy: Config::new(),
+    })?;
     write_secure(&path, serialized.as_bytes())?;
     Ok(())
 }
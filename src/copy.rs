@@ -1,45 +1,896 @@
-use indicatif::ProgressBar;
-use std::path::Path;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use tokio::fs;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::sync::CancellationToken;
 
-const BUFFER_SIZE: usize = 8 * 1024 * 1024; // 8MB chunks
+use crate::cache::Dropper;
+use crate::hash::{Hasher, HashingReader};
+use crate::sink::ProgressSink;
+use crate::throttle::Throttle;
+use crate::timings::{self, Phase};
 
-// Copy a file with dual progress bars (file + main)
+/// Read/write buffer size used unless `--buffer-size` says otherwise.
+pub const DEFAULT_BUFFER_SIZE: usize = 8 * 1024 * 1024; // 8MB chunks
+
+/// Why copying a file failed.
+#[derive(Debug, thiserror::Error)]
+pub enum CopyError {
+    /// Reading, writing or otherwise touching `path` failed.
+    #[error("cannot {operation} {}: {source}", path.display())]
+    Io {
+        /// What was being done, e.g. `open` or `write`.
+        operation: &'static str,
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// Source and destination are the same file, which copying would
+    /// truncate.
+    #[error("'{}' and '{}' are the same file", from.display(), to.display())]
+    SameFile { from: PathBuf, to: PathBuf },
+    /// A directory would be copied into itself or one of its descendants.
+    #[error("{}", if directory == destination {
+        "source and destination are the same directory"
+    } else {
+        "cannot copy a directory into itself"
+    })]
+    DestinationInsideSource { directory: PathBuf, destination: PathBuf },
+    /// The copy was stopped through its cancellation token.
+    #[error("copy cancelled")]
+    Interrupted,
+    /// The destination doesn't hold as many bytes as the source.
+    #[error("size mismatch after copy of {}: expected {expected} bytes, wrote {written}", path.display())]
+    VerificationFailed { path: PathBuf, expected: u64, written: u64 },
+    /// The destination read back doesn't hash to the digest of the source.
+    #[error("digest mismatch after copy of {}: the destination differs from the source", path.display())]
+    DigestMismatch { path: PathBuf },
+    /// The copy failed after the destination had been created or truncated,
+    /// so it now holds a partial file. Errors raised before the destination
+    /// was touched (e.g. an unreadable source) aren't wrapped.
+    #[error(transparent)]
+    Partial(Box<CopyError>),
+}
+
+impl CopyError {
+    /// Builds an [`CopyError::Io`] for `operation` on `path`, for use with
+    /// `map_err`.
+    pub(crate) fn io<'a>(operation: &'static str, path: &'a Path) -> impl FnOnce(std::io::Error) -> CopyError + 'a {
+        move |source| CopyError::Io {
+            operation,
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+
+    /// Marks the error as having left a partial destination behind.
+    pub(crate) fn partial(self) -> Self {
+        match self {
+            CopyError::Partial(_) => self,
+            e => CopyError::Partial(Box::new(e)),
+        }
+    }
+
+    /// Whether the destination holds a partial file that should be removed.
+    pub fn is_partial(&self) -> bool {
+        matches!(self, CopyError::Partial(_))
+    }
+
+    /// What went wrong, whether or not a partial file was left behind.
+    pub fn cause(&self) -> &CopyError {
+        match self {
+            CopyError::Partial(inner) => inner.cause(),
+            e => e,
+        }
+    }
+}
+
+/// Upper bound for the delay between two retries of the same file.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+/// Whether a failed copy is worth retrying. Errors that won't go away on
+/// their own (missing files, permissions, a full disk, ...) are not;
+/// anything else, such as EIO or a timeout on a network share, is.
+pub fn is_transient(err: &(dyn std::error::Error + 'static)) -> bool {
+    use std::io::ErrorKind::*;
+    !matches!(
+        find_io_error(err).map(|io| io.kind()),
+        Some(
+            NotFound
+                | PermissionDenied
+                | InvalidInput
+                | IsADirectory
+                | NotADirectory
+                | ReadOnlyFilesystem
+                | StorageFull
+                | QuotaExceeded
+                | FileTooLarge
+                | InvalidFilename
+                | Unsupported
+        )
+    )
+}
+
+/// Whether `err` means the source is held open exclusively by another
+/// process (a sharing or lock violation on Windows). Always false elsewhere,
+/// where file locks are advisory and don't stop a copy.
+pub fn is_locked(err: &(dyn std::error::Error + 'static)) -> bool {
+    // ERROR_SHARING_VIOLATION and ERROR_LOCK_VIOLATION.
+    cfg!(windows) && matches!(find_io_error(err).and_then(|io| io.raw_os_error()), Some(32 | 33))
+}
+
+/// The first I/O error in the source chain of `err`, if any.
+pub fn find_io_error<'a>(err: &'a (dyn std::error::Error + 'static)) -> Option<&'a std::io::Error> {
+    let mut current = Some(err);
+    while let Some(e) = current {
+        if let Some(io) = e.downcast_ref::<std::io::Error>() {
+            return Some(io);
+        }
+        current = e.source();
+    }
+    None
+}
+
+/// Delay before retry number `attempt` (starting at 1): `base` doubled for
+/// every earlier attempt, capped at five minutes.
+pub fn retry_delay(base: Duration, attempt: u32) -> Duration {
+    base.saturating_mul(1 << attempt.saturating_sub(1).min(16))
+        .min(MAX_RETRY_DELAY)
+}
+
+/// Whether `a` and `b` are the same file: the same device and inode on Unix
+/// (so hard links count too), or the same canonical path elsewhere. Missing
+/// files are never the same.
+pub fn same_file(a: &Path, b: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        match (std::fs::metadata(a), std::fs::metadata(b)) {
+            (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+            _ => false,
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// Creates a FIFO, socket or device node at `to` with the type, permissions
+/// and device number of `from`, replacing any non-directory already there.
+/// Device nodes need root privileges (`CAP_MKNOD` on Linux).
+#[cfg(unix)]
+pub fn recreate_special(from: &Path, to: &Path) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+    let meta = std::fs::symlink_metadata(from)?;
+    match std::fs::symlink_metadata(to) {
+        Ok(existing) if existing.is_dir() => {
+            return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, "destination is a directory"));
+        }
+        Ok(_) => std::fs::remove_file(to)?,
+        Err(_) => {}
+    }
+    let path = std::ffi::CString::new(to.as_os_str().as_bytes())?;
+    // SAFETY: `path` is a valid NUL-terminated string for the duration of the call.
+    if unsafe { libc::mknod(path.as_ptr(), meta.mode() as libc::mode_t, meta.rdev() as libc::dev_t) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Special files only exist on Unix.
+#[cfg(not(unix))]
+pub fn recreate_special(_from: &Path, _to: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "special files are not supported on this platform"))
+}
+
+/// Creates `to` as a reflink of `from`: a new file sharing its data blocks
+/// until either is written to. Only copy-on-write filesystems such as
+/// Btrfs, XFS and APFS support this; elsewhere it fails with the error the
+/// kernel reports (usually "operation not supported").
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn reflink(from: &Path, to: &Path) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+    let source = std::fs::File::open(from)?;
+    let target = std::fs::File::create_new(to)?;
+    // SAFETY: both descriptors stay open for the duration of the call.
+    if unsafe { libc::ioctl(target.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) } != 0 {
+        let error = std::io::Error::last_os_error();
+        drop(target);
+        let _ = std::fs::remove_file(to);
+        return Err(error);
+    }
+    Ok(())
+}
+
+/// Creates `to` as a reflink (a clone) of `from`, sharing its data blocks
+/// until either is written to. Needs APFS.
+#[cfg(target_os = "macos")]
+pub fn reflink(from: &Path, to: &Path) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    let from = std::ffi::CString::new(from.as_os_str().as_bytes())?;
+    let to = std::ffi::CString::new(to.as_os_str().as_bytes())?;
+    // SAFETY: both paths are valid NUL-terminated strings for the duration of the call.
+    if unsafe { libc::clonefile(from.as_ptr(), to.as_ptr(), 0) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Reflinks are only supported on Linux and macOS.
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+pub fn reflink(_from: &Path, _to: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "reflinks are not supported on this platform"))
+}
+
+/// Refuses to copy a file onto itself, which would truncate it.
+fn ensure_distinct(from: &Path, to: &Path) -> Result<(), CopyError> {
+    if same_file(from, to) {
+        return Err(CopyError::SameFile {
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+        });
+    }
+    Ok(())
+}
+
+/// Copies `from` to `to`, reporting the bytes written to `progress`.
+///
+/// Like the other copy functions, it stops between two buffers once
+/// `cancel` fires, failing with [`CopyError::Interrupted`] and leaving a
+/// partial destination behind.
 pub async fn copy_file_with_dual_progress(
     from: &Path,
     to: &Path,
-    file_pb: Option<&ProgressBar>,
-    main_pb: Option<&ProgressBar>,
+    progress: Option<&dyn ProgressSink>,
+    sync: bool,
+    throttle: Option<&Throttle>,
+    cancel: Option<&CancellationToken>,
+) -> Result<u64, CopyError> {
+    let mut buffer = vec![0u8; DEFAULT_BUFFER_SIZE];
+    copy_file_with_buffer(from, to, &mut buffer, progress, sync, throttle, cancel).await
+}
+
+/// Same as [`copy_file_with_dual_progress`], but reuses a caller-provided
+/// buffer so batches of small files don't allocate one per file.
+#[allow(clippy::too_many_arguments)]
+pub async fn copy_file_with_buffer(
+    from: &Path,
+    to: &Path,
+    buffer: &mut [u8],
+    progress: Option<&dyn ProgressSink>,
+    sync: bool,
+    throttle: Option<&Throttle>,
+    cancel: Option<&CancellationToken>,
+) -> Result<u64, CopyError> {
+    ensure_distinct(from, to)?;
+    let mut source = timings::time(from, Phase::Open, fs::File::open(from)).await.map_err(CopyError::io("open", from))?;
+    let mut dest = timings::time(from, Phase::Open, fs::File::create(to)).await.map_err(CopyError::io("create", to))?;
+
+    let mut cache = Dropper::new(&source, 0, &dest, 0);
+    write_contents(from, to, &mut source, &mut dest, &mut cache, buffer, progress, sync, throttle, cancel)
+        .await
+        .map_err(CopyError::partial)
+}
+
+/// Same as [`copy_file_with_buffer`], but also feeds everything read from
+/// `from` to `hasher`, so its digest comes without reading it again.
+#[allow(clippy::too_many_arguments)]
+pub async fn copy_file_hashed(
+    from: &Path,
+    to: &Path,
+    buffer: &mut [u8],
+    hasher: &mut Hasher,
+    progress: Option<&dyn ProgressSink>,
+    sync: bool,
+    throttle: Option<&Throttle>,
+    cancel: Option<&CancellationToken>,
+) -> Result<u64, CopyError> {
+    ensure_distinct(from, to)?;
+    let source = timings::time(from, Phase::Open, fs::File::open(from)).await.map_err(CopyError::io("open", from))?;
+    let mut dest = timings::time(from, Phase::Open, fs::File::create(to)).await.map_err(CopyError::io("create", to))?;
+    let mut cache = Dropper::new(&source, 0, &dest, 0);
+    let mut source = HashingReader { inner: source, hasher };
+
+    write_contents(from, to, &mut source, &mut dest, &mut cache, buffer, progress, sync, throttle, cancel)
+        .await
+        .map_err(CopyError::partial)
+}
+
+/// Copies `from` to every path in `to` while reading it only once: each
+/// `buffer` read is written to all destinations concurrently before the next
+/// one is read, which matters when the source is a slow disk. Progress is
+/// reported in bytes read from `from`, which is also what is returned.
+#[allow(clippy::too_many_arguments)]
+pub async fn copy_file_to_many(
+    from: &Path,
+    to: &[PathBuf],
+    buffer: &mut [u8],
+    progress: Option<&dyn ProgressSink>,
+    sync: bool,
+    throttle: Option<&Throttle>,
+    cancel: Option<&CancellationToken>,
+) -> Result<u64, CopyError> {
+    for to in to {
+        ensure_distinct(from, to)?;
+    }
+    let mut source = timings::time(from, Phase::Open, fs::File::open(from)).await.map_err(CopyError::io("open", from))?;
+    let mut dests = Vec::with_capacity(to.len());
+    for to in to {
+        let dest = timings::time(from, Phase::Open, fs::File::create(to)).await.map_err(CopyError::io("create", to));
+        // The destinations created before one that fails are left empty.
+        let dest = dest.map_err(|e| if dests.is_empty() { e } else { e.partial() })?;
+        dests.push((to.as_path(), dest));
+    }
+
+    let copied = async {
+        let mut total_bytes = 0u64;
+        loop {
+            let bytes_read = timings::time(from, Phase::Read, source.read(buffer)).await.map_err(CopyError::io("read", from))?;
+            if bytes_read == 0 {
+                break;
+            }
+            pace(throttle, bytes_read as u64, cancel).await?;
+            let chunk = &buffer[..bytes_read];
+            let writes = dests
+                .iter_mut()
+                .map(|(to, dest)| async move { dest.write_all(chunk).await.map_err(CopyError::io("write", to)) });
+            timings::time(from, Phase::Write, futures_util::future::try_join_all(writes)).await?;
+            total_bytes += bytes_read as u64;
+            if let Some(progress) = progress {
+                progress.on_bytes(from, bytes_read as u64);
+            }
+        }
+        for (to, dest) in &mut dests {
+            dest.flush().await.map_err(CopyError::io("flush", to))?;
+            if sync {
+                timings::time(from, Phase::Sync, dest.sync_all()).await.map_err(CopyError::io("sync", to))?;
+            }
+        }
+        Ok(total_bytes)
+    };
+    copied.await.map_err(CopyError::partial)
+}
+
+/// Bytes [`copy_file_kernel`] hands to the kernel at a time; progress is
+/// reported, and cancellation noticed, between two of them.
+const KERNEL_COPY_CHUNK: u64 = 64 * 1024 * 1024;
+
+/// Copies `from` to `to` without passing the data through a buffer of
+/// cp2's: the kernel moves it (with `copy_file_range` on Linux, which can
+/// also reflink or copy server-side on NFS and SMB) in steps of
+/// [`KERNEL_COPY_CHUNK`]. Unlike `std::fs::copy` it leaves the permissions
+/// of the destination as [`copy_file_with_buffer`] does. Progress comes in
+/// those large steps, so it suits small files and runs that show none.
+pub async fn copy_file_kernel(
+    from: &Path,
+    to: &Path,
+    progress: Option<&dyn ProgressSink>,
+    sync: bool,
+    throttle: Option<&Throttle>,
+    cancel: Option<&CancellationToken>,
+) -> Result<u64, CopyError> {
+    use std::io::Read;
+    use std::sync::Arc;
+
+    ensure_distinct(from, to)?;
+    let source = timings::time(from, Phase::Open, fs::File::open(from)).await.map_err(CopyError::io("open", from))?;
+    let dest = timings::time(from, Phase::Open, fs::File::create(to)).await.map_err(CopyError::io("create", to))?;
+    let mut cache = Dropper::new(&source, 0, &dest, 0);
+    let source = Arc::new(source.into_std().await);
+    let dest = Arc::new(dest.into_std().await);
+
+    let copied = async {
+        let mut total_bytes = 0u64;
+        loop {
+            let (source, dest) = (Arc::clone(&source), Arc::clone(&dest));
+            let copying = blocking(move || std::io::copy(&mut (&*source).take(KERNEL_COPY_CHUNK), &mut &*dest));
+            let bytes = timings::time(from, Phase::Write, copying).await.map_err(CopyError::io("copy", from))?;
+            if bytes == 0 {
+                break;
+            }
+            pace(throttle, bytes, cancel).await?;
+            total_bytes += bytes;
+            cache.copied(bytes);
+
+            if let Some(progress) = progress {
+                progress.on_bytes(from, bytes);
+            }
+        }
+
+        if sync {
+            let dest = Arc::clone(&dest);
+            timings::time(from, Phase::Sync, blocking(move || dest.sync_all()))
+                .await
+                .map_err(CopyError::io("sync", to))?;
+        }
+        cache.finish();
+        Ok(total_bytes)
+    };
+    copied.await.map_err(CopyError::partial)
+}
+
+/// Copies `from` to `to` with blocking calls, for callers that already run
+/// on a thread of their own such as `--engine threads`. The data moves as
+/// in [`copy_file_kernel`], but in one go, and `progress` hears of the whole
+/// file once it is done.
+pub fn copy_file_blocking(
+    from: &Path,
+    to: &Path,
+    progress: Option<&dyn ProgressSink>,
+    sync: bool,
+) -> Result<u64, CopyError> {
+    ensure_distinct(from, to)?;
+    let started = Instant::now();
+    let mut source = std::fs::File::open(from).map_err(CopyError::io("open", from))?;
+    let mut dest = std::fs::File::create(to).map_err(CopyError::io("create", to))?;
+    timings::record(from, Phase::Open, started.elapsed());
+
+    let mut copied = || {
+        let started = Instant::now();
+        let bytes = std::io::copy(&mut source, &mut dest).map_err(CopyError::io("copy", from))?;
+        timings::record(from, Phase::Write, started.elapsed());
+        if sync {
+            let started = Instant::now();
+            dest.sync_all().map_err(CopyError::io("sync", to))?;
+            timings::record(from, Phase::Sync, started.elapsed());
+        }
+        Ok(bytes)
+    };
+    let bytes = copied().map_err(CopyError::partial)?;
+    if let Some(progress) = progress {
+        progress.on_bytes(from, bytes);
+    }
+    Ok(bytes)
+}
+
+/// Alignment of the buffer, file offsets and lengths for O_DIRECT: the
+/// logical block size of nearly every disk, or a multiple of it.
+#[cfg(target_os = "linux")]
+const DIRECT_IO_ALIGN: usize = 4096;
+
+/// A buffer whose start and length are multiples of [`DIRECT_IO_ALIGN`], as
+/// O_DIRECT requires. Moving it keeps the allocation, and so the alignment.
+#[cfg(target_os = "linux")]
+struct AlignedBuffer {
+    bytes: Vec<u8>,
+    offset: usize,
+    len: usize,
+}
+
+#[cfg(target_os = "linux")]
+impl AlignedBuffer {
+    fn new(len: usize) -> Self {
+        let len = len.max(DIRECT_IO_ALIGN).next_multiple_of(DIRECT_IO_ALIGN);
+        let bytes = vec![0u8; len + DIRECT_IO_ALIGN];
+        let offset = bytes.as_ptr().align_offset(DIRECT_IO_ALIGN);
+        AlignedBuffer { bytes, offset, len }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.bytes[self.offset..self.offset + self.len]
+    }
+}
+
+/// Copies `from` to `to` with O_DIRECT, so neither file passes through the
+/// page cache: copying a file much larger than RAM then doesn't evict
+/// everything else cached. The reads and writes use a `buffer_size` buffer
+/// rounded up to the block alignment, and the last block is written padded
+/// and the file truncated back to its size. Filesystems that refuse
+/// O_DIRECT, such as tmpfs, get a regular buffered copy instead. A read
+/// that comes up short before the end of the source fails the copy with
+/// `CopyError::VerificationFailed` rather than leaving the file cut off.
+#[cfg(target_os = "linux")]
+#[allow(clippy::too_many_arguments)]
+pub async fn copy_file_direct(
+    from: &Path,
+    to: &Path,
+    progress: Option<&dyn ProgressSink>,
+    buffer_size: usize,
     sync: bool,
-) -> Result<u64, Box<dyn std::error::Error>> {
-    let mut source = fs::File::open(from).await?;
-    let mut dest = fs::File::create(to).await?;
+    throttle: Option<&Throttle>,
+    cancel: Option<&CancellationToken>,
+) -> Result<u64, CopyError> {
+    use std::io::{Read, Write};
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::sync::Arc;
+
+    ensure_distinct(from, to)?;
+    let open = |path: &Path, write: bool| {
+        let path = path.to_path_buf();
+        blocking(move || {
+            let mut options = std::fs::OpenOptions::new();
+            match write {
+                true => options.write(true).create(true).truncate(true),
+                false => options.read(true),
+            };
+            options.custom_flags(libc::O_DIRECT).open(path)
+        })
+    };
+    let unsupported = |e: &std::io::Error| e.raw_os_error() == Some(libc::EINVAL);
+    let source = match timings::time(from, Phase::Open, open(from, false)).await {
+        Err(e) if unsupported(&e) => {
+            log::debug!("{} doesn't support direct I/O, copying it through the page cache", from.display());
+            let mut buffer = vec![0u8; buffer_size];
+            return copy_file_with_buffer(from, to, &mut buffer, progress, sync, throttle, cancel).await;
+        }
+        opened => Arc::new(opened.map_err(CopyError::io("open", from))?),
+    };
+    let size = source.metadata().map_err(CopyError::io("stat", from))?.len();
+    let dest = match timings::time(from, Phase::Open, open(to, true)).await {
+        Err(e) if unsupported(&e) => {
+            log::debug!("{} doesn't support direct I/O, copying to it through the page cache", to.display());
+            let mut buffer = vec![0u8; buffer_size];
+            return copy_file_with_buffer(from, to, &mut buffer, progress, sync, throttle, cancel).await;
+        }
+        opened => Arc::new(opened.map_err(CopyError::io("create", to))?),
+    };
+
+    let copied = async {
+        let mut buffer = AlignedBuffer::new(buffer_size);
+        let mut total_bytes = 0u64;
+        loop {
+            let source = Arc::clone(&source);
+            let reading = blocking(move || Ok(((&*source).read(buffer.as_mut_slice())?, buffer)));
+            let bytes_read;
+            (bytes_read, buffer) = timings::time(from, Phase::Read, reading).await.map_err(CopyError::io("read", from))?;
+            if bytes_read == 0 {
+                break;
+            }
+            pace(throttle, bytes_read as u64, cancel).await?;
+
+            // Only the end of the file gives a read that isn't a whole number
+            // of blocks; it is written padded and cut off below.
+            let padded = bytes_read.next_multiple_of(DIRECT_IO_ALIGN);
+            let dest = Arc::clone(&dest);
+            let writing = blocking(move || {
+                (&*dest).write_all(&buffer.as_mut_slice()[..padded])?;
+                Ok(buffer)
+            });
+            buffer = timings::time(from, Phase::Write, writing).await.map_err(CopyError::io("write", to))?;
+            total_bytes += bytes_read as u64;
+
+            if let Some(progress) = progress {
+                progress.on_bytes(from, bytes_read as u64);
+            }
+            if padded != bytes_read {
+                break;
+            }
+        }
+        if total_bytes != size {
+            return Err(CopyError::VerificationFailed {
+                path: from.to_path_buf(),
+                expected: size,
+                written: total_bytes,
+            });
+        }
 
-    let mut buffer = vec![0u8; BUFFER_SIZE];
+        let dest = Arc::clone(&dest);
+        let finishing = blocking(move || {
+            dest.set_len(total_bytes)?;
+            if sync {
+                dest.sync_all()?;
+            }
+            Ok(())
+        });
+        timings::time(from, Phase::Sync, finishing).await.map_err(CopyError::io("sync", to))?;
+        Ok(total_bytes)
+    };
+    copied.await.map_err(CopyError::partial)
+}
+
+/// Runs blocking file I/O on tokio's blocking threads.
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> std::io::Result<T> + Send + 'static) -> std::io::Result<T> {
+    tokio::task::spawn_blocking(f).await.map_err(std::io::Error::other)?
+}
+
+/// Direct I/O is only supported on Linux; elsewhere this is a regular
+/// buffered copy like [`copy_file_with_buffer`].
+#[cfg(not(target_os = "linux"))]
+#[allow(clippy::too_many_arguments)]
+pub async fn copy_file_direct(
+    from: &Path,
+    to: &Path,
+    progress: Option<&dyn ProgressSink>,
+    buffer_size: usize,
+    sync: bool,
+    throttle: Option<&Throttle>,
+    cancel: Option<&CancellationToken>,
+) -> Result<u64, CopyError> {
+    let mut buffer = vec![0u8; buffer_size];
+    copy_file_with_buffer(from, to, &mut buffer, progress, sync, throttle, cancel).await
+}
+
+/// Waits until `throttle` lets `bytes` more through, or fails as soon as
+/// `cancel` fires, which is also checked when there is no throttle.
+pub(crate) async fn pace(throttle: Option<&Throttle>, bytes: u64, cancel: Option<&CancellationToken>) -> Result<(), CopyError> {
+    let cancelled = async {
+        match cancel {
+            Some(cancel) => cancel.cancelled().await,
+            None => std::future::pending().await,
+        }
+    };
+    let acquired = async {
+        if let Some(throttle) = throttle {
+            throttle.acquire(bytes).await;
+        }
+    };
+    tokio::select! {
+        biased;
+        _ = cancelled => Err(CopyError::Interrupted),
+        _ = acquired => Ok(()),
+    }
+}
+
+/// Streams `source`, read from `from`, into the freshly created `dest` at
+/// `to` through `buffer`, keeping `cache` up to date with what was copied.
+#[allow(clippy::too_many_arguments)]
+async fn write_contents(
+    from: &Path,
+    to: &Path,
+    source: &mut (impl AsyncRead + Unpin),
+    dest: &mut fs::File,
+    cache: &mut Dropper,
+    buffer: &mut [u8],
+    progress: Option<&dyn ProgressSink>,
+    sync: bool,
+    throttle: Option<&Throttle>,
+    cancel: Option<&CancellationToken>,
+) -> Result<u64, CopyError> {
     let mut total_bytes = 0u64;
 
     loop {
-        let bytes_read = source.read(&mut buffer).await?;
+        let bytes_read = timings::time(from, Phase::Read, source.read(buffer)).await.map_err(CopyError::io("read", from))?;
         if bytes_read == 0 {
             break;
         }
+        pace(throttle, bytes_read as u64, cancel).await?;
 
-        dest.write_all(&buffer[..bytes_read]).await?;
+        timings::time(from, Phase::Write, dest.write_all(&buffer[..bytes_read]))
+            .await
+            .map_err(CopyError::io("write", to))?;
         total_bytes += bytes_read as u64;
+        cache.copied(bytes_read as u64);
 
-        if let Some(pb) = file_pb {
-            pb.inc(bytes_read as u64);
+        if let Some(progress) = progress {
+            progress.on_bytes(from, bytes_read as u64);
         }
-        if let Some(pb) = main_pb {
-            pb.inc(bytes_read as u64);
+    }
+
+    if sync {
+        timings::time(from, Phase::Sync, async {
+            dest.flush().await.map_err(CopyError::io("flush", to))?;
+            dest.sync_all().await.map_err(CopyError::io("sync", to))
+        })
+        .await?;
+    }
+    cache.finish();
+
+    Ok(total_bytes)
+}
+
+/// Describes a file written as numbered chunks by [`copy_file_split`]. It is
+/// stored as JSON next to the chunks, at [`split_manifest_path`].
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SplitManifest {
+    /// Name of the original file.
+    pub file: String,
+    /// Size of the original file in bytes.
+    pub size: u64,
+    /// Size of every chunk but the last.
+    pub chunk_size: u64,
+    /// Chunk file names in the order they must be concatenated.
+    pub chunks: Vec<String>,
+}
+
+/// Path of chunk number `index` of a file split at `to`, e.g. `disk.img.000`.
+pub fn split_chunk_path(to: &Path, index: usize, chunks: usize) -> PathBuf {
+    let width = chunks.saturating_sub(1).to_string().len().max(3);
+    let mut name = to.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{:0width$}", index));
+    to.with_file_name(name)
+}
+
+/// Path of the manifest of a file split at `to`, e.g. `disk.img.cp2-split.json`.
+pub fn split_manifest_path(to: &Path) -> PathBuf {
+    let mut name = to.file_name().unwrap_or_default().to_os_string();
+    name.push(".cp2-split.json");
+    to.with_file_name(name)
+}
+
+/// Copies `from` as numbered chunks of at most `chunk_size` bytes next to
+/// `to` (`to.000`, `to.001`, ...), followed by a [`SplitManifest`], for
+/// destinations that can't hold a file this large (e.g. FAT32). The chunks
+/// concatenated in order give back the original file.
+#[allow(clippy::too_many_arguments)]
+pub async fn copy_file_split(
+    from: &Path,
+    to: &Path,
+    chunk_size: u64,
+    progress: Option<&dyn ProgressSink>,
+    buffer_size: usize,
+    sync: bool,
+    throttle: Option<&Throttle>,
+    cancel: Option<&CancellationToken>,
+) -> Result<u64, CopyError> {
+    let mut source = timings::time(from, Phase::Open, fs::File::open(from)).await.map_err(CopyError::io("open", from))?;
+    let size = source.metadata().await.map_err(CopyError::io("stat", from))?.len();
+    let count = size.div_ceil(chunk_size.max(1)).max(1) as usize;
+    let mut buffer = vec![0u8; buffer_size];
+    let mut manifest = SplitManifest {
+        file: from.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+        size,
+        chunk_size,
+        chunks: Vec::with_capacity(count),
+    };
+
+    let mut total_bytes = 0u64;
+    for index in 0..count {
+        let chunk = split_chunk_path(to, index, count);
+        let mut dest = timings::time(from, Phase::Open, fs::File::create(&chunk))
+            .await
+            .map_err(CopyError::io("create", &chunk))
+            .map_err(CopyError::partial)?;
+        let mut cache = Dropper::new(&source, total_bytes, &dest, 0);
+        let mut part = (&mut source).take(chunk_size);
+        total_bytes += write_contents(from, &chunk, &mut part, &mut dest, &mut cache, &mut buffer, progress, sync, throttle, cancel)
+            .await
+            .map_err(CopyError::partial)?;
+        manifest
+            .chunks
+            .push(chunk.file_name().unwrap_or_default().to_string_lossy().into_owned());
+    }
+    if total_bytes != size {
+        return Err(CopyError::VerificationFailed {
+            path: from.to_path_buf(),
+            expected: size,
+            written: total_bytes,
         }
+        .partial());
+    }
+
+    let manifest_path = split_manifest_path(to);
+    let json = serde_json::to_vec_pretty(&manifest)
+        .map_err(std::io::Error::other)
+        .map_err(CopyError::io("write", &manifest_path))
+        .map_err(CopyError::partial)?;
+    fs::write(&manifest_path, json)
+        .await
+        .map_err(CopyError::io("write", &manifest_path))
+        .map_err(CopyError::partial)?;
+    Ok(total_bytes)
+}
+
+/// Copies a large file by splitting it into `workers` contiguous ranges that
+/// are copied concurrently into a preallocated destination. Once all ranges
+/// are done the destination length is verified against the source before the
+/// copy is considered complete.
+#[allow(clippy::too_many_arguments)]
+pub async fn copy_file_chunked(
+    from: &Path,
+    to: &Path,
+    size: u64,
+    workers: usize,
+    progress: Option<&dyn ProgressSink>,
+    buffer_size: usize,
+    sync: bool,
+    throttle: Option<&Throttle>,
+    cancel: Option<&CancellationToken>,
+) -> Result<u64, CopyError> {
+    ensure_distinct(from, to)?;
+    // Preallocate so every worker can write at its own offset.
+    let dest = timings::time(from, Phase::Open, fs::File::create(to)).await.map_err(CopyError::io("create", to))?;
+    fill_ranges(from, to, dest, size, workers, progress, buffer_size, sync, throttle, cancel)
+        .await
+        .map_err(CopyError::partial)
+}
+
+/// Sizes the freshly created `dest` and fills it range by range.
+#[allow(clippy::too_many_arguments)]
+async fn fill_ranges(
+    from: &Path,
+    to: &Path,
+    dest: fs::File,
+    size: u64,
+    workers: usize,
+    progress: Option<&dyn ProgressSink>,
+    buffer_size: usize,
+    sync: bool,
+    throttle: Option<&Throttle>,
+    cancel: Option<&CancellationToken>,
+) -> Result<u64, CopyError> {
+    dest.set_len(size).await.map_err(CopyError::io("allocate", to))?;
+    drop(dest);
+
+    let workers = (workers as u64).clamp(1, size.max(1));
+    let range_len = size.div_ceil(workers);
+
+    // The ranges are driven together from this task; their file I/O runs
+    // on tokio's blocking pool, so it still proceeds in parallel.
+    let ranges = (0..workers).map(|i| {
+        let start = i * range_len;
+        let len = range_len.min(size - start);
+        copy_range(from, to, start, len, progress, buffer_size, throttle, cancel)
+    });
+    let total_bytes: u64 = futures_util::future::try_join_all(ranges).await?.into_iter().sum();
+
+    let written = fs::metadata(to).await.map_err(CopyError::io("stat", to))?.len();
+    if total_bytes != size || written != size {
+        return Err(CopyError::VerificationFailed {
+            path: from.to_path_buf(),
+            expected: size,
+            written: total_bytes,
+        });
     }
 
     if sync {
-        dest.flush().await?;
-        dest.sync_all().await?;
+        timings::time(from, Phase::Sync, async {
+            let dest = fs::OpenOptions::new().write(true).open(to).await.map_err(CopyError::io("open", to))?;
+            dest.sync_all().await.map_err(CopyError::io("sync", to))
+        })
+        .await?;
     }
 
     Ok(total_bytes)
 }
+
+/// Copies `len` bytes starting at `offset` from `from` into the same offset of
+/// the (already sized) file at `to`.
+#[allow(clippy::too_many_arguments)]
+async fn copy_range(
+    from: &Path,
+    to: &Path,
+    offset: u64,
+    len: u64,
+    progress: Option<&dyn ProgressSink>,
+    buffer_size: usize,
+    throttle: Option<&Throttle>,
+    cancel: Option<&CancellationToken>,
+) -> Result<u64, CopyError> {
+    let (mut source, mut dest) = timings::time(from, Phase::Open, async {
+        let source = fs::File::open(from).await.map_err(CopyError::io("open", from))?;
+        let dest = fs::OpenOptions::new().write(true).open(to).await.map_err(CopyError::io("open", to))?;
+        Ok::<_, CopyError>((source, dest))
+    })
+    .await?;
+    source.seek(SeekFrom::Start(offset)).await.map_err(CopyError::io("seek", from))?;
+    dest.seek(SeekFrom::Start(offset)).await.map_err(CopyError::io("seek", to))?;
+    let mut cache = Dropper::new(&source, offset, &dest, offset);
+
+    let mut buffer = vec![0u8; buffer_size.min(len as usize)];
+    let mut remaining = len;
+
+    while remaining > 0 {
+        let want = buffer.len().min(remaining as usize);
+        let bytes_read = timings::time(from, Phase::Read, source.read(&mut buffer[..want]))
+            .await
+            .map_err(CopyError::io("read", from))?;
+        if bytes_read == 0 {
+            return Err(CopyError::Io {
+                operation: "read",
+                path: from.to_path_buf(),
+                source: std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "source file shrank during copy"),
+            });
+        }
+        pace(throttle, bytes_read as u64, cancel).await?;
+
+        timings::time(from, Phase::Write, dest.write_all(&buffer[..bytes_read]))
+            .await
+            .map_err(CopyError::io("write", to))?;
+        remaining -= bytes_read as u64;
+        cache.copied(bytes_read as u64);
+
+        if let Some(progress) = progress {
+            progress.on_bytes(from, bytes_read as u64);
+        }
+    }
+
+    dest.flush().await.map_err(CopyError::io("flush", to))?;
+    cache.finish();
+    Ok(len)
+}
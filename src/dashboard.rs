@@ -0,0 +1,234 @@
+use colored::Colorize;
+use indicatif::{HumanCount, ProgressBar};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+use crate::progress::{format_bytes, format_elapsed, format_eta, BarSlots, RunStats};
+
+/// How often the dashboard is redrawn.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Redraws between two throughput samples, which are one second apart.
+const REDRAWS_PER_SAMPLE: u32 = 4;
+
+/// Rows of the throughput graph.
+const GRAPH_HEIGHT: usize = 5;
+
+/// Most errors and warnings kept for the "Recent" panel.
+const RECENT_MESSAGES: usize = 5;
+
+/// Width of the bars of active transfers.
+const FILE_BAR_WIDTH: usize = 20;
+
+/// Whether a dashboard has the terminal on its alternate screen.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Latest errors and warnings, newest last; `None` while no dashboard runs.
+static RECENT: Mutex<Option<VecDeque<String>>> = Mutex::new(None);
+
+/// Shows `message` in the dashboard's panel of recent errors and warnings.
+/// Returns `false` when no dashboard is on screen, so the caller prints it
+/// as usual.
+pub(crate) fn show_message(message: String) -> bool {
+    let mut recent = RECENT.lock().unwrap();
+    let Some(recent) = recent.as_mut() else {
+        return false;
+    };
+    if recent.len() == RECENT_MESSAGES {
+        recent.pop_front();
+    }
+    recent.push_back(message);
+    true
+}
+
+/// Leaves the alternate screen if a dashboard is on it; used before exiting
+/// the process without going through [`Dashboard::stop`].
+pub(crate) fn restore_terminal() {
+    if ACTIVE.swap(false, Ordering::Relaxed) {
+        eprint!("\x1b[?25h\x1b[?1049l");
+    }
+}
+
+/// What the dashboard reads the state of the run from.
+pub(crate) struct Sources {
+    /// The overall bar, whose message holds the pause/limit state.
+    pub(crate) main: ProgressBar,
+    pub(crate) stats: Arc<RunStats>,
+    pub(crate) total_files: u64,
+    /// Smoothed bytes per second, stored as `f64` bits.
+    pub(crate) rate: Arc<AtomicU64>,
+    /// Bars of the files being copied.
+    pub(crate) file_bars: Arc<BarSlots>,
+}
+
+/// Full-screen view of a run for `--tui`: overall figures, a graph of the
+/// throughput over time, the files being copied and the latest errors,
+/// redrawn in place on the terminal's alternate screen.
+pub(crate) struct Dashboard {
+    handle: JoinHandle<()>,
+}
+
+impl Dashboard {
+    /// Switches stderr to the alternate screen and starts redrawing.
+    pub(crate) fn start(sources: Sources) -> Self {
+        *RECENT.lock().unwrap() = Some(VecDeque::new());
+        ACTIVE.store(true, Ordering::Relaxed);
+        eprint!("\x1b[?1049h\x1b[?25l");
+        let handle = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(REDRAW_INTERVAL);
+            let mut history = VecDeque::new();
+            let mut last = 0;
+            let mut redraws = 0;
+            loop {
+                interval.tick().await;
+                if redraws % REDRAWS_PER_SAMPLE == 0 {
+                    let position = sources.main.position();
+                    history.push_back(position.saturating_sub(last));
+                    last = position;
+                }
+                redraws += 1;
+                let (rows, cols) = console::Term::stderr().size();
+                while history.len() > usize::from(cols) {
+                    history.pop_front();
+                }
+                let frame = render(&sources, &history, usize::from(rows), usize::from(cols));
+                let mut stderr = std::io::stderr().lock();
+                let _ = stderr.write_all(frame.as_bytes());
+                let _ = stderr.flush();
+            }
+        });
+        Dashboard { handle }
+    }
+
+    /// Stops redrawing and gives the terminal its normal screen back.
+    pub(crate) async fn stop(self) {
+        self.handle.abort();
+        let _ = self.handle.await;
+        *RECENT.lock().unwrap() = None;
+        restore_terminal();
+    }
+}
+
+/// Draws the whole screen, `rows` by `cols` characters.
+fn render(sources: &Sources, history: &VecDeque<u64>, rows: usize, cols: usize) -> String {
+    let main = &sources.main;
+    let stats = &sources.stats;
+    let total = main.length().unwrap_or(0);
+    let position = main.position();
+    let elapsed = main.elapsed();
+    let rate = f64::from_bits(sources.rate.load(Ordering::Relaxed));
+    let average = (position as f64 / elapsed.as_secs_f64().max(f64::EPSILON)) as u64;
+    let peak = history.iter().copied().max().unwrap_or(0);
+    let done = stats.files_done.load(Ordering::Relaxed);
+    let skipped = stats.files_skipped.load(Ordering::Relaxed);
+    let failed = stats.files_failed.load(Ordering::Relaxed);
+
+    let mut lines = Vec::with_capacity(rows);
+    let status = main.message();
+    lines.push(format!(" {} {}", "cp2".bold(), status.yellow()));
+    lines.push(format!(" {} {:>3}%", gauge(position, total, cols.saturating_sub(8)), percent(position, total)));
+    lines.push(format!(
+        " {:<8} {} of {}   ETA {}   elapsed {}",
+        "Copied".bold(),
+        format_bytes(position),
+        format_bytes(total),
+        format_eta(total.saturating_sub(position), rate),
+        format_elapsed(elapsed)
+    ));
+    let failed_text = format!("{} failed", HumanCount(failed));
+    lines.push(format!(
+        " {:<8} {} done, {} skipped, {} of {}",
+        "Files".bold(),
+        HumanCount(done),
+        HumanCount(skipped),
+        if failed > 0 { failed_text.red().to_string() } else { failed_text },
+        HumanCount(sources.total_files)
+    ));
+    lines.push(format!(
+        " {:<8} {}/s now, {}/s average, {}/s peak",
+        "Speed".bold(),
+        format_bytes(rate as u64),
+        format_bytes(average),
+        format_bytes(peak)
+    ));
+    lines.push(String::new());
+    lines.push(format!(" {} (one column per second)", "Throughput".bold()));
+    lines.extend(graph(history, cols.saturating_sub(2)));
+    lines.push(String::new());
+
+    let recent: Vec<String> = RECENT.lock().unwrap().iter().flatten().cloned().collect();
+    // The recent messages take their lines from the bottom of the screen,
+    // and the active transfers get what is left between the two.
+    let recent_rows = if recent.is_empty() { 0 } else { recent.len() + 2 };
+    let active = sources.file_bars.active();
+    let room = rows.saturating_sub(lines.len() + recent_rows + 1);
+    lines.push(format!(" {} ({})", "Active transfers".bold(), HumanCount(active.len() as u64)));
+    let shown = if active.len() > room { room.saturating_sub(1) } else { active.len() };
+    let name_width = cols.saturating_sub(FILE_BAR_WIDTH + 34).max(10);
+    for pb in active.iter().take(shown) {
+        let len = pb.length().unwrap_or(0);
+        let name = console::truncate_str(&pb.message(), name_width, "…").into_owned();
+        lines.push(format!(
+            "   {:<name_width$} {} {:>3}% {}/{}",
+            name,
+            gauge(pb.position(), len, FILE_BAR_WIDTH),
+            percent(pb.position(), len),
+            format_bytes(pb.position()),
+            format_bytes(len)
+        ));
+    }
+    if shown < active.len() {
+        lines.push(format!("   … and {} more", HumanCount((active.len() - shown) as u64)));
+    }
+    if !recent.is_empty() {
+        lines.push(String::new());
+        lines.push(format!(" {}", "Recent errors and warnings".bold()));
+        lines.extend(recent.iter().map(|message| format!("   {}", message.red())));
+    }
+
+    let mut frame = String::from("\x1b[H");
+    for line in lines.iter().take(rows) {
+        frame.push_str(&console::truncate_str(line, cols, "…"));
+        frame.push_str("\x1b[K\n");
+    }
+    frame.push_str("\x1b[J");
+    frame
+}
+
+/// Completion in whole percent; nothing to copy counts as complete.
+fn percent(position: u64, total: u64) -> u64 {
+    (position * 100).checked_div(total).unwrap_or(100)
+}
+
+/// A bar `width` characters wide filled up to `position` out of `total`.
+fn gauge(position: u64, total: u64, width: usize) -> String {
+    let filled = match total {
+        0 => width,
+        total => (position.min(total) as u128 * width as u128 / total as u128) as usize,
+    };
+    format!("{}{}", "█".repeat(filled), "░".repeat(width - filled))
+}
+
+/// The bytes copied each second as [`GRAPH_HEIGHT`] rows of columns made of
+/// eighth blocks, scaled to the fastest second and ending with the latest.
+fn graph(history: &VecDeque<u64>, width: usize) -> Vec<String> {
+    const LEVELS: [char; 9] = [' ', '▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let max = history.iter().copied().max().unwrap_or(0).max(1);
+    let samples: Vec<u64> = history.iter().copied().skip(history.len().saturating_sub(width)).collect();
+    let eighths: Vec<usize> = samples
+        .iter()
+        .map(|&bytes| (bytes as u128 * (GRAPH_HEIGHT * 8) as u128 / max as u128) as usize)
+        .collect();
+    (0..GRAPH_HEIGHT)
+        .rev()
+        .map(|row| {
+            let mut line = " ".repeat(1 + width - samples.len());
+            line.extend(eighths.iter().map(|&height| LEVELS[height.saturating_sub(row * 8).min(8)]));
+            line.green().to_string()
+        })
+        .collect()
+}
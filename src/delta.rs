@@ -0,0 +1,270 @@
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::Path;
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::sync::CancellationToken;
+
+use crate::copy::{pace, same_file, CopyError};
+use crate::sink::ProgressSink;
+use crate::throttle::Throttle;
+
+/// Smallest and largest block compared by [`copy_file_delta`].
+const MIN_BLOCK_SIZE: usize = 4 * 1024;
+const MAX_BLOCK_SIZE: usize = 1024 * 1024;
+
+/// Unmatched source bytes held back before they are written out.
+const MAX_LITERAL: usize = 8 * 1024 * 1024;
+
+/// What [`copy_file_delta`] did.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DeltaStats {
+    /// Size of the copied file.
+    pub bytes: u64,
+    /// Bytes written to the destination; the rest was already in place.
+    pub written: u64,
+}
+
+/// Block size for a destination of `size` bytes: about its square root, as
+/// in rsync, rounded to a power of two so blocks line up with the
+/// filesystem's.
+pub fn block_size(size: u64) -> usize {
+    ((size as f64).sqrt() as usize)
+        .next_power_of_two()
+        .clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+}
+
+/// rsync's rolling checksum: cheap to move along one byte at a time, so
+/// every offset of the source can be looked up among the destination's
+/// blocks.
+#[derive(Clone, Copy)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(block: &[u8]) -> Self {
+        let len = block.len() as u32;
+        let (mut a, mut b) = (0u32, 0u32);
+        for (i, &byte) in block.iter().enumerate() {
+            a = a.wrapping_add(byte as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(byte as u32));
+        }
+        Self { a, b, len }
+    }
+
+    fn roll(&mut self, out: u8, into: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(into as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn strong(block: &[u8]) -> [u8; 32] {
+    Sha256::digest(block).into()
+}
+
+/// The full blocks of the destination, keyed by their rolling checksum, as
+/// `(offset, strong hash)` pairs.
+async fn signature(
+    to: &Path,
+    dest: &mut fs::File,
+    block_size: usize,
+) -> Result<HashMap<u32, Vec<(u64, [u8; 32])>>, CopyError> {
+    let mut blocks: HashMap<u32, Vec<(u64, [u8; 32])>> = HashMap::new();
+    let mut block = vec![0u8; block_size];
+    let mut offset = 0u64;
+    loop {
+        let n = read_full(dest, &mut block).await.map_err(CopyError::io("read", to))?;
+        if n < block_size {
+            break;
+        }
+        blocks
+            .entry(Rolling::new(&block).digest())
+            .or_default()
+            .push((offset, strong(&block)));
+        offset += block_size as u64;
+    }
+    Ok(blocks)
+}
+
+/// Copies `from` over the existing file `to` the way rsync does, writing
+/// only what changed: every offset of the source is looked up among the
+/// destination's blocks with a rolling checksum, confirmed by SHA-256.
+/// Blocks found at the same offset are left alone, blocks found further on
+/// are moved into place, and everything else is written from the source.
+///
+/// The destination is updated in place, so it is a mix of both versions
+/// (and the error [partial](CopyError::is_partial)) when the copy fails.
+/// When `to` doesn't exist or is empty, this is a plain copy.
+pub async fn copy_file_delta(
+    from: &Path,
+    to: &Path,
+    progress: Option<&dyn ProgressSink>,
+    sync: bool,
+    throttle: Option<&Throttle>,
+    cancel: Option<&CancellationToken>,
+) -> Result<DeltaStats, CopyError> {
+    if same_file(from, to) {
+        return Err(CopyError::SameFile {
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+        });
+    }
+    let mut source = fs::File::open(from).await.map_err(CopyError::io("open", from))?;
+    let old_size = fs::metadata(to).await.map(|m| m.len()).unwrap_or(0);
+    let mut dest = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(to)
+        .await
+        .map_err(CopyError::io("open", to))?;
+    let block_size = block_size(old_size);
+    let blocks = signature(to, &mut dest, block_size).await?;
+    apply(from, to, &mut source, &mut dest, &blocks, block_size, progress, sync, throttle, cancel)
+        .await
+        .map_err(CopyError::partial)
+}
+
+/// Scans `source` and rewrites `dest` in place, front to back. Only blocks
+/// at or after the current offset may be reused, as everything before it
+/// has been overwritten already.
+#[allow(clippy::too_many_arguments)]
+async fn apply(
+    from: &Path,
+    to: &Path,
+    source: &mut fs::File,
+    dest: &mut fs::File,
+    blocks: &HashMap<u32, Vec<(u64, [u8; 32])>>,
+    block_size: usize,
+    progress: Option<&dyn ProgressSink>,
+    sync: bool,
+    throttle: Option<&Throttle>,
+    cancel: Option<&CancellationToken>,
+) -> Result<DeltaStats, CopyError> {
+    let mut stats = DeltaStats::default();
+    // Source bytes from offset `base` on; `data[..literal]` is written out
+    // and `data[literal..pos]` still has to be. The block being looked up
+    // starts at `pos`.
+    let mut data: Vec<u8> = Vec::new();
+    let mut base = 0u64;
+    let mut literal = 0;
+    let mut pos = 0;
+    let mut eof = false;
+    let mut rolling: Option<Rolling> = None;
+    let mut moved = vec![0u8; block_size];
+    let mut chunk = vec![0u8; block_size.max(256 * 1024)];
+
+    loop {
+        while !eof && data.len() < pos + block_size {
+            data.drain(..literal);
+            base += literal as u64;
+            pos -= literal;
+            literal = 0;
+            let n = source.read(&mut chunk).await.map_err(CopyError::io("read", from))?;
+            if n == 0 {
+                eof = true;
+            }
+            data.extend_from_slice(&chunk[..n]);
+        }
+        if data.len() < pos + block_size {
+            break;
+        }
+
+        let window = &data[pos..pos + block_size];
+        let digest = rolling.get_or_insert_with(|| Rolling::new(window)).digest();
+        let offset = base + pos as u64;
+        // A block already in place beats one that has to be moved.
+        let found = blocks.get(&digest).and_then(|candidates| {
+            let hash = strong(window);
+            let mut usable = candidates
+                .iter()
+                .filter(|&&(at, h)| at >= offset && h == hash)
+                .map(|&(at, _)| at);
+            usable.clone().find(|&at| at == offset).or_else(|| usable.next())
+        });
+        match found {
+            Some(at) => {
+                stats.written += write_at(to, dest, base + literal as u64, &data[literal..pos]).await?;
+                if at != offset {
+                    dest.seek(SeekFrom::Start(at)).await.map_err(CopyError::io("seek", to))?;
+                    read_full(dest, &mut moved).await.map_err(CopyError::io("read", to))?;
+                    stats.written += write_at(to, dest, offset, &moved).await?;
+                }
+                pos += block_size;
+                literal = pos;
+                rolling = None;
+            }
+            None => {
+                if let Some(next) = data.get(pos + block_size) {
+                    rolling.as_mut().expect("set above").roll(data[pos], *next);
+                } else {
+                    rolling = None;
+                }
+                pos += 1;
+                if pos - literal >= MAX_LITERAL {
+                    stats.written += write_at(to, dest, base + literal as u64, &data[literal..pos]).await?;
+                    literal = pos;
+                }
+            }
+        }
+        let done = base + literal as u64 - stats.bytes;
+        if done > 0 {
+            pace(throttle, done, cancel).await?;
+            stats.bytes += done;
+            if let Some(progress) = progress {
+                progress.on_bytes(from, done);
+            }
+        }
+    }
+
+    // The tail, shorter than a block, is always written.
+    let rest = data.len() - literal;
+    stats.written += write_at(to, dest, base + literal as u64, &data[literal..]).await?;
+    stats.bytes += rest as u64;
+    if let Some(progress) = progress
+        && rest > 0
+    {
+        progress.on_bytes(from, rest as u64);
+    }
+    dest.set_len(stats.bytes).await.map_err(CopyError::io("truncate", to))?;
+    dest.flush().await.map_err(CopyError::io("flush", to))?;
+    if sync {
+        dest.sync_all().await.map_err(CopyError::io("sync", to))?;
+    }
+    Ok(stats)
+}
+
+/// Writes `bytes` at `offset` in `dest` and returns how many that was.
+async fn write_at(to: &Path, dest: &mut fs::File, offset: u64, bytes: &[u8]) -> Result<u64, CopyError> {
+    if bytes.is_empty() {
+        return Ok(0);
+    }
+    dest.seek(SeekFrom::Start(offset)).await.map_err(CopyError::io("seek", to))?;
+    dest.write_all(bytes).await.map_err(CopyError::io("write", to))?;
+    Ok(bytes.len() as u64)
+}
+
+/// Reads until `buffer` is full or the file ends; returns the bytes read.
+async fn read_full(file: &mut fs::File, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        let n = file.read(&mut buffer[filled..]).await?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    Ok(filled)
+}
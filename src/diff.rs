@@ -0,0 +1,253 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::hash::{self, HashAlgorithm};
+
+/// Modification times closer than this count as equal, as some filesystems
+/// only keep whole (or even two) seconds.
+const MTIME_TOLERANCE: Duration = Duration::from_secs(2);
+
+/// How deeply files present in both trees are compared.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DiffOptions {
+    /// Compare contents by this digest instead of modification times.
+    pub hash: Option<HashAlgorithm>,
+    /// Only compare sizes.
+    pub size_only: bool,
+}
+
+/// What happened to a path on the way from the first tree to the second.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    /// Only in the second tree.
+    Added,
+    /// Only in the first tree.
+    Removed,
+    /// In both, but different.
+    Modified,
+}
+
+/// Why a path present in both trees counts as modified.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    /// A file in one tree is a directory in the other.
+    Type,
+    Size,
+    ModifiedTime,
+    Contents,
+    /// One of the files couldn't be read to compare contents.
+    Unreadable,
+}
+
+impl Reason {
+    pub fn describe(self) -> &'static str {
+        match self {
+            Reason::Type => "file in one, directory in the other",
+            Reason::Size => "size differs",
+            Reason::ModifiedTime => "modification time differs",
+            Reason::Contents => "contents differ",
+            Reason::Unreadable => "cannot read",
+        }
+    }
+}
+
+/// One entry of the change list; serialized as a JSON object such as
+/// `{"change":"modified","path":"a.txt","reasons":["size"],"detail":"3 vs 4 bytes"}`.
+/// Directory paths end with `/`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Change {
+    pub change: ChangeKind,
+    pub path: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub reasons: Vec<Reason>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Change {
+    fn new(change: ChangeKind, path: String) -> Self {
+        Self {
+            change,
+            path,
+            reasons: Vec::new(),
+            detail: None,
+        }
+    }
+
+    /// The reasons and detail as shown to people, e.g. `size differs: 3 vs
+    /// 4 bytes`.
+    pub fn describe(&self) -> String {
+        let reasons: Vec<&str> = self.reasons.iter().map(|r| r.describe()).collect();
+        match &self.detail {
+            Some(detail) => format!("{}: {}", reasons.join(", "), detail),
+            None => reasons.join(", "),
+        }
+    }
+}
+
+/// The changes between two trees, sorted by path, and what they share.
+#[derive(Debug, Default)]
+pub struct Diff {
+    pub changes: Vec<Change>,
+    /// Files found equal in both trees, and their total size.
+    pub same_files: u64,
+    pub same_bytes: u64,
+}
+
+/// The files and directories below a root, by `/`-separated path relative
+/// to it.
+#[derive(Default)]
+struct Tree {
+    files: BTreeMap<String, (PathBuf, Metadata)>,
+    dirs: BTreeSet<String>,
+}
+
+impl Tree {
+    fn walk(root: &Path) -> Self {
+        let mut tree = Tree::default();
+        tree.add_dir(root, "");
+        tree
+    }
+
+    /// `path` alone, under `name`, or nothing if it doesn't exist.
+    fn single(path: &Path, name: &str) -> Self {
+        let mut tree = Tree::default();
+        if let Ok(metadata) = std::fs::metadata(path) {
+            tree.files.insert(name.to_string(), (path.to_path_buf(), metadata));
+        }
+        tree
+    }
+
+    fn add_dir(&mut self, dir: &Path, relative: &str) {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::warn!("Cannot read {}: {}", dir.display(), e);
+                return;
+            }
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let relative = match relative {
+                "" => name,
+                _ => format!("{relative}/{name}"),
+            };
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if metadata.is_dir() {
+                self.add_dir(&entry.path(), &relative);
+                self.dirs.insert(relative);
+            } else if metadata.is_file() {
+                self.files.insert(relative, (entry.path(), metadata));
+            }
+        }
+    }
+}
+
+/// How `second` differs from `first`, two copies of the same file.
+async fn compare_files(
+    first: &(PathBuf, Metadata),
+    second: &(PathBuf, Metadata),
+    options: &DiffOptions,
+    on_bytes: &(dyn Fn(u64) + Sync),
+) -> Option<(Vec<Reason>, Option<String>)> {
+    let (size, second_size) = (first.1.len(), second.1.len());
+    if size != second_size {
+        return Some((vec![Reason::Size], Some(format!("{} vs {} bytes", size, second_size))));
+    }
+    if let Some(algorithm) = options.hash {
+        let (digest, second_digest) = tokio::join!(
+            hash::hash_file(&first.0, algorithm, on_bytes),
+            hash::hash_file(&second.0, algorithm, |_| {})
+        );
+        return match (digest, second_digest) {
+            (Ok(digest), Ok(second_digest)) if digest == second_digest => None,
+            (Ok(_), Ok(_)) => Some((vec![Reason::Contents], None)),
+            (Err(e), _) | (_, Err(e)) => Some((vec![Reason::Unreadable], Some(e.to_string()))),
+        };
+    }
+    if !options.size_only
+        && let (Ok(modified), Ok(second_modified)) = (first.1.modified(), second.1.modified())
+    {
+        let apart = modified
+            .duration_since(second_modified)
+            .unwrap_or_else(|e| e.duration());
+        if apart >= MTIME_TOLERANCE {
+            return Some((vec![Reason::ModifiedTime], None));
+        }
+    }
+    None
+}
+
+/// Compares the tree at `first` with the one at `second`. When `first` is a
+/// file, it is compared with `second`, or with the file of the same name in
+/// it when `second` is a directory.
+///
+/// `progress` is told which file is being compared and, with
+/// [`DiffOptions::hash`], how many of its bytes were read.
+pub async fn diff_trees(
+    first: &Path,
+    second: &Path,
+    options: &DiffOptions,
+    progress: &(dyn Fn(&str, u64) + Sync),
+) -> Diff {
+    let (old, new) = if first.is_dir() {
+        (Tree::walk(first), Tree::walk(second))
+    } else {
+        let name = first.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let target = match second.is_dir() {
+            true => second.join(&name),
+            false => second.to_path_buf(),
+        };
+        (Tree::single(first, &name), Tree::single(&target, &name))
+    };
+
+    let mut diff = Diff::default();
+    for (name, file) in &old.files {
+        progress(name, 0);
+        match new.files.get(name) {
+            None if new.dirs.contains(name) => {
+                let mut change = Change::new(ChangeKind::Modified, name.clone());
+                change.reasons.push(Reason::Type);
+                diff.changes.push(change);
+            }
+            None => diff.changes.push(Change::new(ChangeKind::Removed, name.clone())),
+            Some(other) => match compare_files(file, other, options, &|n| progress(name, n)).await {
+                Some((reasons, detail)) => diff.changes.push(Change {
+                    reasons,
+                    detail,
+                    ..Change::new(ChangeKind::Modified, name.clone())
+                }),
+                None => {
+                    diff.same_files += 1;
+                    diff.same_bytes += file.1.len();
+                }
+            },
+        }
+    }
+    for dir in old.dirs.difference(&new.dirs) {
+        if !new.files.contains_key(dir) {
+            diff.changes.push(Change::new(ChangeKind::Removed, format!("{dir}/")));
+        }
+    }
+    for name in new.files.keys().filter(|name| !old.files.contains_key(*name)) {
+        if !old.dirs.contains(name) {
+            diff.changes.push(Change::new(ChangeKind::Added, name.clone()));
+        }
+    }
+    for dir in new.dirs.difference(&old.dirs) {
+        match old.files.contains_key(dir) {
+            // Already reported as a modified file.
+            true => {}
+            false => diff.changes.push(Change::new(ChangeKind::Added, format!("{dir}/"))),
+        }
+    }
+    diff.changes.sort_by(|a, b| a.path.cmp(&b.path));
+    diff
+}
@@ -0,0 +1,103 @@
+use std::path::{Path, PathBuf};
+
+use crate::filter::Filter;
+
+/// gpg options shared by encryption and decryption with a passphrase: no
+/// prompts, no agent caching the passphrase, and the passphrase read from
+/// the first line of a file rather than the command line.
+const GPG_PASSPHRASE: &[&str] = &["--batch", "--quiet", "--no-symkey-cache", "--pinentry-mode", "loopback"];
+
+/// How destination files are encrypted with `--encrypt`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Encryption {
+    /// age, to a recipient (an `age1…` key or an SSH public key) or to every
+    /// recipient listed in a file.
+    Age(String),
+    /// gpg's symmetric AES-256 with the passphrase in a file.
+    Passphrase(PathBuf),
+}
+
+impl Encryption {
+    /// Suffix appended to the names of encrypted files.
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            Encryption::Age(_) => ".age",
+            Encryption::Passphrase(_) => ".gpg",
+        }
+    }
+
+    /// The program that encrypts stdin to stdout.
+    pub fn filter(&self) -> Filter {
+        match self {
+            Encryption::Age(recipients) if Path::new(recipients).is_file() => {
+                Filter::new("age", &["-R"], "encrypt").arg(recipients)
+            }
+            Encryption::Age(recipient) => Filter::new("age", &["-r"], "encrypt").arg(recipient),
+            Encryption::Passphrase(file) => Filter::new("gpg", GPG_PASSPHRASE, "encrypt")
+                .arg("--passphrase-file")
+                .arg(file)
+                .arg("--symmetric")
+                .arg("--cipher-algo")
+                .arg("AES256")
+                .arg("--output")
+                .arg("-"),
+        }
+    }
+}
+
+/// How source files are decrypted with `--decrypt`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Decryption {
+    /// age, with the identities (private keys) in a file.
+    Age(PathBuf),
+    /// gpg, with the passphrase in a file.
+    Passphrase(PathBuf),
+}
+
+impl Decryption {
+    /// Suffix removed from the names of decrypted files, when they have it.
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            Decryption::Age(_) => ".age",
+            Decryption::Passphrase(_) => ".gpg",
+        }
+    }
+
+    /// The program that decrypts stdin to stdout.
+    pub fn filter(&self) -> Filter {
+        match self {
+            Decryption::Age(identities) => Filter::new("age", &["-d", "-i"], "decrypt").arg(identities),
+            Decryption::Passphrase(file) => Filter::new("gpg", GPG_PASSPHRASE, "decrypt")
+                .arg("--passphrase-file")
+                .arg(file)
+                .arg("--decrypt"),
+        }
+    }
+}
+
+/// The file a `--encrypt` or `--decrypt` value names, which must exist.
+fn existing_file(file: &str, what: &str) -> Result<PathBuf, String> {
+    match Path::new(file).is_file() {
+        true => Ok(PathBuf::from(file)),
+        false => Err(format!("{what} file `{file}` does not exist")),
+    }
+}
+
+/// Parses a `--encrypt` value: `age:RECIPIENT`, where the recipient may also
+/// be a file of recipients, or `passphrase:FILE`.
+pub fn parse_encryption(s: &str) -> Result<Encryption, String> {
+    match s.split_once(':') {
+        Some(("age", recipient)) if !recipient.is_empty() => Ok(Encryption::Age(recipient.to_string())),
+        Some(("passphrase", file)) => existing_file(file, "passphrase").map(Encryption::Passphrase),
+        _ => Err(format!("`{s}` is not a supported encryption (use age:RECIPIENT or passphrase:FILE)")),
+    }
+}
+
+/// Parses a `--decrypt` value: `age:IDENTITY_FILE` or `passphrase:FILE`.
+pub fn parse_decryption(s: &str) -> Result<Decryption, String> {
+    match s.split_once(':') {
+        Some(("age", file)) => existing_file(file, "identity").map(Decryption::Age),
+        Some(("passphrase", file)) => existing_file(file, "passphrase").map(Decryption::Passphrase),
+        _ => Err(format!("`{s}` is not a supported decryption (use age:IDENTITY_FILE or passphrase:FILE)")),
+    }
+}
@@ -0,0 +1,608 @@
+use crate::attrs::Attrs;
+use crate::copy::{copy_file_with_buffer, same_file, CopyError, DEFAULT_BUFFER_SIZE};
+use crate::events::CopyEvent;
+use crate::report::{FileRecord, Recorder, ReportTotals};
+use crate::sink::ProgressSink;
+use crate::throttle::Throttle;
+use crate::utils::{collect_copy_entries, CopyEntry, Preserve};
+use futures_util::Stream;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Semaphore};
+use tokio_util::sync::CancellationToken;
+
+/// What to do when a destination file already exists.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Overwrite {
+    /// Replace it.
+    #[default]
+    Always,
+    /// Leave it alone and skip the file.
+    Never,
+    /// Replace it only when the source was modified more recently.
+    IfNewer,
+}
+
+impl Overwrite {
+    /// Why `entry` must be skipped under this policy, if it must.
+    fn skip_reason(self, entry: &CopyEntry) -> Option<&'static str> {
+        let existing = std::fs::metadata(&entry.to).ok()?;
+        match self {
+            Overwrite::Always => None,
+            Overwrite::Never => Some("destination exists"),
+            Overwrite::IfNewer => {
+                let source = std::fs::metadata(&entry.from).and_then(|m| m.modified()).ok()?;
+                let dest = existing.modified().ok()?;
+                (source <= dest).then_some("destination is not older than the source")
+            }
+        }
+    }
+}
+
+/// Settings of a copy started with [`copy`], built up from
+/// [`CopyOptions::new`]. The `cp2` command builds one from its flags too,
+/// for the settings its own local copies share with [`copy`]: all but
+/// [`overwrite`](CopyOptions::overwrite), [`progress`](CopyOptions::progress),
+/// [`cancel`](CopyOptions::cancel) and [`throttle`](CopyOptions::throttle).
+///
+/// ```no_run
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// let options = cp2::CopyOptions::new()
+///     .recursive(true)
+///     .overwrite(cp2::Overwrite::IfNewer)
+///     .parallel(8);
+/// let report = cp2::copy(&["photos"], "/mnt/backup", &options).await?;
+/// println!("{} files copied", report.totals.files_copied);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct CopyOptions {
+    pub(crate) recursive: bool,
+    pub(crate) overwrite: Overwrite,
+    /// Copy what symbolic links point to instead of skipping them.
+    pub(crate) dereference: bool,
+    /// Leave out hidden files and directories when walking directories.
+    pub(crate) skip_hidden: bool,
+    pub(crate) preserve: Preserve,
+    /// Read/write buffer size of each file copy.
+    pub(crate) buffer_size: usize,
+    /// How many files are copied at once.
+    pub(crate) parallel: usize,
+    /// Flush every file to disk before counting it as copied.
+    pub(crate) sync: bool,
+    /// Where the progress of [`copy`] is reported.
+    pub(crate) progress: Option<Arc<dyn ProgressSink>>,
+    /// Stops [`copy`] when cancelled.
+    pub(crate) cancel: Option<CancellationToken>,
+    /// Limits the combined rate of the file copies.
+    pub(crate) throttle: Option<Throttle>,
+}
+
+impl std::fmt::Debug for CopyOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CopyOptions")
+            .field("recursive", &self.recursive)
+            .field("overwrite", &self.overwrite)
+            .field("dereference", &self.dereference)
+            .field("skip_hidden", &self.skip_hidden)
+            .field("preserve", &self.preserve)
+            .field("buffer_size", &self.buffer_size)
+            .field("parallel", &self.parallel)
+            .field("sync", &self.sync)
+            .field("progress", &self.progress.is_some())
+            .field("cancel", &self.cancel)
+            .field("throttle", &self.throttle.as_ref().map(Throttle::limit))
+            .finish()
+    }
+}
+
+impl Default for CopyOptions {
+    fn default() -> Self {
+        CopyOptions {
+            recursive: false,
+            overwrite: Overwrite::default(),
+            dereference: false,
+            skip_hidden: false,
+            preserve: Preserve::default(),
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            parallel: 4,
+            sync: false,
+            progress: None,
+            cancel: None,
+            throttle: None,
+        }
+    }
+}
+
+impl CopyOptions {
+    /// Options matching the defaults of the `cp2` command.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Copy directories and their contents.
+    pub fn recursive(mut self, recursive: bool) -> Self {
+        self.recursive = recursive;
+        self
+    }
+
+    /// What to do with destination files that already exist.
+    pub fn overwrite(mut self, overwrite: Overwrite) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Copy what symbolic links point to instead of skipping them.
+    pub fn dereference(mut self, dereference: bool) -> Self {
+        self.dereference = dereference;
+        self
+    }
+
+    /// Leave out hidden files and directories found inside recursively
+    /// copied directories: dotfiles, and on Windows files with the hidden or
+    /// system attribute. Sources named directly are always copied.
+    pub fn skip_hidden(mut self, skip_hidden: bool) -> Self {
+        self.skip_hidden = skip_hidden;
+        self
+    }
+
+    /// Attributes carried over from the sources.
+    pub fn preserve(mut self, preserve: Preserve) -> Self {
+        self.preserve = preserve;
+        self
+    }
+
+    /// Read/write buffer size in bytes; at least one.
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = buffer_size.max(1);
+        self
+    }
+
+    /// How many files are copied at once; at least one.
+    pub fn parallel(mut self, parallel: usize) -> Self {
+        self.parallel = parallel.max(1);
+        self
+    }
+
+    /// Flush every file to disk before counting it as copied.
+    pub fn sync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    /// Reports the files and bytes copied to `sink` as the copy goes.
+    pub fn progress(mut self, sink: Arc<dyn ProgressSink>) -> Self {
+        self.progress = Some(sink);
+        self
+    }
+
+    /// Stops the copy once `cancel` is cancelled: files in flight stop
+    /// between two buffers and are removed, and no new ones start.
+    pub fn cancel(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Paces the file copies through `throttle`, which may be shared with
+    /// other copies to limit their combined rate.
+    pub fn throttle(mut self, throttle: Throttle) -> Self {
+        self.throttle = Some(throttle);
+        self
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(CancellationToken::is_cancelled)
+    }
+}
+
+/// Outcome of a [`copy`]: what happened to every file, sorted by source,
+/// and the run totals, as written by `--report`.
+#[derive(Clone, Debug, Serialize)]
+pub struct CopyReport {
+    pub files: Vec<FileRecord>,
+    pub totals: ReportTotals,
+    /// Attributes that couldn't be preserved; these don't fail a file.
+    pub warnings: Vec<String>,
+    /// The copy was stopped through [`CopyOptions::cancel`]; files it didn't
+    /// get to are recorded as skipped.
+    pub cancelled: bool,
+}
+
+impl CopyReport {
+    /// Whether every file was either copied or deliberately skipped, and
+    /// the copy ran to the end.
+    pub fn is_success(&self) -> bool {
+        self.totals.files_failed == 0 && !self.cancelled
+    }
+}
+
+/// Reason recorded for files a cancelled [`copy`] didn't finish.
+const CANCELLED: &str = "cancelled";
+
+/// State shared by the copy tasks of [`copy`].
+struct Job {
+    options: CopyOptions,
+    attrs: Attrs,
+    recorder: Recorder,
+    warnings: Mutex<Vec<String>>,
+}
+
+impl Job {
+    /// Records a file that is deliberately not copied.
+    fn skip(&self, from: &Path, to: &Path, reason: String) {
+        if let Some(ref sink) = self.options.progress {
+            sink.on_file_skipped(from, to, &reason);
+        }
+        self.recorder.skipped(from, to.display().to_string(), reason);
+    }
+
+    /// Records a file that couldn't be copied.
+    fn fail(&self, entry: &CopyEntry, elapsed: Duration, message: String) {
+        if let Some(ref sink) = self.options.progress {
+            sink.on_error(&entry.from, &message);
+        }
+        self.recorder.record(&entry.from, entry.to.display().to_string(), 0, elapsed, Some(message));
+    }
+}
+
+/// Copies `sources` into the directory `destination` without printing
+/// anything.
+///
+/// This is a plain copier, and the `cp2` command's local copies don't go
+/// through it; they only share its options and report format. Every file
+/// is read and written through a buffer of [`CopyOptions::buffer_size`], up
+/// to [`CopyOptions::parallel`] at once, and what the options set is all it
+/// does. Failed files aren't retried, small files aren't batched, nothing
+/// is staged, logged for `cp2 undo` or locked, and special files are
+/// skipped. A directory copied into itself and a file copied onto itself
+/// are refused.
+///
+/// Only a destination that isn't a directory stops the whole copy, as
+/// [`CopyError::Io`]. Sources, directories and files that can't be copied
+/// are recorded as failed in the report and the copy carries on.
+pub async fn copy<S: AsRef<Path>>(
+    sources: &[S],
+    destination: impl AsRef<Path>,
+    options: &CopyOptions,
+) -> Result<CopyReport, CopyError> {
+    let destination = destination.as_ref();
+    match std::fs::metadata(destination) {
+        Ok(meta) if meta.is_dir() => {}
+        Ok(_) => return Err(CopyError::io("copy into", destination)(std::io::ErrorKind::NotADirectory.into())),
+        Err(e) => return Err(CopyError::io("copy into", destination)(e)),
+    }
+    let started = Instant::now();
+    let job = Arc::new(Job {
+        options: options.clone(),
+        attrs: Attrs::preserving(options.preserve),
+        recorder: Recorder::new(),
+        warnings: Mutex::new(Vec::new()),
+    });
+
+    let mut entries = Vec::new();
+    let mut dirs: Vec<(PathBuf, PathBuf)> = Vec::new();
+    for source in sources {
+        let source = source.as_ref();
+        let problem = if !source.exists() {
+            "source path does not exist".to_string()
+        } else if source.is_dir() && !options.recursive {
+            "source path is a directory, but recursive is not set".to_string()
+        } else {
+            match collect_copy_entries(source, destination, options.parallel, options.dereference, options.skip_hidden, None).await {
+                Ok((found, found_dirs, _count, _size, specials, _)) => {
+                    for special in specials {
+                        job.skip(&special.from, &special.to, format!("{} not copied", special.kind));
+                    }
+                    entries.extend(found);
+                    dirs.extend(found_dirs);
+                    continue;
+                }
+                Err(e) => e.to_string(),
+            }
+        };
+        if let Some(ref sink) = options.progress {
+            sink.on_error(source, &problem);
+        }
+        job.recorder.record(source, String::new(), 0, Duration::ZERO, Some(problem));
+    }
+
+    if let Some(ref sink) = options.progress {
+        sink.on_scan_finished(entries.len() as u64, entries.iter().map(|e| e.size).sum());
+    }
+    // A directory that can't be created fails the files below it, not the
+    // whole copy.
+    let mut failed_dirs: Vec<(PathBuf, String)> = Vec::new();
+    for (from, dir) in &dirs {
+        if let Err(e) = tokio::fs::create_dir_all(dir).await {
+            let message = CopyError::io("create directory", dir)(e).to_string();
+            if let Some(ref sink) = options.progress {
+                sink.on_error(from, &message);
+            }
+            failed_dirs.push((dir.clone(), message));
+        }
+    }
+    dirs.retain(|(_, to)| !failed_dirs.iter().any(|(dir, _)| to.starts_with(dir)));
+
+    let semaphore = Arc::new(Semaphore::new(options.parallel));
+    let mut tasks = Vec::new();
+    for entry in entries {
+        if let Some((_, message)) = failed_dirs.iter().find(|(dir, _)| entry.to.starts_with(dir)) {
+            job.fail(&entry, Duration::ZERO, message.clone());
+            continue;
+        }
+        let sem = Arc::clone(&semaphore);
+        let task_job = Arc::clone(&job);
+        let (from, to) = (entry.from.clone(), entry.to.clone());
+        let handle = tokio::spawn(async move {
+            let _permit = sem.acquire().await.expect("failed to acquire semaphore permit");
+            copy_entry(&task_job, &entry).await;
+        });
+        tasks.push((from, to, handle));
+    }
+    for (from, to, task) in tasks {
+        // A copy task that panicked fails its file only.
+        if let Err(e) = task.await {
+            job.fail(&CopyEntry { from, to, size: 0 }, Duration::ZERO, format!("copy task failed: {e}"));
+        }
+    }
+
+    // Creating files bumps the times of their directories, so directory
+    // attributes are applied last, deepest directories first.
+    let cancelled = options.is_cancelled();
+    if job.attrs.is_enabled() && !cancelled {
+        dirs.sort_by_key(|(_, to)| std::cmp::Reverse(to.components().count()));
+        for (from, to) in &dirs {
+            job.warnings.lock().unwrap().extend(job.attrs.apply(from, to));
+        }
+    }
+
+    let (files, totals) = job.recorder.collect(started.elapsed());
+    let warnings = std::mem::take(&mut *job.warnings.lock().unwrap());
+    Ok(CopyReport {
+        files,
+        totals,
+        warnings,
+        cancelled,
+    })
+}
+
+/// Copies a single entry of [`copy`] and records the outcome.
+async fn copy_entry(job: &Job, entry: &CopyEntry) {
+    if job.options.is_cancelled() {
+        job.skip(&entry.from, &entry.to, CANCELLED.to_string());
+        return;
+    }
+    if same_file(&entry.from, &entry.to) {
+        let e = CopyError::SameFile {
+            from: entry.from.clone(),
+            to: entry.to.clone(),
+        };
+        job.fail(entry, Duration::ZERO, e.to_string());
+        return;
+    }
+    if let Some(reason) = job.options.overwrite.skip_reason(entry) {
+        job.skip(&entry.from, &entry.to, reason.to_string());
+        return;
+    }
+    let destination = entry.to.display().to_string();
+
+    let sink = job.options.progress.as_deref();
+    if let Some(sink) = sink {
+        sink.on_file_start(&entry.from, &entry.to, entry.size);
+    }
+    let started = Instant::now();
+    let mut buffer = vec![0u8; job.options.buffer_size.min(entry.size.max(1) as usize)];
+    let result = copy_file_with_buffer(
+        &entry.from,
+        &entry.to,
+        &mut buffer,
+        sink,
+        job.options.sync,
+        job.options.throttle.as_ref(),
+        job.options.cancel.as_ref(),
+    )
+    .await;
+    match result {
+        Ok(bytes) => {
+            job.warnings.lock().unwrap().extend(job.attrs.apply(&entry.from, &entry.to));
+            if let Some(sink) = sink {
+                sink.on_file_done(&entry.from, &entry.to, bytes);
+            }
+            job.recorder.record(&entry.from, destination, bytes, started.elapsed(), None);
+        }
+        Err(e) => {
+            if e.is_partial() {
+                let _ = tokio::fs::remove_file(&entry.to).await;
+            }
+            if job.options.is_cancelled() {
+                job.skip(&entry.from, &entry.to, CANCELLED.to_string());
+                return;
+            }
+            job.fail(entry, started.elapsed(), e.to_string());
+        }
+    }
+}
+
+/// Minimum time between two [`CopyEvent::Progress`] events of
+/// [`copy_with_events`] while bytes are being written.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The events of a [`copy_with_events`] run, in the order they happened.
+/// The stream ends after the final [`CopyEvent::Summary`].
+pub struct CopyEvents {
+    receiver: mpsc::UnboundedReceiver<CopyEvent>,
+}
+
+impl CopyEvents {
+    /// The next event, or `None` once the copy has finished.
+    pub async fn next(&mut self) -> Option<CopyEvent> {
+        self.receiver.recv().await
+    }
+}
+
+impl Stream for CopyEvents {
+    type Item = CopyEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<CopyEvent>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// Turns the [`ProgressSink`] calls of a copy into [`CopyEvent`]s, passing
+/// them on to the sink set in the options, if any.
+struct EventForwarder {
+    sender: mpsc::UnboundedSender<CopyEvent>,
+    inner: Option<Arc<dyn ProgressSink>>,
+    bytes_total: AtomicU64,
+    files_total: AtomicU64,
+    bytes_done: AtomicU64,
+    files_done: AtomicU64,
+    last_progress: Mutex<Instant>,
+}
+
+impl EventForwarder {
+    fn send(&self, event: CopyEvent) {
+        // The receiver going away just means nobody is listening any more.
+        let _ = self.sender.send(event);
+    }
+
+    fn send_progress(&self) {
+        *self.last_progress.lock().unwrap() = Instant::now();
+        self.send(CopyEvent::Progress {
+            bytes_done: self.bytes_done.load(Ordering::Relaxed),
+            bytes_total: self.bytes_total.load(Ordering::Relaxed),
+            files_done: self.files_done.load(Ordering::Relaxed),
+            files_total: self.files_total.load(Ordering::Relaxed),
+        });
+    }
+}
+
+impl ProgressSink for EventForwarder {
+    fn on_scan_finished(&self, files: u64, bytes: u64) {
+        self.files_total.store(files, Ordering::Relaxed);
+        self.bytes_total.store(bytes, Ordering::Relaxed);
+        self.send(CopyEvent::ScanFinished { files, bytes });
+        if let Some(ref inner) = self.inner {
+            inner.on_scan_finished(files, bytes);
+        }
+    }
+
+    fn on_bytes(&self, from: &Path, bytes: u64) {
+        self.bytes_done.fetch_add(bytes, Ordering::Relaxed);
+        if self.last_progress.lock().unwrap().elapsed() >= PROGRESS_INTERVAL {
+            self.send_progress();
+        }
+        if let Some(ref inner) = self.inner {
+            inner.on_bytes(from, bytes);
+        }
+    }
+
+    fn on_file_start(&self, from: &Path, to: &Path, size: u64) {
+        self.send(CopyEvent::FileStarted {
+            source: from.display().to_string(),
+            destination: to.display().to_string(),
+            bytes: size,
+        });
+        if let Some(ref inner) = self.inner {
+            inner.on_file_start(from, to, size);
+        }
+    }
+
+    fn on_file_done(&self, from: &Path, to: &Path, bytes: u64) {
+        self.files_done.fetch_add(1, Ordering::Relaxed);
+        self.send(CopyEvent::FileDone {
+            source: from.display().to_string(),
+            destination: to.display().to_string(),
+            bytes,
+        });
+        self.send_progress();
+        if let Some(ref inner) = self.inner {
+            inner.on_file_done(from, to, bytes);
+        }
+    }
+
+    fn on_file_skipped(&self, from: &Path, to: &Path, reason: &str) {
+        self.send(CopyEvent::FileSkipped {
+            source: from.display().to_string(),
+            destination: to.display().to_string(),
+            reason: reason.to_string(),
+        });
+        if let Some(ref inner) = self.inner {
+            inner.on_file_skipped(from, to, reason);
+        }
+    }
+
+    fn on_error(&self, path: &Path, message: &str) {
+        self.send(CopyEvent::Error {
+            path: Some(path.display().to_string()),
+            message: message.to_string(),
+        });
+        if let Some(ref inner) = self.inner {
+            inner.on_error(path, message);
+        }
+    }
+}
+
+/// Same as [`copy`], but runs the copy in a background task and returns
+/// its progress as a stream of the events `--json` prints: `ScanStarted`,
+/// `ScanFinished`, then `FileStarted`, `Progress`, `FileDone`,
+/// `FileSkipped` and `Error` as files are copied, and a final `Summary`.
+/// An error that stops the whole copy arrives as an `Error` without a path
+/// just before the summary.
+///
+/// Must be called from within a tokio runtime. Dropping the stream doesn't
+/// stop the copy; use [`CopyOptions::cancel`] for that.
+pub fn copy_with_events<S: AsRef<Path>>(
+    sources: &[S],
+    destination: impl AsRef<Path>,
+    options: &CopyOptions,
+) -> CopyEvents {
+    let sources: Vec<PathBuf> = sources.iter().map(|s| s.as_ref().to_path_buf()).collect();
+    let destination = destination.as_ref().to_path_buf();
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let forwarder = Arc::new(EventForwarder {
+        sender,
+        inner: options.progress.clone(),
+        bytes_total: AtomicU64::new(0),
+        files_total: AtomicU64::new(0),
+        bytes_done: AtomicU64::new(0),
+        files_done: AtomicU64::new(0),
+        last_progress: Mutex::new(Instant::now()),
+    });
+    forwarder.send(CopyEvent::ScanStarted {
+        sources: sources.iter().map(|s| s.display().to_string()).collect(),
+    });
+    let options = options.clone().progress(forwarder.clone());
+
+    let started = Instant::now();
+    tokio::spawn(async move {
+        let summary = match copy(&sources, &destination, &options).await.map_err(|e| e.to_string()) {
+            Ok(report) => CopyEvent::Summary {
+                files_copied: report.totals.files_copied,
+                files_skipped: report.totals.files_skipped,
+                files_failed: report.totals.files_failed,
+                bytes_copied: report.totals.bytes_copied,
+                elapsed_secs: report.totals.elapsed_secs,
+            },
+            Err(message) => {
+                forwarder.send(CopyEvent::Error { path: None, message });
+                CopyEvent::Summary {
+                    files_copied: 0,
+                    files_skipped: 0,
+                    files_failed: 0,
+                    bytes_copied: 0,
+                    elapsed_secs: started.elapsed().as_secs_f64(),
+                }
+            }
+        };
+        forwarder.send(summary);
+    });
+    CopyEvents { receiver }
+}
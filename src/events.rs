@@ -0,0 +1,283 @@
+use serde::Serialize;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+
+/// A machine-readable event describing the progress of a copy run.
+///
+/// With `--json` each event is written to stdout as one JSON object per line,
+/// tagged by its `event` field (e.g. `{"event":"file_done",...}`).
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum CopyEvent {
+    /// Scanning of the given sources has begun.
+    ScanStarted { sources: Vec<String> },
+    /// Scanning finished; these are the totals that will be copied.
+    ScanFinished { files: u64, bytes: u64 },
+    /// A file is about to be copied.
+    FileStarted {
+        source: String,
+        destination: String,
+        bytes: u64,
+    },
+    /// Periodic overall progress.
+    Progress {
+        bytes_done: u64,
+        bytes_total: u64,
+        files_done: u64,
+        files_total: u64,
+    },
+    /// A file was copied successfully.
+    FileDone {
+        source: String,
+        destination: String,
+        bytes: u64,
+    },
+    /// A file was deliberately not copied, e.g. rejected by `--pre-file-cmd`.
+    FileSkipped {
+        source: String,
+        destination: String,
+        reason: String,
+    },
+    /// Something went wrong; `path` is set when the error concerns one file.
+    Error { path: Option<String>, message: String },
+    /// Final totals for the run.
+    Summary {
+        files_copied: u64,
+        files_skipped: u64,
+        files_failed: u64,
+        bytes_copied: u64,
+        elapsed_secs: f64,
+    },
+}
+
+/// Where events are delivered. Shared by every copy task of a run; emitting
+/// is a no-op when no output is enabled.
+pub(crate) struct EventSink {
+    json_stdout: bool,
+    socket: Option<ProgressSocket>,
+}
+
+impl EventSink {
+    pub(crate) fn new(json_stdout: bool) -> Self {
+        Self {
+            json_stdout,
+            socket: None,
+        }
+    }
+
+    /// Additionally publishes every event to clients connected to `path`, a
+    /// Unix domain socket (or a named pipe such as `\\.\pipe\cp2` on
+    /// Windows). Must be called from within the tokio runtime.
+    pub(crate) fn listen(&mut self, path: &Path) -> io::Result<()> {
+        self.socket = Some(ProgressSocket::bind(path)?);
+        Ok(())
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.json_stdout || self.socket.is_some()
+    }
+
+    pub(crate) fn emit(&self, event: &CopyEvent) {
+        if !self.is_enabled() {
+            return;
+        }
+        let line = match serde_json::to_string(event) {
+            Ok(line) => line,
+            Err(e) => {
+                log::warn!("failed to serialize event: {}", e);
+                return;
+            }
+        };
+        if let Some(ref socket) = self.socket {
+            socket.publish(&line);
+        }
+        if self.json_stdout {
+            // A single println! holds the stdout lock for the whole line, so
+            // events from concurrent tasks never interleave.
+            println!("{line}");
+        }
+    }
+
+    /// Shorthand for emitting [`CopyEvent::Error`].
+    pub(crate) fn error(&self, path: Option<&Path>, message: impl Into<String>) {
+        if self.is_enabled() {
+            self.emit(&CopyEvent::Error {
+                path: path.map(|p| p.display().to_string()),
+                message: message.into(),
+            });
+        }
+    }
+
+    /// Flushes pending events to socket clients and stops listening. Call once
+    /// the final `summary` event has been emitted.
+    pub(crate) async fn close(&self) {
+        if let Some(ref socket) = self.socket {
+            socket.close().await;
+        }
+    }
+}
+
+/// Number of events buffered per socket client before a slow reader starts
+/// missing them.
+const SOCKET_BACKLOG: usize = 1024;
+
+/// How long [`EventSink::close`] waits for clients to drain their backlog.
+const SOCKET_DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Listens on the Unix socket at `path`. A socket left behind by an earlier
+/// run would make bind() fail, so it is removed, but never anything that
+/// isn't a socket.
+#[cfg(unix)]
+pub(crate) fn bind_unix_socket(path: &Path) -> io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists and is not a socket", path.display()),
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+    tokio::net::UnixListener::bind(path)
+}
+
+/// Broadcasts event lines to every client connected to the progress socket.
+/// Clients receive events emitted after they connect.
+struct ProgressSocket {
+    sender: Mutex<Option<broadcast::Sender<Arc<str>>>>,
+    acceptor: Mutex<Option<JoinHandle<()>>>,
+    clients: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    #[cfg(unix)]
+    path: std::path::PathBuf,
+}
+
+impl ProgressSocket {
+    #[cfg(unix)]
+    fn bind(path: &Path) -> io::Result<Self> {
+        let listener = bind_unix_socket(path)?;
+
+        let (sender, _) = broadcast::channel(SOCKET_BACKLOG);
+        let clients = Arc::new(Mutex::new(Vec::new()));
+        let acceptor = {
+            let sender = sender.clone();
+            let clients = Arc::clone(&clients);
+            tokio::spawn(async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, _)) => {
+                            let client = tokio::spawn(forward(stream, sender.subscribe()));
+                            clients.lock().unwrap().push(client);
+                        }
+                        Err(e) => {
+                            log::warn!("progress socket stopped accepting clients: {}", e);
+                            break;
+                        }
+                    }
+                }
+            })
+        };
+
+        Ok(Self {
+            sender: Mutex::new(Some(sender)),
+            acceptor: Mutex::new(Some(acceptor)),
+            clients,
+            path: path.to_path_buf(),
+        })
+    }
+
+    #[cfg(windows)]
+    fn bind(path: &Path) -> io::Result<Self> {
+        use tokio::net::windows::named_pipe::ServerOptions;
+
+        let name = path.as_os_str().to_owned();
+        let mut server = ServerOptions::new().first_pipe_instance(true).create(&name)?;
+
+        let (sender, _) = broadcast::channel(SOCKET_BACKLOG);
+        let clients = Arc::new(Mutex::new(Vec::new()));
+        let acceptor = {
+            let sender = sender.clone();
+            let clients = Arc::clone(&clients);
+            tokio::spawn(async move {
+                loop {
+                    if let Err(e) = server.connect().await {
+                        log::warn!("progress pipe stopped accepting clients: {}", e);
+                        break;
+                    }
+                    // Each client gets its own pipe instance; create the next
+                    // one before handing this one off.
+                    let connected = server;
+                    server = match ServerOptions::new().create(&name) {
+                        Ok(next) => next,
+                        Err(e) => {
+                            log::warn!("progress pipe stopped accepting clients: {}", e);
+                            break;
+                        }
+                    };
+                    let client = tokio::spawn(forward(connected, sender.subscribe()));
+                    clients.lock().unwrap().push(client);
+                }
+            })
+        };
+
+        Ok(Self {
+            sender: Mutex::new(Some(sender)),
+            acceptor: Mutex::new(Some(acceptor)),
+            clients,
+        })
+    }
+
+    fn publish(&self, line: &str) {
+        if let Some(ref sender) = *self.sender.lock().unwrap() {
+            // Sending only fails when no client is connected.
+            let _ = sender.send(Arc::from(line));
+        }
+    }
+
+    async fn close(&self) {
+        // Dropping every sender lets each client task drain its backlog and exit.
+        let acceptor = self.acceptor.lock().unwrap().take();
+        if let Some(acceptor) = acceptor {
+            acceptor.abort();
+            let _ = acceptor.await;
+        }
+        self.sender.lock().unwrap().take();
+        let clients = std::mem::take(&mut *self.clients.lock().unwrap());
+        for client in clients {
+            if tokio::time::timeout(SOCKET_DRAIN_TIMEOUT, client).await.is_err() {
+                log::warn!("timed out flushing events to a progress socket client");
+            }
+        }
+        #[cfg(unix)]
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Writes event lines to one socket client until the run ends or the client
+/// disconnects.
+async fn forward<W: AsyncWrite + Unpin>(mut client: W, mut events: broadcast::Receiver<Arc<str>>) {
+    loop {
+        match events.recv().await {
+            Ok(line) => {
+                let mut buf = Vec::with_capacity(line.len() + 1);
+                buf.extend_from_slice(line.as_bytes());
+                buf.push(b'\n');
+                if client.write_all(&buf).await.is_err() {
+                    return;
+                }
+            }
+            Err(RecvError::Lagged(missed)) => {
+                log::warn!("progress socket client fell behind; {} events dropped", missed);
+            }
+            Err(RecvError::Closed) => break,
+        }
+    }
+    let _ = client.flush().await;
+}
@@ -0,0 +1,36 @@
+use crate::progress::RunStats;
+use std::sync::atomic::Ordering;
+
+// Exit statuses other than 0 (success), so scripts can tell "retry
+// everything" from "retry the failed subset". Keep `HELP` in sync.
+
+/// The run failed and no file was copied.
+pub(crate) const FAILURE: i32 = 1;
+/// Some files were copied, but others failed.
+pub(crate) const PARTIAL: i32 = 2;
+/// Invalid arguments, or a check before copying failed (missing destination,
+/// unknown remote, ...); nothing was copied.
+pub(crate) const USAGE: i32 = 3;
+/// `cp2 diff` found differences, as `diff` reports them.
+pub(crate) const DIFFERENT: i32 = 1;
+/// Stopped with Ctrl-C (128 + SIGINT, as shells report it).
+pub(crate) const INTERRUPTED: i32 = 130;
+
+/// The exit status table shown at the end of `--help`.
+pub(crate) const HELP: &str = "\
+Exit status:
+  0    All files were copied
+  1    Nothing was copied because every file failed
+  2    Some files were copied, but others failed
+  3    Usage error, or a check before copying failed
+  130  Interrupted with Ctrl-C";
+
+/// Status of a finished run that had failures: [`PARTIAL`] if at least one
+/// file made it, [`FAILURE`] otherwise.
+pub(crate) fn for_failed_run(stats: &RunStats) -> i32 {
+    if stats.files_done.load(Ordering::Relaxed) > 0 {
+        PARTIAL
+    } else {
+        FAILURE
+    }
+}
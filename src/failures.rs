@@ -0,0 +1,205 @@
+use colored::Colorize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use crate::copy::{find_io_error, CopyError};
+use crate::dashboard;
+
+/// Error kind reported for a failing `--pre-file-cmd` or `--post-file-cmd`.
+pub(crate) const COMMAND_FAILED: &str = "command failed";
+
+/// Whether `--errors json` asked for errors as JSON objects.
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Prints errors as one JSON object per line instead of colored text, for
+/// `--errors json`.
+pub(crate) fn set_json() {
+    JSON.store(true, Ordering::Relaxed);
+}
+
+/// Whether errors are printed as JSON objects.
+pub(crate) fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Broad class of an error, used to group the report.
+#[derive(Clone, Debug)]
+pub(crate) struct Kind {
+    /// e.g. `permission denied`.
+    name: String,
+    /// Code of the OS error behind it, if there is one.
+    os_error: Option<i32>,
+}
+
+impl From<String> for Kind {
+    fn from(name: String) -> Self {
+        Kind { name, os_error: None }
+    }
+}
+
+/// Groups an error for the report by the I/O error behind it, using the OS
+/// description where there is one (e.g. `no such file or directory`) and the
+/// std kind otherwise. Copy errors without an I/O cause are grouped by what
+/// went wrong; anything else is `other error`.
+pub(crate) fn kind_of(err: &(dyn std::error::Error + 'static)) -> Kind {
+    let kind = match err.downcast_ref::<CopyError>().map(CopyError::cause) {
+        Some(CopyError::SameFile { .. }) => Some("same file"),
+        Some(CopyError::DestinationInsideSource { .. }) => Some("destination inside source"),
+        Some(CopyError::Interrupted) => Some("interrupted"),
+        Some(CopyError::VerificationFailed { .. } | CopyError::DigestMismatch { .. }) => Some("verification failed"),
+        _ => None,
+    };
+    if let Some(kind) = kind {
+        return kind.to_string().into();
+    }
+    match find_io_error(err) {
+        Some(io) => match io.raw_os_error() {
+            Some(code) => {
+                let description = std::io::Error::from_raw_os_error(code).to_string();
+                Kind {
+                    name: description.split(" (os error").next().unwrap_or_default().to_lowercase(),
+                    os_error: Some(code),
+                }
+            }
+            None => io.kind().to_string().into(),
+        },
+        None => std::io::ErrorKind::Other.to_string().into(),
+    }
+}
+
+/// An error as printed to stderr with `--errors json`.
+#[derive(Serialize)]
+struct ErrorLine<'a> {
+    path: Option<&'a str>,
+    operation: &'a str,
+    kind: &'a str,
+    os_error: Option<i32>,
+    message: &'a str,
+}
+
+impl ErrorLine<'_> {
+    fn print(&self) {
+        match serde_json::to_string(self) {
+            // A single eprintln! holds the stderr lock for the whole line.
+            Ok(line) => eprintln!("{line}"),
+            Err(e) => log::warn!("failed to serialize error: {}", e),
+        }
+    }
+}
+
+/// Prints an error of `operation` on `path` that isn't kept for the report,
+/// such as one that ends the run: as a JSON object with `--errors json`, and
+/// by calling `text` otherwise.
+pub(crate) fn print_error(path: Option<&Path>, operation: &str, kind: Kind, message: &str, text: impl FnOnce()) {
+    if !JSON.load(Ordering::Relaxed) {
+        text();
+        return;
+    }
+    let path = path.map(|path| path.display().to_string());
+    ErrorLine {
+        path: path.as_deref(),
+        operation,
+        kind: &kind.name,
+        os_error: kind.os_error,
+        message,
+    }
+    .print();
+}
+
+/// A file that couldn't be copied, kept for the report printed at the end of
+/// the run.
+struct Failure {
+    path: String,
+    /// What was being done when it failed, e.g. `copy` or `post-file command`.
+    operation: &'static str,
+    /// Broad class of the error used to group the report.
+    kind: Kind,
+    message: String,
+}
+
+/// Collects per-file failures while files are copied, so they can be listed
+/// together once the progress bars are gone instead of scrolling past
+/// between them.
+pub(crate) struct Failures {
+    entries: Mutex<Vec<Failure>>,
+}
+
+impl Failures {
+    pub(crate) fn new() -> Self {
+        Self {
+            entries: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Records a failure of `operation` on `path`. It is logged right away
+    /// (visible with `-v`), shown on the `--tui` dashboard, and printed in
+    /// the final report.
+    pub(crate) fn record(&self, path: &Path, operation: &'static str, kind: impl Into<Kind>, message: String) {
+        log::warn!("{} failed for {}: {}", operation, path.display(), message);
+        dashboard::show_message(format!("{} {}: {}", operation, path.display(), message));
+        self.entries.lock().unwrap().push(Failure {
+            path: path.display().to_string(),
+            operation,
+            kind: kind.into(),
+            message,
+        });
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    /// Prints all failures to stderr, grouped by error kind and sorted by
+    /// kind, then path, or with `--errors json` as one JSON object each in
+    /// the order they happened. Prints nothing if there were none.
+    pub(crate) fn print_report(&self) {
+        let entries = self.entries.lock().unwrap();
+        if entries.is_empty() {
+            return;
+        }
+        if JSON.load(Ordering::Relaxed) {
+            for failure in entries.iter() {
+                ErrorLine {
+                    path: Some(&failure.path),
+                    operation: failure.operation,
+                    kind: &failure.kind.name,
+                    os_error: failure.kind.os_error,
+                    message: &failure.message,
+                }
+                .print();
+            }
+            return;
+        }
+        let mut groups: BTreeMap<&str, Vec<&Failure>> = BTreeMap::new();
+        for failure in entries.iter() {
+            groups.entry(&failure.kind.name).or_default().push(failure);
+        }
+
+        eprintln!("{}", format!("Errors ({}):", entries.len()).red().bold());
+        for (kind, mut failures) in groups {
+            failures.sort_by(|a, b| a.path.cmp(&b.path));
+            eprintln!("  {} ({})", kind.red(), failures.len());
+            for failure in failures {
+                eprintln!("    {:<17} {}: {}", failure.operation, failure.path, failure.message);
+            }
+        }
+    }
+
+    /// Prints the line shown just before exiting because of the recorded
+    /// failures, e.g. `Exiting with status 1: 3 errors`. JSON consumers
+    /// have the exit status and the report instead.
+    pub(crate) fn print_exit_message(&self, status: i32) {
+        if JSON.load(Ordering::Relaxed) {
+            return;
+        }
+        let message = match self.len() {
+            0 => format!("Exiting with status {}", status),
+            1 => format!("Exiting with status {}: 1 error", status),
+            n => format!("Exiting with status {}: {} errors", status, n),
+        };
+        eprintln!("{}", message.red());
+    }
+}
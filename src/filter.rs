@@ -0,0 +1,144 @@
+use std::ffi::OsString;
+use std::io;
+use std::path::Path;
+use std::process::Stdio;
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::process::{Child, Command};
+use tokio_util::sync::CancellationToken;
+
+use crate::copy::{pace, CopyError};
+use crate::sink::ProgressSink;
+use crate::throttle::Throttle;
+
+/// Size of the chunks fed to the first filter.
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// A program that file contents are piped through on their way to the
+/// destination, reading stdin and writing stdout, such as `zstd -c`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Filter {
+    pub program: &'static str,
+    pub args: Vec<OsString>,
+    /// What it does to the data, as in "Failed to compress"; also used to
+    /// say why the program is needed.
+    pub action: &'static str,
+}
+
+impl Filter {
+    pub fn new(program: &'static str, args: &[&str], action: &'static str) -> Self {
+        Self {
+            program,
+            args: args.iter().map(OsString::from).collect(),
+            action,
+        }
+    }
+
+    pub fn arg(mut self, arg: impl Into<OsString>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+}
+
+/// Copies `from` to `to` through `filters`, one process each, chained by
+/// pipes. This task feeds the first while another drains the last into `to`,
+/// so reading, filtering and writing overlap; with parallel copies the
+/// processes form a pool of workers.
+///
+/// Progress is reported in bytes read from `from`, which is also what is
+/// returned. Once `to` has been created, errors are
+/// [partial](CopyError::is_partial).
+pub async fn copy_file_filtered(
+    from: &Path,
+    to: &Path,
+    filters: &[Filter],
+    progress: Option<&dyn ProgressSink>,
+    sync: bool,
+    throttle: Option<&Throttle>,
+    cancel: Option<&CancellationToken>,
+) -> Result<u64, CopyError> {
+    let mut source = fs::File::open(from).await.map_err(CopyError::io("open", from))?;
+    let mut children: Vec<(Child, &Filter)> = Vec::new();
+    let mut input = Some(Stdio::piped());
+    for (i, filter) in filters.iter().enumerate() {
+        let mut child = Command::new(filter.program)
+            .args(&filter.args)
+            .stdin(input.take().expect("set for each filter"))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| {
+                let e = match e.kind() {
+                    io::ErrorKind::NotFound => {
+                        io::Error::new(e.kind(), format!("`{}` is needed to {}", filter.program, filter.action))
+                    }
+                    _ => e,
+                };
+                CopyError::io(filter.action, from)(e)
+            })?;
+        // Each filter reads what the one before it writes.
+        if i + 1 < filters.len() {
+            let stdout = child.stdout.take().expect("stdout is piped");
+            input = Some(stdout.try_into().map_err(CopyError::io(filter.action, from))?);
+        }
+        children.push((child, filter));
+    }
+    let (first, first_filter) = children.first_mut().expect("at least one filter");
+    let mut stdin = first.stdin.take().expect("stdin is piped");
+    let first_action = first_filter.action;
+    let (last, _) = children.last_mut().expect("at least one filter");
+    let mut stdout = last.stdout.take().expect("stdout is piped");
+    let dest = fs::File::create(to).await.map_err(CopyError::io("create", to))?;
+
+    let feed = async {
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        let mut total = 0u64;
+        loop {
+            let n = source.read(&mut buffer).await.map_err(CopyError::io("read", from))?;
+            if n == 0 {
+                break;
+            }
+            pace(throttle, n as u64, cancel).await?;
+            stdin.write_all(&buffer[..n]).await.map_err(CopyError::io(first_action, from))?;
+            total += n as u64;
+            if let Some(progress) = progress {
+                progress.on_bytes(from, n as u64);
+            }
+        }
+        // Closing stdin lets the filters finish.
+        drop(stdin);
+        Ok(total)
+    };
+    let drain = async {
+        let mut out = BufWriter::new(dest);
+        tokio::io::copy(&mut stdout, &mut out).await.map_err(CopyError::io("write", to))?;
+        out.flush().await.map_err(CopyError::io("flush", to))?;
+        Ok::<_, CopyError>(out.into_inner())
+    };
+    let copied = tokio::try_join!(feed, drain);
+
+    // A filter that gave up usually shows up above as a broken pipe; what it
+    // printed says more.
+    let mut failed = None;
+    for (child, filter) in children {
+        let output = child
+            .wait_with_output()
+            .await
+            .map_err(|e| CopyError::io(filter.action, from)(e).partial())?;
+        if !output.status.success() && failed.is_none() {
+            let message = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            let e = io::Error::other(format!("`{}` failed ({}): {}", filter.program, output.status, message));
+            failed = Some(CopyError::io(filter.action, from)(e));
+        }
+    }
+    let (total, dest) = match (copied, failed) {
+        (Err(CopyError::Interrupted), _) => return Err(CopyError::Interrupted.partial()),
+        (_, Some(e)) | (Err(e), None) => return Err(e.partial()),
+        (Ok(copied), None) => copied,
+    };
+    if sync {
+        dest.sync_all().await.map_err(|e| CopyError::io("sync", to)(e).partial())?;
+    }
+    Ok(total)
+}
@@ -0,0 +1,173 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+
+use crate::blake3;
+
+/// Digest algorithms cp2 can compute while copying.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    /// Suffix of the sidecar file holding the digest of a single file.
+    pub fn suffix(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => ".sha256",
+            HashAlgorithm::Blake3 => ".b3",
+        }
+    }
+
+    /// Name of the file listing the digests of a whole tree, as written by
+    /// `sha256sum` and `b3sum`.
+    pub fn sums_file(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "SHA256SUMS",
+            HashAlgorithm::Blake3 => "B3SUMS",
+        }
+    }
+
+    /// The algorithm a checksum file's name points to: `B3SUMS` and `*.b3`
+    /// hold BLAKE3 digests, anything else SHA-256 ones.
+    pub fn for_sums_file(path: &Path) -> Self {
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_ascii_lowercase();
+        if name.starts_with("b3sum") || name.ends_with(".b3") || name.contains("blake3") {
+            HashAlgorithm::Blake3
+        } else {
+            HashAlgorithm::Sha256
+        }
+    }
+
+    pub fn hasher(self) -> Hasher {
+        match self {
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::default()),
+        }
+    }
+}
+
+/// A digest being computed over data as it streams past.
+pub enum Hasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Blake3(hasher) => hasher.update(data),
+        }
+    }
+
+    /// The digest as lowercase hex.
+    pub fn finish(self) -> String {
+        let digest: Vec<u8> = match self {
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            Hasher::Blake3(hasher) => hasher.finalize().to_vec(),
+        };
+        digest.iter().map(|b| format!("{b:02x}")).collect()
+    }
+}
+
+/// Passes reads through to `inner`, feeding everything read to `hasher`.
+pub struct HashingReader<'a, R> {
+    pub inner: R,
+    pub hasher: &'a mut Hasher,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HashingReader<'_, R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let this = &mut *self;
+        let polled = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = polled {
+            this.hasher.update(&buf.filled()[before..]);
+        }
+        polled
+    }
+}
+
+/// Size of the reads [`hash_file`] makes.
+const READ_SIZE: usize = 1024 * 1024;
+
+/// Digest of the file at `path` as lowercase hex, calling `on_bytes` with
+/// the size of each read as it goes.
+pub async fn hash_file(path: &Path, algorithm: HashAlgorithm, mut on_bytes: impl FnMut(u64)) -> io::Result<String> {
+    let mut file = fs::File::open(path).await?;
+    let mut hasher = algorithm.hasher();
+    let mut buffer = vec![0u8; READ_SIZE];
+    loop {
+        let n = file.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+        on_bytes(n as u64);
+    }
+    Ok(hasher.finish())
+}
+
+/// Whether the files at `a` and `b` have the same size and SHA-256 digest;
+/// `false` when either can't be read.
+pub async fn same_contents(a: &Path, b: &Path) -> bool {
+    match (fs::metadata(a).await, fs::metadata(b).await) {
+        (Ok(a_meta), Ok(b_meta)) if a_meta.is_file() && b_meta.is_file() && a_meta.len() == b_meta.len() => {}
+        _ => return false,
+    }
+    let (a, b) = tokio::join!(
+        hash_file(a, HashAlgorithm::Sha256, |_| {}),
+        hash_file(b, HashAlgorithm::Sha256, |_| {})
+    );
+    matches!((a, b), (Ok(a), Ok(b)) if a == b)
+}
+
+/// One line of a checksum file, `<digest>  <path>`, as `sha256sum -c` and
+/// `b3sum -c` read it.
+pub fn sums_line(digest: &str, path: &str) -> String {
+    format!("{digest}  {path}\n")
+}
+
+/// Parses a checksum file into its digests by path, skipping lines that
+/// aren't `<digest>  <path>` (or `<digest> *<path>`, binary mode) or, as
+/// BSD tools and `shasum --tag` write them, `SHA256 (<path>) = <digest>`.
+pub fn parse_sums(contents: &str) -> BTreeMap<String, String> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            if let Some((tag, digest)) = line.rsplit_once(") = ")
+                && let Some((_, path)) = tag.split_once(" (")
+            {
+                return (!digest.is_empty() && digest.bytes().all(|b| b.is_ascii_hexdigit()))
+                    .then(|| (path.to_string(), digest.to_ascii_lowercase()));
+            }
+            let (digest, path) = line.split_once(' ')?;
+            let path = path.strip_prefix(' ').or_else(|| path.strip_prefix('*'))?;
+            (!digest.is_empty() && digest.bytes().all(|b| b.is_ascii_hexdigit()))
+                .then(|| (path.to_string(), digest.to_ascii_lowercase()))
+        })
+        .collect()
+}
+
+/// Adds `digests` (by path) to the checksum file at `path`, replacing the
+/// lines of paths it already lists, and writes it back sorted by path.
+pub async fn update_sums_file(path: &Path, digests: impl IntoIterator<Item = (String, String)>) -> io::Result<()> {
+    let mut sums = match fs::read_to_string(path).await {
+        Ok(contents) => parse_sums(&contents),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => return Err(e),
+    };
+    sums.extend(digests);
+    let contents: String = sums.iter().map(|(path, digest)| sums_line(digest, path)).collect();
+    fs::write(path, contents).await
+}
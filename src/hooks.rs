@@ -0,0 +1,149 @@
+use colored::Colorize;
+use std::path::Path;
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::events::CopyEvent;
+
+/// How long the `--on-complete-url` request may take before it is abandoned.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Builds a command that runs `cmd` through the platform shell, so hooks can
+/// use pipes, quoting and environment variables like any shell one-liner.
+pub(crate) fn shell(cmd: &str) -> Command {
+    #[cfg(windows)]
+    let mut command = {
+        let mut command = Command::new("cmd");
+        command.arg("/C").arg(cmd);
+        command
+    };
+    #[cfg(not(windows))]
+    let mut command = {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(cmd);
+        command
+    };
+    command.kill_on_drop(true);
+    command
+}
+
+/// Delivers the run's `summary` event to the `--on-complete-url` webhook
+/// and/or `--on-complete-cmd` command.
+///
+/// Hooks run after the copy has finished, so a failing hook is reported on
+/// stderr but never changes the outcome of the run.
+pub(crate) async fn on_complete(url: Option<&reqwest::Url>, cmd: Option<&str>, summary: &CopyEvent) {
+    let body = match serde_json::to_string(summary) {
+        Ok(body) => body,
+        Err(e) => {
+            log::warn!("failed to serialize run summary: {}", e);
+            return;
+        }
+    };
+
+    if let Some(url) = url
+        && let Err(e) = post_summary(url, &body).await
+    {
+        eprintln!("{} {}", "On-complete webhook failed:".red(), e);
+    }
+    if let Some(cmd) = cmd
+        && let Err(e) = pipe_summary(cmd, &body).await
+    {
+        eprintln!("{} {}", "On-complete command failed:".red(), e);
+    }
+}
+
+async fn post_summary(url: &reqwest::Url, body: &str) -> Result<(), Box<dyn std::error::Error>> {
+    log::debug!("Posting run summary to {}", url);
+    reqwest::Client::new()
+        .post(url.clone())
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body.to_string())
+        .timeout(WEBHOOK_TIMEOUT)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+async fn pipe_summary(cmd: &str, body: &str) -> Result<(), Box<dyn std::error::Error>> {
+    log::debug!("Running on-complete command: {}", cmd);
+    let mut child = shell(cmd).stdin(Stdio::piped()).spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // A command that ignores its input may exit before reading it all.
+        let _ = stdin.write_all(body.as_bytes()).await;
+        let _ = stdin.write_all(b"\n").await;
+    }
+    let status = child.wait().await?;
+    if !status.success() {
+        return Err(format!("`{}` exited with {}", cmd, status).into());
+    }
+    Ok(())
+}
+
+/// Runs a per-file hook such as `--post-file-cmd 'setfattr -n user.done {dest}'`
+/// with `{src}` and `{dest}` replaced by the shell-quoted paths. A non-zero
+/// exit status is an error.
+pub(crate) async fn file_cmd(template: &str, src: &Path, dest: &str) -> Result<(), String> {
+    let (cmd, status) = run_file_cmd(template, src, dest).await?;
+    if !status.success() {
+        return Err(format!("`{}` exited with {}", cmd, status));
+    }
+    Ok(())
+}
+
+/// Runs the `--pre-file-cmd` check for one file. Returns `Ok(false)` when the
+/// command exits non-zero, meaning the file should be skipped; failing to run
+/// the command at all is an error.
+pub(crate) async fn pre_file_check(template: &str, src: &Path, dest: &str) -> Result<bool, String> {
+    let (_, status) = run_file_cmd(template, src, dest).await?;
+    Ok(status.success())
+}
+
+/// Expands and runs a per-file hook. Its output goes to stderr so it can't
+/// corrupt `--json` on stdout.
+async fn run_file_cmd(template: &str, src: &Path, dest: &str) -> Result<(String, ExitStatus), String> {
+    let cmd = expand(template, &src.display().to_string(), dest);
+    log::debug!("Running file hook: {}", cmd);
+    let status = shell(&cmd)
+        .stdin(Stdio::null())
+        .stdout(std::io::stderr())
+        .status()
+        .await
+        .map_err(|e| format!("failed to run `{}`: {}", cmd, e))?;
+    Ok((cmd, status))
+}
+
+/// Substitutes `{src}` and `{dest}` in a single pass, so a path that itself
+/// contains a placeholder is never expanded twice.
+fn expand(template: &str, src: &str, dest: &str) -> String {
+    let mut out = String::with_capacity(template.len() + src.len() + dest.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("{src}") {
+            out.push_str(&quote(src));
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("{dest}") {
+            out.push_str(&quote(dest));
+            rest = after;
+        } else {
+            out.push('{');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Quotes `arg` as a single word for the platform shell.
+fn quote(arg: &str) -> String {
+    if cfg!(windows) {
+        format!("\"{}\"", arg.replace('"', "\"\""))
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
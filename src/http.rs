@@ -0,0 +1,494 @@
+use reqwest::header::{ACCEPT_RANGES, CONTENT_LENGTH, RANGE};
+use reqwest::{Client, StatusCode, Url};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use tokio::fs;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio_util::sync::CancellationToken;
+
+use crate::blake3;
+use crate::copy::{pace, CopyError};
+use crate::resume::{self, with_suffix, ResumeState};
+use crate::sink::ProgressSink;
+use crate::throttle::Throttle;
+use crate::transport::{RemoteEntry, RemoteStat, Transport};
+
+/// Smallest range worth a request of its own in a segmented download.
+const MIN_SEGMENT_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Whether a source is an `http://` or `https://` URL.
+pub fn is_url(s: &str) -> bool {
+    let lower = s.get(..8).unwrap_or(s).to_ascii_lowercase();
+    lower.starts_with("http://") || lower.starts_with("https://")
+}
+
+/// What a HEAD request told about a URL.
+#[derive(Clone, Copy, Debug)]
+struct Probe {
+    /// `Content-Length`, if the server sent one.
+    size: Option<u64>,
+    /// The server accepts `Range` requests (`Accept-Ranges: bytes`).
+    ranges: bool,
+}
+
+/// Downloads `http://` and `https://` URLs. Files are written to
+/// `<name>.part` first and renamed once complete; a `.part` file left by an
+/// interrupted run is resumed with a `Range` request when the server allows
+/// it. Large files are fetched as several ranges in parallel.
+///
+/// URLs can only be read, so everything but [`Transport::stat`] and
+/// [`Transport::download`] fails.
+pub struct HttpTransport {
+    client: Client,
+    /// Most ranges a single file is split into.
+    segments: usize,
+    probes: Mutex<HashMap<String, Probe>>,
+}
+
+impl HttpTransport {
+    pub fn new(segments: usize) -> Self {
+        Self {
+            client: Client::builder()
+                .user_agent(concat!("cp2/", env!("CARGO_PKG_VERSION")))
+                .build()
+                .expect("HTTP client"),
+            segments: segments.max(1),
+            probes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sends a HEAD request for `url`; `None` if it doesn't exist. Servers
+    /// that don't answer HEAD are assumed to send an unknown size and no
+    /// ranges.
+    async fn probe(&self, url: &str) -> io::Result<Option<Probe>> {
+        if let Some(probe) = self.probes.lock().unwrap().get(url) {
+            return Ok(Some(*probe));
+        }
+        let response = self.client.head(url).send().await.map_err(request_error)?;
+        let probe = match response.status() {
+            StatusCode::NOT_FOUND | StatusCode::GONE => return Ok(None),
+            status if status.is_success() => Probe {
+                size: response
+                    .headers()
+                    .get(CONTENT_LENGTH)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.parse().ok()),
+                ranges: response
+                    .headers()
+                    .get(ACCEPT_RANGES)
+                    .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"bytes")),
+            },
+            StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED => Probe {
+                size: None,
+                ranges: false,
+            },
+            status => return Err(status_error(status)),
+        };
+        self.probes.lock().unwrap().insert(url.to_string(), probe);
+        Ok(Some(probe))
+    }
+
+    /// How many ranges a file of `size` bytes is fetched in.
+    fn segment_count(&self, size: u64) -> usize {
+        (size / MIN_SEGMENT_SIZE).clamp(1, self.segments as u64) as usize
+    }
+
+    /// Fetches `url` in one request, appending to `part` if it holds the
+    /// beginning of the file already.
+    #[allow(clippy::too_many_arguments)]
+    async fn fetch_stream(
+        &self,
+        url: &str,
+        source: &Path,
+        part: &Path,
+        probe: Probe,
+        progress: Option<&dyn ProgressSink>,
+        throttle: Option<&Throttle>,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<u64, CopyError> {
+        // A `.part` file written in segments may have holes, so it can't be
+        // resumed from its length.
+        let segmented = fs::try_exists(with_suffix(part, ".json")).await.unwrap_or(false);
+        let existing = match probe.ranges && !segmented {
+            true => fs::metadata(part).await.map(|m| m.len()).unwrap_or(0),
+            false => 0,
+        };
+        let mut request = self.client.get(url);
+        if existing > 0 {
+            request = request.header(RANGE, format!("bytes={}-", existing));
+        }
+        let mut response = request.send().await.map_err(request_error).map_err(CopyError::io("download", source))?;
+        let resumed = match response.status() {
+            StatusCode::RANGE_NOT_SATISFIABLE if probe.size == Some(existing) => {
+                report(progress, source, existing);
+                return Ok(existing);
+            }
+            StatusCode::PARTIAL_CONTENT if existing > 0 => true,
+            status if status.is_success() => false,
+            status => return Err(CopyError::io("download", source)(status_error(status))),
+        };
+
+        let mut file = match resumed {
+            true => fs::OpenOptions::new().append(true).open(part).await,
+            false => fs::File::create(part).await,
+        }
+        .map_err(CopyError::io("create", part))?;
+        let mut total = if resumed { existing } else { 0 };
+        report(progress, source, total);
+        // What a crashed run left in `part` is checked against the source
+        // while the rest is fetched, and fetched again if it differs.
+        let prefix = match resumed {
+            true => vec![(0, existing)],
+            false => Vec::new(),
+        };
+        let (result, verified) = tokio::join!(
+            async {
+                while let Some(chunk) = response
+                    .chunk()
+                    .await
+                    .map_err(request_error)
+                    .map_err(CopyError::io("download", source))?
+                {
+                    pace(throttle, chunk.len() as u64, cancel).await?;
+                    file.write_all(&chunk).await.map_err(CopyError::io("write", part))?;
+                    total += chunk.len() as u64;
+                    received(progress, source, chunk.len() as u64);
+                }
+                file.flush().await.map_err(CopyError::io("flush", part))
+            },
+            self.verify_resumed(url, source, part, &prefix),
+        );
+        result?;
+        if !verified? {
+            log::warn!("The resumed part of {} doesn't match {}, fetching it again", part.display(), url);
+            self.fetch_range(url, source, part, 0, existing, &AtomicU64::new(0), None, throttle, cancel)
+                .await?;
+        }
+        match probe.size {
+            Some(size) if size != total => Err(CopyError::VerificationFailed {
+                path: source.to_path_buf(),
+                expected: size,
+                written: total,
+            }),
+            _ => Ok(total),
+        }
+    }
+
+    /// Fetches `url` as several ranges in parallel, each written at its
+    /// offset in `part`. The ranges fetched are saved next to `part` whenever
+    /// the download stops early, and only the missing ones are fetched on
+    /// the next attempt.
+    #[allow(clippy::too_many_arguments)]
+    async fn fetch_segments(
+        &self,
+        url: &str,
+        source: &Path,
+        part: &Path,
+        size: u64,
+        progress: Option<&dyn ProgressSink>,
+        throttle: Option<&Throttle>,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<u64, CopyError> {
+        let state_path = with_suffix(part, ".json");
+        let saved = match fs::try_exists(part).await.unwrap_or(false) {
+            true => ResumeState::load(&state_path, size, None).await,
+            false => None,
+        };
+        let (mut state, ranges) = match saved {
+            Some(state) => {
+                let missing = state.pending().into_iter().filter(|&(start, _)| start < size).collect();
+                (state, missing)
+            }
+            None => {
+                let count = self.segment_count(size) as u64;
+                let step = size.div_ceil(count);
+                let state = ResumeState::new(size, None);
+                fs::File::create(part).await.map_err(CopyError::io("create", part))?;
+                state.save(&state_path).await.map_err(CopyError::io("write", &state_path))?;
+                (state, (0..count).map(|i| (i * step, ((i + 1) * step).min(size))).collect::<Vec<_>>())
+            }
+        };
+
+        let done: Vec<AtomicU64> = ranges.iter().map(|_| AtomicU64::new(0)).collect();
+        report(progress, source, state.done());
+        // The ranges a crashed run left are checked against the source while
+        // the missing ones are fetched, and fetched again if they differ.
+        let resumed = state.ranges.clone();
+        let fetching = &AtomicUsize::new(0);
+        let (result, verified) = tokio::join!(
+            futures_util::future::try_join_all(ranges.iter().zip(&done).map(|(&(start, end), done)| async move {
+                let in_flight = |n| {
+                    if let Some(progress) = progress {
+                        progress.on_in_flight(source, n);
+                    }
+                };
+                in_flight(fetching.fetch_add(1, Ordering::Relaxed) + 1);
+                let result = self.fetch_range(url, source, part, start, end, done, progress, throttle, cancel).await;
+                in_flight(fetching.fetch_sub(1, Ordering::Relaxed) - 1);
+                result
+            })),
+            self.verify_resumed(url, source, part, &resumed),
+        );
+        let result = match verified {
+            Ok(true) => result.map(drop),
+            Ok(false) => {
+                log::warn!("The resumed part of {} doesn't match {}, fetching it again", part.display(), url);
+                for &(start, end) in &resumed {
+                    state.remove(start, end);
+                }
+                match result {
+                    Ok(_) => {
+                        let refetched = futures_util::future::try_join_all(resumed.iter().map(|&(start, end)| async move {
+                            let done = AtomicU64::new(0);
+                            self.fetch_range(url, source, part, start, end, &done, None, throttle, cancel)
+                                .await
+                        }))
+                        .await;
+                        refetched.map(drop)
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            Err(e) => result.map(drop).and(Err(e)),
+        };
+        match result {
+            Ok(_) => Ok(size),
+            Err(e) => {
+                for (&(start, _), done) in ranges.iter().zip(&done) {
+                    state.add(start, start + done.load(Ordering::Relaxed));
+                }
+                state.save(&state_path).await.map_err(CopyError::io("write", &state_path))?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Whether `resumed`, the ranges a download resumed with, hold the same
+    /// data in `part` as at `url`. Each range is fetched again and hashed.
+    async fn verify_resumed(
+        &self,
+        url: &str,
+        source: &Path,
+        part: &Path,
+        resumed: &[(u64, u64)],
+    ) -> Result<bool, CopyError> {
+        if resumed.is_empty() {
+            return Ok(true);
+        }
+        let (local, remote) = tokio::try_join!(
+            async { resume::hash_ranges(part, resumed).await.map_err(CopyError::io("read", part)) },
+            async {
+                let mut hasher = blake3::Hasher::new();
+                for &(start, end) in resumed {
+                    let mut response = self
+                        .client
+                        .get(url)
+                        .header(RANGE, format!("bytes={}-{}", start, end - 1))
+                        .send()
+                        .await
+                        .map_err(request_error)
+                        .map_err(CopyError::io("download", source))?;
+                    match response.status() {
+                        StatusCode::PARTIAL_CONTENT => {}
+                        status => return Err(CopyError::io("download", source)(status_error(status))),
+                    }
+                    while let Some(chunk) = response
+                        .chunk()
+                        .await
+                        .map_err(request_error)
+                        .map_err(CopyError::io("download", source))?
+                    {
+                        hasher.update(&chunk);
+                    }
+                }
+                Ok(hasher.finalize())
+            },
+        )?;
+        Ok(local == remote)
+    }
+
+    /// Fetches the rest of the range `start..end` of `url`, of which `done`
+    /// bytes are in `part` already.
+    #[allow(clippy::too_many_arguments)]
+    async fn fetch_range(
+        &self,
+        url: &str,
+        source: &Path,
+        part: &Path,
+        start: u64,
+        end: u64,
+        done: &AtomicU64,
+        progress: Option<&dyn ProgressSink>,
+        throttle: Option<&Throttle>,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<(), CopyError> {
+        let from = start + done.load(Ordering::Relaxed);
+        if from >= end {
+            return Ok(());
+        }
+        let mut response = self
+            .client
+            .get(url)
+            .header(RANGE, format!("bytes={}-{}", from, end - 1))
+            .send()
+            .await
+            .map_err(request_error)
+            .map_err(CopyError::io("download", source))?;
+        match response.status() {
+            StatusCode::PARTIAL_CONTENT => {}
+            status if status.is_success() => {
+                let ignored = io::Error::other("server ignored the range request");
+                return Err(CopyError::io("download", source)(ignored));
+            }
+            status => return Err(CopyError::io("download", source)(status_error(status))),
+        }
+
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .open(part)
+            .await
+            .map_err(CopyError::io("open", part))?;
+        file.seek(io::SeekFrom::Start(from)).await.map_err(CopyError::io("seek", part))?;
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(request_error)
+            .map_err(CopyError::io("download", source))?
+        {
+            let len = (chunk.len() as u64).min(end - start - done.load(Ordering::Relaxed));
+            pace(throttle, len, cancel).await?;
+            file.write_all(&chunk[..len as usize]).await.map_err(CopyError::io("write", part))?;
+            done.fetch_add(len, Ordering::Relaxed);
+            received(progress, source, len);
+        }
+        file.flush().await.map_err(CopyError::io("flush", part))?;
+        let written = done.load(Ordering::Relaxed);
+        if written != end - start {
+            return Err(CopyError::VerificationFailed {
+                path: source.to_path_buf(),
+                expected: end - start,
+                written,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Transport for HttpTransport {
+    fn display(&self, path: &str) -> String {
+        path.to_string()
+    }
+
+    /// The last segment of the URL's path, without the query, or the host
+    /// name for a URL without a path.
+    fn file_name(&self, path: &str) -> String {
+        let Ok(url) = Url::parse(path) else {
+            return crate::transport::remote_file_name(path).to_string();
+        };
+        url.path_segments()
+            .and_then(|mut segments| segments.rfind(|s| !s.is_empty()).map(str::to_string))
+            .or_else(|| url.host_str().map(str::to_string))
+            .unwrap_or_else(|| "index.html".to_string())
+    }
+
+    async fn stat(&self, path: &str) -> io::Result<Option<RemoteStat>> {
+        Ok(self.probe(path).await?.map(|probe| RemoteStat {
+            size: probe.size.unwrap_or(0),
+            is_dir: false,
+        }))
+    }
+
+    async fn list(&self, _path: &str) -> io::Result<Vec<RemoteEntry>> {
+        Err(read_only())
+    }
+
+    async fn create_dir(&self, _path: &str) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    async fn remove_file(&self, _path: &str) -> io::Result<()> {
+        Err(read_only())
+    }
+
+    async fn upload(
+        &self,
+        _from: &Path,
+        to: &str,
+        _progress: Option<&dyn ProgressSink>,
+        _throttle: Option<&Throttle>,
+        _cancel: Option<&CancellationToken>,
+    ) -> Result<u64, CopyError> {
+        Err(CopyError::io("upload", Path::new(to))(read_only()))
+    }
+
+    /// Downloads `from` to `to` through `to.part`, which is kept when the
+    /// download fails so that the next run can resume it.
+    async fn download(
+        &self,
+        from: &str,
+        to: &Path,
+        progress: Option<&dyn ProgressSink>,
+        throttle: Option<&Throttle>,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<u64, CopyError> {
+        let source = PathBuf::from(from);
+        let probe = self
+            .probe(from)
+            .await
+            .and_then(|probe| probe.ok_or_else(|| status_error(StatusCode::NOT_FOUND)))
+            .map_err(CopyError::io("download", &source))?;
+        let part = with_suffix(to, ".part");
+        let written = match probe {
+            Probe {
+                size: Some(size),
+                ranges: true,
+            } if self.segment_count(size) > 1 => {
+                self.fetch_segments(from, &source, &part, size, progress, throttle, cancel).await?
+            }
+            _ => self.fetch_stream(from, &source, &part, probe, progress, throttle, cancel).await?,
+        };
+        fs::rename(&part, to).await.map_err(CopyError::io("rename", &part))?;
+        let _ = fs::remove_file(with_suffix(&part, ".json")).await;
+        Ok(written)
+    }
+}
+
+fn report(progress: Option<&dyn ProgressSink>, source: &Path, bytes: u64) {
+    if let Some(progress) = progress
+        && bytes > 0
+    {
+        progress.on_bytes(source, bytes);
+    }
+}
+
+/// Reports `bytes` that came from the server and were written to the file.
+fn received(progress: Option<&dyn ProgressSink>, source: &Path, bytes: u64) {
+    report(progress, source, bytes);
+    if let Some(progress) = progress {
+        progress.on_network_bytes(source, bytes);
+    }
+}
+
+pub(crate) fn request_error(e: reqwest::Error) -> io::Error {
+    let kind = if e.is_timeout() { io::ErrorKind::TimedOut } else { io::ErrorKind::Other };
+    io::Error::new(kind, e)
+}
+
+/// An error for an unsuccessful response, of a kind that tells whether a
+/// retry could help.
+pub(crate) fn status_error(status: StatusCode) -> io::Error {
+    let kind = match status {
+        StatusCode::NOT_FOUND | StatusCode::GONE => io::ErrorKind::NotFound,
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => io::ErrorKind::PermissionDenied,
+        StatusCode::BAD_REQUEST | StatusCode::RANGE_NOT_SATISFIABLE => io::ErrorKind::InvalidInput,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, format!("HTTP {}", status))
+}
+
+fn read_only() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "URLs can only be downloaded")
+}
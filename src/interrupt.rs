@@ -0,0 +1,35 @@
+use colored::Colorize;
+use tokio_util::sync::CancellationToken;
+
+use crate::dashboard;
+use crate::exit;
+use crate::keys;
+use crate::snapshot;
+
+/// Installs the Ctrl-C handler used while files are being copied and returns
+/// the token it cancels.
+///
+/// The first Ctrl-C stops new files from being started and aborts the ones in
+/// flight so their partial destinations can be cleaned up; a second one exits
+/// immediately, only removing the snapshots sources are read from.
+pub(crate) fn install() -> CancellationToken {
+    let token = CancellationToken::new();
+    let cancel = token.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        eprintln!(
+            "{}",
+            "Interrupted, cleaning up partial files... (press Ctrl-C again to quit immediately)".yellow()
+        );
+        cancel.cancel();
+        if tokio::signal::ctrl_c().await.is_ok() {
+            keys::restore_terminal();
+            dashboard::restore_terminal();
+            snapshot::release_all();
+            std::process::exit(exit::INTERRUPTED);
+        }
+    });
+    token
+}
@@ -0,0 +1,197 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::resume::jobs_dir;
+use crate::snapshot;
+use crate::utils::CopyEntry;
+
+/// Version of the job file format, bumped on incompatible changes.
+const VERSION: u32 = 1;
+
+/// One line of a job file. The header comes first, then the planned
+/// directories and files, then a `done` line for every file copied so far,
+/// appended as the copy goes. A line cut short by a crash is ignored.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Line {
+    Header {
+        version: u32,
+        /// The command line of the copy, program name first.
+        args: Vec<String>,
+        /// Directory the copy was started in, which relative paths in
+        /// `args` are relative to.
+        cwd: PathBuf,
+        /// When the copy started, in seconds since the epoch.
+        started: u64,
+    },
+    Dir {
+        dir: (PathBuf, PathBuf),
+    },
+    File {
+        from: PathBuf,
+        to: PathBuf,
+        size: u64,
+    },
+    Done {
+        done: PathBuf,
+    },
+}
+
+/// A copy read back from its job file.
+pub(crate) struct JobPlan {
+    pub(crate) id: String,
+    path: PathBuf,
+    pub(crate) args: Vec<String>,
+    pub(crate) cwd: PathBuf,
+    pub(crate) started: u64,
+    /// Directories to create, as (source, destination) pairs.
+    pub(crate) dirs: Vec<(PathBuf, PathBuf)>,
+    /// Files not copied yet.
+    pub(crate) pending: Vec<CopyEntry>,
+    /// Number of files already copied.
+    pub(crate) done: usize,
+}
+
+impl JobPlan {
+    /// The job `job`, which is either the ID printed when it was
+    /// interrupted or the path of its file.
+    pub(crate) fn load(job: &str) -> io::Result<Self> {
+        let path = match Path::new(job).is_file() {
+            true => PathBuf::from(job),
+            false => jobs_dir().join(format!("{job}.jsonl")),
+        };
+        let file = std::fs::File::open(&path).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => io::Error::new(e.kind(), format!("no job {job}")),
+            _ => e,
+        })?;
+        let mut lines = io::BufReader::new(file).lines();
+        let Some(Ok(Line::Header { version, args, cwd, started })) =
+            lines.next().transpose()?.map(|line| serde_json::from_str(&line))
+        else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a job file", path.display())));
+        };
+        if version > VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("job {job} was written by a newer cp2"),
+            ));
+        }
+        let mut dirs = Vec::new();
+        let mut files = Vec::new();
+        let mut done = HashSet::new();
+        for line in lines {
+            match serde_json::from_str(&line?) {
+                Ok(Line::Dir { dir }) => dirs.push(dir),
+                Ok(Line::File { from, to, size }) => files.push(CopyEntry { from, to, size }),
+                Ok(Line::Done { done: to }) => {
+                    done.insert(to);
+                }
+                _ => {}
+            }
+        }
+        let pending: Vec<CopyEntry> = files.into_iter().filter(|file| !done.contains(&file.to)).collect();
+        Ok(Self {
+            id: path.file_stem().unwrap_or_default().to_string_lossy().into_owned(),
+            path,
+            args,
+            cwd,
+            started,
+            dirs,
+            pending,
+            done: done.len(),
+        })
+    }
+
+    /// Every job that can be resumed, oldest first.
+    pub(crate) fn list() -> Vec<Self> {
+        let mut jobs: Vec<Self> = std::fs::read_dir(jobs_dir())
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "jsonl"))
+            .filter_map(|entry| Self::load(&entry.path().to_string_lossy()).ok())
+            .collect();
+        jobs.sort_by_key(|job| job.started);
+        jobs
+    }
+}
+
+/// The job file of a running copy, to which finished files are appended.
+pub(crate) struct JobLog {
+    pub(crate) id: String,
+    path: PathBuf,
+    file: Mutex<std::fs::File>,
+}
+
+impl JobLog {
+    /// Starts the job file of a copy of `files` that creates `dirs`. Sources
+    /// read from a snapshot are recorded by their live paths, as the snapshot
+    /// is gone by the time the copy is resumed.
+    pub(crate) fn create(dirs: &[(PathBuf, PathBuf)], files: &[CopyEntry]) -> io::Result<Self> {
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let id = format!("{}-{}", started, std::process::id());
+        let dir = jobs_dir();
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("{id}.jsonl"));
+        let mut out = io::BufWriter::new(std::fs::File::create(&path)?);
+        let header = Line::Header {
+            version: VERSION,
+            args: std::env::args().collect(),
+            cwd: std::env::current_dir()?,
+            started,
+        };
+        let mut write = |line: &Line| -> io::Result<()> {
+            serde_json::to_writer(&mut out, line)?;
+            out.write_all(b"\n")
+        };
+        write(&header)?;
+        for (from, to) in dirs {
+            write(&Line::Dir {
+                dir: (snapshot::live(from), to.clone()),
+            })?;
+        }
+        for file in files {
+            write(&Line::File {
+                from: snapshot::live(&file.from),
+                to: file.to.clone(),
+                size: file.size,
+            })?;
+        }
+        let file = out.into_inner().map_err(|e| e.into_error())?;
+        Ok(Self {
+            id,
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Continues the job file of `plan`.
+    pub(crate) fn reopen(plan: &JobPlan) -> io::Result<Self> {
+        Ok(Self {
+            id: plan.id.clone(),
+            file: Mutex::new(std::fs::OpenOptions::new().append(true).open(&plan.path)?),
+            path: plan.path.clone(),
+        })
+    }
+
+    /// Records that the file to `to` was copied. Failing to is only logged:
+    /// at worst the file is copied again on resume.
+    pub(crate) fn done(&self, to: &Path) {
+        let mut line = serde_json::to_vec(&Line::Done { done: to.to_path_buf() }).expect("job lines serialize");
+        line.push(b'\n');
+        if let Err(e) = self.file.lock().unwrap().write_all(&line) {
+            log::warn!("Cannot update job file {}: {}", self.path.display(), e);
+        }
+    }
+
+    /// Removes the job file once the copy has finished.
+    pub(crate) fn remove(&self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            log::warn!("Cannot remove job file {}: {}", self.path.display(), e);
+        }
+    }
+}
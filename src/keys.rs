@@ -0,0 +1,133 @@
+use indicatif::ProgressBar;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::progress;
+use crate::throttle::Throttle;
+
+/// Lowest limit `-` will go down to.
+const MIN_LIMIT: u64 = 64 * 1024;
+
+/// Keyboard controls while the progress bars are on screen:
+///
+/// - `p` pauses all IO, `r` resumes it
+/// - `+` doubles the bandwidth limit, `-` halves it (starting from the
+///   current speed when there is no limit yet)
+///
+/// Only available on Unix terminals; elsewhere [`listen`] returns `None`.
+pub(crate) struct KeyListener {
+    stopped: Arc<AtomicBool>,
+}
+
+impl KeyListener {
+    /// Stops reacting to keys and gives the terminal back its normal mode.
+    pub(crate) fn stop(self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        tty::restore();
+    }
+}
+
+/// Starts listening for keys on stdin if it is a terminal. The current
+/// pause/limit state is shown as the message of `main_pb`.
+pub(crate) fn listen(throttle: Throttle, main_pb: Arc<ProgressBar>) -> Option<KeyListener> {
+    if !tty::enter() {
+        return None;
+    }
+    main_pb.set_message(status(&throttle));
+    let stopped = Arc::new(AtomicBool::new(false));
+    let stop = Arc::clone(&stopped);
+    // Reading stdin blocks, so this runs on a plain thread that is simply
+    // left behind when the process exits.
+    std::thread::spawn(move || {
+        use std::io::Read;
+        for key in std::io::stdin().lock().bytes() {
+            let Ok(key) = key else { break };
+            if stop.load(Ordering::Relaxed) {
+                break;
+            }
+            match key {
+                b'p' => throttle.pause(),
+                b'r' => throttle.resume(),
+                b'+' | b'=' => {
+                    if let Some(limit) = throttle.limit() {
+                        throttle.set_limit(Some(limit.saturating_mul(2)));
+                    }
+                }
+                b'-' | b'_' => {
+                    let current = throttle.limit().unwrap_or(main_pb.per_sec() as u64);
+                    throttle.set_limit(Some((current / 2).max(MIN_LIMIT)));
+                }
+                _ => continue,
+            }
+            main_pb.set_message(status(&throttle));
+        }
+    });
+    Some(KeyListener { stopped })
+}
+
+/// Restores the terminal if a listener changed its mode; used before exiting
+/// the process without going through [`KeyListener::stop`].
+pub(crate) fn restore_terminal() {
+    tty::restore();
+}
+
+fn status(throttle: &Throttle) -> String {
+    let limit = throttle.limit().map(|limit| format!("limit {}/s", progress::format_bytes(limit)));
+    match (throttle.is_paused(), limit) {
+        (true, Some(limit)) => format!("[paused, r to resume] {limit}"),
+        (true, None) => "[paused, r to resume]".to_string(),
+        (false, Some(limit)) => limit,
+        (false, None) => String::new(),
+    }
+}
+
+#[cfg(unix)]
+mod tty {
+    use std::sync::Mutex;
+
+    /// Terminal settings to put back once the listener stops.
+    static SAVED: Mutex<Option<libc::termios>> = Mutex::new(None);
+
+    /// Switches stdin to unbuffered, no-echo input so single key presses are
+    /// seen immediately. Signals and output processing are left alone, so
+    /// Ctrl-C and the progress bars behave as usual.
+    pub(super) fn enter() -> bool {
+        // SAFETY: plain termios calls on stdin with a zero-initialised struct.
+        unsafe {
+            if libc::isatty(libc::STDIN_FILENO) != 1 {
+                return false;
+            }
+            let mut term: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut term) != 0 {
+                return false;
+            }
+            let saved = term;
+            term.c_lflag &= !(libc::ICANON | libc::ECHO);
+            term.c_cc[libc::VMIN] = 1;
+            term.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &term) != 0 {
+                return false;
+            }
+            *SAVED.lock().unwrap() = Some(saved);
+        }
+        true
+    }
+
+    pub(super) fn restore() {
+        if let Some(saved) = SAVED.lock().unwrap().take() {
+            // SAFETY: restores settings previously read by tcgetattr.
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &saved);
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod tty {
+    pub(super) fn enter() -> bool {
+        false
+    }
+
+    pub(super) fn restore() {}
+}
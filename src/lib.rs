@@ -1,9 +1,66 @@
+pub mod blake3;
 pub mod cli;
+pub mod compress;
 pub mod config;
 pub mod copy;
+pub mod delta;
+pub mod diff;
+pub mod encrypt;
+pub mod engine;
+pub mod events;
+pub mod filter;
+pub mod hash;
+pub mod http;
+pub mod manifest;
+pub mod report;
 pub mod s3;
+pub mod sftp;
+pub mod sink;
+pub mod tar;
+pub mod throttle;
+pub mod transport;
 pub mod utils;
+pub mod webdav;
 
+pub(crate) mod attrs;
+pub(crate) mod cache;
+pub(crate) mod cmd_archive;
+pub(crate) mod cmd_bench;
 pub(crate) mod cmd_config;
+pub(crate) mod cmd_daemon;
+pub(crate) mod cmd_dedupe;
+pub(crate) mod cmd_diff;
+pub(crate) mod cmd_du;
+pub(crate) mod cmd_hash;
 pub(crate) mod cmd_local;
+pub(crate) mod cmd_remote;
+pub(crate) mod cmd_resume;
 pub(crate) mod cmd_s3;
+pub(crate) mod cmd_undo;
+pub(crate) mod cmd_verify;
+pub(crate) mod cmd_watch;
+pub(crate) mod dashboard;
+pub(crate) mod exit;
+pub(crate) mod failures;
+pub(crate) mod hooks;
+pub(crate) mod interrupt;
+pub(crate) mod job;
+pub(crate) mod keys;
+pub(crate) mod lock;
+pub(crate) mod logfile;
+pub(crate) mod notify;
+pub(crate) mod preflight;
+pub(crate) mod progress;
+pub(crate) mod resume;
+pub(crate) mod snapshot;
+pub(crate) mod timings;
+pub(crate) mod transaction;
+pub(crate) mod undo;
+pub(crate) mod watch;
+
+pub use copy::CopyError;
+pub use engine::{copy, copy_with_events, CopyEvents, CopyOptions, CopyReport, Overwrite};
+pub use events::CopyEvent;
+pub use sink::ProgressSink;
+pub use tokio_util::sync::CancellationToken;
+pub use utils::Preserve;
@@ -0,0 +1,91 @@
+use std::fs::{File, TryLockError};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the lock file a run keeps in its destination directory.
+pub(crate) const LOCK_FILE: &str = ".cp2.lock";
+
+/// An advisory lock on a destination directory, so that two runs don't
+/// write into it at once. The operating system releases it when cp2 exits,
+/// however it exits; the file itself is removed when the lock is dropped.
+pub(crate) struct DestinationLock {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+/// Why a destination couldn't be locked.
+pub(crate) enum LockError {
+    /// Another run holds the lock; its process ID, if it recorded one.
+    Held(Option<u32>),
+    Io(io::Error),
+}
+
+impl From<io::Error> for LockError {
+    fn from(e: io::Error) -> Self {
+        LockError::Io(e)
+    }
+}
+
+impl DestinationLock {
+    /// Locks `dir`, failing at once if another run holds the lock, or with
+    /// `wait` waiting for it to be released.
+    pub(crate) fn acquire(dir: &Path, wait: bool) -> Result<Self, LockError> {
+        let path = dir.join(LOCK_FILE);
+        loop {
+            let mut file = File::options().read(true).write(true).create(true).truncate(false).open(&path)?;
+            match file.try_lock() {
+                Ok(()) => {}
+                Err(TryLockError::WouldBlock) if wait => file.lock()?,
+                Err(TryLockError::WouldBlock) => return Err(LockError::Held(holder(&mut file))),
+                Err(TryLockError::Error(e)) => return Err(e.into()),
+            }
+            // The run that held the lock removes the file when it is done,
+            // so the lock may be on a file that is gone by now.
+            if !is_same_file(&file, &path) {
+                continue;
+            }
+            file.set_len(0)?;
+            write!(file, "{}", std::process::id())?;
+            return Ok(Self { path, file: Some(file) });
+        }
+    }
+}
+
+impl Drop for DestinationLock {
+    fn drop(&mut self) {
+        // On Unix the file is removed while still locked, so that no other
+        // run can lock it in between. Windows can't remove open files, and
+        // fails to if another run has opened it meanwhile, which is fine.
+        if cfg!(not(unix)) {
+            self.file.take();
+        }
+        match std::fs::remove_file(&self.path) {
+            Ok(()) => {}
+            Err(e) if cfg!(not(unix)) => log::debug!("Leaving lock file {}: {}", self.path.display(), e),
+            Err(e) => log::warn!("Cannot remove lock file {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+fn holder(file: &mut File) -> Option<u32> {
+    let mut pid = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut pid).ok()?;
+    pid.trim().parse().ok()
+}
+
+#[cfg(unix)]
+fn is_same_file(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (file.metadata(), std::fs::metadata(path)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+/// Files that are open can't be removed on Windows, so the file at `path`
+/// is still the one locked.
+#[cfg(not(unix))]
+fn is_same_file(_file: &File, path: &Path) -> bool {
+    path.exists()
+}
@@ -0,0 +1,102 @@
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::progress;
+
+/// Append-only per-file log written with `--log-file`, independent of the
+/// terminal verbosity. Each line is prefixed with a UTC timestamp.
+pub(crate) struct FileLog {
+    file: Mutex<std::fs::File>,
+}
+
+impl FileLog {
+    pub(crate) fn open(path: &Path) -> std::io::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Writes a free-form line such as the run start/end markers.
+    pub(crate) fn note(&self, message: &str) {
+        self.write_line(&format!("[{}] {}", format_utc(SystemTime::now()), message));
+    }
+
+    /// Records one file's outcome with its start/end times and throughput.
+    pub(crate) fn file(
+        &self,
+        source: &Path,
+        destination: &str,
+        started: SystemTime,
+        bytes: u64,
+        error: Option<&str>,
+    ) {
+        let ended = SystemTime::now();
+        let secs = ended
+            .duration_since(started)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let rate = if secs > 0.0 {
+            (bytes as f64 / secs) as u64
+        } else {
+            bytes
+        };
+        let result = match error {
+            None => "ok".to_string(),
+            Some(e) => format!("failed: {e}"),
+        };
+        self.write_line(&format!(
+            "[{}] {} -> {} start={} bytes={} duration={:.3}s rate={}/s result={}",
+            format_utc(ended),
+            source.display(),
+            destination,
+            format_utc(started),
+            bytes,
+            secs,
+            progress::format_bytes(rate),
+            result
+        ));
+    }
+
+    fn write_line(&self, line: &str) {
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = writeln!(file, "{line}") {
+            log::warn!("failed to write to log file: {}", e);
+        }
+    }
+}
+
+/// Formats a timestamp as RFC 3339 in UTC with millisecond precision, e.g.
+/// `2024-05-01T13:37:00.123Z`.
+pub(crate) fn format_utc(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+
+    // Civil-from-days conversion (Howard Hinnant's algorithm).
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    )
+}
@@ -0,0 +1,113 @@
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::Path;
+
+use crate::hash::{self, HashAlgorithm};
+
+/// Version written to new manifests. Readers accept any manifest of this
+/// version or older; fields are only ever added.
+pub const VERSION: u32 = 1;
+
+/// How `--manifest` writes the manifest.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ManifestFormat {
+    /// cp2's own JSON, with sizes and modification times.
+    #[default]
+    Json,
+    /// hashdeep's CSV of sizes, digests and paths, for `hashdeep -a -k`.
+    Hashdeep,
+    /// `SHA256 (path) = digest` lines, as BSD `sha256` and `shasum --tag`
+    /// write them.
+    Bsd,
+    /// `digest  path` lines, as `sha256sum` and `b3sum` write them.
+    Gnu,
+}
+
+/// The files a run copied, as written by `--manifest` and read by
+/// `cp2 verify --manifest`:
+///
+/// ```json
+/// {"version":1,"algorithm":"sha256","files":[{"path":"photos/a.jpg","size":5120,"mtime":1714570620,"hash":"9f86d0…"}]}
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    /// Algorithm of every `hash` in `files`.
+    pub algorithm: HashAlgorithm,
+    /// Sorted by path.
+    pub files: Vec<ManifestEntry>,
+}
+
+/// One copied file.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Path relative to the destination, with `/` separators.
+    pub path: String,
+    pub size: u64,
+    /// Modification time of the source, in seconds since the Unix epoch.
+    pub mtime: Option<u64>,
+    /// Digest of the contents as lowercase hex.
+    pub hash: String,
+}
+
+impl Manifest {
+    pub fn new(algorithm: HashAlgorithm, mut files: Vec<ManifestEntry>) -> Self {
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        Self {
+            version: VERSION,
+            algorithm,
+            files,
+        }
+    }
+
+    pub async fn read(path: &Path) -> io::Result<Self> {
+        let contents = tokio::fs::read(path).await?;
+        let manifest: Manifest =
+            serde_json::from_slice(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if manifest.version > VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("manifest version {} is newer than this cp2 understands", manifest.version),
+            ));
+        }
+        Ok(manifest)
+    }
+
+    /// The manifest in `format`. hashdeep lists paths relative to
+    /// `root`, which it records as the directory it was invoked from.
+    pub fn render(&self, format: ManifestFormat, root: &Path) -> io::Result<String> {
+        let algorithm = match self.algorithm {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+        };
+        Ok(match format {
+            ManifestFormat::Json => serde_json::to_string_pretty(self).map_err(io::Error::other)? + "\n",
+            ManifestFormat::Hashdeep => {
+                let mut out = format!(
+                    "%%%% HASHDEEP-1.0\n%%%% size,{algorithm},filename\n## Invoked from: {}\n## $ {}\n##\n",
+                    root.display(),
+                    std::env::args().collect::<Vec<_>>().join(" ")
+                );
+                for file in &self.files {
+                    out.push_str(&format!("{},{},{}\n", file.size, file.hash, file.path));
+                }
+                out
+            }
+            ManifestFormat::Bsd => self
+                .files
+                .iter()
+                .map(|file| format!("{} ({}) = {}\n", algorithm.to_ascii_uppercase(), file.path, file.hash))
+                .collect(),
+            ManifestFormat::Gnu => self.files.iter().map(|file| hash::sums_line(&file.hash, &file.path)).collect(),
+        })
+    }
+
+    /// Writes the manifest to `path` in `format`, under a temporary name
+    /// first, so an existing manifest is only replaced by a complete one.
+    pub async fn write(&self, path: &Path, format: ManifestFormat, root: &Path) -> io::Result<()> {
+        let contents = self.render(format, root)?;
+        let temp = crate::resume::with_suffix(path, ".tmp");
+        tokio::fs::write(&temp, contents).await?;
+        tokio::fs::rename(&temp, path).await
+    }
+}
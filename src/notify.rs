@@ -0,0 +1,39 @@
+use notify_rust::Notification;
+use std::sync::atomic::Ordering;
+
+use crate::progress::{RunStats, files, format_bytes, format_elapsed};
+
+/// Shows a native desktop notification with the run's totals, e.g.
+/// "Copy complete" / "51 files, 47.68 MiB in 1m02s".
+///
+/// Failing to notify (no notification daemon, headless session, ...) only
+/// logs a warning; it never changes the outcome of the run.
+pub(crate) async fn run_finished(summary: String, stats: &RunStats) {
+    let copied = stats.files_done.load(Ordering::Relaxed);
+    let failed_files = stats.files_failed.load(Ordering::Relaxed);
+    let mut body = format!(
+        "{}, {} in {}",
+        files(copied),
+        format_bytes(stats.bytes_copied.load(Ordering::Relaxed)),
+        format_elapsed(stats.started.elapsed())
+    );
+    if failed_files > 0 {
+        body.push_str(&format!("; {} failed", files(failed_files)));
+    }
+
+    // Delivery can block on the desktop's notification service.
+    let shown = tokio::task::spawn_blocking(move || {
+        Notification::new()
+            .appname("cp2")
+            .summary(&summary)
+            .body(&body)
+            .show()
+            .map(|_| ())
+    })
+    .await;
+    match shown {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => log::warn!("failed to show desktop notification: {}", e),
+        Err(e) => log::warn!("failed to show desktop notification: {}", e),
+    }
+}
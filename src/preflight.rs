@@ -0,0 +1,407 @@
+use colored::Colorize;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::events::EventSink;
+use crate::failures;
+use crate::progress;
+use crate::utils::CopyEntry;
+
+/// Verifies that `entries` fit into the free space of the filesystem holding
+/// `destination`. Space taken by destination files that will be overwritten
+/// counts as available, since they are truncated first, unless
+/// `keeps_overwritten` says they stay on disk until the copy is done: moved
+/// aside for `cp2 undo`, or next to staged copies of a transactional run.
+///
+/// Passes when the free space can't be determined (e.g. on platforms
+/// without `statvfs`).
+pub(crate) fn check_free_space(destination: &Path, entries: &[CopyEntry], keeps_overwritten: bool) -> Result<(), String> {
+    let Some(available) = available_space(destination) else {
+        log::debug!("Free space of {} is unknown, skipping check", destination.display());
+        return Ok(());
+    };
+    let needed: u64 = entries
+        .iter()
+        .map(|entry| match keeps_overwritten {
+            true => entry.size,
+            false => {
+                let existing = std::fs::metadata(&entry.to).map(|m| m.len()).unwrap_or(0);
+                entry.size.saturating_sub(existing)
+            }
+        })
+        .sum();
+    log::debug!(
+        "Destination {} needs {} bytes, {} available",
+        destination.display(),
+        needed,
+        available
+    );
+    if needed > available {
+        return Err(format!(
+            "{} needed but only {} available on {}",
+            progress::format_bytes(needed),
+            progress::format_bytes(available),
+            destination.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Bytes available to unprivileged users on the filesystem holding `path`.
+#[cfg(unix)]
+fn available_space(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    // SAFETY: `path` is a valid C string and `stat` a zero-initialised struct
+    // that statvfs fills in.
+    unsafe {
+        let mut stat: libc::statvfs = std::mem::zeroed();
+        if libc::statvfs(path.as_ptr(), &mut stat) != 0 {
+            return None;
+        }
+        #[allow(clippy::unnecessary_cast)]
+        Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+    }
+}
+
+#[cfg(not(unix))]
+fn available_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Largest file FAT12/16/32 can hold.
+const FAT_MAX_FILE_SIZE: u64 = (4 << 30) - 1;
+
+/// Largest file the filesystem holding `destination` can store, if it is
+/// known to have a limit below what cp2 may copy (i.e. it is FAT).
+#[cfg(target_os = "linux")]
+pub(crate) fn max_file_size(destination: &Path) -> Option<u64> {
+    const MSDOS_SUPER_MAGIC: u32 = 0x4d44;
+    (fs_magic(destination)? == MSDOS_SUPER_MAGIC).then_some(FAT_MAX_FILE_SIZE)
+}
+
+/// The `f_type` magic number statfs reports for the filesystem holding `path`.
+#[cfg(target_os = "linux")]
+pub(crate) fn fs_magic(path: &Path) -> Option<u32> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    // SAFETY: `path` is a valid C string and `stat` a zero-initialised struct
+    // that statfs fills in.
+    unsafe {
+        let mut stat: libc::statfs = std::mem::zeroed();
+        if libc::statfs(path.as_ptr(), &mut stat) != 0 {
+            return None;
+        }
+        // f_type is signed on some targets; the magic numbers are 32 bits.
+        Some(stat.f_type as u32)
+    }
+}
+
+/// Largest file the filesystem holding `destination` can store, if it is
+/// known to have a limit below what cp2 may copy (i.e. it is FAT).
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+pub(crate) fn max_file_size(destination: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(destination.as_os_str().as_bytes()).ok()?;
+    // SAFETY: `path` is a valid C string and `stat` a zero-initialised struct
+    // that statfs fills in; f_fstypename is NUL-terminated.
+    let is_fat = unsafe {
+        let mut stat: libc::statfs = std::mem::zeroed();
+        if libc::statfs(path.as_ptr(), &mut stat) != 0 {
+            return None;
+        }
+        std::ffi::CStr::from_ptr(stat.f_fstypename.as_ptr()).to_bytes() == b"msdos"
+    };
+    is_fat.then_some(FAT_MAX_FILE_SIZE)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "freebsd")))]
+pub(crate) fn max_file_size(_destination: &Path) -> Option<u64> {
+    None
+}
+
+/// Whether `path` is on an SMB/CIFS network share.
+#[cfg(target_os = "linux")]
+pub(crate) fn is_smb(path: &Path) -> bool {
+    const CIFS_MAGIC_NUMBER: u32 = 0xff53_4d42;
+    const SMB2_MAGIC_NUMBER: u32 = 0xfe53_4d42;
+    const SMB_SUPER_MAGIC: u32 = 0x517b;
+    matches!(fs_magic(path), Some(CIFS_MAGIC_NUMBER | SMB2_MAGIC_NUMBER | SMB_SUPER_MAGIC))
+}
+
+/// Whether `path` is on an SMB/CIFS network share.
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+pub(crate) fn is_smb(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    let Ok(path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: `path` is a valid C string and `stat` a zero-initialised struct
+    // that statfs fills in; f_fstypename is NUL-terminated.
+    unsafe {
+        let mut stat: libc::statfs = std::mem::zeroed();
+        libc::statfs(path.as_ptr(), &mut stat) == 0
+            && std::ffi::CStr::from_ptr(stat.f_fstypename.as_ptr()).to_bytes() == b"smbfs"
+    }
+}
+
+/// Whether `path` is on an SMB network share, i.e. a UNC path like
+/// `\\server\share`. Mapped drive letters aren't detected.
+#[cfg(windows)]
+pub(crate) fn is_smb(path: &Path) -> bool {
+    let path = path.as_os_str().to_string_lossy();
+    (path.starts_with(r"\\") && !path.starts_with(r"\\?\")) || path.starts_with(r"\\?\UNC\")
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "freebsd", windows)))]
+pub(crate) fn is_smb(_path: &Path) -> bool {
+    false
+}
+
+/// Longest paths Windows accepts without the `\\?\` extended-length prefix.
+#[cfg(windows)]
+const WINDOWS_MAX_PATH: usize = 259;
+
+/// Longest file name and path the destination filesystem accepts, measured
+/// in bytes (UTF-16 units on Windows).
+pub(crate) struct PathLimits {
+    name_max: usize,
+    path_max: usize,
+}
+
+impl PathLimits {
+    /// Limits of the filesystem holding `destination`, as reported by
+    /// `pathconf` on Unix. On Windows paths are limited to 32767 units
+    /// through the extended-length prefix.
+    pub(crate) fn of(destination: &Path) -> Self {
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+
+            let limit = |name, default| {
+                let Ok(path) = std::ffi::CString::new(destination.as_os_str().as_bytes()) else {
+                    return default;
+                };
+                // SAFETY: `path` is a valid C string.
+                match unsafe { libc::pathconf(path.as_ptr(), name) } {
+                    n if n > 0 => n as usize,
+                    _ => default,
+                }
+            };
+            PathLimits {
+                name_max: limit(libc::_PC_NAME_MAX, 255),
+                path_max: limit(libc::_PC_PATH_MAX, 4096),
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = destination;
+            PathLimits {
+                name_max: 255,
+                path_max: 32767,
+            }
+        }
+    }
+
+    /// Checks a planned destination path against the limits. On Windows a
+    /// path longer than `MAX_PATH` is switched to its `\\?\` extended-length
+    /// form, which lifts that limit.
+    pub(crate) fn check(&self, path: &mut PathBuf) -> Result<(), String> {
+        if let Some(name) = path.file_name()
+            && units(name) > self.name_max
+        {
+            return Err(format!("file name too long ({} > {})", units(name), self.name_max));
+        }
+
+        let absolute = std::path::absolute(&*path).unwrap_or_else(|_| path.clone());
+        #[cfg(windows)]
+        if units(absolute.as_os_str()) > WINDOWS_MAX_PATH
+            && let Some(extended) = extended_length(&absolute)
+        {
+            *path = extended;
+            return self.check_total(path);
+        }
+        self.check_total(&absolute)
+    }
+
+    fn check_total(&self, absolute: &Path) -> Result<(), String> {
+        let length = units(absolute.as_os_str());
+        if length > self.path_max {
+            return Err(format!("path too long ({} > {})", length, self.path_max));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+fn units(s: &std::ffi::OsStr) -> usize {
+    use std::os::windows::ffi::OsStrExt;
+    s.encode_wide().count()
+}
+
+#[cfg(not(windows))]
+fn units(s: &std::ffi::OsStr) -> usize {
+    s.len()
+}
+
+/// The `\\?\` form of an absolute path (`\\?\UNC\` for network shares).
+#[cfg(windows)]
+fn extended_length(absolute: &Path) -> Option<PathBuf> {
+    let s = absolute.to_str()?;
+    if s.starts_with(r"\\?\") {
+        return None;
+    }
+    Some(match s.strip_prefix(r"\\") {
+        Some(unc) => PathBuf::from(format!(r"\\?\UNC\{}", unc)),
+        None => PathBuf::from(format!(r"\\?\{}", s)),
+    })
+}
+
+/// Whether the filesystem holding `destination` ignores letter case in
+/// file names, found by creating a probe file and looking it up in upper
+/// case. Destinations that can't be probed count as case-sensitive.
+pub(crate) fn is_case_insensitive(destination: &Path) -> bool {
+    let name = format!(".cp2-case-probe-{}", std::process::id());
+    let probe = destination.join(&name);
+    if std::fs::File::create(&probe).is_err() {
+        return false;
+    }
+    let insensitive = destination.join(name.to_uppercase()).exists();
+    let _ = std::fs::remove_file(&probe);
+    insensitive
+}
+
+/// How many paths of each kind the preflight report lists before
+/// summarising the rest.
+const REPORT_LIMIT: usize = 10;
+
+/// Problems found before copying, presented together before any bars appear.
+#[derive(Default)]
+pub(crate) struct Report {
+    /// Sources that can't be opened for reading, with the reason.
+    unreadable: Vec<(PathBuf, String)>,
+    /// Existing destination files that will be overwritten.
+    collisions: Vec<PathBuf>,
+    /// Why nothing can be written to the destination, if that's the case.
+    unwritable: Option<String>,
+}
+
+impl Report {
+    /// Opens the sources the scan flagged as `maybe_unreadable`, looks for
+    /// existing destination files and probes whether `destination` accepts
+    /// new files. Each destination directory is listed once rather than
+    /// every destination looked up, so large trees don't pay for a second
+    /// pass of system calls.
+    pub(crate) fn check(destination: &Path, entries: &[CopyEntry], maybe_unreadable: &[PathBuf]) -> Self {
+        let mut report = Report::default();
+        for path in maybe_unreadable {
+            if let Err(e) = std::fs::File::open(path) {
+                report.unreadable.push((path.clone(), e.to_string()));
+            }
+        }
+        let mut listings: HashMap<&Path, Option<HashSet<OsString>>> = HashMap::new();
+        for entry in entries {
+            let (Some(parent), Some(name)) = (entry.to.parent(), entry.to.file_name()) else {
+                continue;
+            };
+            let names = listings.entry(parent).or_insert_with(|| {
+                std::fs::read_dir(parent)
+                    .ok()
+                    .map(|dir| dir.flatten().map(|e| e.file_name()).collect())
+            });
+            if names.as_ref().is_some_and(|names| names.contains(name)) {
+                report.collisions.push(entry.to.clone());
+            }
+        }
+
+        let probe = destination.join(format!(".cp2-preflight-{}", std::process::id()));
+        match std::fs::File::create(&probe) {
+            Ok(_) => {
+                let _ = std::fs::remove_file(&probe);
+            }
+            Err(e) => report.unwritable = Some(e.to_string()),
+        }
+        report
+    }
+
+    pub(crate) fn is_clean(&self) -> bool {
+        self.unreadable.is_empty() && self.collisions.is_empty() && self.unwritable.is_none()
+    }
+
+    /// Whether the copy can't succeed at all.
+    pub(crate) fn is_fatal(&self) -> bool {
+        self.unwritable.is_some()
+    }
+
+    /// Sends the problems that stop files from being copied as error events.
+    pub(crate) fn emit(&self, destination: &Path, events: &EventSink) {
+        for (path, e) in &self.unreadable {
+            events.error(Some(path), format!("source is not readable: {e}"));
+        }
+        if let Some(ref e) = self.unwritable {
+            events.error(Some(destination), format!("destination is not writable: {e}"));
+        }
+    }
+
+    /// Prints why the destination can't be written to, for runs that don't
+    /// print the whole report; a JSON object with `--errors json`.
+    pub(crate) fn print_fatal(&self, destination: &Path) {
+        if let Some(ref e) = self.unwritable {
+            failures::print_error(Some(destination), "preflight check", "destination is not writable".to_string().into(), e, || {
+                eprintln!("{} {}: {}", "Destination is not writable:".red(), destination.display(), e)
+            });
+        }
+    }
+
+    /// Prints the report to stderr. Prints nothing if it is clean.
+    pub(crate) fn print(&self, destination: &Path) {
+        if self.is_clean() {
+            return;
+        }
+        eprintln!("{}", "Preflight check:".bold());
+        if let Some(ref e) = self.unwritable {
+            eprintln!("  {} {}: {}", "Destination is not writable:".red(), destination.display(), e);
+        }
+        if !self.unreadable.is_empty() {
+            eprintln!("  {}", format!("Unreadable sources ({}):", self.unreadable.len()).red());
+            for (path, e) in self.unreadable.iter().take(REPORT_LIMIT) {
+                eprintln!("    {}: {}", path.display(), e);
+            }
+            print_remainder(self.unreadable.len());
+        }
+        if !self.collisions.is_empty() {
+            eprintln!(
+                "  {}",
+                format!("Existing files that will be overwritten ({}):", self.collisions.len()).yellow()
+            );
+            for path in self.collisions.iter().take(REPORT_LIMIT) {
+                eprintln!("    {}", path.display());
+            }
+            print_remainder(self.collisions.len());
+        }
+    }
+}
+
+fn print_remainder(total: usize) {
+    if total > REPORT_LIMIT {
+        eprintln!("    ... and {} more", total - REPORT_LIMIT);
+    }
+}
+
+/// Asks a yes/no question on the terminal; anything but `y`/`yes` is a no.
+pub(crate) fn confirm(question: &str) -> bool {
+    eprint!("{} [y/N]: ", question);
+    let _ = std::io::stderr().flush();
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    let answer = answer.trim();
+    answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes")
+}
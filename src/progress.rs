@@ -0,0 +1,807 @@
+use indicatif::{
+    DecimalBytes, HumanBytes, HumanCount, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressState, ProgressStyle,
+};
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+use crate::dashboard::{self, Dashboard};
+use crate::events::{CopyEvent, EventSink};
+use crate::sink::ProgressSink;
+use colored::Colorize;
+
+/// How progress is reported while files are being copied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ProgressMode {
+    /// Animated indicatif bars: one overall bar plus one per file.
+    Bars,
+    /// Periodic single-line status updates, for CI logs and `nohup` output.
+    Plain,
+    /// A full-screen dashboard on the terminal's alternate screen.
+    Tui,
+    /// No display; progress is reported through `progress` events only.
+    Json,
+    /// No progress output at all.
+    Hidden,
+}
+
+/// Whether sizes are shown in SI units (kB, MB, GB) rather than binary ones
+/// (KiB, MiB, GiB). Set once from `--units` before anything is displayed.
+static SI_UNITS: AtomicBool = AtomicBool::new(false);
+
+/// Selects SI units (`true`) or binary units for every size cp2 displays.
+pub(crate) fn set_si_units(si: bool) {
+    SI_UNITS.store(si, Ordering::Relaxed);
+}
+
+/// Most per-file bars shown at once; set from `--max-bars`.
+static MAX_FILE_BARS: AtomicUsize = AtomicUsize::new(10);
+
+/// Limits how many per-file bars are shown at once.
+pub(crate) fn set_max_file_bars(max: usize) {
+    MAX_FILE_BARS.store(max, Ordering::Relaxed);
+}
+
+/// Formats a byte count in the units chosen with `--units`.
+pub(crate) fn format_bytes(bytes: u64) -> String {
+    if SI_UNITS.load(Ordering::Relaxed) {
+        DecimalBytes(bytes).to_string()
+    } else {
+        HumanBytes(bytes).to_string()
+    }
+}
+
+/// A built-in look for the progress bars, selected with `--theme`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum Theme {
+    /// Spinners and `=>-` bars with elapsed time and ETA
+    #[default]
+    Classic,
+    /// Percentages and figures only, no bars or spinners
+    Minimal,
+    /// Smooth bars drawn with Unicode block characters
+    Blocks,
+    /// Like classic, but without any Unicode characters
+    Ascii,
+}
+
+impl Theme {
+    /// Template of the overall progress bar.
+    fn main_template(self) -> &'static str {
+        match self {
+            Theme::Classic | Theme::Blocks => {
+                "{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} {files} {bytes_per_sec}{net} ({smooth_eta}) {msg}"
+            }
+            Theme::Minimal => "{percent:>3}% {bytes}/{total_bytes} {files} {bytes_per_sec}{net} ETA {smooth_eta} {msg}",
+            Theme::Ascii => {
+                "{spinner} [{elapsed_precise}] [{wide_bar}] {bytes}/{total_bytes} {files} {bytes_per_sec}{net} ({smooth_eta}) {msg}"
+            }
+        }
+    }
+
+    /// Template of the per-file progress bars.
+    fn file_template(self) -> &'static str {
+        match self {
+            Theme::Classic | Theme::Blocks => {
+                "  {spinner:.green} {msg:<30} [{wide_bar:.yellow/blue}] {bytes}/{total_bytes} {bytes_per_sec}"
+            }
+            Theme::Minimal => "  {msg:<30} {percent:>3}% {bytes_per_sec}",
+            Theme::Ascii => "  {spinner} {msg:<30} [{wide_bar}] {bytes}/{total_bytes} {bytes_per_sec}",
+        }
+    }
+
+    /// Template of the spinner shown while scanning.
+    fn scan_template(self) -> &'static str {
+        match self {
+            Theme::Classic | Theme::Blocks => "{spinner:.green} Scanning… {human_pos} files, {total_bytes} found",
+            Theme::Minimal => "Scanning: {human_pos} files, {total_bytes} found",
+            Theme::Ascii => "{spinner} Scanning... {human_pos} files, {total_bytes} found",
+        }
+    }
+
+    /// Characters for the filled part, the tip and the empty part of a bar.
+    fn progress_chars(self) -> &'static str {
+        match self {
+            Theme::Blocks => "█▉▊▋▌▍▎▏ ",
+            Theme::Classic | Theme::Minimal | Theme::Ascii => "=>-",
+        }
+    }
+
+    /// Spinner frames, the last one shown once finished, where the default
+    /// braille ones won't do.
+    fn tick_chars(self) -> Option<&'static str> {
+        match self {
+            Theme::Ascii => Some("|/-\\ "),
+            Theme::Classic | Theme::Minimal | Theme::Blocks => None,
+        }
+    }
+
+    /// Applies the theme's bar and spinner characters to `style`.
+    fn apply(self, style: ProgressStyle) -> ProgressStyle {
+        let style = style.progress_chars(self.progress_chars());
+        match self.tick_chars() {
+            Some(ticks) => style.tick_chars(ticks),
+            None => style,
+        }
+    }
+}
+
+/// How the progress bars look, as chosen on the command line.
+#[derive(Default)]
+pub(crate) struct BarLook {
+    /// Built-in look the templates below are layered over (`--theme`).
+    pub(crate) theme: Theme,
+    /// Replaces the overall bar's template (`--bar-template`).
+    pub(crate) main_template: Option<String>,
+    /// Replaces the per-file bars' template (`--file-bar-template`).
+    pub(crate) file_template: Option<String>,
+}
+
+/// Set once from the command line before any bar is drawn.
+static BAR_LOOK: OnceLock<BarLook> = OnceLock::new();
+
+/// Applies the command-line choices for how bars look.
+pub(crate) fn set_bar_look(look: BarLook) {
+    let _ = BAR_LOOK.set(look);
+}
+
+fn bar_look() -> &'static BarLook {
+    BAR_LOOK.get_or_init(BarLook::default)
+}
+
+/// Checks that `template` is a valid indicatif template, for
+/// `--bar-template` and `--file-bar-template`.
+pub(crate) fn parse_template(template: &str) -> Result<String, String> {
+    ProgressStyle::with_template(template)
+        .map(|_| template.to_string())
+        .map_err(|e| e.to_string())
+}
+
+/// Switches the byte placeholders of a bar template to SI units when they
+/// were chosen with `--units`.
+fn units_template(template: &str) -> String {
+    if SI_UNITS.load(Ordering::Relaxed) {
+        template
+            .replace("{bytes}", "{decimal_bytes}")
+            .replace("{total_bytes}", "{decimal_total_bytes}")
+            .replace("{bytes_per_sec}", "{decimal_bytes_per_sec}")
+    } else {
+        template.to_string()
+    }
+}
+
+/// How often a status line is printed in [`ProgressMode::Plain`].
+const PLAIN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often the OSC 9;4 terminal progress indicator is refreshed.
+const TERM_PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often a `progress` event is emitted while events are enabled.
+const EVENT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Counters shared by all copy tasks of a run.
+pub(crate) struct RunStats {
+    pub(crate) files_done: AtomicU64,
+    pub(crate) files_skipped: AtomicU64,
+    pub(crate) files_failed: AtomicU64,
+    pub(crate) bytes_copied: AtomicU64,
+    pub(crate) started: Instant,
+}
+
+impl RunStats {
+    pub(crate) fn new() -> Self {
+        Self {
+            files_done: AtomicU64::new(0),
+            files_skipped: AtomicU64::new(0),
+            files_failed: AtomicU64::new(0),
+            bytes_copied: AtomicU64::new(0),
+            started: Instant::now(),
+        }
+    }
+
+    /// Prints the human-readable end-of-run summary to stderr.
+    pub(crate) fn print_summary(&self, verb: &str) {
+        let copied = self.files_done.load(Ordering::Relaxed);
+        let skipped = self.files_skipped.load(Ordering::Relaxed);
+        let failed = self.files_failed.load(Ordering::Relaxed);
+        let bytes = self.bytes_copied.load(Ordering::Relaxed);
+        let elapsed = self.started.elapsed();
+        let average = match elapsed.as_secs_f64() {
+            secs if secs > 0.0 => (bytes as f64 / secs) as u64,
+            _ => bytes,
+        };
+
+        let failed_line = format!("  Failed:   {}", files(failed));
+        eprintln!("Summary:");
+        eprintln!("  {:<9} {}", format!("{verb}:"), files(copied));
+        eprintln!("  Skipped:  {}", files(skipped));
+        if failed > 0 {
+            eprintln!("{}", failed_line.red());
+        } else {
+            eprintln!("{}", failed_line);
+        }
+        eprintln!(
+            "  Total:    {} in {} (avg {}/s)",
+            format_bytes(bytes),
+            format_elapsed(elapsed),
+            format_bytes(average)
+        );
+    }
+
+    /// How many of `total_files` were neither copied, skipped nor failed,
+    /// e.g. because the run was interrupted.
+    pub(crate) fn not_finished(&self, total_files: u64) -> u64 {
+        total_files
+            .saturating_sub(self.files_done.load(Ordering::Relaxed))
+            .saturating_sub(self.files_skipped.load(Ordering::Relaxed))
+            .saturating_sub(self.files_failed.load(Ordering::Relaxed))
+    }
+
+    /// The final [`CopyEvent::Summary`] for these counters.
+    pub(crate) fn summary_event(&self) -> CopyEvent {
+        CopyEvent::Summary {
+            files_copied: self.files_done.load(Ordering::Relaxed),
+            files_skipped: self.files_skipped.load(Ordering::Relaxed),
+            files_failed: self.files_failed.load(Ordering::Relaxed),
+            bytes_copied: self.bytes_copied.load(Ordering::Relaxed),
+            elapsed_secs: self.started.elapsed().as_secs_f64(),
+        }
+    }
+}
+
+/// Time constant of the smoothed throughput used for the ETA. Longer values
+/// ride out bursts of tiny files; shorter ones react faster to slowdowns.
+const ETA_SMOOTHING: Duration = Duration::from_secs(10);
+
+/// Style for the overall progress bar shown above the per-file bars.
+///
+/// `stats` is shared with the copy tasks, which bump `files_done` as each file
+/// completes; the bar renders it as `done/total files`. The ETA comes from
+/// the [`SpeedTracker`]'s smoothed rate rather than indicatif's estimator.
+/// Once bytes have crossed the network, `{net}` adds the network throughput
+/// from `network`, e.g. ` (net 3.20 MiB/s)`, next to that of the files.
+pub(crate) fn main_bar_style(
+    stats: Arc<RunStats>,
+    total_files: u64,
+    speed: &SpeedTracker,
+    network: &NetworkMeter,
+) -> ProgressStyle {
+    let rate = Arc::clone(&speed.rate);
+    let network_rate = Arc::clone(&network.speed.rate);
+    let network_pb = network.bar.clone();
+    let look = bar_look();
+    let template = look.main_template.as_deref().unwrap_or(look.theme.main_template());
+    let style = ProgressStyle::default_bar()
+        .template(&units_template(template))
+        .unwrap()
+        .with_key("files", move |_: &ProgressState, w: &mut dyn Write| {
+            let done = stats.files_done.load(Ordering::Relaxed);
+            let _ = write!(w, "{}/{} files", HumanCount(done), HumanCount(total_files));
+        })
+        .with_key("smooth_eta", move |state: &ProgressState, w: &mut dyn Write| {
+            let rate = f64::from_bits(rate.load(Ordering::Relaxed));
+            let remaining = state.len().unwrap_or(0).saturating_sub(state.pos());
+            let _ = write!(w, "{}", format_eta(remaining, rate));
+        })
+        .with_key("net", move |_: &ProgressState, w: &mut dyn Write| {
+            if network_pb.position() > 0 {
+                let rate = f64::from_bits(network_rate.load(Ordering::Relaxed)) as u64;
+                let _ = write!(w, " (net {}/s)", format_bytes(rate));
+            }
+        });
+    look.theme.apply(style)
+}
+
+/// Style for an individual file's progress bar.
+pub(crate) fn file_bar_style() -> ProgressStyle {
+    let look = bar_look();
+    let template = look.file_template.as_deref().unwrap_or(look.theme.file_template());
+    let style = ProgressStyle::default_bar()
+        .template(&units_template(template))
+        .unwrap();
+    look.theme.apply(style)
+}
+
+/// Style for the bar of a file sent to or received from a server: a file bar
+/// followed by how many of its chunks are waiting for the server.
+pub(crate) fn remote_file_bar_style() -> ProgressStyle {
+    let look = bar_look();
+    let template = look.file_template.as_deref().unwrap_or(look.theme.file_template());
+    let style = ProgressStyle::default_bar()
+        .template(&units_template(&format!("{} {{prefix}}", template)))
+        .unwrap();
+    look.theme.apply(style)
+}
+
+/// Style for the spinner shown while sources are being scanned.
+pub(crate) fn scan_spinner_style() -> ProgressStyle {
+    let theme = bar_look().theme;
+    let style = ProgressStyle::default_spinner()
+        .template(&units_template(theme.scan_template()))
+        .unwrap();
+    theme.apply(style)
+}
+
+/// Feeds the bytes written for one file to its own bar, the bar of the
+/// directory source it was found in, and the overall one, and the bytes
+/// that crossed the network to the run's [`NetworkMeter`].
+pub(crate) struct FileBars<'a> {
+    pub(crate) file: Option<&'a ProgressBar>,
+    pub(crate) source: Option<&'a ProgressBar>,
+    pub(crate) main: Option<&'a ProgressBar>,
+    pub(crate) network: Option<&'a ProgressBar>,
+}
+
+impl ProgressSink for FileBars<'_> {
+    fn on_bytes(&self, _from: &Path, bytes: u64) {
+        if let Some(pb) = self.file {
+            pb.inc(bytes);
+        }
+        if let Some(pb) = self.source {
+            pb.inc(bytes);
+        }
+        if let Some(pb) = self.main {
+            pb.inc(bytes);
+        }
+    }
+
+    fn on_network_bytes(&self, _from: &Path, bytes: u64) {
+        if let Some(pb) = self.network {
+            pb.inc(bytes);
+        }
+    }
+
+    fn on_in_flight(&self, _from: &Path, chunks: usize) {
+        if let Some(pb) = self.file {
+            match chunks {
+                0 => pb.set_prefix(""),
+                n => pb.set_prefix(format!("{} in flight", n)),
+            }
+        }
+    }
+}
+
+/// Counts the bytes a run sends and receives over the network, which differ
+/// from the bytes of the files when transfers are compressed, and how fast
+/// they go.
+pub(crate) struct NetworkMeter {
+    /// Hidden bar whose position is the bytes counted so far.
+    pub(crate) bar: ProgressBar,
+    speed: SpeedTracker,
+}
+
+impl NetworkMeter {
+    fn new() -> Self {
+        let bar = ProgressBar::hidden();
+        let speed = SpeedTracker::track(bar.clone());
+        NetworkMeter { bar, speed }
+    }
+}
+
+/// The progress display for one run: the overall bar (hidden in plain mode,
+/// where it only does the accounting), the container for per-file bars, and
+/// the background tasks feeding them.
+pub(crate) struct RunProgress {
+    /// Container for per-file bars; `None` when only the overall progress is shown.
+    pub(crate) multi: Option<Arc<MultiProgress>>,
+    /// Hands out the per-file bars in `multi`.
+    pub(crate) file_bars: Option<Arc<BarSlots>>,
+    pub(crate) main: Arc<ProgressBar>,
+    pub(crate) network: NetworkMeter,
+    speed: SpeedTracker,
+    /// Periodic plain-text status and `progress` event tasks.
+    reporters: Vec<JoinHandle<()>>,
+    dashboard: Option<Dashboard>,
+    mode: ProgressMode,
+    term_progress: bool,
+}
+
+impl RunProgress {
+    /// Sets up progress reporting for a run of `total_files` files totalling
+    /// `total_size` bytes. Returns `None` for [`ProgressMode::Hidden`] unless
+    /// an event consumer still needs `progress` events. With `term_progress`
+    /// the overall percentage is also mirrored to the terminal's tab/taskbar.
+    pub(crate) fn start(
+        mode: ProgressMode,
+        term_progress: bool,
+        total_size: u64,
+        total_files: u64,
+        stats: Arc<RunStats>,
+        events: Arc<EventSink>,
+    ) -> Option<Self> {
+        if mode == ProgressMode::Hidden && !events.is_enabled() {
+            return None;
+        }
+
+        let (multi, main) = match mode {
+            ProgressMode::Bars => {
+                let multi = MultiProgress::new();
+                let main = multi.add(ProgressBar::new(total_size));
+                (Some(Arc::new(multi)), main)
+            }
+            // The dashboard draws the bars itself, and warnings printed
+            // above them go to its panel of recent messages instead.
+            ProgressMode::Tui => {
+                let multi = MultiProgress::with_draw_target(ProgressDrawTarget::hidden());
+                let main = ProgressBar::with_draw_target(Some(total_size), ProgressDrawTarget::hidden());
+                (Some(Arc::new(multi)), main)
+            }
+            // Plain, JSON and socket-only runs still need a bar to account bytes.
+            _ => (None, ProgressBar::with_draw_target(Some(total_size), ProgressDrawTarget::hidden())),
+        };
+        let speed = SpeedTracker::track(main.clone());
+        let network = NetworkMeter::new();
+        let file_bars = multi.clone().map(BarSlots::new);
+        let dashboard = match (mode, &file_bars) {
+            (ProgressMode::Tui, Some(file_bars)) => Some(Dashboard::start(dashboard::Sources {
+                main: main.clone(),
+                stats: Arc::clone(&stats),
+                total_files,
+                rate: Arc::clone(&speed.rate),
+                file_bars: Arc::clone(file_bars),
+            })),
+            _ => None,
+        };
+
+        let mut reporters = Vec::new();
+        if mode == ProgressMode::Bars {
+            main.set_style(main_bar_style(Arc::clone(&stats), total_files, &speed, &network));
+            main.enable_steady_tick(Duration::from_millis(100));
+        }
+        if mode == ProgressMode::Plain {
+            let rate = Arc::clone(&speed.rate);
+            let pb = main.clone();
+            reporters.push(tokio::spawn(async move {
+                let mut interval = tokio::time::interval(PLAIN_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    eprintln!("{}", plain_status_line(&pb, f64::from_bits(rate.load(Ordering::Relaxed))));
+                }
+            }));
+        }
+        if term_progress {
+            let pb = main.clone();
+            let stats = Arc::clone(&stats);
+            reporters.push(tokio::spawn(async move {
+                let mut interval = tokio::time::interval(TERM_PROGRESS_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    let state = if stats.files_failed.load(Ordering::Relaxed) > 0 {
+                        TermState::Error
+                    } else {
+                        TermState::Normal
+                    };
+                    set_term_progress(state, percent(&pb));
+                }
+            }));
+        }
+        if events.is_enabled() {
+            let pb = main.clone();
+            reporters.push(tokio::spawn(async move {
+                let mut interval = tokio::time::interval(EVENT_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    events.emit(&CopyEvent::Progress {
+                        bytes_done: pb.position(),
+                        bytes_total: total_size,
+                        files_done: stats.files_done.load(Ordering::Relaxed),
+                        files_total: total_files,
+                    });
+                }
+            }));
+        }
+
+        Some(Self {
+            file_bars,
+            multi,
+            main: Arc::new(main),
+            network,
+            speed,
+            reporters,
+            dashboard,
+            mode,
+            term_progress,
+        })
+    }
+
+    /// Stops the background tasks and shows the completion message, e.g.
+    /// `Copy complete! avg 112.4 MiB/s, peak 180.0 MiB/s` for a `headline`
+    /// of `Copy complete!`.
+    pub(crate) async fn finish(self, headline: &str) {
+        let message = completion_message(headline, &self.main, self.speed.finish());
+        self.network.speed.finish();
+        // Wait for the reporters to stop so no `progress` event or status
+        // line can follow the summary.
+        for reporter in self.reporters {
+            reporter.abort();
+            let _ = reporter.await;
+        }
+        if let Some(dashboard) = self.dashboard {
+            dashboard.stop().await;
+        }
+        if self.term_progress {
+            set_term_progress(TermState::Clear, 0);
+        }
+        match self.mode {
+            ProgressMode::Bars => self.main.finish_with_message(message),
+            ProgressMode::Plain => {
+                let rate = self.main.per_sec();
+                eprintln!("{} {}", plain_status_line(&self.main, rate), message);
+                self.main.finish();
+            }
+            ProgressMode::Tui => {
+                eprintln!("{}", message);
+                self.main.finish();
+            }
+            ProgressMode::Json | ProgressMode::Hidden => self.main.finish(),
+        }
+    }
+}
+
+/// Hands out per-file bars, showing at most [`set_max_file_bars`] of them.
+/// A file started while they are all taken gets a hidden bar and is counted
+/// on a single "… and N more files" line below them; when a shown file
+/// finishes, the file that has been waiting longest takes over its slot,
+/// its bar showing the progress it made meanwhile.
+pub(crate) struct BarSlots {
+    multi: Arc<MultiProgress>,
+    state: Mutex<SlotState>,
+}
+
+#[derive(Default)]
+struct SlotState {
+    /// Bars currently shown.
+    shown: usize,
+    /// Hidden bars of files being copied, oldest first, by slot id.
+    waiting: VecDeque<(u64, ProgressBar)>,
+    /// The "… and N more files" line, while there are waiting files.
+    more: Option<ProgressBar>,
+    /// Bars of all files being copied, shown or waiting, by slot id.
+    active: Vec<(u64, ProgressBar)>,
+    next_id: u64,
+}
+
+impl BarSlots {
+    fn new(multi: Arc<MultiProgress>) -> Arc<Self> {
+        Arc::new(BarSlots {
+            multi,
+            state: Mutex::new(SlotState::default()),
+        })
+    }
+
+    /// A bar for a file of `len` bytes, shown now if a slot is free and
+    /// otherwise once one is. It is cleared when the slot is dropped.
+    pub(crate) fn add(self: &Arc<Self>, len: u64) -> FileSlot {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        let bar = ProgressBar::with_draw_target(Some(len), ProgressDrawTarget::hidden());
+        state.active.push((id, bar.clone()));
+        if state.shown < MAX_FILE_BARS.load(Ordering::Relaxed) {
+            state.shown += 1;
+            self.show(&state, bar.clone());
+        } else {
+            state.waiting.push_back((id, bar.clone()));
+            self.update_more(&mut state);
+        }
+        FileSlot {
+            id,
+            bar,
+            slots: Arc::clone(self),
+        }
+    }
+
+    /// Adds `bar` to the display, above the "more files" line.
+    fn show(&self, state: &SlotState, bar: ProgressBar) {
+        match state.more {
+            Some(ref more) => self.multi.insert_before(more, bar),
+            None => self.multi.add(bar),
+        };
+    }
+
+    fn update_more(&self, state: &mut SlotState) {
+        match state.waiting.len() {
+            0 => {
+                if let Some(more) = state.more.take() {
+                    more.finish_and_clear();
+                }
+            }
+            waiting => {
+                let more = state.more.get_or_insert_with(|| {
+                    let more = self.multi.add(ProgressBar::new_spinner());
+                    more.set_style(ProgressStyle::with_template("  {msg}").unwrap());
+                    more
+                });
+                let noun = if waiting == 1 { "file" } else { "files" };
+                more.set_message(format!("… and {} more {}", HumanCount(waiting as u64), noun));
+            }
+        }
+    }
+
+    /// Bars of all files being copied, including those waiting for a slot,
+    /// oldest first.
+    pub(crate) fn active(&self) -> Vec<ProgressBar> {
+        let state = self.state.lock().unwrap();
+        state.active.iter().map(|(_, bar)| bar.clone()).collect()
+    }
+
+    fn release(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.active.retain(|(active, _)| *active != id);
+        if let Some(index) = state.waiting.iter().position(|(waiting, _)| *waiting == id) {
+            state.waiting.remove(index);
+        } else if let Some((_, bar)) = state.waiting.pop_front() {
+            self.show(&state, bar);
+        } else {
+            state.shown -= 1;
+        }
+        self.update_more(&mut state);
+    }
+}
+
+/// The bar of one file from [`BarSlots::add`], which frees its slot when
+/// dropped.
+pub(crate) struct FileSlot {
+    id: u64,
+    bar: ProgressBar,
+    slots: Arc<BarSlots>,
+}
+
+impl Deref for FileSlot {
+    type Target = ProgressBar;
+
+    fn deref(&self) -> &ProgressBar {
+        &self.bar
+    }
+}
+
+impl Drop for FileSlot {
+    fn drop(&mut self) {
+        self.bar.finish_and_clear();
+        self.slots.release(self.id);
+    }
+}
+
+/// Overall completion in whole percent; an empty run counts as complete.
+fn percent(pb: &ProgressBar) -> u64 {
+    (pb.position() * 100).checked_div(pb.length().unwrap_or(0)).unwrap_or(100)
+}
+
+/// Taskbar/tab progress states understood by OSC 9;4 terminals.
+#[derive(Clone, Copy)]
+enum TermState {
+    Clear = 0,
+    Normal = 1,
+    Error = 2,
+}
+
+/// Reports progress to the terminal itself with an OSC 9;4 sequence, which
+/// Windows Terminal, ConEmu and several Linux terminals show in the tab or
+/// taskbar. Terminals without support ignore it.
+fn set_term_progress(state: TermState, percent: u64) {
+    eprint!("\x1b]9;4;{};{}\x1b\\", state as u8, percent.min(100));
+}
+
+/// Formats a single plain-mode status line such as
+/// `42% 12.30 GiB/29.00 GiB 210.00 MiB/s ETA 1m20s`.
+fn plain_status_line(pb: &ProgressBar, rate: f64) -> String {
+    let total = pb.length().unwrap_or(0);
+    let pos = pb.position();
+    format!(
+        "{}% {}/{} {}/s ETA {}",
+        percent(pb),
+        format_bytes(pos),
+        format_bytes(total),
+        format_bytes(rate as u64),
+        format_eta(total.saturating_sub(pos), rate)
+    )
+}
+
+/// `1 file`, `1,204 files`.
+pub(crate) fn files(n: u64) -> String {
+    if n == 1 {
+        "1 file".to_string()
+    } else {
+        format!("{} files", HumanCount(n))
+    }
+}
+
+/// Formats a run duration, e.g. `850ms`, `12.4s` or `1h02m`.
+pub(crate) fn format_elapsed(elapsed: Duration) -> String {
+    let secs = elapsed.as_secs();
+    match secs {
+        0 => format!("{}ms", elapsed.as_millis()),
+        1..60 => format!("{:.1}s", elapsed.as_secs_f64()),
+        60..3600 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+/// Formats the time left to copy `remaining` bytes at `rate` bytes/second in
+/// a compact form such as `1h05m`, `1m20s` or `45s`.
+pub(crate) fn format_eta(remaining: u64, rate: f64) -> String {
+    if remaining == 0 {
+        return "0s".to_string();
+    }
+    if rate <= 0.0 {
+        return "--".to_string();
+    }
+    let secs = (remaining as f64 / rate).ceil() as u64;
+    match (secs / 3600, secs % 3600 / 60, secs % 60) {
+        (0, 0, s) => format!("{s}s"),
+        (0, m, s) => format!("{m}m{s:02}s"),
+        (h, m, _) => format!("{h}h{m:02}m"),
+    }
+}
+
+/// Samples a bar's position once per second, keeping an exponentially
+/// weighted throughput for the ETA and the highest rate seen for the
+/// completion message.
+pub(crate) struct SpeedTracker {
+    /// Smoothed bytes per second, stored as `f64` bits.
+    rate: Arc<AtomicU64>,
+    peak: Arc<AtomicU64>,
+    handle: JoinHandle<()>,
+}
+
+impl SpeedTracker {
+    pub(crate) fn track(pb: ProgressBar) -> Self {
+        let rate = Arc::new(AtomicU64::new(0f64.to_bits()));
+        let peak = Arc::new(AtomicU64::new(0));
+        let rate_clone = Arc::clone(&rate);
+        let peak_clone = Arc::clone(&peak);
+        let handle = tokio::spawn(async move {
+            let sample = Duration::from_secs(1);
+            let alpha = 1.0 - (-sample.as_secs_f64() / ETA_SMOOTHING.as_secs_f64()).exp();
+            let mut interval = tokio::time::interval(sample);
+            // The first tick completes immediately.
+            interval.tick().await;
+            let mut last = pb.position();
+            let mut smoothed: Option<f64> = None;
+            loop {
+                interval.tick().await;
+                let pos = pb.position();
+                let delta = pos.saturating_sub(last);
+                last = pos;
+                peak_clone.fetch_max(delta, Ordering::Relaxed);
+
+                let current = delta as f64 / sample.as_secs_f64();
+                let next = match smoothed {
+                    Some(prev) => prev + alpha * (current - prev),
+                    None => current,
+                };
+                smoothed = Some(next);
+                rate_clone.store(next.to_bits(), Ordering::Relaxed);
+            }
+        });
+        Self { rate, peak, handle }
+    }
+
+    /// Stops sampling and returns the peak rate in bytes per second.
+    pub(crate) fn finish(self) -> u64 {
+        self.handle.abort();
+        self.peak.load(Ordering::Relaxed)
+    }
+}
+
+/// Builds the message shown once everything has finished.
+fn completion_message(headline: &str, pb: &ProgressBar, peak: u64) -> String {
+    let secs = pb.elapsed().as_secs_f64();
+    let average = if secs > 0.0 {
+        (pb.position() as f64 / secs) as u64
+    } else {
+        pb.position()
+    };
+    // Runs shorter than one sample never record a peak.
+    let peak = peak.max(average);
+    format!(
+        "{headline} avg {}/s, peak {}/s",
+        format_bytes(average),
+        format_bytes(peak)
+    )
+}
@@ -0,0 +1,219 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Outcome of a single file in a run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileStatus {
+    Copied,
+    /// Left alone on purpose, e.g. rejected by `--pre-file-cmd`.
+    Skipped,
+    Failed,
+}
+
+impl FileStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            FileStatus::Copied => "copied",
+            FileStatus::Skipped => "skipped",
+            FileStatus::Failed => "failed",
+        }
+    }
+}
+
+/// What happened to one file, as recorded for `--report`.
+#[derive(Clone, Debug, Serialize)]
+pub struct FileRecord {
+    pub source: String,
+    pub destination: String,
+    pub bytes: u64,
+    pub duration_secs: f64,
+    pub status: FileStatus,
+    pub error: Option<String>,
+    /// The destination the file would have had if it hadn't been renamed,
+    /// e.g. by `--sanitize-names`.
+    pub renamed_from: Option<String>,
+}
+
+/// Run-wide totals written alongside the per-file records.
+#[derive(Clone, Debug, Serialize)]
+pub struct ReportTotals {
+    pub files_copied: u64,
+    pub files_skipped: u64,
+    pub files_failed: u64,
+    pub bytes_copied: u64,
+    pub elapsed_secs: f64,
+}
+
+/// Collects [`FileRecord`]s from concurrent copy tasks.
+pub(crate) struct Recorder {
+    records: Mutex<Vec<FileRecord>>,
+    /// Original destination of every renamed file, keyed by the new one.
+    renames: Mutex<HashMap<String, String>>,
+    /// Run-wide remarks, such as tuning applied to the copy.
+    notes: Mutex<Vec<String>>,
+}
+
+impl Recorder {
+    pub(crate) fn new() -> Self {
+        Self {
+            records: Mutex::new(Vec::new()),
+            renames: Mutex::new(HashMap::new()),
+            notes: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Adds a run-wide remark to the report, e.g. why settings were changed.
+    pub(crate) fn note(&self, note: String) {
+        self.notes.lock().unwrap().push(note);
+    }
+
+    /// Notes that the file now headed for `destination` was planned to go
+    /// to `original`.
+    pub(crate) fn renamed(&self, destination: String, original: String) {
+        self.renames.lock().unwrap().insert(destination, original);
+    }
+
+    pub(crate) fn record(
+        &self,
+        source: &Path,
+        destination: String,
+        bytes: u64,
+        duration: Duration,
+        error: Option<String>,
+    ) {
+        let status = if error.is_some() {
+            FileStatus::Failed
+        } else {
+            FileStatus::Copied
+        };
+        self.records.lock().unwrap().push(FileRecord {
+            source: source.display().to_string(),
+            destination,
+            bytes,
+            duration_secs: duration.as_secs_f64(),
+            status,
+            error,
+            renamed_from: None,
+        });
+    }
+
+    /// Records a file that was deliberately not copied; `reason` goes in the
+    /// `error` column.
+    pub(crate) fn skipped(&self, source: &Path, destination: String, reason: String) {
+        self.records.lock().unwrap().push(FileRecord {
+            source: source.display().to_string(),
+            destination,
+            bytes: 0,
+            duration_secs: 0.0,
+            status: FileStatus::Skipped,
+            error: Some(reason),
+            renamed_from: None,
+        });
+    }
+
+    /// The records collected so far, sorted by source, and their totals.
+    pub(crate) fn collect(&self, elapsed: Duration) -> (Vec<FileRecord>, ReportTotals) {
+        let mut records = self.records.lock().unwrap().clone();
+        records.sort_by(|a, b| a.source.cmp(&b.source));
+        let renames = self.renames.lock().unwrap();
+        for record in &mut records {
+            record.renamed_from = renames.get(&record.destination).cloned();
+        }
+        let totals = ReportTotals {
+            files_copied: records.iter().filter(|r| r.status == FileStatus::Copied).count() as u64,
+            files_skipped: records.iter().filter(|r| r.status == FileStatus::Skipped).count() as u64,
+            files_failed: records.iter().filter(|r| r.status == FileStatus::Failed).count() as u64,
+            bytes_copied: records
+                .iter()
+                .filter(|r| r.status == FileStatus::Copied)
+                .map(|r| r.bytes)
+                .sum(),
+            elapsed_secs: elapsed.as_secs_f64(),
+        };
+        (records, totals)
+    }
+
+    /// Writes the collected records to `path`. A `.csv` extension selects
+    /// CSV; anything else is written as JSON.
+    pub(crate) fn write(&self, path: &Path, elapsed: Duration) -> Result<(), Box<dyn std::error::Error>> {
+        let (records, totals) = self.collect(elapsed);
+        let notes = self.notes.lock().unwrap();
+        let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+        let is_csv = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("csv"));
+        if is_csv {
+            write_csv(&mut out, &records, &notes, &totals)?;
+        } else {
+            #[derive(Serialize)]
+            struct JsonReport<'a> {
+                files: &'a [FileRecord],
+                #[serde(skip_serializing_if = "<[String]>::is_empty")]
+                notes: &'a [String],
+                totals: &'a ReportTotals,
+            }
+            serde_json::to_writer_pretty(
+                &mut out,
+                &JsonReport {
+                    files: &records,
+                    notes: &notes,
+                    totals: &totals,
+                },
+            )?;
+            writeln!(out)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// Writes one row per file, a `note` row per remark, then a `total` row
+/// carrying the run totals.
+fn write_csv(
+    out: &mut impl Write,
+    records: &[FileRecord],
+    notes: &[String],
+    totals: &ReportTotals,
+) -> std::io::Result<()> {
+    writeln!(out, "source,destination,bytes,duration_secs,status,error,renamed_from")?;
+    for r in records {
+        writeln!(
+            out,
+            "{},{},{},{:.6},{},{},{}",
+            csv_field(&r.source),
+            csv_field(&r.destination),
+            r.bytes,
+            r.duration_secs,
+            r.status.as_str(),
+            csv_field(r.error.as_deref().unwrap_or("")),
+            csv_field(r.renamed_from.as_deref().unwrap_or("")),
+        )?;
+    }
+    for note in notes {
+        writeln!(out, ",,,,note,{},", csv_field(note))?;
+    }
+    writeln!(
+        out,
+        ",,{},{:.6},total,{},",
+        totals.bytes_copied,
+        totals.elapsed_secs,
+        csv_field(&format!(
+            "{} copied, {} skipped, {} failed",
+            totals.files_copied, totals.files_skipped, totals.files_failed
+        )),
+    )
+}
+
+/// Quotes a CSV field when it contains a delimiter, quote or newline.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
@@ -0,0 +1,202 @@
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::blake3;
+
+/// Environment variable that overrides where upload state is kept. Primarily
+/// used by tests so they don't write into the user's state directory.
+const STATE_DIR_ENV: &str = "CP2_STATE_DIR";
+
+/// The byte ranges of a file transferred so far, kept in a sidecar JSON file
+/// so that an interrupted transfer continues where it stopped instead of
+/// starting again from zero.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct ResumeState {
+    /// Size of the source when the transfer started.
+    pub(crate) size: u64,
+    /// Modification time of a local source, in seconds since the epoch.
+    /// Together with `size` it tells whether the source changed since.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) modified: Option<u64>,
+    /// Transferred ranges `start..end`, sorted and without overlaps.
+    pub(crate) ranges: Vec<(u64, u64)>,
+    /// The S3 multipart upload the parts below belong to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) upload_id: Option<String>,
+    /// ETags of the uploaded S3 parts, part 1 first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) etags: Vec<String>,
+}
+
+impl ResumeState {
+    pub(crate) fn new(size: u64, modified: Option<u64>) -> Self {
+        Self {
+            size,
+            modified,
+            ..Self::default()
+        }
+    }
+
+    /// The state saved at `path`, if there is one for a source of this size
+    /// and modification time.
+    pub(crate) async fn load(path: &Path, size: u64, modified: Option<u64>) -> Option<Self> {
+        let json = fs::read(path).await.ok()?;
+        serde_json::from_slice::<Self>(&json)
+            .ok()
+            .filter(|state| state.size == size && state.modified == modified)
+    }
+
+    pub(crate) async fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::write(path, serde_json::to_vec(self).expect("resume state serializes")).await
+    }
+
+    /// Saves the state, only warning when it can't be: a transfer shouldn't
+    /// fail because it couldn't be resumed later.
+    pub(crate) async fn keep(&self, path: &Path) {
+        if let Err(e) = self.save(path).await {
+            log::warn!("Cannot save transfer state to {}: {}", path.display(), e);
+        }
+    }
+
+    /// Bytes transferred so far.
+    pub(crate) fn done(&self) -> u64 {
+        self.ranges.iter().map(|(start, end)| end - start).sum()
+    }
+
+    /// Records `start..end` as transferred.
+    pub(crate) fn add(&mut self, start: u64, end: u64) {
+        if start >= end {
+            return;
+        }
+        let at = self.ranges.partition_point(|&(s, _)| s < start);
+        self.ranges.insert(at, (start, end));
+        let mut merged: Vec<(u64, u64)> = Vec::with_capacity(self.ranges.len());
+        for &(s, e) in &self.ranges {
+            match merged.last_mut() {
+                Some(last) if s <= last.1 => last.1 = last.1.max(e),
+                _ => merged.push((s, e)),
+            }
+        }
+        self.ranges = merged;
+    }
+
+    /// Marks `start..end` as not transferred after all.
+    pub(crate) fn remove(&mut self, start: u64, end: u64) {
+        self.ranges = self
+            .ranges
+            .iter()
+            .flat_map(|&(s, e)| [(s, e.min(start)), (s.max(end), e)])
+            .filter(|(s, e)| s < e)
+            .collect();
+    }
+
+    /// The ranges still to transfer: the gaps in `0..size`, then everything
+    /// from `size` on (`u64::MAX` as the end) for sources that grew.
+    pub(crate) fn pending(&self) -> Vec<(u64, u64)> {
+        let mut pending = Vec::new();
+        let mut offset = 0;
+        for &(start, end) in &self.ranges {
+            if start > offset {
+                pending.push((offset, start.min(self.size)));
+            }
+            offset = offset.max(end);
+        }
+        if offset < self.size {
+            pending.push((offset, self.size));
+        }
+        pending.retain(|(start, end)| start < end);
+        pending.push((offset.max(self.size), u64::MAX));
+        pending
+    }
+}
+
+/// `path` with `suffix` appended to its file name, e.g. `file.part`.
+pub(crate) fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Size and modification time of the local file `path`.
+pub(crate) async fn identity(path: &Path) -> io::Result<(u64, Option<u64>)> {
+    let metadata = fs::metadata(path).await?;
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs());
+    Ok((metadata.len(), modified))
+}
+
+/// BLAKE3 digest of `ranges` of the local file `path`, one after the other,
+/// which a resumed transfer compares with the same ranges at the other end.
+/// A file that ends early just hashes differently.
+pub(crate) async fn hash_ranges(path: &Path, ranges: &[(u64, u64)]) -> io::Result<[u8; 32]> {
+    let mut file = fs::File::open(path).await?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0; 256 * 1024];
+    let capacity = buffer.len() as u64;
+    for &(start, end) in ranges {
+        file.seek(io::SeekFrom::Start(start)).await?;
+        let mut left = end - start;
+        while left > 0 {
+            let n = file.read(&mut buffer[..left.min(capacity) as usize]).await?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buffer[..n]);
+            left -= n as u64;
+        }
+    }
+    Ok(hasher.finalize())
+}
+
+/// cp2's own state directory: `$XDG_STATE_HOME/cp2` on Linux, in the cache
+/// directory elsewhere.
+fn state_dir() -> PathBuf {
+    dirs::state_dir()
+        .or_else(dirs::cache_dir)
+        .unwrap_or_else(std::env::temp_dir)
+        .join("cp2")
+}
+
+/// Where the files of interrupted copies are listed for `cp2 resume`, in
+/// cp2's state directory or the one `CP2_STATE_DIR` names.
+pub(crate) fn jobs_dir() -> PathBuf {
+    match std::env::var_os(STATE_DIR_ENV) {
+        Some(dir) => PathBuf::from(dir).join("jobs"),
+        None => state_dir().join("jobs"),
+    }
+}
+
+/// Where the undo journals of local copies are kept for `cp2 undo`, in
+/// cp2's state directory or the one `CP2_STATE_DIR` names.
+pub(crate) fn undo_dir() -> PathBuf {
+    match std::env::var_os(STATE_DIR_ENV) {
+        Some(dir) => PathBuf::from(dir).join("undo"),
+        None => state_dir().join("undo"),
+    }
+}
+
+/// Where the state of an upload to `destination` is kept. Remote
+/// destinations have no room for a sidecar file, so it goes in cp2's state
+/// directory (`$XDG_STATE_HOME/cp2/resume` on Linux, the cache directory
+/// elsewhere), named after a hash of the destination.
+pub(crate) fn upload_state_path(destination: &str) -> PathBuf {
+    let dir = match std::env::var_os(STATE_DIR_ENV) {
+        Some(dir) => PathBuf::from(dir),
+        None => state_dir().join("resume"),
+    };
+    // FNV-1a, which unlike the std hasher is stable across releases.
+    let hash = destination
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, b| (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3));
+    dir.join(format!("{hash:016x}.json"))
+}
@@ -3,12 +3,16 @@ use aws_credential_types::Credentials;
 use aws_sdk_s3::primitives::ByteStream;
 use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
 use aws_sdk_s3::Client;
-use indicatif::ProgressBar;
 use std::path::{Path, PathBuf};
 use tokio::fs::File;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::sync::CancellationToken;
 
 use crate::config::RemoteConfig;
+use crate::resume::{self, ResumeState};
+use crate::sink::ProgressSink;
+use crate::throttle::Throttle;
+use crate::utils::walk_dir;
 
 /// Files below this threshold are uploaded with a single PutObject call.
 /// Files at or above it use multipart upload.
@@ -23,6 +27,9 @@ pub const MAX_PARTS: u64 = 10_000;
 /// S3's hard limit on a single part.
 const MAX_PART_SIZE: u64 = 5 * 1024 * 1024 * 1024; // 5 GiB
 
+/// Number of directories listed concurrently while collecting upload entries.
+const SCAN_PARALLEL: usize = 8;
+
 /// A source file paired with the S3 key it should be uploaded to.
 pub struct S3UploadEntry {
     pub from: PathBuf,
@@ -94,9 +101,14 @@ pub async fn create_client(
 ///   `uploads/foo.txt`.
 /// - For a directory `mydir` with `key_prefix = "uploads"` each file is keyed
 ///   as `uploads/mydir/<relative-path>`.
+///
+/// With `skip_hidden`, hidden files and directories inside a directory are
+/// not uploaded.
 pub async fn collect_s3_upload_entries(
     source: &Path,
     key_prefix: &str,
+    follow_symlinks: bool,
+    skip_hidden: bool,
 ) -> Result<(Vec<S3UploadEntry>, u64, u64), Box<dyn std::error::Error>> {
     let mut entries = Vec::new();
     let mut total_count = 0u64;
@@ -110,7 +122,11 @@ pub async fn collect_s3_upload_entries(
         format!("{}/", key_prefix.trim_end_matches('/'))
     };
 
-    let meta = tokio::fs::symlink_metadata(source).await?;
+    let meta = if follow_symlinks {
+        tokio::fs::metadata(source).await?
+    } else {
+        tokio::fs::symlink_metadata(source).await?
+    };
 
     if meta.file_type().is_file() {
         let file_name = source
@@ -133,35 +149,25 @@ pub async fn collect_s3_upload_entries(
             .and_then(|n| n.to_str())
             .ok_or("source has no directory name")?;
 
-        let mut stack = vec![source.to_path_buf()];
-        while let Some(p) = stack.pop() {
-            let m = tokio::fs::symlink_metadata(&p).await?;
-            if m.file_type().is_dir() {
-                let mut dir_entries = tokio::fs::read_dir(&p).await?;
-                while let Some(entry) = dir_entries.next_entry().await? {
-                    stack.push(entry.path());
-                }
-            } else if m.file_type().is_file() {
-                let relative = p.strip_prefix(source)?;
-                // Convert path separators to '/' for S3 keys.
-                let rel_str = relative
-                    .to_str()
-                    .ok_or("non-UTF-8 path")?
-                    .replace('\\', "/");
-                let key = format!("{}{}/{}", prefix, dir_name, rel_str);
-                let size = m.len();
-                total_count += 1;
-                total_size += size;
-                entries.push(S3UploadEntry {
-                    from: p,
-                    key,
-                    size,
-                });
-            } else if m.file_type().is_symlink() {
-                log::warn!("skipping symlink: {}", p.display());
-            } else {
-                log::warn!("skipping non-regular file: {}", p.display());
-            }
+        let (files, _dirs, specials, _) = walk_dir(source, SCAN_PARALLEL, follow_symlinks, skip_hidden, None).await?;
+        for (path, kind) in specials {
+            log::warn!("skipping {}: {}", kind, path.display());
+        }
+        for (p, size) in files {
+            let relative = p.strip_prefix(source)?;
+            // Convert path separators to '/' for S3 keys.
+            let rel_str = relative
+                .to_str()
+                .ok_or("non-UTF-8 path")?
+                .replace('\\', "/");
+            let key = format!("{}{}/{}", prefix, dir_name, rel_str);
+            total_count += 1;
+            total_size += size;
+            entries.push(S3UploadEntry {
+                from: p,
+                key,
+                size,
+            });
         }
     }
 
@@ -169,22 +175,36 @@ pub async fn collect_s3_upload_entries(
 }
 
 /// Uploads a single file to S3, choosing between a simple PutObject and a
-/// multipart upload based on the file size.
+/// multipart upload based on the file size recorded during the scan.
+///
+/// When `cancel` fires the upload stops with an error. A multipart upload in
+/// progress is left open with its parts, and the next attempt at the same
+/// file and key continues it.
+#[allow(clippy::too_many_arguments)]
 pub async fn upload_file(
     client: &Client,
     from: &Path,
+    file_size: u64,
     bucket: &str,
     key: &str,
-    file_pb: Option<&ProgressBar>,
-    main_pb: Option<&ProgressBar>,
+    progress: Option<&dyn ProgressSink>,
+    throttle: Option<&Throttle>,
+    cancel: &CancellationToken,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let file_size = tokio::fs::metadata(from).await?.len();
-
     if file_size < MULTIPART_THRESHOLD {
-        upload_single(client, from, bucket, key, file_size, file_pb, main_pb).await
+        let upload = async {
+            if let Some(throttle) = throttle {
+                throttle.acquire(file_size).await;
+            }
+            upload_single(client, from, bucket, key, file_size, progress).await
+        };
+        tokio::select! {
+            result = upload => result,
+            _ = cancel.cancelled() => Err("upload interrupted".into()),
+        }
     } else {
         let part_size = pick_part_size(file_size)?;
-        upload_multipart(client, from, bucket, key, part_size, file_pb, main_pb).await
+        upload_multipart(client, from, bucket, key, part_size, progress, throttle, cancel).await
     }
 }
 
@@ -195,8 +215,7 @@ async fn upload_single(
     bucket: &str,
     key: &str,
     file_size: u64,
-    file_pb: Option<&ProgressBar>,
-    main_pb: Option<&ProgressBar>,
+    progress: Option<&dyn ProgressSink>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let body = ByteStream::from_path(from).await?;
 
@@ -208,11 +227,9 @@ async fn upload_single(
         .send()
         .await?;
 
-    if let Some(pb) = file_pb {
-        pb.inc(file_size);
-    }
-    if let Some(pb) = main_pb {
-        pb.inc(file_size);
+    if let Some(progress) = progress {
+        progress.on_bytes(from, file_size);
+        progress.on_network_bytes(from, file_size);
     }
 
     Ok(())
@@ -220,35 +237,63 @@ async fn upload_single(
 
 /// Uploads a file using S3 multipart upload, reporting progress after each
 /// part.
+///
+/// The upload id and the ETag of every part are saved in cp2's state
+/// directory as the parts complete. An upload that fails or is interrupted
+/// is therefore left open, and the next attempt at an unchanged file only
+/// sends the missing parts. It is aborted only when S3 refuses to complete it.
+#[allow(clippy::too_many_arguments)]
 async fn upload_multipart(
     client: &Client,
     from: &Path,
     bucket: &str,
     key: &str,
     part_size: u64,
-    file_pb: Option<&ProgressBar>,
-    main_pb: Option<&ProgressBar>,
+    progress: Option<&dyn ProgressSink>,
+    throttle: Option<&Throttle>,
+    cancel: &CancellationToken,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    // Initiate multipart upload.
-    let create_resp = client
-        .create_multipart_upload()
-        .bucket(bucket)
-        .key(key)
-        .send()
-        .await?;
-
-    let upload_id = create_resp
-        .upload_id()
-        .ok_or("S3 did not return an upload ID")?
-        .to_string();
+    let state_path = resume::upload_state_path(&format!("s3://{bucket}/{key}"));
+    let (size, modified) = resume::identity(from).await?;
+    let saved = ResumeState::load(&state_path, size, modified).await;
+    let mut state = match saved {
+        Some(state) if is_open(client, bucket, key, state.upload_id.as_deref()).await => state,
+        _ => {
+            // Initiate multipart upload.
+            let create_resp = client
+                .create_multipart_upload()
+                .bucket(bucket)
+                .key(key)
+                .send()
+                .await?;
+            let upload_id = create_resp
+                .upload_id()
+                .ok_or("S3 did not return an upload ID")?
+                .to_string();
+            let state = ResumeState {
+                upload_id: Some(upload_id),
+                ..ResumeState::new(size, modified)
+            };
+            state.keep(&state_path).await;
+            state
+        }
+    };
+    let upload_id = state.upload_id.clone().unwrap_or_default();
 
     let mut file = File::open(from).await?;
     let part_size_usize: usize = part_size
         .try_into()
         .map_err(|_| "part size does not fit in usize on this platform")?;
     let mut buf = vec![0u8; part_size_usize];
-    let mut completed_parts: Vec<CompletedPart> = Vec::new();
-    let mut part_number = 1i32;
+    let mut part_number = state.etags.len() as i32 + 1;
+    let resumed = state.done();
+    if resumed > 0 {
+        log::info!("Resuming upload of {} at part {}", from.display(), part_number);
+        file.seek(std::io::SeekFrom::Start(resumed)).await?;
+        if let Some(progress) = progress {
+            progress.on_bytes(from, resumed);
+        }
+    }
 
     loop {
         let bytes_read = read_at_least(&mut file, &mut buf).await?;
@@ -259,48 +304,53 @@ async fn upload_multipart(
         let data = buf[..bytes_read].to_vec();
         let chunk_len = bytes_read as u64;
 
-        let part_result = client
+        let upload_part = client
             .upload_part()
             .bucket(bucket)
             .key(key)
             .upload_id(&upload_id)
             .part_number(part_number)
             .body(ByteStream::from(data))
-            .send()
-            .await;
+            .send();
+        // Waiting on the throttle is cancellable too, so Ctrl-C works while paused.
+        let part_result = tokio::select! {
+            result = async {
+                if let Some(throttle) = throttle {
+                    throttle.acquire(chunk_len).await;
+                }
+                upload_part.await
+            } => result,
+            _ = cancel.cancelled() => return Err("upload interrupted".into()),
+        };
 
         match part_result {
             Ok(resp) => {
                 let etag = match resp.e_tag() {
                     Some(v) => v.to_string(),
-                    None => {
-                        abort_multipart(client, bucket, key, &upload_id).await;
-                        return Err("S3 upload_part response missing ETag".into());
-                    }
+                    None => return Err("S3 upload_part response missing ETag".into()),
                 };
-                completed_parts.push(
-                    CompletedPart::builder()
-                        .part_number(part_number)
-                        .e_tag(etag)
-                        .build(),
-                );
-
-                if let Some(pb) = file_pb {
-                    pb.inc(chunk_len);
+                let offset = state.done();
+                state.etags.push(etag);
+                state.add(offset, offset + chunk_len);
+                state.keep(&state_path).await;
+
+                if let Some(progress) = progress {
+                    progress.on_bytes(from, chunk_len);
+                    progress.on_network_bytes(from, chunk_len);
                 }
-                if let Some(pb) = main_pb {
-                    pb.inc(chunk_len);
-                }
-            }
-            Err(e) => {
-                abort_multipart(client, bucket, key, &upload_id).await;
-                return Err(e.into());
             }
+            Err(e) => return Err(e.into()),
         }
 
         part_number += 1;
     }
 
+    let completed_parts = state
+        .etags
+        .iter()
+        .zip(1..)
+        .map(|(etag, number)| CompletedPart::builder().part_number(number).e_tag(etag).build())
+        .collect();
     let completed = CompletedMultipartUpload::builder()
         .set_parts(Some(completed_parts))
         .build();
@@ -315,12 +365,31 @@ async fn upload_multipart(
         .await
     {
         abort_multipart(client, bucket, key, &upload_id).await;
+        let _ = tokio::fs::remove_file(&state_path).await;
         return Err(e.into());
     }
 
+    let _ = tokio::fs::remove_file(&state_path).await;
     Ok(())
 }
 
+/// Whether the multipart upload `upload_id` still takes parts; S3 forgets
+/// uploads once they are completed, aborted or expired by a lifecycle rule.
+async fn is_open(client: &Client, bucket: &str, key: &str, upload_id: Option<&str>) -> bool {
+    let Some(upload_id) = upload_id else {
+        return false;
+    };
+    client
+        .list_parts()
+        .bucket(bucket)
+        .key(key)
+        .upload_id(upload_id)
+        .max_parts(1)
+        .send()
+        .await
+        .is_ok()
+}
+
 /// Best-effort abort so an incomplete multipart upload doesn't incur
 /// storage costs.
 async fn abort_multipart(client: &Client, bucket: &str, key: &str, upload_id: &str) {
@@ -0,0 +1,1103 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::process::{Child, ChildStdin, Command};
+use tokio::sync::oneshot;
+use tokio_util::sync::CancellationToken;
+
+use crate::blake3;
+use crate::copy::{pace, CopyError};
+use crate::resume::{self, with_suffix, ResumeState};
+use crate::sink::ProgressSink;
+use crate::throttle::Throttle;
+use crate::transport::{RemoteEntry, RemoteStat, Transport};
+
+// SFTP version 3 (draft-ietf-secsh-filexfer-02), the version OpenSSH speaks.
+const SSH_FXP_INIT: u8 = 1;
+const SSH_FXP_VERSION: u8 = 2;
+const SSH_FXP_OPEN: u8 = 3;
+const SSH_FXP_CLOSE: u8 = 4;
+const SSH_FXP_READ: u8 = 5;
+const SSH_FXP_WRITE: u8 = 6;
+const SSH_FXP_OPENDIR: u8 = 11;
+const SSH_FXP_READDIR: u8 = 12;
+const SSH_FXP_REMOVE: u8 = 13;
+const SSH_FXP_MKDIR: u8 = 14;
+const SSH_FXP_STAT: u8 = 17;
+const SSH_FXP_RENAME: u8 = 18;
+const SSH_FXP_STATUS: u8 = 101;
+const SSH_FXP_HANDLE: u8 = 102;
+const SSH_FXP_DATA: u8 = 103;
+const SSH_FXP_NAME: u8 = 104;
+const SSH_FXP_ATTRS: u8 = 105;
+
+const SSH_FXF_READ: u32 = 0x01;
+const SSH_FXF_WRITE: u32 = 0x02;
+const SSH_FXF_CREAT: u32 = 0x08;
+const SSH_FXF_TRUNC: u32 = 0x10;
+
+const SSH_FILEXFER_ATTR_SIZE: u32 = 0x01;
+const SSH_FILEXFER_ATTR_UIDGID: u32 = 0x02;
+const SSH_FILEXFER_ATTR_PERMISSIONS: u32 = 0x04;
+const SSH_FILEXFER_ATTR_ACMODTIME: u32 = 0x08;
+const SSH_FILEXFER_ATTR_EXTENDED: u32 = 0x8000_0000;
+
+const SSH_FX_OK: u32 = 0;
+const SSH_FX_EOF: u32 = 1;
+const SSH_FX_NO_SUCH_FILE: u32 = 2;
+const SSH_FX_PERMISSION_DENIED: u32 = 3;
+const SSH_FX_OP_UNSUPPORTED: u32 = 8;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+/// Bytes moved by a single READ or WRITE request; every server accepts this much.
+const CHUNK_SIZE: usize = 32 * 1024;
+/// READ or WRITE requests sent ahead of their replies, so that a transfer
+/// isn't limited to one chunk per round trip.
+const MAX_IN_FLIGHT: usize = 32;
+/// Largest packet accepted from the server.
+const MAX_PACKET: usize = 256 * 1024 + 1024;
+
+/// A file or directory on an SFTP server, written `[user@]host:path` or
+/// `sftp://[user@]host[:port]/path`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SftpLocation {
+    pub user: Option<String>,
+    pub host: String,
+    pub port: Option<u16>,
+    /// Path on the server; relative paths start in the user's home directory.
+    pub path: String,
+}
+
+impl SftpLocation {
+    /// Parses `sftp://[user@]host[:port][/path]`, `user@host:[path]` or
+    /// `host:/path`. A `name:bucket/prefix` without a user or a leading slash
+    /// is left to the S3 remotes, and a single letter before the colon to
+    /// Windows drive letters.
+    pub fn parse(s: &str) -> Option<Self> {
+        if let Some(rest) = s.strip_prefix("sftp://") {
+            let (authority, path) = match rest.find('/') {
+                Some(slash) => (&rest[..slash], &rest[slash..]),
+                None => (rest, ""),
+            };
+            let (user, host_port) = split_user(authority);
+            let (host, port) = match host_port.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port.parse().ok()?)),
+                None => (host_port, None),
+            };
+            if host.is_empty() {
+                return None;
+            }
+            return Some(Self {
+                user,
+                host: host.to_string(),
+                port,
+                path: if path.is_empty() { ".".to_string() } else { path.to_string() },
+            });
+        }
+
+        let (target, path) = s.split_once(':')?;
+        if target.contains('/') || target.contains('\\') {
+            return None;
+        }
+        let (user, host) = split_user(target);
+        // `scheme://...` is a URL, not a path on a host called `scheme`.
+        if host.len() < 2 || path.starts_with("//") || (user.is_none() && !path.starts_with('/')) {
+            return None;
+        }
+        Some(Self {
+            user,
+            host: host.to_string(),
+            port: None,
+            path: if path.is_empty() { ".".to_string() } else { path.to_string() },
+        })
+    }
+
+    /// The `[user@]host` argument given to ssh.
+    pub fn target(&self) -> String {
+        match self.user {
+            Some(ref user) => format!("{}@{}", user, self.host),
+            None => self.host.clone(),
+        }
+    }
+
+    /// Whether `other` is on the same server, reached as the same user.
+    pub fn same_server(&self, other: &SftpLocation) -> bool {
+        self.user == other.user && self.host == other.host && self.port == other.port
+    }
+}
+
+impl fmt::Display for SftpLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.port {
+            Some(port) => write!(f, "sftp://{}:{}/{}", self.target(), port, self.path.trim_start_matches('/')),
+            None => write!(f, "{}:{}", self.target(), self.path),
+        }
+    }
+}
+
+fn split_user(target: &str) -> (Option<String>, &str) {
+    match target.rsplit_once('@') {
+        Some((user, host)) if !user.is_empty() => (Some(user.to_string()), host),
+        Some((_, host)) => (None, host),
+        None => (None, target),
+    }
+}
+
+/// A reply from the server: its packet type and the payload after the
+/// request id.
+struct Reply {
+    kind: u8,
+    body: Vec<u8>,
+}
+
+/// Requests waiting for their reply, by request id; `None` once the
+/// connection is gone.
+type Pending = Arc<Mutex<Option<HashMap<u32, oneshot::Sender<Reply>>>>>;
+
+/// An SFTP connection run over the `sftp` subsystem of an ssh process, so
+/// that keys, agents, `~/.ssh/config` and known hosts work as they do for
+/// `scp` and `sftp`.
+///
+/// Requests may be sent concurrently; a background task hands each reply to
+/// the request with its id.
+pub struct SftpSession {
+    server: SftpLocation,
+    writer: tokio::sync::Mutex<ChildStdin>,
+    pending: Pending,
+    next_id: AtomicU32,
+    _child: Mutex<Child>,
+    /// `ssh_command` with the port and host, for running commands on the
+    /// server.
+    exec: Vec<String>,
+    /// File contents travel zstd-compressed; see
+    /// [`enable_compression`](Self::enable_compression).
+    zstd: bool,
+}
+
+impl SftpSession {
+    /// Starts `ssh_command` (the program and its leading arguments, e.g.
+    /// `ssh -i key`) for the server of `location` and opens an SFTP session.
+    pub async fn connect(location: &SftpLocation, ssh_command: &[String]) -> io::Result<Self> {
+        let (program, _) = ssh_command
+            .split_first()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty ssh command"))?;
+        let mut exec = ssh_command.to_vec();
+        if let Some(port) = location.port {
+            exec.extend(["-p".to_string(), port.to_string()]);
+        }
+        let mut command = Command::new(program);
+        command
+            .args(&exec[1..])
+            .arg("-s")
+            .arg(location.target())
+            .arg("sftp")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true);
+        let mut child = command
+            .spawn()
+            .map_err(|e| io::Error::new(e.kind(), format!("cannot run {}: {}", program, e)))?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let mut stdout = child.stdout.take().expect("stdout is piped");
+
+        let mut init = Packet::default();
+        init.u32(3);
+        write_packet(&mut stdin, SSH_FXP_INIT, &init.0).await?;
+        let lost = |e: io::Error| match e.kind() {
+            io::ErrorKind::UnexpectedEof => {
+                io::Error::new(io::ErrorKind::ConnectionRefused, format!("connection to {} closed", location.host))
+            }
+            _ => e,
+        };
+        let (kind, _) = read_packet(&mut stdout).await.map_err(lost)?;
+        if kind != SSH_FXP_VERSION {
+            return Err(bad_message());
+        }
+
+        let pending: Pending = Arc::new(Mutex::new(Some(HashMap::new())));
+        let replies = Arc::clone(&pending);
+        tokio::spawn(async move {
+            while let Ok((kind, body)) = read_packet(&mut stdout).await {
+                let Some(id) = body.get(..4) else { break };
+                let id = u32::from_be_bytes(id.try_into().unwrap());
+                let sender = replies.lock().unwrap().as_mut().and_then(|p| p.remove(&id));
+                if let Some(sender) = sender {
+                    let _ = sender.send(Reply {
+                        kind,
+                        body: body[4..].to_vec(),
+                    });
+                }
+            }
+            // Dropping the senders fails every request still waiting.
+            replies.lock().unwrap().take();
+        });
+
+        Ok(Self {
+            server: SftpLocation {
+                path: String::new(),
+                ..location.clone()
+            },
+            writer: tokio::sync::Mutex::new(stdin),
+            pending,
+            next_id: AtomicU32::new(0),
+            _child: Mutex::new(child),
+            exec: [exec, vec![location.target()]].concat(),
+            zstd: false,
+        })
+    }
+
+    /// Sends file contents zstd-compressed from now on, trading CPU for
+    /// bandwidth: each file goes through `zstd` here and a `zstd` run over
+    /// ssh on the server, next to the SFTP session. Returns whether both ends
+    /// have `zstd`; when either doesn't, transfers stay uncompressed.
+    pub async fn enable_compression(&mut self) -> bool {
+        let local = Command::new("zstd").arg("--version").output().await;
+        let remote = self.command("zstd --version").output().await;
+        self.zstd = local.is_ok_and(|o| o.status.success()) && remote.is_ok_and(|o| o.status.success());
+        self.zstd
+    }
+
+    /// Runs the shell command `command` on the server.
+    fn command(&self, command: &str) -> Command {
+        let mut ssh = Command::new(&self.exec[0]);
+        ssh.args(&self.exec[1..])
+            .arg(command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        ssh
+    }
+
+    /// Sends a request and returns where its reply will arrive.
+    async fn send(&self, kind: u8, payload: &[u8]) -> io::Result<oneshot::Receiver<Reply>> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        match self.pending.lock().unwrap().as_mut() {
+            Some(pending) => pending.insert(id, sender),
+            None => return Err(connection_lost()),
+        };
+        let mut packet = Vec::with_capacity(payload.len() + 4);
+        packet.extend_from_slice(&id.to_be_bytes());
+        packet.extend_from_slice(payload);
+        write_packet(&mut *self.writer.lock().await, kind, &packet).await?;
+        Ok(receiver)
+    }
+
+    async fn call(&self, kind: u8, payload: &[u8]) -> io::Result<Reply> {
+        await_reply(self.send(kind, payload).await?).await
+    }
+
+    async fn open(&self, path: &str, flags: u32) -> io::Result<Vec<u8>> {
+        let mut request = Packet::default();
+        request.string(path.as_bytes());
+        request.u32(flags);
+        request.u32(0);
+        let reply = self.call(SSH_FXP_OPEN, &request.0).await?;
+        Ok(expect(&reply, SSH_FXP_HANDLE)?.string()?.to_vec())
+    }
+
+    async fn opendir(&self, path: &str) -> io::Result<Vec<u8>> {
+        let reply = self.call(SSH_FXP_OPENDIR, &Packet::with_string(path.as_bytes()).0).await?;
+        Ok(expect(&reply, SSH_FXP_HANDLE)?.string()?.to_vec())
+    }
+
+    async fn close(&self, handle: &[u8]) -> io::Result<()> {
+        status(&self.call(SSH_FXP_CLOSE, &Packet::with_string(handle).0).await?)
+    }
+
+    /// Renames `from` to `to`, replacing `to`.
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut request = Packet::with_string(from.as_bytes());
+        request.string(to.as_bytes());
+        match status(&self.call(SSH_FXP_RENAME, &request.0).await?) {
+            // SFTP v3 servers refuse to replace an existing file.
+            Err(_) if self.stat(to).await?.is_some() => {
+                self.remove_file(to).await?;
+                status(&self.call(SSH_FXP_RENAME, &request.0).await?)
+            }
+            result => result,
+        }
+    }
+
+    /// Sends WRITE requests for the parts of `source` that `state` doesn't
+    /// have yet, keeping up to [`MAX_IN_FLIGHT`] unanswered, and records
+    /// each one the server acknowledges in `state`.
+    #[allow(clippy::too_many_arguments)]
+    async fn write_contents(
+        &self,
+        handle: &[u8],
+        from: &Path,
+        remote: &Path,
+        source: &mut fs::File,
+        state: &mut ResumeState,
+        progress: Option<&dyn ProgressSink>,
+        throttle: Option<&Throttle>,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<(), CopyError> {
+        let mut buffer = vec![0; CHUNK_SIZE];
+        let mut in_flight = VecDeque::new();
+        for (start, end) in state.pending() {
+            source.seek(io::SeekFrom::Start(start)).await.map_err(CopyError::io("seek", from))?;
+            let mut offset = start;
+            while offset < end {
+                let len = (end - offset).min(CHUNK_SIZE as u64) as usize;
+                let n = source.read(&mut buffer[..len]).await.map_err(CopyError::io("read", from))?;
+                if n == 0 {
+                    break;
+                }
+                pace(throttle, n as u64, cancel).await?;
+                let mut request = Packet::with_string(handle);
+                request.u64(offset);
+                request.string(&buffer[..n]);
+                let receiver = self.send(SSH_FXP_WRITE, &request.0).await.map_err(CopyError::io("write", remote))?;
+                in_flight.push_back((offset, n as u64, receiver));
+                if let Some(progress) = progress {
+                    progress.on_network_bytes(from, request.0.len() as u64);
+                    progress.on_in_flight(from, in_flight.len());
+                }
+                offset += n as u64;
+                if in_flight.len() >= MAX_IN_FLIGHT {
+                    let (offset, n, receiver) = in_flight.pop_front().unwrap();
+                    written(receiver, n, from, remote, progress).await?;
+                    state.add(offset, offset + n);
+                }
+            }
+        }
+        while let Some((offset, n, receiver)) = in_flight.pop_front() {
+            written(receiver, n, from, remote, progress).await?;
+            state.add(offset, offset + n);
+            if let Some(progress) = progress {
+                progress.on_in_flight(from, in_flight.len());
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends READ requests for the parts of the file that `state` doesn't
+    /// have yet, until the server reports the end of the file, keeping up to
+    /// [`MAX_IN_FLIGHT`] unanswered. Each chunk is written at its offset in
+    /// `dest` and recorded in `state`.
+    #[allow(clippy::too_many_arguments)]
+    async fn read_contents(
+        &self,
+        handle: &[u8],
+        remote: &Path,
+        to: &Path,
+        dest: &mut fs::File,
+        state: &mut ResumeState,
+        progress: Option<&dyn ProgressSink>,
+        throttle: Option<&Throttle>,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<(), CopyError> {
+        let read = |offset: u64, len: usize| {
+            let mut request = Packet::with_string(handle);
+            request.u64(offset);
+            request.u32(len as u32);
+            async move {
+                let receiver = self.send(SSH_FXP_READ, &request.0).await.map_err(CopyError::io("read", remote))?;
+                Ok::<_, CopyError>((offset, len, receiver))
+            }
+        };
+        let mut in_flight = VecDeque::new();
+        let mut pending = state.pending().into_iter();
+        let mut next = pending.next();
+        let mut eof = false;
+        loop {
+            while !eof && in_flight.len() < MAX_IN_FLIGHT {
+                let Some((offset, end)) = next else {
+                    break;
+                };
+                let len = (end - offset).min(CHUNK_SIZE as u64);
+                pace(throttle, len, cancel).await?;
+                in_flight.push_back(read(offset, len as usize).await?);
+                if let Some(progress) = progress {
+                    progress.on_in_flight(remote, in_flight.len());
+                }
+                next = match offset + len {
+                    offset if offset < end => Some((offset, end)),
+                    _ => pending.next(),
+                };
+            }
+            let Some((offset, len, receiver)) = in_flight.pop_front() else {
+                break;
+            };
+            let reply = await_reply(receiver).await.map_err(CopyError::io("read", remote))?;
+            if let Some(progress) = progress {
+                progress.on_network_bytes(remote, reply.body.len() as u64);
+                progress.on_in_flight(remote, in_flight.len());
+            }
+            if reply.kind == SSH_FXP_STATUS {
+                match status(&reply) {
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                        eof = true;
+                        continue;
+                    }
+                    result => {
+                        result.map_err(CopyError::io("read", remote))?;
+                        return Err(CopyError::io("read", remote)(bad_message()));
+                    }
+                }
+            }
+            let data = expect(&reply, SSH_FXP_DATA)
+                .and_then(|mut r| r.string().map(<[u8]>::to_vec))
+                .map_err(CopyError::io("read", remote))?;
+            if data.is_empty() {
+                eof = true;
+                continue;
+            }
+            dest.seek(io::SeekFrom::Start(offset)).await.map_err(CopyError::io("seek", to))?;
+            dest.write_all(&data).await.map_err(CopyError::io("write", to))?;
+            state.add(offset, offset + data.len() as u64);
+            if let Some(progress) = progress {
+                progress.on_bytes(remote, data.len() as u64);
+            }
+            // The server may return less than asked for before the end of
+            // the file; ask again for the rest.
+            if data.len() < len {
+                in_flight.push_back(read(offset + data.len() as u64, len - data.len()).await?);
+            }
+        }
+        dest.flush().await.map_err(CopyError::io("flush", to))?;
+        Ok(())
+    }
+
+    /// Whether `resumed`, the ranges a transfer resumed with, hold the same
+    /// data in the open file `handle` and in the local file `local`.
+    async fn verify_resumed(
+        &self,
+        handle: &[u8],
+        remote: &Path,
+        local: &Path,
+        resumed: &[(u64, u64)],
+    ) -> Result<bool, CopyError> {
+        if resumed.is_empty() {
+            return Ok(true);
+        }
+        let (local_hash, remote_hash) = tokio::try_join!(
+            async { resume::hash_ranges(local, resumed).await.map_err(CopyError::io("read", local)) },
+            self.hash_ranges(handle, remote, resumed),
+        )?;
+        Ok(local_hash == remote_hash)
+    }
+
+    /// BLAKE3 digest of `ranges` of the open file `handle`, one after the
+    /// other, keeping up to [`MAX_IN_FLIGHT`] READ requests unanswered. A
+    /// file that ends early just hashes differently.
+    async fn hash_ranges(&self, handle: &[u8], remote: &Path, ranges: &[(u64, u64)]) -> Result<[u8; 32], CopyError> {
+        let read = |offset: u64, len: u64| {
+            let mut request = Packet::with_string(handle);
+            request.u64(offset);
+            request.u32(len as u32);
+            async move { self.send(SSH_FXP_READ, &request.0).await.map_err(CopyError::io("read", remote)) }
+        };
+        let mut hasher = blake3::Hasher::new();
+        let mut chunks = ranges.iter().flat_map(|&(start, end)| {
+            (start..end).step_by(CHUNK_SIZE).map(move |offset| (offset, (end - offset).min(CHUNK_SIZE as u64)))
+        });
+        let mut in_flight = VecDeque::new();
+        loop {
+            while in_flight.len() < MAX_IN_FLIGHT {
+                let Some((offset, len)) = chunks.next() else {
+                    break;
+                };
+                in_flight.push_back((offset, len, read(offset, len).await?));
+            }
+            let Some((mut offset, mut len, mut receiver)) = in_flight.pop_front() else {
+                break;
+            };
+            // Data is hashed in order, so a short read is completed before
+            // moving on to the next chunk.
+            loop {
+                let reply = await_reply(receiver).await.map_err(CopyError::io("read", remote))?;
+                let data = match reply.kind {
+                    SSH_FXP_STATUS => match status(&reply) {
+                        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                        result => {
+                            result.map_err(CopyError::io("read", remote))?;
+                            return Err(CopyError::io("read", remote)(bad_message()));
+                        }
+                    },
+                    _ => expect(&reply, SSH_FXP_DATA)
+                        .and_then(|mut r| r.string().map(<[u8]>::to_vec))
+                        .map_err(CopyError::io("read", remote))?,
+                };
+                if data.is_empty() {
+                    break;
+                }
+                hasher.update(&data);
+                offset += data.len() as u64;
+                len = len.saturating_sub(data.len() as u64);
+                if len == 0 {
+                    break;
+                }
+                receiver = read(offset, len).await?;
+            }
+        }
+        Ok(hasher.finalize())
+    }
+}
+
+/// Starts `program` with `args`, reading from and writing to pipes.
+fn spawn_piped(command: &mut Command, what: &str) -> io::Result<Child> {
+    command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| io::Error::new(e.kind(), format!("cannot run {}: {}", what, e)))
+}
+
+/// Waits for `child` and turns a failed exit into an error carrying what it
+/// printed on stderr.
+async fn finished(child: Child, what: &str) -> io::Result<()> {
+    let output = child.wait_with_output().await?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let message = String::from_utf8_lossy(&output.stderr).trim().to_string();
+    Err(io::Error::other(match message.is_empty() {
+        true => format!("{} failed ({})", what, output.status),
+        false => format!("{} failed: {}", what, message),
+    }))
+}
+
+/// Copies `reader` to `writer` like [`tokio::io::copy`], reporting what
+/// passes through as network bytes of `path`.
+async fn copy_counted(
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    path: &Path,
+    progress: Option<&dyn ProgressSink>,
+) -> io::Result<()> {
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let n = reader.read(&mut buffer).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        writer.write_all(&buffer[..n]).await?;
+        if let Some(progress) = progress {
+            progress.on_network_bytes(path, n as u64);
+        }
+    }
+}
+
+/// Quotes `arg` as a single word for the server's shell.
+fn sh_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+impl SftpSession {
+    /// Uploads `from` to `part` through `zstd` on both ends. Progress counts
+    /// the bytes read from `from`.
+    async fn upload_compressed(
+        &self,
+        from: &Path,
+        part: &str,
+        remote: &Path,
+        progress: Option<&dyn ProgressSink>,
+        throttle: Option<&Throttle>,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<u64, CopyError> {
+        let mut source = fs::File::open(from).await.map_err(CopyError::io("open", from))?;
+        let mut compressor =
+            spawn_piped(Command::new("zstd").args(["-q", "-c"]), "zstd").map_err(CopyError::io("compress", from))?;
+        let mut server = spawn_piped(&mut self.command(&format!("zstd -d -q -c > {}", sh_quote(part))), "ssh")
+            .map_err(CopyError::io("create", remote))?;
+        let mut input = compressor.stdin.take().expect("stdin is piped");
+        let mut compressed = compressor.stdout.take().expect("stdout is piped");
+        let mut output = server.stdin.take().expect("stdin is piped");
+
+        let feed = async {
+            let mut buffer = vec![0; CHUNK_SIZE];
+            let mut total = 0u64;
+            loop {
+                let n = source.read(&mut buffer).await.map_err(CopyError::io("read", from))?;
+                if n == 0 {
+                    break;
+                }
+                pace(throttle, n as u64, cancel).await?;
+                input.write_all(&buffer[..n]).await.map_err(CopyError::io("compress", from))?;
+                total += n as u64;
+                if let Some(progress) = progress {
+                    progress.on_bytes(from, n as u64);
+                }
+            }
+            drop(input);
+            Ok(total)
+        };
+        let send = async {
+            copy_counted(&mut compressed, &mut output, from, progress)
+                .await
+                .map_err(CopyError::io("write", remote))?;
+            drop(output);
+            Ok(())
+        };
+        let (total, ()) = tokio::try_join!(feed, send)?;
+        finished(compressor, "zstd").await.map_err(CopyError::io("compress", from))?;
+        finished(server, "zstd on the server").await.map_err(CopyError::io("write", remote))?;
+        Ok(total)
+    }
+
+    /// Downloads `from` to the local file `part` through `zstd` on both ends.
+    /// Progress counts the bytes written to `part`.
+    async fn download_compressed(
+        &self,
+        from: &str,
+        remote: &Path,
+        part: &Path,
+        progress: Option<&dyn ProgressSink>,
+        throttle: Option<&Throttle>,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<u64, CopyError> {
+        let mut server = spawn_piped(&mut self.command(&format!("zstd -q -c < {}", sh_quote(from))), "ssh")
+            .map_err(CopyError::io("open", remote))?;
+        let mut decompressor = spawn_piped(Command::new("zstd").args(["-d", "-q", "-c"]), "zstd")
+            .map_err(CopyError::io("decompress", remote))?;
+        let mut dest = fs::File::create(part).await.map_err(CopyError::io("create", part))?;
+        let mut compressed = server.stdout.take().expect("stdout is piped");
+        let mut input = decompressor.stdin.take().expect("stdin is piped");
+        let mut output = decompressor.stdout.take().expect("stdout is piped");
+
+        let receive = async {
+            copy_counted(&mut compressed, &mut input, remote, progress)
+                .await
+                .map_err(CopyError::io("read", remote))?;
+            drop(input);
+            Ok(())
+        };
+        let write = async {
+            let mut buffer = vec![0; CHUNK_SIZE];
+            let mut total = 0u64;
+            loop {
+                let n = output.read(&mut buffer).await.map_err(CopyError::io("decompress", remote))?;
+                if n == 0 {
+                    break;
+                }
+                pace(throttle, n as u64, cancel).await?;
+                dest.write_all(&buffer[..n]).await.map_err(CopyError::io("write", part))?;
+                total += n as u64;
+                if let Some(progress) = progress {
+                    progress.on_bytes(remote, n as u64);
+                }
+            }
+            dest.flush().await.map_err(CopyError::io("flush", part))?;
+            Ok(total)
+        };
+        let ((), total) = tokio::try_join!(receive, write)?;
+        finished(server, "zstd on the server").await.map_err(CopyError::io("read", remote))?;
+        finished(decompressor, "zstd").await.map_err(CopyError::io("decompress", remote))?;
+        Ok(total)
+    }
+}
+
+impl Transport for SftpSession {
+    fn display(&self, path: &str) -> String {
+        SftpLocation {
+            path: path.to_string(),
+            ..self.server.clone()
+        }
+        .to_string()
+    }
+
+    async fn stat(&self, path: &str) -> io::Result<Option<RemoteStat>> {
+        let reply = self.call(SSH_FXP_STAT, &Packet::with_string(path.as_bytes()).0).await?;
+        match expect(&reply, SSH_FXP_ATTRS) {
+            Ok(mut r) => {
+                let attrs = r.attrs()?;
+                Ok(Some(RemoteStat {
+                    size: attrs.size,
+                    is_dir: attrs.mode & S_IFMT == S_IFDIR,
+                }))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn list(&self, path: &str) -> io::Result<Vec<RemoteEntry>> {
+        let handle = self.opendir(path).await?;
+        let mut entries = Vec::new();
+        let result = async {
+            loop {
+                let reply = self.call(SSH_FXP_READDIR, &Packet::with_string(&handle).0).await?;
+                let mut r = match expect(&reply, SSH_FXP_NAME) {
+                    Ok(r) => r,
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                    Err(e) => return Err(e),
+                };
+                for _ in 0..r.u32()? {
+                    let name = String::from_utf8_lossy(r.string()?).into_owned();
+                    r.string()?;
+                    let attrs = r.attrs()?;
+                    let is_dir = match attrs.mode & S_IFMT {
+                        S_IFDIR => true,
+                        S_IFREG => false,
+                        _ => {
+                            log::warn!("skipping non-regular file: {}", self.display(&name));
+                            continue;
+                        }
+                    };
+                    if name != "." && name != ".." {
+                        entries.push(RemoteEntry {
+                            name,
+                            stat: RemoteStat { size: attrs.size, is_dir },
+                        });
+                    }
+                }
+            }
+        }
+        .await;
+        let closed = self.close(&handle).await;
+        result.and(closed)?;
+        Ok(entries)
+    }
+
+    async fn create_dir(&self, path: &str) -> io::Result<()> {
+        let mut request = Packet::with_string(path.as_bytes());
+        request.u32(0);
+        let result = status(&self.call(SSH_FXP_MKDIR, &request.0).await?);
+        // Servers report an existing directory as a generic failure.
+        match result {
+            Err(_) if self.stat(path).await?.is_some_and(|s| s.is_dir) => Ok(()),
+            result => result,
+        }
+    }
+
+    async fn remove_file(&self, path: &str) -> io::Result<()> {
+        status(&self.call(SSH_FXP_REMOVE, &Packet::with_string(path.as_bytes()).0).await?)
+    }
+
+    /// Uploads `from` to `to.part` on the server and renames it once
+    /// complete. The ranges the server acknowledged are saved in cp2's state
+    /// directory when the upload fails, so that the next attempt only sends
+    /// the rest.
+    async fn upload(
+        &self,
+        from: &Path,
+        to: &str,
+        progress: Option<&dyn ProgressSink>,
+        throttle: Option<&Throttle>,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<u64, CopyError> {
+        let remote = PathBuf::from(self.display(to));
+        let part = format!("{to}.part");
+        let state_path = resume::upload_state_path(&self.display(to));
+        if self.zstd {
+            // A compressed stream can't pick up where another left off, so
+            // it always starts over.
+            let _ = fs::remove_file(&state_path).await;
+            let total = self.upload_compressed(from, &part, &remote, progress, throttle, cancel).await?;
+            self.rename(&part, to).await.map_err(CopyError::io("rename", &remote))?;
+            return Ok(total);
+        }
+        let mut source = fs::File::open(from).await.map_err(CopyError::io("open", from))?;
+        let (size, modified) = resume::identity(from).await.map_err(CopyError::io("stat", from))?;
+        let saved = match self.stat(&part).await {
+            Ok(Some(_)) => ResumeState::load(&state_path, size, modified).await,
+            _ => None,
+        };
+        let flags = match saved {
+            Some(_) => SSH_FXF_READ | SSH_FXF_WRITE | SSH_FXF_CREAT,
+            None => SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_TRUNC,
+        };
+        let mut state = saved.unwrap_or_else(|| ResumeState::new(size, modified));
+        let handle = self.open(&part, flags).await.map_err(CopyError::io("create", &remote))?;
+        report_resumed(progress, from, &state);
+        // What a crashed run left in the `.part` file is checked against the
+        // source while the rest is sent, and sent again if it differs.
+        let resumed = state.ranges.clone();
+        let (result, verified) = tokio::join!(
+            self.write_contents(&handle, from, &remote, &mut source, &mut state, progress, throttle, cancel),
+            self.verify_resumed(&handle, &remote, from, &resumed),
+        );
+        let result = match (result, verified) {
+            (result, Ok(true)) => result,
+            (result, Ok(false)) => {
+                log::warn!("The resumed part of {} doesn't match {}, sending it again", remote.display(), from.display());
+                for &(start, end) in &resumed {
+                    state.remove(start, end);
+                }
+                match result {
+                    Ok(()) => {
+                        self.write_contents(&handle, from, &remote, &mut source, &mut state, None, throttle, cancel)
+                            .await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            (result, Err(e)) => result.and(Err(e)),
+        };
+        // The server may only report a failed write when the file is closed.
+        let closed = self.close(&handle).await.map_err(CopyError::io("close", &remote));
+        if let Err(e) = result.and(closed) {
+            if state.done() > 0 {
+                state.keep(&state_path).await;
+            }
+            return Err(e);
+        }
+        self.rename(&part, to).await.map_err(CopyError::io("rename", &remote))?;
+        let _ = fs::remove_file(&state_path).await;
+        Ok(state.done())
+    }
+
+    /// Downloads `from` to `to.part` and renames it once complete. The
+    /// ranges received are saved in `to.part.json` when the download fails,
+    /// so that the next attempt only asks for the rest.
+    async fn download(
+        &self,
+        from: &str,
+        to: &Path,
+        progress: Option<&dyn ProgressSink>,
+        throttle: Option<&Throttle>,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<u64, CopyError> {
+        let remote = PathBuf::from(self.display(from));
+        let size = match self.stat(from).await.map_err(CopyError::io("stat", &remote))? {
+            Some(stat) => stat.size,
+            None => return Err(CopyError::io("open", &remote)(io::ErrorKind::NotFound.into())),
+        };
+        let part = with_suffix(to, ".part");
+        let state_path = with_suffix(&part, ".json");
+        if self.zstd {
+            let _ = fs::remove_file(&state_path).await;
+            let total = self.download_compressed(from, &remote, &part, progress, throttle, cancel).await?;
+            fs::rename(&part, to).await.map_err(CopyError::io("rename", &part))?;
+            return Ok(total);
+        }
+        let handle = self.open(from, SSH_FXF_READ).await.map_err(CopyError::io("open", &remote))?;
+        let saved = match fs::try_exists(&part).await {
+            Ok(true) => ResumeState::load(&state_path, size, None).await,
+            _ => None,
+        };
+        let opened = match saved {
+            Some(_) => fs::OpenOptions::new().write(true).open(&part).await,
+            None => fs::File::create(&part).await,
+        };
+        let mut state = saved.unwrap_or_else(|| ResumeState::new(size, None));
+        let result = match opened {
+            Ok(mut dest) => {
+                report_resumed(progress, &remote, &state);
+                // What a crashed run left in the `.part` file is checked
+                // against the source while the rest is fetched, and fetched
+                // again if it differs.
+                let resumed = state.ranges.clone();
+                let (result, verified) = tokio::join!(
+                    self.read_contents(&handle, &remote, &part, &mut dest, &mut state, progress, throttle, cancel),
+                    self.verify_resumed(&handle, &remote, &part, &resumed),
+                );
+                match (result, verified) {
+                    (result, Ok(true)) => result,
+                    (result, Ok(false)) => {
+                        log::warn!("The resumed part of {} doesn't match {}, fetching it again", part.display(), remote.display());
+                        for &(start, end) in &resumed {
+                            state.remove(start, end);
+                        }
+                        match result {
+                            Ok(()) => {
+                                self.read_contents(&handle, &remote, &part, &mut dest, &mut state, None, throttle, cancel)
+                                    .await
+                            }
+                            Err(e) => Err(e),
+                        }
+                    }
+                    (result, Err(e)) => result.and(Err(e)),
+                }
+            }
+            Err(e) => Err(CopyError::io("create", &part)(e)),
+        };
+        let _ = self.close(&handle).await;
+        if let Err(e) = result {
+            if state.done() > 0 {
+                state.keep(&state_path).await;
+            }
+            return Err(e);
+        }
+        fs::rename(&part, to).await.map_err(CopyError::io("rename", &part))?;
+        let _ = fs::remove_file(&state_path).await;
+        Ok(state.done())
+    }
+}
+
+/// Counts the bytes a resumed transfer already has towards its progress.
+fn report_resumed(progress: Option<&dyn ProgressSink>, path: &Path, state: &ResumeState) {
+    if let Some(progress) = progress
+        && state.done() > 0
+    {
+        progress.on_bytes(path, state.done());
+    }
+}
+
+/// Waits for the reply to a WRITE request of `n` bytes and reports them.
+async fn written(
+    receiver: oneshot::Receiver<Reply>,
+    n: u64,
+    from: &Path,
+    remote: &Path,
+    progress: Option<&dyn ProgressSink>,
+) -> Result<(), CopyError> {
+    let reply = await_reply(receiver).await.map_err(CopyError::io("write", remote))?;
+    status(&reply).map_err(CopyError::io("write", remote))?;
+    if let Some(progress) = progress {
+        progress.on_bytes(from, n);
+    }
+    Ok(())
+}
+
+async fn await_reply(receiver: oneshot::Receiver<Reply>) -> io::Result<Reply> {
+    receiver.await.map_err(|_| connection_lost())
+}
+
+/// The payload of `reply` if it has the expected type, or the error the
+/// server sent instead.
+fn expect(reply: &Reply, kind: u8) -> io::Result<Reader<'_>> {
+    if reply.kind == kind {
+        return Ok(Reader(&reply.body));
+    }
+    match status(reply) {
+        Ok(()) => Err(bad_message()),
+        Err(e) => Err(e),
+    }
+}
+
+/// The outcome reported by a STATUS reply.
+fn status(reply: &Reply) -> io::Result<()> {
+    if reply.kind != SSH_FXP_STATUS {
+        return Err(bad_message());
+    }
+    let mut r = Reader(&reply.body);
+    let code = r.u32()?;
+    let message = String::from_utf8_lossy(r.string().unwrap_or_default()).into_owned();
+    let kind = match code {
+        SSH_FX_OK => return Ok(()),
+        SSH_FX_EOF => io::ErrorKind::UnexpectedEof,
+        SSH_FX_NO_SUCH_FILE => io::ErrorKind::NotFound,
+        SSH_FX_PERMISSION_DENIED => io::ErrorKind::PermissionDenied,
+        SSH_FX_OP_UNSUPPORTED => io::ErrorKind::Unsupported,
+        _ => io::ErrorKind::Other,
+    };
+    Err(match message.is_empty() {
+        true => io::Error::from(kind),
+        false => io::Error::new(kind, message),
+    })
+}
+
+fn bad_message() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed reply from SFTP server")
+}
+
+fn connection_lost() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "connection to SFTP server lost")
+}
+
+async fn write_packet(writer: &mut ChildStdin, kind: u8, payload: &[u8]) -> io::Result<()> {
+    let mut packet = Vec::with_capacity(payload.len() + 5);
+    packet.extend_from_slice(&(payload.len() as u32 + 1).to_be_bytes());
+    packet.push(kind);
+    packet.extend_from_slice(payload);
+    writer.write_all(&packet).await?;
+    writer.flush().await
+}
+
+async fn read_packet(reader: &mut (impl AsyncRead + Unpin)) -> io::Result<(u8, Vec<u8>)> {
+    let len = reader.read_u32().await? as usize;
+    if len == 0 || len > MAX_PACKET {
+        return Err(bad_message());
+    }
+    let kind = reader.read_u8().await?;
+    let mut body = vec![0; len - 1];
+    reader.read_exact(&mut body).await?;
+    Ok((kind, body))
+}
+
+/// Request payload under construction.
+#[derive(Default)]
+struct Packet(Vec<u8>);
+
+impl Packet {
+    fn with_string(s: &[u8]) -> Self {
+        let mut packet = Self::default();
+        packet.string(s);
+        packet
+    }
+
+    fn u32(&mut self, n: u32) {
+        self.0.extend_from_slice(&n.to_be_bytes());
+    }
+
+    fn u64(&mut self, n: u64) {
+        self.0.extend_from_slice(&n.to_be_bytes());
+    }
+
+    fn string(&mut self, s: &[u8]) {
+        self.u32(s.len() as u32);
+        self.0.extend_from_slice(s);
+    }
+}
+
+/// The attributes of a file that cp2 looks at.
+struct Attrs {
+    size: u64,
+    mode: u32,
+}
+
+/// Reads the fields of a reply payload in order.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(bad_message());
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn attrs(&mut self) -> io::Result<Attrs> {
+        let flags = self.u32()?;
+        let mut attrs = Attrs { size: 0, mode: 0 };
+        if flags & SSH_FILEXFER_ATTR_SIZE != 0 {
+            attrs.size = self.u64()?;
+        }
+        if flags & SSH_FILEXFER_ATTR_UIDGID != 0 {
+            self.take(8)?;
+        }
+        if flags & SSH_FILEXFER_ATTR_PERMISSIONS != 0 {
+            attrs.mode = self.u32()?;
+        }
+        if flags & SSH_FILEXFER_ATTR_ACMODTIME != 0 {
+            self.take(8)?;
+        }
+        if flags & SSH_FILEXFER_ATTR_EXTENDED != 0 {
+            for _ in 0..self.u32()? {
+                self.string()?;
+                self.string()?;
+            }
+        }
+        Ok(attrs)
+    }
+}
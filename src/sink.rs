@@ -0,0 +1,37 @@
+use std::path::Path;
+
+/// Receives the progress of a copy. The copy primitives in [`crate::copy`]
+/// report the bytes they write, and [`crate::copy()`] reports the scan and
+/// the files it starts, finishes, skips or fails, so a GUI or a test can
+/// follow along without depending on indicatif. Every method does nothing by default.
+///
+/// Files are copied concurrently, so calls for different files interleave;
+/// they are told apart by the source path.
+pub trait ProgressSink: Send + Sync {
+    /// The sources were scanned: `files` files of `bytes` bytes in total
+    /// will be copied.
+    fn on_scan_finished(&self, _files: u64, _bytes: u64) {}
+
+    /// `bytes` more bytes of `from` were written to the destination.
+    fn on_bytes(&self, _from: &Path, _bytes: u64) {}
+
+    /// `bytes` more bytes of `from` were sent to or received from a server.
+    /// They differ from the bytes written when the transfer is compressed
+    /// or sent ahead of what the server has acknowledged.
+    fn on_network_bytes(&self, _from: &Path, _bytes: u64) {}
+
+    /// `chunks` requests for `from` are waiting for the server's answer.
+    fn on_in_flight(&self, _from: &Path, _chunks: usize) {}
+
+    /// Copying `from` (`size` bytes) to `to` has begun.
+    fn on_file_start(&self, _from: &Path, _to: &Path, _size: u64) {}
+
+    /// `from` was copied to `to` in full.
+    fn on_file_done(&self, _from: &Path, _to: &Path, _bytes: u64) {}
+
+    /// `from` was deliberately not copied to `to`, e.g. because it exists.
+    fn on_file_skipped(&self, _from: &Path, _to: &Path, _reason: &str) {}
+
+    /// `path` could not be copied.
+    fn on_error(&self, _path: &Path, _message: &str) {}
+}
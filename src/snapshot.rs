@@ -0,0 +1,295 @@
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+
+/// Snapshots this run reads sources from, released before it exits.
+static TAKEN: Mutex<Vec<Snapshot>> = Mutex::new(Vec::new());
+
+/// A point-in-time snapshot of the filesystem holding some sources, which
+/// they are read from instead of the live files so that files changing
+/// during the copy are copied as they were when it started.
+struct Snapshot {
+    /// Directory of the live filesystem the snapshot was taken of.
+    live: PathBuf,
+    /// Where that directory is found in the snapshot. It has the same name,
+    /// so sources read from it keep their names at the destination.
+    root: PathBuf,
+    kind: Kind,
+}
+
+enum Kind {
+    /// A btrfs snapshot of the subvolume at `live`, created at `root`.
+    #[cfg(target_os = "linux")]
+    Btrfs,
+    /// An LVM snapshot volume (`vg/lv`), mounted read-only at `root`.
+    #[cfg(target_os = "linux")]
+    Lvm(String),
+    /// A VSS shadow copy with this ID.
+    #[cfg(windows)]
+    Vss(String),
+}
+
+impl Snapshot {
+    /// Where `path` is found in the snapshot, if it is inside it.
+    fn resolve(&self, path: &Path) -> Option<PathBuf> {
+        let relative = path.strip_prefix(&self.live).ok()?;
+        Some(match relative.as_os_str().is_empty() {
+            true => self.root.clone(),
+            false => self.root.join(relative),
+        })
+    }
+
+    /// Where `path`, inside the snapshot, is found on the live filesystem.
+    fn unresolve(&self, path: &Path) -> Option<PathBuf> {
+        let relative = path.strip_prefix(&self.root).ok()?;
+        Some(match relative.as_os_str().is_empty() {
+            true => self.live.clone(),
+            false => self.live.join(relative),
+        })
+    }
+}
+
+/// Points every source at its copy in a snapshot of its filesystem, taking
+/// one per filesystem. Sources on filesystems that can't be snapshotted are
+/// read live; each is returned with the reason.
+pub(crate) fn read_from_snapshots(sources: &mut [String]) -> Vec<(String, String)> {
+    let mut taken = TAKEN.lock().unwrap();
+    let mut unsupported = Vec::new();
+    for source in sources.iter_mut() {
+        let Some(path) = live_path(Path::new(source)) else {
+            continue;
+        };
+        let resolved = match taken.iter().find_map(|snapshot| snapshot.resolve(&path)) {
+            Some(resolved) => resolved,
+            None => match take(&path) {
+                Ok(snapshot) => {
+                    log::info!("Took a snapshot of {} at {}", snapshot.live.display(), snapshot.root.display());
+                    let resolved = snapshot.resolve(&path).expect("the snapshot holds the path");
+                    taken.push(snapshot);
+                    resolved
+                }
+                Err(e) => {
+                    unsupported.push((source.clone(), e));
+                    continue;
+                }
+            },
+        };
+        if let Ok(resolved) = resolved.into_os_string().into_string() {
+            *source = resolved;
+        }
+    }
+    unsupported
+}
+
+/// Where `path` is read from: its copy in a snapshot this run took, or
+/// `path` itself.
+pub(crate) fn in_snapshot(path: &Path) -> PathBuf {
+    let taken = TAKEN.lock().unwrap();
+    live_path(path)
+        .and_then(|live| taken.iter().find_map(|snapshot| snapshot.resolve(&live)))
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+/// The live path of `path`, which may be inside a snapshot this run took.
+pub(crate) fn live(path: &Path) -> PathBuf {
+    let taken = TAKEN.lock().unwrap();
+    taken
+        .iter()
+        .find_map(|snapshot| snapshot.unresolve(path))
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+/// Removes the snapshots taken by this run.
+pub(crate) fn release_all() {
+    for snapshot in TAKEN.lock().unwrap().drain(..) {
+        if let Err(e) = release(&snapshot) {
+            log::warn!("Cannot remove the snapshot at {}: {}", snapshot.root.display(), e);
+        }
+    }
+}
+
+/// `path` made absolute with its parent's symlinks resolved, leaving a
+/// symlink at `path` itself to be copied as one.
+fn live_path(path: &Path) -> Option<PathBuf> {
+    let path = std::path::absolute(path).ok()?;
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => Some(std::fs::canonicalize(parent).ok()?.join(name)),
+        _ => std::fs::canonicalize(path).ok(),
+    }
+}
+
+/// Runs `program` and returns what it printed, or its error output when it
+/// fails.
+fn run(program: &str, args: impl IntoIterator<Item = impl AsRef<OsStr>>) -> Result<String, String> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| format!("cannot run {}: {}", program, e))?;
+    if !output.status.success() {
+        return Err(format!("{} failed: {}", program, String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Name of the directory a snapshot is placed in.
+#[cfg(target_os = "linux")]
+fn snapshot_dir_name() -> String {
+    format!(".cp2-snapshot-{}", std::process::id())
+}
+
+/// Takes a snapshot of the btrfs subvolume or LVM volume holding `path`.
+#[cfg(target_os = "linux")]
+fn take(path: &Path) -> Result<Snapshot, String> {
+    match btrfs_subvolume(path) {
+        Some(subvolume) => take_btrfs(subvolume),
+        None => take_lvm(path),
+    }
+}
+
+/// The root of the btrfs subvolume holding `path`, whose inode number is
+/// always 256.
+#[cfg(target_os = "linux")]
+fn btrfs_subvolume(path: &Path) -> Option<PathBuf> {
+    use std::os::unix::fs::MetadataExt;
+
+    const BTRFS_SUPER_MAGIC: u32 = 0x9123_683e;
+    const BTRFS_FIRST_FREE_OBJECTID: u64 = 256;
+    if crate::preflight::fs_magic(path) != Some(BTRFS_SUPER_MAGIC) {
+        return None;
+    }
+    let dev = std::fs::metadata(path).ok()?.dev();
+    path.ancestors()
+        .filter_map(|dir| Some((dir, std::fs::metadata(dir).ok()?)))
+        .take_while(|(_, meta)| meta.dev() == dev)
+        .find(|(_, meta)| meta.is_dir() && meta.ino() == BTRFS_FIRST_FREE_OBJECTID)
+        .map(|(dir, _)| dir.to_path_buf())
+}
+
+/// Snapshots `subvolume` into a directory inside it. Creating the directory
+/// first puts an empty copy of it in the snapshot, which is removed before
+/// the snapshot is made read-only.
+#[cfg(target_os = "linux")]
+fn take_btrfs(subvolume: PathBuf) -> Result<Snapshot, String> {
+    let dir_name = snapshot_dir_name();
+    let dir = subvolume.join(&dir_name);
+    std::fs::create_dir(&dir).map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+    let root = dir.join(subvolume.file_name().unwrap_or(OsStr::new("root")));
+    let snapshot = Snapshot {
+        live: subvolume,
+        root,
+        kind: Kind::Btrfs,
+    };
+    let made = run("btrfs", [OsStr::new("subvolume"), OsStr::new("snapshot"), snapshot.live.as_os_str(), snapshot.root.as_os_str()])
+        .and_then(|_| std::fs::remove_dir(snapshot.root.join(&dir_name)).map_err(|e| e.to_string()))
+        .and_then(|_| run("btrfs", [OsStr::new("property"), OsStr::new("set"), OsStr::new("-ts"), snapshot.root.as_os_str(), OsStr::new("ro"), OsStr::new("true")]));
+    match made {
+        Ok(_) => Ok(snapshot),
+        Err(e) => {
+            let _ = release(&snapshot);
+            Err(e)
+        }
+    }
+}
+
+/// Snapshots the LVM logical volume mounted where `path` is and mounts the
+/// snapshot read-only in the temporary directory. The snapshot gets 10% of
+/// the volume's size for the changes made while the copy runs.
+#[cfg(target_os = "linux")]
+fn take_lvm(path: &Path) -> Result<Snapshot, String> {
+    let mount = run("findmnt", [OsStr::new("-n"), OsStr::new("-o"), OsStr::new("SOURCE,TARGET,FSTYPE"), OsStr::new("--target"), path.as_os_str()])?;
+    let [device, target, fstype] = mount.split_whitespace().collect::<Vec<_>>()[..] else {
+        return Err("not on a btrfs subvolume or an LVM volume".to_string());
+    };
+    let volume = run("lvs", ["--noheadings", "-o", "vg_name,lv_name", device])
+        .map_err(|_| "not on a btrfs subvolume or an LVM volume".to_string())?;
+    let [vg, lv] = volume.split_whitespace().collect::<Vec<_>>()[..] else {
+        return Err("not on a btrfs subvolume or an LVM volume".to_string());
+    };
+    let name = snapshot_dir_name().trim_start_matches('.').to_string();
+    run("lvcreate", ["--snapshot", "--extents", "10%ORIGIN", "--name", &name, &format!("{vg}/{lv}")])?;
+    let live = PathBuf::from(target);
+    let root = std::env::temp_dir()
+        .join(&name)
+        .join(live.file_name().unwrap_or(OsStr::new("root")));
+    let snapshot = Snapshot {
+        live,
+        root,
+        kind: Kind::Lvm(format!("{vg}/{name}")),
+    };
+    // XFS refuses to mount a second filesystem with the same UUID.
+    let options = if fstype == "xfs" { "ro,nouuid" } else { "ro" };
+    let mounted = std::fs::create_dir_all(&snapshot.root)
+        .map_err(|e| format!("cannot create {}: {}", snapshot.root.display(), e))
+        .and_then(|_| run("mount", [OsStr::new("-o"), OsStr::new(options), OsStr::new(&format!("/dev/{vg}/{name}")), snapshot.root.as_os_str()]));
+    match mounted {
+        Ok(_) => Ok(snapshot),
+        Err(e) => {
+            let _ = release(&snapshot);
+            Err(e)
+        }
+    }
+}
+
+/// Takes a VSS shadow copy of the volume holding `path`, which needs an
+/// elevated prompt.
+#[cfg(windows)]
+fn take(path: &Path) -> Result<Snapshot, String> {
+    use std::path::Component;
+
+    let mut components = path.components();
+    let (Some(Component::Prefix(prefix)), Some(Component::RootDir)) = (components.next(), components.next()) else {
+        return Err("not on a local volume".to_string());
+    };
+    let live = PathBuf::from(prefix.as_os_str()).join("\\");
+    let script = format!(
+        "$r = Invoke-CimMethod -ClassName Win32_ShadowCopy -MethodName Create -Arguments @{{Volume='{}'; Context='ClientAccessible'}}; \
+         if ($r.ReturnValue -ne 0) {{ [Console]::Error.WriteLine(\"error $($r.ReturnValue)\"); exit 1 }}; \
+         $s = Get-CimInstance Win32_ShadowCopy | Where-Object ID -eq $r.ShadowID; $s.ID; $s.DeviceObject",
+        live.display()
+    );
+    let output = run("powershell", ["-NoProfile", "-NonInteractive", "-Command", &script])?;
+    let [id, device] = output.lines().map(str::trim).collect::<Vec<_>>()[..] else {
+        return Err(format!("unexpected output from powershell: {}", output.trim()));
+    };
+    Ok(Snapshot {
+        live,
+        root: PathBuf::from(format!("{device}\\")),
+        kind: Kind::Vss(id.to_string()),
+    })
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn take(_path: &Path) -> Result<Snapshot, String> {
+    Err("snapshots aren't supported on this platform".to_string())
+}
+
+/// Removes `snapshot` and what was created to hold it.
+fn release(snapshot: &Snapshot) -> Result<(), String> {
+    match snapshot.kind {
+        #[cfg(target_os = "linux")]
+        Kind::Btrfs => {
+            if snapshot.root.exists() {
+                run("btrfs", [OsStr::new("subvolume"), OsStr::new("delete"), snapshot.root.as_os_str()])?;
+            }
+            if let Some(dir) = snapshot.root.parent() {
+                std::fs::remove_dir(dir).map_err(|e| e.to_string())?;
+            }
+        }
+        #[cfg(target_os = "linux")]
+        Kind::Lvm(ref volume) => {
+            let _ = run("umount", [snapshot.root.as_os_str()]);
+            run("lvremove", ["-f", volume])?;
+            let _ = std::fs::remove_dir(&snapshot.root);
+            if let Some(dir) = snapshot.root.parent() {
+                let _ = std::fs::remove_dir(dir);
+            }
+        }
+        #[cfg(windows)]
+        Kind::Vss(ref id) => {
+            let script = format!("Get-CimInstance Win32_ShadowCopy | Where-Object ID -eq '{id}' | Remove-CimInstance");
+            run("powershell", ["-NoProfile", "-NonInteractive", "-Command", &script])?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,457 @@
+use std::io;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::sync::CancellationToken;
+
+use crate::copy::{pace, CopyError};
+use crate::sink::ProgressSink;
+use crate::throttle::Throttle;
+
+/// Tar archives are made of 512-byte blocks.
+const BLOCK: usize = 512;
+
+/// What a tar entry holds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+    /// A hard link to an earlier entry, named by [`Header::link`].
+    HardLink,
+    /// Anything else, such as a FIFO or a device node, by its type flag.
+    Other(u8),
+}
+
+impl EntryKind {
+    fn type_flag(self) -> u8 {
+        match self {
+            EntryKind::File => b'0',
+            EntryKind::Dir => b'5',
+            EntryKind::Symlink => b'2',
+            EntryKind::HardLink => b'1',
+            EntryKind::Other(flag) => flag,
+        }
+    }
+
+    fn from_type_flag(flag: u8) -> Self {
+        match flag {
+            b'0' | b'\0' | b'7' => EntryKind::File,
+            b'5' => EntryKind::Dir,
+            b'2' => EntryKind::Symlink,
+            b'1' => EntryKind::HardLink,
+            flag => EntryKind::Other(flag),
+        }
+    }
+}
+
+/// The header of one tar entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    /// Path inside the archive, `/`-separated and relative.
+    pub path: String,
+    pub kind: EntryKind,
+    /// Size of the file's data; 0 for directories and links.
+    pub size: u64,
+    /// Permission bits.
+    pub mode: u32,
+    /// Modification time, in seconds since the epoch.
+    pub mtime: u64,
+    pub uid: u64,
+    pub gid: u64,
+    /// Target of a symbolic link.
+    pub link: Option<String>,
+}
+
+impl Header {
+    /// A header for `path` carrying the type, size, permissions, owner and
+    /// modification time in `metadata`.
+    pub fn from_metadata(path: String, metadata: &std::fs::Metadata) -> Self {
+        let kind = if metadata.is_dir() {
+            EntryKind::Dir
+        } else if metadata.file_type().is_symlink() {
+            EntryKind::Symlink
+        } else {
+            EntryKind::File
+        };
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+        #[cfg(unix)]
+        let (mode, uid, gid) = {
+            use std::os::unix::fs::MetadataExt;
+            (metadata.mode() & 0o7777, metadata.uid() as u64, metadata.gid() as u64)
+        };
+        #[cfg(not(unix))]
+        let (mode, uid, gid) = (if kind == EntryKind::Dir { 0o755 } else { 0o644 }, 0, 0);
+        Self {
+            path,
+            kind,
+            size: if kind == EntryKind::File { metadata.len() } else { 0 },
+            mode,
+            mtime,
+            uid,
+            gid,
+            link: None,
+        }
+    }
+
+    /// The 512-byte ustar header block, preceded by a pax extended header
+    /// for values that don't fit in it (long paths, files of 8 GiB or more).
+    fn encode(&self) -> Vec<u8> {
+        let mut path = self.path.clone();
+        if self.kind == EntryKind::Dir && !path.ends_with('/') {
+            path.push('/');
+        }
+        let mut pax = Vec::new();
+        if path.len() > 100 {
+            pax_record(&mut pax, "path", &path);
+        }
+        if let Some(ref link) = self.link
+            && link.len() > 100
+        {
+            pax_record(&mut pax, "linkpath", link);
+        }
+        for (key, value, width) in [("size", self.size, 12), ("uid", self.uid, 8), ("gid", self.gid, 8), ("mtime", self.mtime, 12)] {
+            if !fits_octal(value, width) {
+                pax_record(&mut pax, key, &value.to_string());
+            }
+        }
+
+        let mut out = Vec::new();
+        if !pax.is_empty() {
+            let name = format!("PaxHeaders/{}", path.trim_end_matches('/').rsplit('/').next().unwrap_or_default());
+            out.extend(block(&name, b'x', pax.len() as u64, 0o644, self.mtime, 0, 0, ""));
+            out.extend_from_slice(&pax);
+            out.resize(out.len().next_multiple_of(BLOCK), 0);
+        }
+        let size = if fits_octal(self.size, 12) { self.size } else { 0 };
+        out.extend(block(
+            &path,
+            self.kind.type_flag(),
+            size,
+            self.mode,
+            self.mtime,
+            self.uid,
+            self.gid,
+            self.link.as_deref().unwrap_or_default(),
+        ));
+        out
+    }
+}
+
+/// Whether `value` can be written as octal digits in a field of `width`
+/// bytes, which ends in a NUL.
+fn fits_octal(value: u64, width: usize) -> bool {
+    value < 1 << (3 * (width - 1))
+}
+
+/// Appends `len key=value\n` to the pax header `out`; `len` counts the
+/// whole record, itself included.
+fn pax_record(out: &mut Vec<u8>, key: &str, value: &str) {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + rest.to_string().len();
+    if len.to_string().len() != rest.to_string().len() {
+        len += 1;
+    }
+    out.extend_from_slice(format!("{len} {key}={value}\n").as_bytes());
+}
+
+#[allow(clippy::too_many_arguments)]
+fn block(path: &str, type_flag: u8, size: u64, mode: u32, mtime: u64, uid: u64, gid: u64, link: &str) -> [u8; BLOCK] {
+    let mut header = [0u8; BLOCK];
+    let mut field = |at: usize, width: usize, bytes: &[u8]| {
+        let n = bytes.len().min(width);
+        header[at..at + n].copy_from_slice(&bytes[..n]);
+    };
+    let octal = |value: u64, width: usize| {
+        let value = if fits_octal(value, width) { value } else { 0 };
+        format!("{:0w$o}", value, w = width - 1).into_bytes()
+    };
+    field(0, 100, path.as_bytes());
+    field(100, 8, &octal(mode as u64, 8));
+    field(108, 8, &octal(uid, 8));
+    field(116, 8, &octal(gid, 8));
+    field(124, 12, &octal(size, 12));
+    field(136, 12, &octal(mtime, 12));
+    field(148, 8, b"        ");
+    field(156, 1, &[type_flag]);
+    field(157, 100, link.as_bytes());
+    field(257, 8, b"ustar\x0000");
+    let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+    header[148..155].copy_from_slice(format!("{checksum:06o}\0").as_bytes());
+    header
+}
+
+/// Writes a tar archive entry by entry.
+pub struct TarWriter<W> {
+    inner: W,
+}
+
+impl<W: AsyncWrite + Unpin> TarWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner }
+    }
+
+    /// Adds an entry without data: a directory or a symbolic link.
+    pub async fn append(&mut self, header: &Header) -> io::Result<()> {
+        self.inner.write_all(&header.encode()).await
+    }
+
+    /// Adds a file whose `header.size` bytes are read from `data`, reporting
+    /// them to `progress` as coming from `from`. Errors writing the archive
+    /// name `to`.
+    ///
+    /// When `from` can't be read to the end, e.g. because it shrank after its
+    /// header was built, the entry is padded with zeros to keep the archive
+    /// readable and the error says so; a file that grew is cut off at its
+    /// old size.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn append_file<R: AsyncRead + Unpin>(
+        &mut self,
+        header: &Header,
+        from: &Path,
+        to: &Path,
+        data: &mut R,
+        progress: Option<&dyn ProgressSink>,
+        throttle: Option<&Throttle>,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<u64, CopyError> {
+        self.append(header).await.map_err(CopyError::io("write", to))?;
+        let mut buffer = vec![0u8; 1024 * 1024];
+        let mut written = 0u64;
+        let mut failed = None;
+        while written < header.size {
+            let want = (header.size - written).min(buffer.len() as u64) as usize;
+            let n = match data.read(&mut buffer[..want]).await {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) => {
+                    failed = Some(CopyError::io("read", from)(e));
+                    break;
+                }
+            };
+            pace(throttle, n as u64, cancel).await?;
+            self.inner.write_all(&buffer[..n]).await.map_err(CopyError::io("write", to))?;
+            written += n as u64;
+            if let Some(progress) = progress {
+                progress.on_bytes(from, n as u64);
+            }
+        }
+        let padded = header.size.next_multiple_of(BLOCK as u64);
+        let mut zeros = padded - written;
+        while zeros > 0 {
+            let n = zeros.min(buffer.len() as u64) as usize;
+            buffer[..n].fill(0);
+            self.inner.write_all(&buffer[..n]).await.map_err(CopyError::io("write", to))?;
+            zeros -= n as u64;
+        }
+        if let Some(e) = failed {
+            return Err(e);
+        }
+        if written < header.size {
+            return Err(CopyError::VerificationFailed {
+                path: from.to_path_buf(),
+                expected: header.size,
+                written,
+            });
+        }
+        Ok(written)
+    }
+
+    /// Writes the end-of-archive marker and returns the underlying writer,
+    /// flushed.
+    pub async fn finish(mut self) -> io::Result<W> {
+        self.inner.write_all(&[0u8; 2 * BLOCK]).await?;
+        self.inner.flush().await?;
+        Ok(self.inner)
+    }
+}
+
+/// Reads a tar archive entry by entry. ustar, pax and GNU long-name headers
+/// are understood.
+pub struct TarReader<R> {
+    inner: R,
+    /// Bytes before the next header: the unread data of the current entry
+    /// and its padding.
+    skip: u64,
+}
+
+impl<R: AsyncRead + Unpin> TarReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner, skip: 0 }
+    }
+
+    /// The header of the next entry, or `None` at the end of the archive.
+    /// Whatever is left of the previous entry's data is skipped.
+    pub async fn next(&mut self) -> io::Result<Option<Header>> {
+        let skip = std::mem::take(&mut self.skip);
+        self.discard(skip).await?;
+        let mut pax = Vec::new();
+        let mut long_name = None;
+        let mut long_link = None;
+        loop {
+            let mut block = [0u8; BLOCK];
+            if !self.read_block(&mut block).await? || block.iter().all(|&b| b == 0) {
+                return Ok(None);
+            }
+            let stored = parse_number(&block[148..156])?;
+            let checksum: u64 = block.iter().enumerate().map(|(i, &b)| if (148..156).contains(&i) { 32 } else { b as u64 }).sum();
+            if stored != checksum {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "not a tar archive (bad header checksum)"));
+            }
+            let size = parse_number(&block[124..136])?;
+            match block[156] {
+                b'x' => {
+                    pax = self.read_extension(size).await?;
+                    continue;
+                }
+                b'L' => {
+                    long_name = Some(c_string(&self.read_extension(size).await?));
+                    continue;
+                }
+                b'K' => {
+                    long_link = Some(c_string(&self.read_extension(size).await?));
+                    continue;
+                }
+                b'g' => {
+                    self.discard(size.next_multiple_of(BLOCK as u64)).await?;
+                    continue;
+                }
+                _ => {}
+            }
+
+            let mut header = Header {
+                path: long_name.take().unwrap_or_else(|| {
+                    let name = c_string(&block[0..100]);
+                    let prefix = c_string(&block[345..500]);
+                    if &block[257..262] == b"ustar" && !prefix.is_empty() { format!("{prefix}/{name}") } else { name }
+                }),
+                kind: EntryKind::from_type_flag(block[156]),
+                size,
+                mode: parse_number(&block[100..108])? as u32,
+                mtime: parse_number(&block[136..148])?,
+                uid: parse_number(&block[108..116])?,
+                gid: parse_number(&block[116..124])?,
+                link: long_link.take().or_else(|| Some(c_string(&block[157..257])).filter(|l| !l.is_empty())),
+            };
+            for line in String::from_utf8_lossy(&pax).lines() {
+                let Some((key, value)) = line.split_once(' ').and_then(|(_, record)| record.split_once('=')) else {
+                    continue;
+                };
+                let number = value.split('.').next().and_then(|v| v.parse().ok());
+                match key {
+                    "path" => header.path = value.to_string(),
+                    "linkpath" => header.link = Some(value.to_string()),
+                    "size" => header.size = number.unwrap_or(header.size),
+                    "mtime" => header.mtime = number.unwrap_or(header.mtime),
+                    "uid" => header.uid = number.unwrap_or(header.uid),
+                    "gid" => header.gid = number.unwrap_or(header.gid),
+                    _ => {}
+                }
+            }
+            // Directories and links have no data, whatever their size says.
+            let data = match header.kind {
+                EntryKind::Dir | EntryKind::Symlink | EntryKind::HardLink => 0,
+                _ => header.size,
+            };
+            self.skip = data.next_multiple_of(BLOCK as u64);
+            return Ok(Some(header));
+        }
+    }
+
+    /// Copies the data of the entry `header`, just returned by
+    /// [`next`](Self::next), to `out`, reporting it to `progress` as coming
+    /// from `from`, the archive. Errors writing the data name `to`.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn copy_data<W: AsyncWrite + Unpin>(
+        &mut self,
+        header: &Header,
+        from: &Path,
+        to: &Path,
+        out: &mut W,
+        progress: Option<&dyn ProgressSink>,
+        throttle: Option<&Throttle>,
+        cancel: Option<&CancellationToken>,
+    ) -> Result<u64, CopyError> {
+        let mut buffer = vec![0u8; 1024 * 1024];
+        let mut copied = 0u64;
+        while copied < header.size {
+            let want = (header.size - copied).min(buffer.len() as u64) as usize;
+            let n = self.inner.read(&mut buffer[..want]).await.map_err(CopyError::io("read", from))?;
+            if n == 0 {
+                let e = io::Error::new(io::ErrorKind::UnexpectedEof, "archive ends in the middle of a file");
+                return Err(CopyError::io("read", from)(e));
+            }
+            self.skip -= n as u64;
+            pace(throttle, n as u64, cancel).await?;
+            out.write_all(&buffer[..n]).await.map_err(CopyError::io("write", to))?;
+            copied += n as u64;
+            if let Some(progress) = progress {
+                progress.on_bytes(from, n as u64);
+            }
+        }
+        out.flush().await.map_err(CopyError::io("flush", to))?;
+        Ok(copied)
+    }
+
+    /// Reads a whole block; `false` when the archive ends before it.
+    async fn read_block(&mut self, block: &mut [u8; BLOCK]) -> io::Result<bool> {
+        let mut filled = 0;
+        while filled < BLOCK {
+            match self.inner.read(&mut block[filled..]).await? {
+                0 if filled == 0 => return Ok(false),
+                0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "archive ends in the middle of a header")),
+                n => filled += n,
+            }
+        }
+        Ok(true)
+    }
+
+    /// The data of a pax or GNU extension header of `size` bytes.
+    async fn read_extension(&mut self, size: u64) -> io::Result<Vec<u8>> {
+        if size > 1024 * 1024 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "tar extension header too large"));
+        }
+        let mut data = vec![0u8; size.next_multiple_of(BLOCK as u64) as usize];
+        self.inner.read_exact(&mut data).await?;
+        data.truncate(size as usize);
+        Ok(data)
+    }
+
+    async fn discard(&mut self, bytes: u64) -> io::Result<()> {
+        let copied = tokio::io::copy(&mut (&mut self.inner).take(bytes), &mut tokio::io::sink()).await?;
+        if copied < bytes {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "archive ends in the middle of a file"));
+        }
+        Ok(())
+    }
+}
+
+/// A NUL-terminated header field as a string.
+fn c_string(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// A numeric header field: octal digits padded with spaces or NULs, or
+/// GNU's base-256 for values too large for them.
+fn parse_number(field: &[u8]) -> io::Result<u64> {
+    if field[0] & 0x80 != 0 {
+        return Ok(field[1..].iter().fold((field[0] & 0x7f) as u64, |n, &b| (n << 8) | b as u64));
+    }
+    let digits = String::from_utf8_lossy(field);
+    let digits = digits.trim_matches(|c: char| c == ' ' || c == '\0');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("not a tar archive (bad number {digits:?})")))
+}
+
+/// Whether `path` names a tar archive, by its extension.
+pub fn is_tar_path(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("tar"))
+}
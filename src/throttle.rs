@@ -0,0 +1,85 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// Shared pause switch and bandwidth limit for all copy tasks of a run.
+///
+/// Copy loops call [`Throttle::acquire`] before moving each chunk; it waits
+/// while the run is paused and spaces chunks out so the combined rate stays
+/// under the limit. Both can be changed while the copy is running. Clones
+/// share the same state, like [`indicatif::ProgressBar`].
+#[derive(Clone)]
+pub struct Throttle {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    /// Bytes per second; 0 means unlimited.
+    limit: AtomicU64,
+    paused: watch::Sender<bool>,
+    /// When the next chunk may start under the current limit.
+    next_slot: Mutex<Instant>,
+}
+
+impl Throttle {
+    pub fn new(limit: Option<u64>) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                limit: AtomicU64::new(limit.unwrap_or(0)),
+                paused: watch::Sender::new(false),
+                next_slot: Mutex::new(Instant::now()),
+            }),
+        }
+    }
+
+    /// The current limit in bytes per second, if any.
+    pub fn limit(&self) -> Option<u64> {
+        match self.inner.limit.load(Ordering::Relaxed) {
+            0 => None,
+            limit => Some(limit),
+        }
+    }
+
+    pub fn set_limit(&self, limit: Option<u64>) {
+        self.inner.limit.store(limit.unwrap_or(0), Ordering::Relaxed);
+        // Forget the schedule built up under the old limit.
+        *self.inner.next_slot.lock().unwrap() = Instant::now();
+    }
+
+    pub fn pause(&self) {
+        self.inner.paused.send_replace(true);
+    }
+
+    pub fn resume(&self) {
+        self.inner.paused.send_replace(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.inner.paused.borrow()
+    }
+
+    /// Waits until `bytes` more may be transferred.
+    pub async fn acquire(&self, bytes: u64) {
+        let mut paused = self.inner.paused.subscribe();
+        // The sender lives as long as `self`, so this can't fail.
+        let _ = paused.wait_for(|paused| !paused).await;
+
+        let limit = self.inner.limit.load(Ordering::Relaxed);
+        if limit == 0 {
+            return;
+        }
+        // Each chunk reserves the next `bytes / limit` seconds of the schedule
+        // and may proceed once its slot has passed.
+        let wait = {
+            let mut next_slot = self.inner.next_slot.lock().unwrap();
+            let now = Instant::now();
+            let end = (*next_slot).max(now) + Duration::from_secs_f64(bytes as f64 / limit as f64);
+            *next_slot = end;
+            end - now
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
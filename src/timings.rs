@@ -0,0 +1,125 @@
+use colored::Colorize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::progress::format_elapsed;
+
+/// Whether `--timings` asked for the time spent in each phase.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Time spent so far in each phase, by source file.
+static FILES: Mutex<Option<HashMap<PathBuf, [Duration; PHASES.len()]>>> = Mutex::new(None);
+
+/// Number of slowest files listed in the breakdown.
+const SLOWEST: usize = 5;
+
+/// A part of copying a file that `--timings` accounts for separately.
+#[derive(Clone, Copy)]
+pub(crate) enum Phase {
+    /// Opening the source and creating the destination.
+    Open,
+    /// Reading the source.
+    Read,
+    /// Writing the destination.
+    Write,
+    /// Flushing and syncing the destination.
+    Sync,
+    /// Setting permissions, times and other metadata on the destination.
+    Metadata,
+}
+
+const PHASES: [Phase; 5] = [Phase::Open, Phase::Read, Phase::Write, Phase::Sync, Phase::Metadata];
+
+impl Phase {
+    fn name(self) -> &'static str {
+        match self {
+            Phase::Open => "open",
+            Phase::Read => "read",
+            Phase::Write => "write",
+            Phase::Sync => "sync",
+            Phase::Metadata => "metadata",
+        }
+    }
+
+    /// What it means when most of the time went to this phase.
+    fn bottleneck(self) -> &'static str {
+        match self {
+            Phase::Open => "opening and creating files: many small files, or a slow filesystem",
+            Phase::Read => "reading: the source is the bottleneck",
+            Phase::Write => "writing: the destination is the bottleneck",
+            Phase::Sync => "syncing: --sync waits for every file to reach the disk",
+            Phase::Metadata => "setting metadata: try --preserve with fewer attributes",
+        }
+    }
+}
+
+/// Starts recording the time spent in each phase, for [`print_breakdown`].
+pub(crate) fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Runs `f`, counting the time it takes towards `phase` of copying `from`.
+pub(crate) async fn time<T>(from: &Path, phase: Phase, f: impl Future<Output = T>) -> T {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return f.await;
+    }
+    let started = Instant::now();
+    let output = f.await;
+    record(from, phase, started.elapsed());
+    output
+}
+
+/// Counts `elapsed` towards `phase` of copying `from`.
+pub(crate) fn record(from: &Path, phase: Phase, elapsed: Duration) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut files = FILES.lock().unwrap();
+    let phases = files.get_or_insert_default().entry(from.to_path_buf()).or_default();
+    phases[phase as usize] += elapsed;
+}
+
+/// Prints the time spent in each phase over all files, the slowest files
+/// and which phase took longest.
+pub(crate) fn print_breakdown() {
+    let files = FILES.lock().unwrap().take().unwrap_or_default();
+    if files.is_empty() {
+        return;
+    }
+    let mut totals = [Duration::ZERO; PHASES.len()];
+    for phases in files.values() {
+        for (total, elapsed) in totals.iter_mut().zip(phases) {
+            *total += *elapsed;
+        }
+    }
+    let sum: Duration = totals.iter().sum();
+    eprintln!();
+    eprintln!("{}", "Timings (summed over all files, which overlap when copied in parallel):".bold());
+    for phase in PHASES {
+        let total = totals[phase as usize];
+        eprintln!(
+            "  {:<9} {:>8} {:>4.0}%",
+            phase.name(),
+            format_elapsed(total),
+            100.0 * total.as_secs_f64() / sum.as_secs_f64().max(f64::EPSILON)
+        );
+    }
+
+    let mut slowest: Vec<_> = files.iter().map(|(path, phases)| (phases.iter().sum::<Duration>(), path, phases)).collect();
+    slowest.sort_by_key(|&(total, _, _)| std::cmp::Reverse(total));
+    eprintln!("{}", "Slowest files:".bold());
+    for (total, path, phases) in slowest.into_iter().take(SLOWEST) {
+        let parts: Vec<_> = PHASES
+            .iter()
+            .filter(|&&phase| !phases[phase as usize].is_zero())
+            .map(|&phase| format!("{} {}", phase.name(), format_elapsed(phases[phase as usize])))
+            .collect();
+        eprintln!("  {:>8}  {} ({})", format_elapsed(total), path.display(), parts.join(", "));
+    }
+
+    let top = PHASES.into_iter().max_by_key(|&phase| totals[phase as usize]).expect("phases");
+    eprintln!("Most time went to {}", top.bottleneck());
+}
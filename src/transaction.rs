@@ -0,0 +1,148 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tokio::fs;
+
+use crate::resume::with_suffix;
+use crate::undo::UndoLog;
+
+/// Suffix of the temporary names `--transactional` writes files under.
+const STAGED_SUFFIX: &str = ".cp2-tmp";
+
+/// Suffix a replaced destination is kept under while a transaction is
+/// committed, so that it can be put back if a later rename fails.
+const BACKUP_SUFFIX: &str = ".cp2-old";
+
+/// What a `--transactional` copy has written so far. Files are written under
+/// temporary names next to their destinations and renamed into place once
+/// every file succeeded; otherwise everything is removed again, leaving the
+/// destination as it was before the run.
+#[derive(Default)]
+pub(crate) struct Transaction {
+    /// Copied files, by destination; each is still under its temporary name.
+    staged: Mutex<Vec<PathBuf>>,
+    /// Directories and special files that didn't exist before the run.
+    created: Mutex<Vec<PathBuf>>,
+}
+
+impl Transaction {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// The temporary name the file for `to` is written under.
+    pub(crate) fn staged_path(to: &Path) -> PathBuf {
+        with_suffix(to, STAGED_SUFFIX)
+    }
+
+    /// Records that the file for `to` was written under its temporary name.
+    pub(crate) fn staged(&self, to: &Path) {
+        self.staged.lock().unwrap().push(to.to_path_buf());
+    }
+
+    /// Records a directory or special file the run created.
+    pub(crate) fn created(&self, path: &Path) {
+        self.created.lock().unwrap().push(path.to_path_buf());
+    }
+
+    /// Renames every copied file into place. The destinations they replace
+    /// are set aside until all are in place, so that when a rename fails
+    /// they can be put back and the copy rolled back. Once committed, the
+    /// changes are recorded in `undo`, which keeps the replaced files.
+    pub(crate) async fn commit(&self, undo: Option<&UndoLog>) -> io::Result<()> {
+        let staged = std::mem::take(&mut *self.staged.lock().unwrap());
+        let mut committed: Vec<(&Path, Option<PathBuf>)> = Vec::with_capacity(staged.len());
+        for (i, to) in staged.iter().enumerate() {
+            match replace(to).await {
+                Ok(backup) => committed.push((to, backup)),
+                Err(e) => {
+                    for (to, backup) in committed.into_iter().rev() {
+                        let restored = match backup {
+                            Some(backup) => fs::rename(backup, to).await,
+                            None => fs::remove_file(to).await,
+                        };
+                        if let Err(e) = restored {
+                            log::warn!("Cannot restore {}: {}", to.display(), e);
+                        }
+                    }
+                    for to in &staged[i..] {
+                        remove(&Self::staged_path(to)).await;
+                    }
+                    self.remove_created().await;
+                    return Err(io::Error::new(e.kind(), format!("cannot move {} into place: {}", to.display(), e)));
+                }
+            }
+        }
+        for (to, backup) in committed {
+            match (undo, backup) {
+                (Some(undo), None) => undo.created(to),
+                (Some(undo), Some(backup)) => {
+                    if let Err(e) = undo.keep(to, &backup) {
+                        log::warn!("Cannot back up {} for cp2 undo: {}", to.display(), e);
+                        remove(&backup).await;
+                    }
+                }
+                (None, Some(backup)) => remove(&backup).await,
+                (None, None) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes everything the run wrote.
+    pub(crate) async fn rollback(&self) {
+        let staged = std::mem::take(&mut *self.staged.lock().unwrap());
+        for to in staged {
+            remove(&Self::staged_path(&to)).await;
+        }
+        self.remove_created().await;
+    }
+
+    /// Removes the directories and special files the run created, deepest
+    /// first so that directories are empty by the time they are removed.
+    async fn remove_created(&self) {
+        let mut created = std::mem::take(&mut *self.created.lock().unwrap());
+        created.sort_by_key(|path| std::cmp::Reverse(path.components().count()));
+        for path in created {
+            match fs::symlink_metadata(&path).await {
+                Ok(meta) if meta.is_dir() => {
+                    if let Err(e) = fs::remove_dir(&path).await {
+                        log::warn!("Cannot remove {}: {}", path.display(), e);
+                    }
+                }
+                Ok(_) => remove(&path).await,
+                Err(_) => {}
+            }
+        }
+    }
+}
+
+/// Moves the file written for `to` into place, returning where the file it
+/// replaced was set aside.
+async fn replace(to: &Path) -> io::Result<Option<PathBuf>> {
+    let backup = match fs::symlink_metadata(to).await {
+        Ok(_) => {
+            let backup = with_suffix(to, BACKUP_SUFFIX);
+            fs::rename(to, &backup).await?;
+            Some(backup)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    if let Err(e) = fs::rename(Transaction::staged_path(to), to).await {
+        if let Some(ref backup) = backup {
+            let _ = fs::rename(backup, to).await;
+        }
+        return Err(e);
+    }
+    Ok(backup)
+}
+
+/// Removes the file at `path`, if there is one.
+async fn remove(path: &Path) {
+    if let Err(e) = fs::remove_file(path).await
+        && e.kind() != io::ErrorKind::NotFound
+    {
+        log::warn!("Cannot remove {}: {}", path.display(), e);
+    }
+}
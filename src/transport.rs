@@ -0,0 +1,114 @@
+use std::future::Future;
+use std::io;
+use std::path::Path;
+use tokio_util::sync::CancellationToken;
+
+use crate::copy::CopyError;
+use crate::sink::ProgressSink;
+use crate::throttle::Throttle;
+
+/// Kind and size of a file on a remote filesystem.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RemoteStat {
+    pub size: u64,
+    pub is_dir: bool,
+}
+
+/// A directory entry on a remote filesystem.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RemoteEntry {
+    pub name: String,
+    pub stat: RemoteStat,
+}
+
+/// The file operations cp2 needs to copy trees to and from a remote
+/// filesystem. Remote paths are `/`-separated strings as the server
+/// understands them.
+pub trait Transport: Send + Sync + 'static {
+    /// How `path` is shown to the user, e.g. `user@host:/srv/app`.
+    fn display(&self, path: &str) -> String;
+
+    /// Name given to `path` when it is copied into a local directory.
+    fn file_name(&self, path: &str) -> String {
+        remote_file_name(path).to_string()
+    }
+
+    /// Kind and size of `path`, or `None` if it doesn't exist.
+    fn stat(&self, path: &str) -> impl Future<Output = io::Result<Option<RemoteStat>>> + Send;
+
+    /// Entries of the directory `path`, without `.` and `..`. Entries that are
+    /// neither regular files nor directories, such as symbolic links, are
+    /// left out.
+    fn list(&self, path: &str) -> impl Future<Output = io::Result<Vec<RemoteEntry>>> + Send;
+
+    /// Creates the directory `path`; one that already exists is fine.
+    fn create_dir(&self, path: &str) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// Removes the file `path`, e.g. a partial upload.
+    fn remove_file(&self, path: &str) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// Copies the local file `from` to `to`, replacing it.
+    fn upload(
+        &self,
+        from: &Path,
+        to: &str,
+        progress: Option<&dyn ProgressSink>,
+        throttle: Option<&Throttle>,
+        cancel: Option<&CancellationToken>,
+    ) -> impl Future<Output = Result<u64, CopyError>> + Send;
+
+    /// Copies the remote file `from` to the local file `to`, replacing it.
+    fn download(
+        &self,
+        from: &str,
+        to: &Path,
+        progress: Option<&dyn ProgressSink>,
+        throttle: Option<&Throttle>,
+        cancel: Option<&CancellationToken>,
+    ) -> impl Future<Output = Result<u64, CopyError>> + Send;
+}
+
+/// Appends `name` to the remote directory `dir`.
+pub fn join_remote(dir: &str, name: &str) -> String {
+    match dir {
+        "" | "." => name.to_string(),
+        _ if dir.ends_with('/') => format!("{dir}{name}"),
+        _ => format!("{dir}/{name}"),
+    }
+}
+
+/// Last component of a remote path, ignoring trailing slashes.
+pub fn remote_file_name(path: &str) -> &str {
+    let trimmed = path.trim_end_matches('/');
+    trimmed.rsplit('/').next().unwrap_or(trimmed)
+}
+
+/// Every file below the remote directory `root`, as paths relative to it
+/// with their sizes, and every directory below it (parents first). With
+/// `skip_hidden`, entries whose name starts with a dot are left out.
+pub async fn walk_remote<T: Transport>(
+    transport: &T,
+    root: &str,
+    skip_hidden: bool,
+) -> io::Result<(Vec<(String, u64)>, Vec<String>)> {
+    let mut files = Vec::new();
+    let mut dirs = Vec::new();
+    let mut pending = vec![String::new()];
+    while let Some(relative) = pending.pop() {
+        let dir = if relative.is_empty() { root.to_string() } else { join_remote(root, &relative) };
+        for entry in transport.list(&dir).await? {
+            if skip_hidden && entry.name.starts_with('.') {
+                continue;
+            }
+            let path = join_remote(&relative, &entry.name);
+            if entry.stat.is_dir {
+                dirs.push(path.clone());
+                pending.push(path);
+            } else {
+                files.push((path, entry.stat.size));
+            }
+        }
+    }
+    dirs.sort_by_key(|d| d.matches('/').count());
+    Ok((files, dirs))
+}
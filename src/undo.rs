@@ -0,0 +1,253 @@
+use serde::{Deserialize, Serialize};
+use std::fs::TryLockError;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::resume::undo_dir;
+
+/// Version of the undo journal format, bumped on incompatible changes.
+const VERSION: u32 = 1;
+
+/// Directory in the destination that overwritten files are moved to until
+/// the next copy, so that renaming them there is cheap.
+const BACKUP_DIR: &str = ".cp2-undo";
+
+/// One line of the undo journal: the header, then every change in the
+/// order it was made.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Line {
+    Header {
+        version: u32,
+        /// The command line of the copy, program name first.
+        args: Vec<String>,
+        /// When the copy started, in seconds since the epoch.
+        started: u64,
+        /// Directory the overwritten files of this copy were moved to.
+        backups: PathBuf,
+    },
+    Created {
+        created: PathBuf,
+    },
+    Overwritten {
+        overwritten: PathBuf,
+        backup: PathBuf,
+    },
+}
+
+/// A change made by a copy.
+pub(crate) enum Change {
+    /// A file, directory or special file that didn't exist before.
+    Created(PathBuf),
+    /// A file that replaced another one, which was kept at `backup`.
+    Overwritten { path: PathBuf, backup: PathBuf },
+}
+
+/// A copy read back from its undo journal, which stays locked so that it
+/// isn't undone or discarded twice at once.
+pub(crate) struct UndoPlan {
+    path: PathBuf,
+    _file: std::fs::File,
+    pub(crate) args: Vec<String>,
+    pub(crate) started: u64,
+    backups: PathBuf,
+    /// The changes of the copy, in the order they were made.
+    pub(crate) changes: Vec<Change>,
+}
+
+impl UndoPlan {
+    /// The last copy, or `None` if none is left to undo. A copy that is
+    /// still running can't be undone yet.
+    pub(crate) fn last() -> io::Result<Option<Self>> {
+        let Some(path) = journals().pop() else {
+            return Ok(None);
+        };
+        match Self::open(&path)? {
+            Some(plan) => Ok(Some(plan)),
+            None => Err(io::Error::new(io::ErrorKind::ResourceBusy, "the last copy is still running")),
+        }
+    }
+
+    /// Reads the journal at `path`; `None` if a running copy holds it.
+    fn open(path: &Path) -> io::Result<Option<Self>> {
+        let file = std::fs::File::open(path)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Ok(None),
+            Err(TryLockError::Error(e)) => return Err(e),
+        }
+        let mut lines = io::BufReader::new(&file).lines();
+        let Some(Ok(Line::Header { version, args, started, backups })) =
+            lines.next().transpose()?.map(|line| serde_json::from_str(&line))
+        else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is not an undo journal", path.display())));
+        };
+        if version > VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "the last copy was made by a newer cp2"));
+        }
+        let mut changes = Vec::new();
+        for line in lines {
+            match serde_json::from_str(&line?) {
+                Ok(Line::Created { created }) => changes.push(Change::Created(created)),
+                Ok(Line::Overwritten { overwritten, backup }) => changes.push(Change::Overwritten { path: overwritten, backup }),
+                _ => {}
+            }
+        }
+        Ok(Some(Self {
+            path: path.to_path_buf(),
+            _file: file,
+            args,
+            started,
+            backups,
+            changes,
+        }))
+    }
+
+    /// Removes the journal and the files it kept, once the copy has been
+    /// undone or a newer copy is recorded instead.
+    pub(crate) fn discard(self) -> io::Result<()> {
+        match std::fs::remove_dir_all(&self.backups) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        // Other copies into the same destination may still have theirs.
+        if let Some(parent) = self.backups.parent() {
+            let _ = std::fs::remove_dir(parent);
+        }
+        std::fs::remove_file(&self.path)
+    }
+}
+
+/// The undo journals, oldest first. They are named after the time their
+/// copy started and its process ID.
+fn journals() -> Vec<PathBuf> {
+    let mut journals: Vec<(u64, u32, PathBuf)> = std::fs::read_dir(undo_dir())
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "jsonl"))
+        .filter_map(|path| {
+            let stem = path.file_stem()?.to_str()?;
+            let (started, pid) = stem.split_once('-')?;
+            Some((started.parse().ok()?, pid.parse().ok()?, path))
+        })
+        .collect();
+    journals.sort();
+    journals.into_iter().map(|(_, _, path)| path).collect()
+}
+
+/// The undo journal of a running copy, to which every change is appended
+/// before it is made. It is locked until the copy has finished.
+pub(crate) struct UndoLog {
+    path: PathBuf,
+    backups: PathBuf,
+    /// Number of files moved to `backups` so far, which names the next one.
+    backed_up: AtomicUsize,
+    file: Mutex<std::fs::File>,
+}
+
+impl UndoLog {
+    /// Starts the journal of a copy into `destination`. Only the last copy
+    /// can be undone, so the journals of earlier ones that have finished are
+    /// discarded, along with the files they kept.
+    pub(crate) fn start(destination: &Path) -> io::Result<Self> {
+        for path in journals() {
+            match UndoPlan::open(&path) {
+                Ok(Some(plan)) => {
+                    if let Err(e) = plan.discard() {
+                        log::warn!("Cannot discard {}: {}", path.display(), e);
+                    }
+                }
+                Ok(None) => {}
+                // A journal that can't be read can't be undone either.
+                Err(e) => {
+                    log::debug!("Removing unreadable undo journal {}: {}", path.display(), e);
+                    let _ = std::fs::remove_file(&path);
+                }
+            }
+        }
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let id = format!("{}-{}", started, std::process::id());
+        let backups = std::path::absolute(destination)?.join(BACKUP_DIR).join(&id);
+        let dir = undo_dir();
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("{id}.jsonl"));
+        let mut file = std::fs::File::create(&path)?;
+        file.lock()?;
+        let mut header = serde_json::to_vec(&Line::Header {
+            version: VERSION,
+            args: std::env::args().collect(),
+            started,
+            backups: backups.clone(),
+        })?;
+        header.push(b'\n');
+        file.write_all(&header)?;
+        Ok(Self {
+            path,
+            backups,
+            backed_up: AtomicUsize::new(0),
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Records that `path` is about to be created.
+    pub(crate) fn created(&self, path: &Path) {
+        self.append(&Line::Created { created: path.to_path_buf() });
+    }
+
+    /// Records that the file at `to` is about to be written: a file already
+    /// there is moved aside to be restored by `cp2 undo`, and where it went
+    /// is returned. Without `back_up`, an existing file is left to be
+    /// updated in place and can't be restored.
+    pub(crate) fn before_write(&self, to: &Path, back_up: bool) -> Option<PathBuf> {
+        match std::fs::symlink_metadata(to) {
+            Ok(meta) if meta.is_dir() => None,
+            Ok(_) if back_up => match self.keep(to, to) {
+                Ok(backup) => Some(backup),
+                Err(e) => {
+                    log::warn!("Cannot back up {} for cp2 undo: {}", to.display(), e);
+                    None
+                }
+            },
+            Ok(_) => None,
+            Err(_) => {
+                self.created(to);
+                None
+            }
+        }
+    }
+
+    /// Moves `file`, the file that was at `to` before the copy, aside to be
+    /// restored by `cp2 undo`, and returns where it went.
+    pub(crate) fn keep(&self, to: &Path, file: &Path) -> io::Result<PathBuf> {
+        std::fs::create_dir_all(&self.backups)?;
+        let backup = self.backups.join(self.backed_up.fetch_add(1, Ordering::Relaxed).to_string());
+        std::fs::rename(file, &backup)?;
+        self.append(&Line::Overwritten {
+            overwritten: to.to_path_buf(),
+            backup: backup.clone(),
+        });
+        Ok(backup)
+    }
+
+    /// Puts the file moved aside for `to` back after its copy failed.
+    pub(crate) fn put_back(&self, backup: &Path, to: &Path) {
+        if let Err(e) = std::fs::rename(backup, to) {
+            log::warn!("Cannot restore {}: {}", to.display(), e);
+        }
+    }
+
+    /// Appends `line` to the journal. Failing to is only logged: the copy
+    /// matters more than being able to undo it.
+    fn append(&self, line: &Line) {
+        let mut line = serde_json::to_vec(line).expect("undo lines serialize");
+        line.push(b'\n');
+        if let Err(e) = self.file.lock().unwrap().write_all(&line) {
+            log::warn!("Cannot update undo journal {}: {}", self.path.display(), e);
+        }
+    }
+}
@@ -1,5 +1,11 @@
+use indicatif::ProgressBar;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tokio::fs;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+use crate::copy::CopyError;
 
 /// Trims long file names for display
 pub fn trim_filename(name: &str, max_len: usize) -> String {
@@ -22,25 +28,487 @@ pub fn trim_filename(name: &str, max_len: usize) -> String {
     format!("{}{}{}", &name[..start_len], ellipsis, &name[name.len() - end_len..])
 }
 
+/// Parses a human-readable size such as `512`, `64K`, `1.5G` or `2TiB` into a
+/// byte count. Suffixes are binary (1K = 1024 bytes) and case-insensitive.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let trimmed = s.trim();
+    let split = trimmed
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(trimmed.len());
+    let (number, suffix) = trimmed.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("`{s}` is not a valid size"))?;
+
+    let multiplier: u64 = match suffix.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => return Err(format!("`{s}` has an unknown size suffix")),
+    };
+
+    let bytes = number * multiplier as f64;
+    if !bytes.is_finite() || bytes > u64::MAX as f64 {
+        return Err(format!("`{s}` is too large"));
+    }
+    Ok(bytes as u64)
+}
+
+/// Parses a duration such as `500ms`, `2s`, `1.5m` or `1h`. A bare number
+/// is taken as seconds.
+pub fn parse_duration(s: &str) -> Result<std::time::Duration, String> {
+    let trimmed = s.trim();
+    let split = trimmed
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(trimmed.len());
+    let (number, unit) = trimmed.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("`{s}` is not a valid duration"))?;
+
+    let seconds = match unit.trim().to_ascii_lowercase().as_str() {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => return Err(format!("`{s}` has an unknown duration unit (use ms, s, m or h)")),
+    };
+    std::time::Duration::try_from_secs_f64(seconds).map_err(|_| format!("`{s}` is out of range"))
+}
+
+/// What to do with a source file that another process holds open
+/// exclusively (`--locked`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockedPolicy {
+    /// Skip the file with a warning.
+    Skip,
+    /// Keep retrying until the file is released or the timeout runs out.
+    Wait(std::time::Duration),
+    /// Treat it like any other failed file.
+    Fail,
+}
+
+/// How long `--locked wait` waits for a file when no timeout is given.
+pub const DEFAULT_LOCK_WAIT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Parses a `--locked` policy: `skip`, `fail`, `wait` or `wait=TIMEOUT`
+/// (e.g. `wait=2m`).
+pub fn parse_locked_policy(s: &str) -> Result<LockedPolicy, String> {
+    match s.trim().split_once('=') {
+        Some(("wait", timeout)) => Ok(LockedPolicy::Wait(parse_duration(timeout)?)),
+        Some(_) => Err(format!("`{s}` is not a valid policy (use skip, wait[=TIMEOUT] or fail)")),
+        None => match s.trim() {
+            "skip" => Ok(LockedPolicy::Skip),
+            "wait" => Ok(LockedPolicy::Wait(DEFAULT_LOCK_WAIT)),
+            "fail" => Ok(LockedPolicy::Fail),
+            _ => Err(format!("`{s}` is not a valid policy (use skip, wait[=TIMEOUT] or fail)")),
+        },
+    }
+}
+
+/// Attributes of the sources carried over to the destination with
+/// `--preserve`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Preserve {
+    /// Access and modification times of files and directories.
+    pub timestamps: bool,
+    /// SELinux security context (the `security.selinux` attribute).
+    pub context: bool,
+    /// File capabilities set with `setcap` (the `security.capability`
+    /// attribute).
+    pub capabilities: bool,
+    /// Owner and group, translated by `--uid-map` and `--gid-map`.
+    pub ownership: bool,
+}
+
+impl Preserve {
+    /// Every attribute `--preserve` knows about.
+    pub const ALL: Preserve = Preserve {
+        timestamps: true,
+        context: true,
+        capabilities: true,
+        ownership: true,
+    };
+}
+
+/// Parses a comma-separated `--preserve` list such as `timestamps,context`
+/// or `all`.
+pub fn parse_preserve(s: &str) -> Result<Preserve, String> {
+    let mut preserve = Preserve::default();
+    for attr in s.split(',') {
+        match attr.trim() {
+            "timestamps" => preserve.timestamps = true,
+            "context" => preserve.context = true,
+            "capabilities" => preserve.capabilities = true,
+            "ownership" => preserve.ownership = true,
+            "all" => preserve = Preserve::ALL,
+            other => return Err(format!("`{other}` is not a known attribute (use timestamps, ownership, context, capabilities or all)")),
+        }
+    }
+    Ok(preserve)
+}
+
+/// Ownership given with `--chown`: either part may be left unchanged.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Owner {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+/// Parses a `--chown` owner: `USER`, `USER:GROUP` or `:GROUP`, where users
+/// and groups are names or numeric ids.
+pub fn parse_owner(s: &str) -> Result<Owner, String> {
+    let (user, group) = match s.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (s, None),
+    };
+    let owner = Owner {
+        uid: (!user.is_empty()).then(|| lookup_id(user, IdKind::User)).transpose()?,
+        gid: group.filter(|g| !g.is_empty()).map(|g| lookup_id(g, IdKind::Group)).transpose()?,
+    };
+    if owner == Owner::default() {
+        return Err("expected USER, USER:GROUP or :GROUP".to_string());
+    }
+    Ok(owner)
+}
+
+#[derive(Clone, Copy)]
+enum IdKind {
+    User,
+    Group,
+}
+
+/// Resolves a user or group name to its id; numeric ids are taken as they are.
+fn lookup_id(name: &str, kind: IdKind) -> Result<u32, String> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+    let what = match kind {
+        IdKind::User => "user",
+        IdKind::Group => "group",
+    };
+    lookup_name(name, kind).ok_or_else(|| format!("unknown {what} `{name}`"))
+}
+
+#[cfg(unix)]
+fn lookup_name(name: &str, kind: IdKind) -> Option<u32> {
+    let c_name = std::ffi::CString::new(name).ok()?;
+    // SAFETY: `c_name` is a valid C string. The returned entries point into
+    // static storage, which is read before any other lookup can replace it;
+    // arguments are parsed before any other thread looks up names.
+    unsafe {
+        match kind {
+            IdKind::User => libc::getpwnam(c_name.as_ptr()).as_ref().map(|pw| pw.pw_uid),
+            IdKind::Group => libc::getgrnam(c_name.as_ptr()).as_ref().map(|gr| gr.gr_gid),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn lookup_name(_name: &str, _kind: IdKind) -> Option<u32> {
+    None
+}
+
+/// A range of user or group ids translated by `--uid-map` or `--gid-map`:
+/// `count` ids starting at `from` become the ids starting at `to`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IdMap {
+    pub from: u32,
+    pub to: u32,
+    pub count: u32,
+}
+
+/// Parses an id mapping: `FROM:TO` for a single id, or `FROM:TO:COUNT` for a
+/// range, as in `/etc/subuid` and user-namespace tooling.
+pub fn parse_id_map(s: &str) -> Result<IdMap, String> {
+    let invalid = || format!("`{s}` is not a valid mapping (use FROM:TO or FROM:TO:COUNT)");
+    let mut parts = s.split(':').map(|part| part.trim().parse::<u32>().map_err(|_| invalid()));
+    let map = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(from), Some(to), None, None) => IdMap { from: from?, to: to?, count: 1 },
+        (Some(from), Some(to), Some(count), None) => IdMap { from: from?, to: to?, count: count? },
+        _ => return Err(invalid()),
+    };
+    if map.count == 0 || map.from.checked_add(map.count - 1).is_none() || map.to.checked_add(map.count - 1).is_none() {
+        return Err(invalid());
+    }
+    Ok(map)
+}
+
+/// Translates `id` with the first mapping whose range contains it; ids
+/// outside every range are kept as they are.
+pub fn map_id(maps: &[IdMap], id: u32) -> u32 {
+    maps.iter()
+        .find(|map| id >= map.from && id - map.from < map.count)
+        .map_or(id, |map| map.to + (id - map.from))
+}
+
+/// Permissions given with `--chmod`, separately for files and directories.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Modes {
+    pub file: Option<u32>,
+    pub dir: Option<u32>,
+}
+
+/// Parses a `--chmod` list: an octal mode for files and directories alike
+/// (`644`), or modes prefixed with `F` for files and `D` for directories
+/// (`D755,F644`).
+pub fn parse_modes(s: &str) -> Result<Modes, String> {
+    let mut modes = Modes::default();
+    for part in s.split(',') {
+        let part = part.trim();
+        let (file, dir, octal) = match part.as_bytes().first() {
+            Some(b'F') => (true, false, &part[1..]),
+            Some(b'D') => (false, true, &part[1..]),
+            _ => (true, true, part),
+        };
+        let mode = u32::from_str_radix(octal, 8)
+            .ok()
+            .filter(|mode| *mode <= 0o7777)
+            .ok_or_else(|| format!("`{part}` is not an octal mode (e.g. 644, or D755,F644)"))?;
+        if file {
+            modes.file = Some(mode);
+        }
+        if dir {
+            modes.dir = Some(mode);
+        }
+    }
+    Ok(modes)
+}
+
 /// A file to be copied with source path, destination path, and size.
+#[derive(Clone)]
 pub struct CopyEntry {
     pub from: PathBuf,
     pub to: PathBuf,
     pub size: u64,
 }
 
+/// Key under which a destination path is stored on a case-insensitive
+/// filesystem.
+pub fn case_fold(path: &Path) -> String {
+    path.to_string_lossy().to_lowercase()
+}
+
+/// Indices of entries whose destination differs only in letter case from
+/// that of an earlier entry, so they would overwrite it on a
+/// case-insensitive filesystem.
+pub fn case_collisions(entries: &[CopyEntry]) -> Vec<usize> {
+    let mut seen = std::collections::HashSet::new();
+    entries
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| !seen.insert(case_fold(&entry.to)))
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Picks a sibling of `path` named like `README (1).md` whose case-folded
+/// form isn't in `taken`.
+pub fn case_unique_path(path: &Path, taken: &std::collections::HashSet<String>) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().map(|e| format!(".{}", e.to_string_lossy()));
+    (1..)
+        .map(|n| path.with_file_name(format!("{} ({}){}", stem, n, extension.as_deref().unwrap_or(""))))
+        .find(|candidate| !taken.contains(&case_fold(candidate)))
+        .expect("an unused name exists")
+}
+
+/// Characters NTFS and FAT reject in file names, besides control characters.
+const INVALID_NAME_CHARS: &[char] = &['\\', ':', '*', '?', '"', '<', '>', '|'];
+
+/// Device names Windows reserves regardless of extension (`CON`, `aux.txt`, ...).
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1",
+    "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Whether NTFS and FAT accept `c` in a file name.
+pub fn is_portable_name_char(c: char) -> bool {
+    !c.is_control() && !INVALID_NAME_CHARS.contains(&c)
+}
+
+/// Makes a file name valid on NTFS and FAT: characters they reject
+/// (`\:*?"<>|` and control characters) and trailing dots or spaces are
+/// replaced with `replacement`, and `replacement` is appended to reserved
+/// device names (`CON` becomes `CON_`). Returns `None` if the name is fine.
+pub fn sanitize_name(name: &str, replacement: &str) -> Option<String> {
+    let kept = name.trim_end_matches(['.', ' ']);
+    let mut sanitized: String = kept
+        .chars()
+        .map(|c| {
+            if is_portable_name_char(c) {
+                c.to_string()
+            } else {
+                replacement.to_string()
+            }
+        })
+        .collect();
+    sanitized.push_str(&replacement.repeat(name.len() - kept.len()));
+
+    let stem_len = sanitized.find('.').unwrap_or(sanitized.len());
+    if RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(&sanitized[..stem_len])) {
+        sanitized.insert_str(stem_len, if replacement.is_empty() { "_" } else { replacement });
+    }
+    if sanitized.is_empty() {
+        sanitized.push('_');
+    }
+    (sanitized != name).then_some(sanitized)
+}
+
+/// Applies [`sanitize_name`] to every component of `path` below `base`.
+/// Returns `None` if nothing had to change.
+pub fn sanitize_path(base: &Path, path: &Path, replacement: &str) -> Option<PathBuf> {
+    rewrite_path(base, path, |name| sanitize_name(name, replacement))
+}
+
+/// Unicode normalization form for destination names.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NormalForm {
+    /// Composed, as produced by most Linux and Windows tools.
+    Nfc,
+    /// Decomposed, as stored by macOS (HFS+).
+    Nfd,
+}
+
+/// Converts a file name to the normalization form `form`. Returns `None` if
+/// the name is already normalized.
+pub fn normalize_name(name: &str, form: NormalForm) -> Option<String> {
+    let normalized = match form {
+        NormalForm::Nfc => icu_normalizer::ComposingNormalizer::new_nfc().normalize(name),
+        NormalForm::Nfd => icu_normalizer::DecomposingNormalizer::new_nfd().normalize(name),
+    };
+    match normalized {
+        std::borrow::Cow::Borrowed(_) => None,
+        std::borrow::Cow::Owned(normalized) => (normalized != name).then_some(normalized),
+    }
+}
+
+/// Applies [`normalize_name`] to every component of `path` below `base`.
+/// Returns `None` if nothing had to change.
+pub fn normalize_path(base: &Path, path: &Path, form: NormalForm) -> Option<PathBuf> {
+    rewrite_path(base, path, |name| normalize_name(name, form))
+}
+
+/// Rewrites the components of `path` below `base` with `rewrite`, leaving
+/// names that aren't valid Unicode untouched.
+fn rewrite_path(base: &Path, path: &Path, rewrite: impl Fn(&str) -> Option<String>) -> Option<PathBuf> {
+    let relative = path.strip_prefix(base).ok()?;
+    let mut changed = false;
+    let mut rewritten = base.to_path_buf();
+    for component in relative.components() {
+        let name = component.as_os_str();
+        match name.to_str().and_then(&rewrite) {
+            Some(fixed) => {
+                changed = true;
+                rewritten.push(fixed);
+            }
+            None => rewritten.push(name),
+        }
+    }
+    changed.then_some(rewritten)
+}
+
+/// The type of a file that is neither a regular file, a directory nor a
+/// symbolic link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpecialKind {
+    Fifo,
+    Socket,
+    CharDevice,
+    BlockDevice,
+}
+
+impl SpecialKind {
+    /// The special kind of `file_type`, or `None` for regular files,
+    /// directories and symbolic links.
+    #[cfg(unix)]
+    pub fn of(file_type: &std::fs::FileType) -> Option<Self> {
+        use std::os::unix::fs::FileTypeExt;
+        if file_type.is_fifo() {
+            Some(SpecialKind::Fifo)
+        } else if file_type.is_socket() {
+            Some(SpecialKind::Socket)
+        } else if file_type.is_char_device() {
+            Some(SpecialKind::CharDevice)
+        } else if file_type.is_block_device() {
+            Some(SpecialKind::BlockDevice)
+        } else {
+            None
+        }
+    }
+
+    /// The special kind of `file_type`, or `None` for regular files,
+    /// directories and symbolic links.
+    #[cfg(not(unix))]
+    pub fn of(_file_type: &std::fs::FileType) -> Option<Self> {
+        None
+    }
+
+    /// Whether this is a device node rather than a FIFO or socket.
+    pub fn is_device(self) -> bool {
+        matches!(self, SpecialKind::CharDevice | SpecialKind::BlockDevice)
+    }
+}
+
+impl std::fmt::Display for SpecialKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            SpecialKind::Fifo => "FIFO",
+            SpecialKind::Socket => "socket",
+            SpecialKind::CharDevice => "character device",
+            SpecialKind::BlockDevice => "block device",
+        })
+    }
+}
+
+/// A FIFO, socket or device node found while scanning, to be recreated at
+/// the destination rather than read.
+#[derive(Debug, Clone)]
+pub struct SpecialEntry {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    pub kind: SpecialKind,
+}
+
 /// Collects all files to copy from a source to a destination directory.
-/// Walks the tree once, returning file entries, directories to create, total count, and total size.
+/// Walks the tree once, returning file entries, directories to create (as
+/// source and destination pairs), total count, total size, special files and
+/// the files whose permission bits suggest they can't be read (see
+/// [`may_be_unreadable`]).
+///
+/// Directories are read concurrently, with at most `parallel` `read_dir` calls in
+/// flight. When `scan_pb` is given it is advanced once per file found and its
+/// length grows by each file's size, so a spinner can show a live
+/// "N files, X found" counter before the scan finishes.
+///
+/// Symbolic links are skipped unless `follow_symlinks` is set, in which case
+/// they are copied as the files and directories they point to. FIFOs, sockets
+/// and device nodes are returned separately, since reading them like files
+/// would block or never end. With `skip_hidden`, hidden files and directories
+/// below `source` (dotfiles, and on Windows those with the hidden or system
+/// attribute) are left out; `source` itself is always taken.
 pub async fn collect_copy_entries(
     source: &Path,
     dest_base: &Path,
-) -> Result<(Vec<CopyEntry>, Vec<PathBuf>, u64, u64), Box<dyn std::error::Error>> {
+    parallel: usize,
+    follow_symlinks: bool,
+    skip_hidden: bool,
+    scan_pb: Option<&ProgressBar>,
+) -> Result<ScannedEntries, Box<dyn std::error::Error>> {
     let mut entries = Vec::new();
     let mut dirs = Vec::new();
+    let mut specials = Vec::new();
+    let mut unreadable = Vec::new();
     let mut total_count = 0u64;
     let mut total_size = 0u64;
 
-    let source_meta = fs::symlink_metadata(source).await?;
+    let source_meta = if follow_symlinks {
+        fs::metadata(source).await?
+    } else {
+        fs::symlink_metadata(source).await?
+    };
 
     if source_meta.file_type().is_file() {
         let file_name = source.file_name().ok_or("source has no file name")?;
@@ -59,12 +527,29 @@ pub async fn collect_copy_entries(
         }
 
         let size = source_meta.len();
+        if let Some(pb) = scan_pb {
+            pb.inc(1);
+            pb.inc_length(size);
+        }
+        if may_be_unreadable(&source_meta) {
+            unreadable.push(source.to_path_buf());
+        }
         entries.push(CopyEntry {
             from: source.to_path_buf(),
             to: dest,
             size,
         });
-        return Ok((entries, dirs, 1, size));
+        return Ok((entries, dirs, 1, size, specials, unreadable));
+    }
+
+    if let Some(kind) = SpecialKind::of(&source_meta.file_type()) {
+        let file_name = source.file_name().ok_or("source has no file name")?;
+        specials.push(SpecialEntry {
+            from: source.to_path_buf(),
+            to: dest_base.join(file_name),
+            kind,
+        });
+        return Ok((entries, dirs, 0, 0, specials, unreadable));
     }
 
     if source_meta.file_type().is_dir() {
@@ -78,37 +563,33 @@ pub async fn collect_copy_entries(
         let dest_base_canon = fs::canonicalize(dest_base).await?;
         let dest_canon = dest_base_canon.join(dir_name);
         if dest_canon.starts_with(&src_canon) {
-            return Err(if dest_canon == src_canon {
-                "source and destination are the same directory"
-            } else {
-                "cannot copy a directory into itself"
+            return Err(CopyError::DestinationInsideSource {
+                directory: src_canon,
+                destination: dest_canon,
             }
             .into());
         }
 
-        dirs.push(dest_dir.clone());
+        dirs.push((source.to_path_buf(), dest_dir.clone()));
 
-        let mut stack = vec![source.to_path_buf()];
-        while let Some(p) = stack.pop() {
-            let meta = fs::symlink_metadata(&p).await?;
-            if meta.file_type().is_dir() {
-                if p != source {
-                    let relative = p.strip_prefix(source)?;
-                    dirs.push(dest_dir.join(relative));
-                }
-                let mut dir_entries = fs::read_dir(&p).await?;
-                while let Some(entry) = dir_entries.next_entry().await? {
-                    stack.push(entry.path());
-                }
-            } else if meta.file_type().is_file() {
-                let relative = p.strip_prefix(source)?;
-                let dest = dest_dir.join(relative);
-                let size = meta.len();
-                total_count += 1;
-                total_size += size;
-                entries.push(CopyEntry { from: p, to: dest, size });
-            }
-            // Symlinks and other special file types are skipped
+        let (files, sub_dirs, found_specials, found_unreadable) =
+            walk_dir(source, parallel, follow_symlinks, skip_hidden, scan_pb).await?;
+        unreadable = found_unreadable;
+        for dir in sub_dirs {
+            let to = dest_dir.join(dir.strip_prefix(source)?);
+            dirs.push((dir, to));
+        }
+        for (path, size) in files {
+            let relative = path.strip_prefix(source)?;
+            let dest = dest_dir.join(relative);
+            total_count += 1;
+            total_size += size;
+            entries.push(CopyEntry { from: path, to: dest, size });
+        }
+        for (path, kind) in found_specials {
+            let relative = path.strip_prefix(source)?;
+            let to = dest_dir.join(relative);
+            specials.push(SpecialEntry { from: path, to, kind });
         }
     } else {
         return Err(format!(
@@ -118,5 +599,214 @@ pub async fn collect_copy_entries(
         .into());
     }
 
-    Ok((entries, dirs, total_count, total_size))
+    Ok((entries, dirs, total_count, total_size, specials, unreadable))
+}
+
+/// What [`collect_copy_entries`] found: files, directories, file count,
+/// total size, special files and files that may be unreadable.
+pub type ScannedEntries = (Vec<CopyEntry>, Vec<(PathBuf, PathBuf)>, u64, u64, Vec<SpecialEntry>, Vec<PathBuf>);
+
+/// Whether the permission bits of a file deny reading it to this process.
+/// Only a hint, read from metadata the scan has anyway: membership of
+/// supplementary groups and ACLs aren't considered, so such files still
+/// need to be opened to be sure.
+#[cfg(unix)]
+pub fn may_be_unreadable(meta: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    // SAFETY: geteuid and getegid have no preconditions and can't fail.
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    let bit = match () {
+        _ if uid == 0 => return false,
+        _ if meta.uid() == uid => 0o400,
+        _ if meta.gid() == gid => 0o040,
+        _ => 0o004,
+    };
+    meta.mode() & bit == 0
+}
+
+/// Whether the permission bits of a file deny reading it to this process.
+/// Windows keeps this in ACLs that metadata doesn't show, so nothing is
+/// flagged.
+#[cfg(not(unix))]
+pub fn may_be_unreadable(_meta: &std::fs::Metadata) -> bool {
+    false
+}
+
+/// Walks everything below `root`, returning every regular file with its size
+/// and every subdirectory. Each entry's metadata is read exactly once, so the
+/// sizes can be handed straight to the copy engine.
+///
+/// Directories are read concurrently, with at most `parallel` `read_dir` calls
+/// in flight. When `scan_pb` is given, its position counts the files found and
+/// its length sums their sizes.
+///
+/// With `follow_symlinks`, links are resolved and walked like the files and
+/// directories they point to. A directory that was already visited (a link
+/// pointing back up the tree, or a second link to the same place) is skipped
+/// with a warning, so link loops can't recurse forever.
+///
+/// FIFOs, sockets and device nodes are returned with their kind instead of
+/// being read. With `skip_hidden`, hidden entries are neither returned nor
+/// descended into. Files that [`may_be_unreadable`] are listed once more at
+/// the end.
+pub(crate) async fn walk_dir(
+    root: &Path,
+    parallel: usize,
+    follow_symlinks: bool,
+    skip_hidden: bool,
+    scan_pb: Option<&ProgressBar>,
+) -> std::io::Result<WalkedDir> {
+    let mut files = Vec::new();
+    let mut dirs = Vec::new();
+    let mut specials = Vec::new();
+    let mut unreadable = Vec::new();
+    let mut visited = std::collections::HashSet::new();
+    if let Some(key) = dir_key(root).await {
+        visited.insert(key);
+    }
+
+    let semaphore = Arc::new(Semaphore::new(parallel.max(1)));
+    let mut pending = JoinSet::new();
+    pending.spawn(scan_dir(root.to_path_buf(), follow_symlinks, skip_hidden, Arc::clone(&semaphore)));
+
+    while let Some(result) = pending.join_next().await {
+        let scanned = result??;
+        for dir in scanned.dirs {
+            if let Some(key) = dir_key(&dir).await
+                && !visited.insert(key)
+            {
+                scan_warning(
+                    scan_pb,
+                    &format!("Skipping directory already visited (symlink loop?): {}", dir.display()),
+                );
+                continue;
+            }
+            pending.spawn(scan_dir(dir.clone(), follow_symlinks, skip_hidden, Arc::clone(&semaphore)));
+            dirs.push(dir);
+        }
+        if let Some(pb) = scan_pb {
+            pb.inc(scanned.files.len() as u64);
+            pb.inc_length(scanned.files.iter().map(|(_, size)| size).sum());
+        }
+        files.extend(scanned.files);
+        specials.extend(scanned.specials);
+        unreadable.extend(scanned.unreadable);
+    }
+
+    Ok((files, dirs, specials, unreadable))
+}
+
+/// What [`walk_dir`] found: files with their sizes, directories, special
+/// files and files that may be unreadable.
+pub(crate) type WalkedDir = (Vec<(PathBuf, u64)>, Vec<PathBuf>, Vec<(PathBuf, SpecialKind)>, Vec<PathBuf>);
+
+/// Identifies a directory regardless of the path it was reached through.
+#[cfg(unix)]
+async fn dir_key(dir: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    let meta = fs::metadata(dir).await.ok()?;
+    Some((meta.dev(), meta.ino()))
+}
+
+/// Identifies a directory regardless of the path it was reached through.
+#[cfg(not(unix))]
+async fn dir_key(dir: &Path) -> Option<PathBuf> {
+    fs::canonicalize(dir).await.ok()
+}
+
+/// Prints a scan warning above the scan spinner, if there is one.
+fn scan_warning(scan_pb: Option<&ProgressBar>, message: &str) {
+    use colored::Colorize;
+    match scan_pb {
+        Some(pb) => pb.suspend(|| eprintln!("{}", message.yellow())),
+        None => eprintln!("{}", message.yellow()),
+    }
+}
+
+/// The immediate contents of one directory, as found by [`scan_dir`].
+struct ScannedDir {
+    files: Vec<(PathBuf, u64)>,
+    dirs: Vec<PathBuf>,
+    specials: Vec<(PathBuf, SpecialKind)>,
+    /// Files among `files` that [`may_be_unreadable`].
+    unreadable: Vec<PathBuf>,
+}
+
+/// Reads a single directory, holding a semaphore permit for the duration so
+/// the number of concurrent `read_dir` calls stays bounded.
+async fn scan_dir(
+    dir: PathBuf,
+    follow_symlinks: bool,
+    skip_hidden: bool,
+    semaphore: Arc<Semaphore>,
+) -> std::io::Result<ScannedDir> {
+    let _permit = semaphore
+        .acquire()
+        .await
+        .expect("failed to acquire semaphore permit");
+
+    let mut scanned = ScannedDir {
+        files: Vec::new(),
+        dirs: Vec::new(),
+        specials: Vec::new(),
+        unreadable: Vec::new(),
+    };
+    let mut dir_entries = fs::read_dir(&dir).await?;
+    while let Some(entry) = dir_entries.next_entry().await? {
+        if skip_hidden && is_hidden(&entry).await {
+            log::debug!("skipping hidden {}", entry.path().display());
+            continue;
+        }
+        let file_type = entry.file_type().await?;
+        if file_type.is_dir() {
+            scanned.dirs.push(entry.path());
+        } else if file_type.is_file() {
+            let meta = entry.metadata().await?;
+            if may_be_unreadable(&meta) {
+                scanned.unreadable.push(entry.path());
+            }
+            scanned.files.push((entry.path(), meta.len()));
+        } else if file_type.is_symlink() && follow_symlinks {
+            match fs::metadata(entry.path()).await {
+                Ok(meta) if meta.is_dir() => scanned.dirs.push(entry.path()),
+                Ok(meta) if meta.is_file() => {
+                    if may_be_unreadable(&meta) {
+                        scanned.unreadable.push(entry.path());
+                    }
+                    scanned.files.push((entry.path(), meta.len()));
+                }
+                Ok(meta) => match SpecialKind::of(&meta.file_type()) {
+                    Some(kind) => scanned.specials.push((entry.path(), kind)),
+                    None => log::warn!("skipping non-regular file: {}", entry.path().display()),
+                },
+                Err(e) => log::warn!("skipping broken symlink: {}: {}", entry.path().display(), e),
+            }
+        } else if file_type.is_symlink() {
+            log::warn!("skipping symlink: {}", entry.path().display());
+        } else if let Some(kind) = SpecialKind::of(&file_type) {
+            scanned.specials.push((entry.path(), kind));
+        } else {
+            log::warn!("skipping non-regular file: {}", entry.path().display());
+        }
+    }
+    Ok(scanned)
+}
+
+/// Whether a directory entry counts as hidden for `--hidden exclude`: its
+/// name starts with a dot, or on Windows it has the hidden or system
+/// attribute.
+pub(crate) async fn is_hidden(entry: &fs::DirEntry) -> bool {
+    if entry.file_name().as_encoded_bytes().starts_with(b".") {
+        return true;
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::MetadataExt;
+        const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+        const FILE_ATTRIBUTE_SYSTEM: u32 = 0x4;
+        if let Ok(meta) = entry.metadata().await {
+            return meta.file_attributes() & (FILE_ATTRIBUTE_HIDDEN | FILE_ATTRIBUTE_SYSTEM) != 0;
+        }
+    }
+    false
 }
@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+
+/// Reports the files and directories below `root` that are created or
+/// changed, as they are. Paths arrive on the returned channel; `root` itself
+/// arrives when changes may have been missed and everything should be
+/// looked at again.
+///
+/// With `poll`, or on platforms without inotify, the tree is rescanned every
+/// `poll` interval instead, which also notices changes made by other
+/// machines on network filesystems.
+pub(crate) fn watch(root: &Path, poll: Option<Duration>) -> std::io::Result<mpsc::UnboundedReceiver<PathBuf>> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    match poll {
+        Some(interval) => spawn_poller(root.to_path_buf(), interval, tx),
+        None => spawn_notifier(root.to_path_buf(), tx)?,
+    }
+    Ok(rx)
+}
+
+/// How often the tree is rescanned where inotify isn't available.
+#[cfg(not(target_os = "linux"))]
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[cfg(not(target_os = "linux"))]
+fn spawn_notifier(root: PathBuf, tx: mpsc::UnboundedSender<PathBuf>) -> std::io::Result<()> {
+    spawn_poller(root, DEFAULT_POLL_INTERVAL, tx);
+    Ok(())
+}
+
+/// Watches `root` and every directory below it with inotify, on a thread of
+/// its own since reading events blocks.
+#[cfg(target_os = "linux")]
+fn spawn_notifier(root: PathBuf, tx: mpsc::UnboundedSender<PathBuf>) -> std::io::Result<()> {
+    // SAFETY: inotify_init1 takes no pointers.
+    let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let mut inotify = Inotify {
+        fd,
+        dirs: HashMap::new(),
+    };
+    inotify.add_tree(&root);
+    std::thread::spawn(move || inotify.run(&root, &tx));
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+struct Inotify {
+    fd: i32,
+    /// Directory of each watch descriptor.
+    dirs: HashMap<i32, PathBuf>,
+}
+
+#[cfg(target_os = "linux")]
+impl Inotify {
+    /// Files count once they are closed after writing or moved in, so
+    /// half-written files aren't copied; new directories are watched too.
+    const MASK: u32 = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_CREATE;
+
+    /// Watches `dir` and the directories below it.
+    fn add_tree(&mut self, dir: &Path) {
+        use std::os::unix::ffi::OsStrExt;
+        let Ok(path) = std::ffi::CString::new(dir.as_os_str().as_bytes()) else {
+            return;
+        };
+        // SAFETY: `path` is a valid NUL-terminated string for the duration of the call.
+        let wd = unsafe { libc::inotify_add_watch(self.fd, path.as_ptr(), Self::MASK) };
+        if wd < 0 {
+            log::warn!("Cannot watch {}: {}", dir.display(), std::io::Error::last_os_error());
+            return;
+        }
+        self.dirs.insert(wd, dir.to_path_buf());
+        for entry in std::fs::read_dir(dir).into_iter().flatten().flatten() {
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                self.add_tree(&entry.path());
+            }
+        }
+    }
+
+    fn run(mut self, root: &Path, tx: &mpsc::UnboundedSender<PathBuf>) {
+        const HEADER: usize = std::mem::size_of::<libc::inotify_event>();
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            // SAFETY: `buffer` is valid for writes of its whole length.
+            let n = unsafe { libc::read(self.fd, buffer.as_mut_ptr().cast(), buffer.len()) };
+            if n < 0 {
+                if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                log::warn!("Stopped watching {}: {}", root.display(), std::io::Error::last_os_error());
+                return;
+            }
+            let mut offset = 0;
+            while offset + HEADER <= n as usize {
+                // SAFETY: the kernel wrote a whole event header at `offset`;
+                // it may not be aligned within the byte buffer.
+                let event: libc::inotify_event =
+                    unsafe { std::ptr::read_unaligned(buffer[offset..].as_ptr().cast()) };
+                let name_bytes = &buffer[offset + HEADER..offset + HEADER + event.len as usize];
+                offset += HEADER + event.len as usize;
+
+                let changed = if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                    Some(root.to_path_buf())
+                } else {
+                    let name = name_bytes.split(|&b| b == 0).next().unwrap_or_default();
+                    let Some(dir) = self.dirs.get(&event.wd) else {
+                        continue;
+                    };
+                    use std::os::unix::ffi::OsStrExt;
+                    let path = dir.join(std::ffi::OsStr::from_bytes(name));
+                    if event.mask & libc::IN_ISDIR != 0 {
+                        self.add_tree(&path);
+                        Some(path)
+                    } else if event.mask & (libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO) != 0 {
+                        Some(path)
+                    } else {
+                        None
+                    }
+                };
+                if let Some(path) = changed
+                    && tx.send(path).is_err()
+                {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Inotify {
+    fn drop(&mut self) {
+        // SAFETY: the descriptor is owned by this struct and closed once.
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// Rescans `root` every `interval` and reports the files that appeared or
+/// changed size or modification time since the previous scan.
+fn spawn_poller(root: PathBuf, interval: Duration, tx: mpsc::UnboundedSender<PathBuf>) {
+    std::thread::spawn(move || {
+        let mut known = HashMap::new();
+        snapshot(&root, &mut known);
+        loop {
+            std::thread::sleep(interval);
+            let mut current = HashMap::new();
+            snapshot(&root, &mut current);
+            for (path, state) in &current {
+                if known.get(path) != Some(state) && tx.send(path.clone()).is_err() {
+                    return;
+                }
+            }
+            known = current;
+        }
+    });
+}
+
+/// Records the size and modification time of every file below `dir`.
+fn snapshot(dir: &Path, files: &mut HashMap<PathBuf, (u64, Option<SystemTime>)>) {
+    for entry in std::fs::read_dir(dir).into_iter().flatten().flatten() {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if metadata.is_dir() {
+            snapshot(&entry.path(), files);
+        } else if metadata.is_file() {
+            files.insert(entry.path(), (metadata.len(), metadata.modified().ok()));
+        }
+    }
+}
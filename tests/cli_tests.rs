@@ -337,7 +337,7 @@ fn test_non_existent_destination_fails() {
         .arg(tmp_dir.path().join("no_such_dest"))
         .assert()
         .failure()
-        .stdout(predicate::str::contains("Destination path does not exist"));
+        .stderr(predicate::str::contains("Destination path does not exist"));
 }
 
 #[test]
@@ -402,3 +402,3292 @@ fn test_copy_dir_into_itself_fails() {
             predicate::str::contains("same directory"),
         ));
 }
+
+#[test]
+fn test_split_threshold_copies_file_in_ranges() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+    let file = tmp_dir.path().join("big.bin");
+    File::create(&file).unwrap().write_all(&data).unwrap();
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("-p")
+        .arg("4")
+        .arg("--split-threshold")
+        .arg("100K")
+        .arg(&file)
+        .arg(&dest)
+        .assert()
+        .success();
+
+    assert_eq!(fs::read(dest.join("big.bin")).unwrap(), data);
+}
+
+#[test]
+fn test_direct_io_copies_file() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+
+    // Not a whole number of blocks, so the last one is written padded.
+    let data: Vec<u8> = (0..300_001u32).map(|i| (i % 251) as u8).collect();
+    let file = tmp_dir.path().join("big.bin");
+    File::create(&file).unwrap().write_all(&data).unwrap();
+
+    Command::new(cargo_bin!("cp2"))
+        .args(["--direct-io", "--buffer-size", "64K", "--sync"])
+        .arg(&file)
+        .arg(&dest)
+        .assert()
+        .success();
+    assert_eq!(fs::read(dest.join("big.bin")).unwrap(), data);
+
+    Command::new(cargo_bin!("cp2"))
+        .args(["--direct-io", "--verify"])
+        .arg(&file)
+        .arg(&dest)
+        .assert()
+        .code(3);
+}
+
+#[test]
+fn test_also_to_writes_every_destination() {
+    let tmp_dir = TempDir::new().unwrap();
+    let big: Vec<u8> = (0..300_001u32).map(|i| (i % 251) as u8).collect();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"aaa"), ("sub/b.txt", b"bbbb"), ("sub/big.bin", &big), ("empty/", b"")]);
+    let dests: Vec<_> = ["dest", "mirror1", "mirror2"].iter().map(|name| tmp_dir.path().join(name)).collect();
+    for dest in &dests {
+        fs::create_dir(dest).unwrap();
+    }
+
+    Command::new(cargo_bin!("cp2"))
+        .args(["-r", "--buffer-size", "64K"])
+        .arg(&source)
+        .arg(&dests[0])
+        .arg("--also-to")
+        .arg(&dests[1])
+        .arg("--also-to")
+        .arg(&dests[2])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Copied:   3 files"));
+    for dest in &dests {
+        assert_dirs_equal(&source, &dest.join("source"));
+    }
+
+    Command::new(cargo_bin!("cp2"))
+        .arg(source.join("a.txt"))
+        .arg(&dests[0])
+        .arg("--also-to")
+        .arg(tmp_dir.path().join("nosuch"))
+        .assert()
+        .code(3)
+        .stderr(predicate::str::contains("--also-to path is not a directory"));
+}
+
+#[test]
+fn test_copy_many_small_files_in_batches() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+
+    let source = tmp_dir.path().join("source");
+    fs::create_dir(&source).unwrap();
+    for i in 0..600 {
+        fs::write(source.join(format!("{i}.txt")), format!("small {i}")).unwrap();
+    }
+    let large: Vec<u8> = vec![7u8; 200 * 1024];
+    fs::write(source.join("large.bin"), &large).unwrap();
+
+    for engine in ["async", "threads"] {
+        let dest = dest.join(engine);
+        fs::create_dir(&dest).unwrap();
+        Command::new(cargo_bin!("cp2"))
+            .args(["-r", "--engine", engine])
+            .arg(&source)
+            .arg(&dest)
+            .assert()
+            .success()
+            .stderr(predicate::str::contains("601 files"));
+
+        let copied = dest.join("source");
+        for i in 0..600 {
+            assert_eq!(
+                fs::read_to_string(copied.join(format!("{i}.txt"))).unwrap(),
+                format!("small {i}")
+            );
+        }
+        assert_eq!(fs::read(copied.join("large.bin")).unwrap(), large);
+    }
+}
+
+#[test]
+fn test_plain_progress_prints_status_lines() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+
+    let file = tmp_dir.path().join("plain.txt");
+    File::create(&file).unwrap().write_all(b"plain progress").unwrap();
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("--progress")
+        .arg("plain")
+        .arg(&file)
+        .arg(&dest)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("100% 14 B/14 B"))
+        .stderr(predicate::str::contains("Copy complete!"))
+        .stderr(predicate::str::contains("\x1b[").not())
+        .stderr(predicate::str::contains("\x1b]9;4").not());
+}
+
+#[test]
+fn test_bars_fall_back_to_status_lines_off_a_terminal() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+
+    let file = tmp_dir.path().join("bars.txt");
+    File::create(&file).unwrap().write_all(b"no terminal").unwrap();
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("--progress")
+        .arg("bars")
+        .arg(&file)
+        .arg(&dest)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("100% 11 B/11 B"))
+        .stderr(predicate::str::contains("Copy complete!"))
+        .stderr(predicate::str::contains("\x1b[").not());
+}
+
+#[test]
+fn test_tui_falls_back_to_status_lines_off_a_terminal() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+
+    let file = tmp_dir.path().join("tui.txt");
+    File::create(&file).unwrap().write_all(b"dashboard").unwrap();
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("--tui")
+        .arg(&file)
+        .arg(&dest)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("100% 9 B/9 B"))
+        .stderr(predicate::str::contains("\x1b[?1049h").not());
+    assert_eq!(fs::read(dest.join("tui.txt")).unwrap(), b"dashboard");
+}
+
+#[test]
+fn test_progress_is_independent_of_quiet() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+
+    let file = tmp_dir.path().join("quiet.txt");
+    File::create(&file).unwrap().write_all(b"quiet").unwrap();
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg("--progress")
+        .arg("plain")
+        .arg(&file)
+        .arg(&dest)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("100% 5 B/5 B"));
+
+    let logged = tmp_dir.path().join("logged");
+    fs::create_dir(&logged).unwrap();
+    Command::new(cargo_bin!("cp2"))
+        .arg("--no-progress")
+        .arg("--progress")
+        .arg("plain")
+        .arg(&file)
+        .arg(&logged)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("100%").not())
+        .stderr(predicate::str::contains("Summary"));
+}
+
+#[test]
+fn test_json_emits_event_stream() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"aaa"), ("sub/b.txt", b"bb")]);
+
+    let output = Command::new(cargo_bin!("cp2"))
+        .arg("--json")
+        .arg("-r")
+        .arg(&source)
+        .arg(&dest)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let events: Vec<serde_json::Value> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("every stdout line is JSON"))
+        .collect();
+    let kinds: Vec<&str> = events.iter().map(|e| e["event"].as_str().unwrap()).collect();
+
+    assert_eq!(kinds.first(), Some(&"scan_started"));
+    assert_eq!(kinds.iter().filter(|k| **k == "file_started").count(), 2);
+    assert_eq!(kinds.iter().filter(|k| **k == "file_done").count(), 2);
+    let summary = events.last().unwrap();
+    assert_eq!(summary["event"], "summary");
+    assert_eq!(summary["files_copied"], 2);
+    assert_eq!(summary["bytes_copied"], 5);
+}
+
+#[test]
+fn test_report_json_and_csv() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"aaa"), ("b,c.txt", b"bb")]);
+
+    let json_report = tmp_dir.path().join("report.json");
+    Command::new(cargo_bin!("cp2"))
+        .arg("-r")
+        .arg("--report")
+        .arg(&json_report)
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success();
+
+    let report: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&json_report).unwrap()).unwrap();
+    assert_eq!(report["files"].as_array().unwrap().len(), 2);
+    assert_eq!(report["files"][0]["status"], "copied");
+    assert_eq!(report["totals"]["files_copied"], 2);
+    assert_eq!(report["totals"]["bytes_copied"], 5);
+
+    let csv_report = tmp_dir.path().join("report.csv");
+    fs::remove_dir_all(dest.join("source")).unwrap();
+    Command::new(cargo_bin!("cp2"))
+        .arg("-r")
+        .arg("--report")
+        .arg(&csv_report)
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success();
+
+    let csv = fs::read_to_string(&csv_report).unwrap();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines[0], "source,destination,bytes,duration_secs,status,error,renamed_from");
+    assert_eq!(lines.len(), 4);
+    assert!(lines.iter().any(|l| l.contains("\"") && l.contains("b,c.txt")));
+    assert!(lines[3].contains(",total,"));
+}
+
+#[test]
+fn test_copy_into_tar_archive() {
+    let tmp_dir = TempDir::new().unwrap();
+    let long_name = "n".repeat(120);
+    let source = create_test_src(
+        &tmp_dir,
+        &[("a.txt", b"aaa"), ("sub/b.txt", b"bbbb"), (&format!("sub/{long_name}"), b"long")],
+    );
+    let archive = tmp_dir.path().join("backup.tar");
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("-r")
+        .arg(&source)
+        .arg(&archive)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Archived: 3 files"));
+    let bytes = fs::read(&archive).unwrap();
+    assert_eq!(bytes.len() % 512, 0);
+    assert_eq!(&bytes[257..262], b"ustar");
+
+    // Checked against the system tar where there is one.
+    let extracted = tmp_dir.path().join("extracted");
+    fs::create_dir(&extracted).unwrap();
+    if let Ok(status) = Command::new("tar").arg("-xf").arg(&archive).arg("-C").arg(&extracted).status() {
+        assert!(status.success());
+        assert_dirs_equal(&source, &extracted.join("source"));
+    }
+
+    // --to-archive doesn't need the extension.
+    let other = tmp_dir.path().join("backup.img");
+    Command::new(cargo_bin!("cp2"))
+        .args(["-r", "--to-archive"])
+        .arg(&source)
+        .arg(&other)
+        .assert()
+        .success();
+    assert_eq!(fs::read(&other).unwrap(), bytes);
+}
+
+#[test]
+fn test_extract_tar_archive() {
+    let tmp_dir = TempDir::new().unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"aaa"), ("sub/b.txt", b"bbbb"), ("empty/", b"")]);
+    let archive = tmp_dir.path().join("backup.tar");
+    Command::new(cargo_bin!("cp2")).arg("-r").arg(&source).arg(&archive).assert().success();
+
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    Command::new(cargo_bin!("cp2"))
+        .arg("--extract")
+        .arg(&archive)
+        .arg(&dest)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Extracted: 2 files"));
+    assert_dirs_equal(&source, &dest.join("source"));
+    let modified = |p: &Path| fs::metadata(p).unwrap().modified().unwrap();
+    let secs = |t: std::time::SystemTime| t.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+    assert_eq!(secs(modified(&dest.join("source/a.txt"))), secs(modified(&source.join("a.txt"))));
+
+    // Entries that would land outside the destination are skipped.
+    let evil = tmp_dir.path().join("evil.tar");
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut writer = cp2::tar::TarWriter::new(tokio::fs::File::create(&evil).await.unwrap());
+        for name in ["../escaped.txt", "inside.txt"] {
+            let header = cp2::tar::Header {
+                path: name.to_string(),
+                kind: cp2::tar::EntryKind::File,
+                size: 1,
+                mode: 0o644,
+                mtime: 0,
+                uid: 0,
+                gid: 0,
+                link: None,
+            };
+            writer
+                .append_file(&header, Path::new(name), &evil, &mut &b"x"[..], None, None, None)
+                .await
+                .unwrap();
+        }
+        writer.finish().await.unwrap();
+    });
+    Command::new(cargo_bin!("cp2"))
+        .arg("--extract")
+        .arg(&evil)
+        .arg(&dest)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Skipped:  1 file"));
+    assert!(dest.join("inside.txt").exists());
+    assert!(!tmp_dir.path().join("escaped.txt").exists());
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("--extract")
+        .arg(source.join("a.txt"))
+        .arg(&dest)
+        .assert()
+        .code(3)
+        .stderr(predicate::str::contains("Not a tar archive"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_extract_does_not_write_through_links() {
+    let tmp_dir = TempDir::new().unwrap();
+    let outside = tmp_dir.path().join("outside");
+    fs::create_dir(&outside).unwrap();
+    fs::write(outside.join("secret"), "original").unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+
+    // A symbolic link out of the destination, a hard link through it, then
+    // a file over the hard link.
+    let evil = tmp_dir.path().join("evil.tar");
+    let entry = |path: &str, kind, size, link: Option<String>| cp2::tar::Header {
+        path: path.to_string(),
+        kind,
+        size,
+        mode: 0o644,
+        mtime: 0,
+        uid: 0,
+        gid: 0,
+        link,
+    };
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut writer = cp2::tar::TarWriter::new(tokio::fs::File::create(&evil).await.unwrap());
+        let symlink = entry("d", cp2::tar::EntryKind::Symlink, 0, Some(outside.display().to_string()));
+        writer.append(&symlink).await.unwrap();
+        let hard_link = entry("h", cp2::tar::EntryKind::HardLink, 0, Some("d/secret".to_string()));
+        writer.append(&hard_link).await.unwrap();
+        writer
+            .append_file(&entry("h", cp2::tar::EntryKind::File, 5, None), Path::new("h"), &evil, &mut &b"pwned"[..], None, None, None)
+            .await
+            .unwrap();
+        writer.finish().await.unwrap();
+    });
+    Command::new(cargo_bin!("cp2"))
+        .args(["--extract", "--yes"])
+        .arg(&evil)
+        .arg(&dest)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("link leads outside the destination"));
+    assert_eq!(fs::read_to_string(outside.join("secret")).unwrap(), "original");
+    assert_eq!(fs::read_to_string(dest.join("h")).unwrap(), "pwned");
+
+    // A hard link already in the destination is replaced, not written through.
+    let linked = dest.join("linked");
+    fs::write(tmp_dir.path().join("kept"), "kept").unwrap();
+    fs::hard_link(tmp_dir.path().join("kept"), &linked).unwrap();
+    rt.block_on(async {
+        let mut writer = cp2::tar::TarWriter::new(tokio::fs::File::create(&evil).await.unwrap());
+        writer
+            .append_file(&entry("linked", cp2::tar::EntryKind::File, 3, None), Path::new("linked"), &evil, &mut &b"new"[..], None, None, None)
+            .await
+            .unwrap();
+        writer.finish().await.unwrap();
+    });
+    Command::new(cargo_bin!("cp2"))
+        .args(["--extract", "--yes"])
+        .arg(&evil)
+        .arg(&dest)
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(&linked).unwrap(), "new");
+    assert_eq!(fs::read_to_string(tmp_dir.path().join("kept")).unwrap(), "kept");
+}
+
+#[test]
+fn test_delta_updates_existing_file_in_place() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let old: Vec<u8> = (0..300_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8).collect();
+    let mut new = old.clone();
+    new[150_000..150_010].fill(0);
+    new.extend_from_slice(b"appended");
+    let source = tmp_dir.path().join("image.bin");
+    fs::write(&source, &new).unwrap();
+    fs::write(dest.join("image.bin"), &old).unwrap();
+
+    Command::new(cargo_bin!("cp2"))
+        .args(["--delta", "--yes", "-vv"])
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Wrote 5096 of 300008 bytes"));
+    assert_eq!(fs::read(dest.join("image.bin")).unwrap(), new);
+}
+
+#[test]
+fn test_compress_writes_suffixed_files() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    let data = "cold data ".repeat(10_000);
+    let source = create_test_src(&tmp_dir, &[("a.log", data.as_bytes()), ("b.log", b"small")]);
+
+    for (compression, program, suffix) in [("zstd:19", "zstd", "zst"), ("gzip", "gzip", "gz")] {
+        if std::process::Command::new(program).arg("--version").output().is_err() {
+            continue;
+        }
+        let _ = fs::remove_dir_all(&dest);
+        fs::create_dir(&dest).unwrap();
+        Command::new(cargo_bin!("cp2"))
+            .args(["-r", "--yes", "--compress", compression])
+            .arg(&source)
+            .arg(&dest)
+            .assert()
+            .success();
+        let compressed = dest.join("source").join(format!("a.log.{suffix}"));
+        assert!(fs::metadata(&compressed).unwrap().len() < data.len() as u64);
+        let output = std::process::Command::new(program).arg("-dc").arg(&compressed).output().unwrap();
+        assert_eq!(output.stdout, data.as_bytes());
+        assert!(dest.join("source").join(format!("b.log.{suffix}")).exists());
+        assert!(!dest.join("source").join("a.log").exists());
+    }
+
+    Command::new(cargo_bin!("cp2"))
+        .args(["--compress", "zstd:30"])
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("out of range"));
+}
+
+#[test]
+fn test_encrypt_and_decrypt_with_passphrase() {
+    if Command::new("gpg").arg("--version").output().is_err() {
+        eprintln!("gpg not found; skipping");
+        return;
+    }
+    let tmp_dir = TempDir::new().unwrap();
+    let gnupg_home = tmp_dir.path().join("gnupg");
+    fs::create_dir(&gnupg_home).unwrap();
+    let passphrase = tmp_dir.path().join("passphrase");
+    fs::write(&passphrase, "correct horse\n").unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"secret"), ("sub/b.txt", b"more secrets")]);
+    let vault = tmp_dir.path().join("vault");
+    fs::create_dir(&vault).unwrap();
+
+    Command::new(cargo_bin!("cp2"))
+        .env("GNUPGHOME", &gnupg_home)
+        .args(["-r", "--yes", "--encrypt"])
+        .arg(format!("passphrase:{}", passphrase.display()))
+        .arg(&source)
+        .arg(&vault)
+        .assert()
+        .success();
+    let encrypted = fs::read(vault.join("source/sub/b.txt.gpg")).unwrap();
+    assert!(!encrypted.windows(7).any(|w| w == b"secrets"));
+
+    let back = tmp_dir.path().join("back");
+    fs::create_dir(&back).unwrap();
+    Command::new(cargo_bin!("cp2"))
+        .env("GNUPGHOME", &gnupg_home)
+        .args(["-r", "--yes", "--decrypt"])
+        .arg(format!("passphrase:{}", passphrase.display()))
+        .arg(vault.join("source"))
+        .arg(&back)
+        .assert()
+        .success();
+    assert_dirs_equal(&source, &back.join("source"));
+
+    // The wrong passphrase fails the file instead of writing garbage.
+    fs::write(&passphrase, "wrong\n").unwrap();
+    let again = tmp_dir.path().join("again");
+    fs::create_dir(&again).unwrap();
+    Command::new(cargo_bin!("cp2"))
+        .env("GNUPGHOME", &gnupg_home)
+        .args(["--decrypt"])
+        .arg(format!("passphrase:{}", passphrase.display()))
+        .arg(vault.join("source/a.txt.gpg"))
+        .arg(&again)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("`gpg` failed"));
+    assert!(!again.join("a.txt").exists());
+}
+
+#[test]
+fn test_write_hashes_while_copying() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let big: Vec<u8> = (0..3 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"abc"), ("sub/big.bin", &big)]);
+
+    Command::new(cargo_bin!("cp2"))
+        .args(["-r", "--yes", "--write-hashes"])
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success();
+    let sums = fs::read_to_string(dest.join("SHA256SUMS")).unwrap();
+    assert_eq!(sums.lines().count(), 2);
+    assert!(sums.contains("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  source/a.txt\n"));
+    if let Ok(output) = std::process::Command::new("sha256sum").arg("-c").arg("SHA256SUMS").current_dir(&dest).output() {
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+    }
+
+    // Later runs add to the list instead of replacing it.
+    fs::write(source.join("c.txt"), b"abc").unwrap();
+    Command::new(cargo_bin!("cp2"))
+        .args(["--yes", "--write-hashes"])
+        .arg(source.join("c.txt"))
+        .arg(&dest)
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(dest.join("SHA256SUMS")).unwrap().lines().count(), 3);
+
+    let sidecars = tmp_dir.path().join("sidecars");
+    fs::create_dir(&sidecars).unwrap();
+    Command::new(cargo_bin!("cp2"))
+        .args(["-r", "--yes", "--write-hashes=blake3", "--hash-sidecars"])
+        .arg(&source)
+        .arg(&sidecars)
+        .assert()
+        .success();
+    assert_eq!(
+        fs::read_to_string(sidecars.join("source/a.txt.b3")).unwrap(),
+        "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85  a.txt\n"
+    );
+    assert!(sidecars.join("source/sub/big.bin.b3").exists());
+    assert!(!sidecars.join("B3SUMS").exists());
+}
+
+#[test]
+fn test_verify_against_checksums() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"abc"), ("sub/b.txt", b"bbb"), ("c.txt", b"ccc")]);
+    Command::new(cargo_bin!("cp2"))
+        .args(["-r", "--yes", "--write-hashes"])
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success();
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("verify")
+        .arg(&dest)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Verified: 3 files"));
+
+    // Damaged and missing files fail; the others still pass.
+    fs::write(dest.join("source/a.txt"), b"abd").unwrap();
+    fs::remove_file(dest.join("source/c.txt")).unwrap();
+    Command::new(cargo_bin!("cp2"))
+        .arg("verify")
+        .arg(&dest)
+        .arg("--sums")
+        .arg(dest.join("SHA256SUMS"))
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("FAILED source/a.txt (checksum mismatch)"))
+        .stderr(predicate::str::contains("FAILED source/c.txt (missing)"))
+        .stderr(predicate::str::contains("Verified: 1 file"));
+
+    // Sidecars are found without a checksum file.
+    let sidecars = tmp_dir.path().join("sidecars");
+    fs::create_dir(&sidecars).unwrap();
+    Command::new(cargo_bin!("cp2"))
+        .args(["-r", "--yes", "--write-hashes=blake3", "--hash-sidecars"])
+        .arg(&source)
+        .arg(&sidecars)
+        .assert()
+        .success();
+    Command::new(cargo_bin!("cp2"))
+        .arg("verify")
+        .arg(&sidecars)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Verified: 3 files"));
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("verify")
+        .arg(&source)
+        .assert()
+        .code(3)
+        .stderr(predicate::str::contains("No checksums found"));
+}
+
+#[test]
+fn test_verify_compares_two_trees() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"abc"), ("sub/b.txt", b"bbb"), ("empty/", b"")]);
+    Command::new(cargo_bin!("cp2"))
+        .args(["-r", "--yes", "--preserve"])
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success();
+    let copy = dest.join("source");
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("verify")
+        .arg(&source)
+        .arg(&copy)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Matched:  2 files"));
+
+    // Same size and time, different contents: only --hash notices.
+    let modified = fs::metadata(copy.join("a.txt")).unwrap().modified().unwrap();
+    fs::write(copy.join("a.txt"), b"abd").unwrap();
+    fs::File::options().write(true).open(copy.join("a.txt")).unwrap().set_modified(modified).unwrap();
+    fs::write(copy.join("extra.txt"), b"x").unwrap();
+    Command::new(cargo_bin!("cp2"))
+        .arg("verify")
+        .arg(&source)
+        .arg(&copy)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Extra extra.txt"));
+    Command::new(cargo_bin!("cp2"))
+        .args(["verify", "--hash"])
+        .arg(&source)
+        .arg(&copy)
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("FAILED a.txt (contents differ)"));
+
+    fs::remove_file(copy.join("sub/b.txt")).unwrap();
+    fs::remove_dir(copy.join("empty")).unwrap();
+    Command::new(cargo_bin!("cp2"))
+        .args(["verify", "--size-only"])
+        .arg(&source)
+        .arg(&copy)
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("FAILED sub/b.txt (missing)"))
+        .stderr(predicate::str::contains("FAILED empty/ (missing)"));
+
+    // A single file is compared with its namesake in a directory.
+    Command::new(cargo_bin!("cp2"))
+        .arg("verify")
+        .arg(source.join("a.txt"))
+        .arg(&copy)
+        .assert()
+        .success();
+}
+
+#[test]
+fn test_diff_lists_changes_as_json() {
+    let tmp_dir = TempDir::new().unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"abc"), ("b.txt", b"bbb"), ("sub/c.txt", b"ccc")]);
+    let copy = tmp_dir.path().join("copy");
+    fs::create_dir(&copy).unwrap();
+    fs::write(copy.join("a.txt"), b"abcd").unwrap();
+    fs::write(copy.join("b.txt"), b"bbb").unwrap();
+    fs::write(copy.join("new.txt"), b"new").unwrap();
+
+    let output = Command::new(cargo_bin!("cp2"))
+        .args(["diff", "--json", "--size-only"])
+        .arg(&source)
+        .arg(&copy)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let changes: Vec<serde_json::Value> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("every stdout line is JSON"))
+        .collect();
+    assert_eq!(
+        changes,
+        vec![
+            serde_json::json!({"change": "modified", "path": "a.txt", "reasons": ["size"], "detail": "3 vs 4 bytes"}),
+            serde_json::json!({"change": "added", "path": "new.txt"}),
+            serde_json::json!({"change": "removed", "path": "sub/"}),
+            serde_json::json!({"change": "removed", "path": "sub/c.txt"}),
+        ]
+    );
+
+    Command::new(cargo_bin!("cp2"))
+        .args(["diff", "--hash"])
+        .arg(source.join("b.txt"))
+        .arg(copy.join("b.txt"))
+        .assert()
+        .success()
+        .stdout("")
+        .stderr(predicate::str::contains("No differences (1 file compared)"));
+}
+
+#[test]
+fn test_hash_writes_sums_that_verify_reads() {
+    let tmp_dir = TempDir::new().unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"abc"), ("sub/b.txt", b"bbb")]);
+    fs::write(source.join("SHA256SUMS"), b"stale").unwrap();
+
+    let output = Command::new(cargo_bin!("cp2"))
+        .args(["hash", "-p", "2"])
+        .arg(&source)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let sums = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        sums,
+        format!(
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  {}\n\
+             {}  {}\n",
+            source.join("a.txt").display(),
+            "3e744b9dc39389baf0c5a0660589b8402f3dbb49b89b3e75f2c9355852a3c677",
+            source.join("sub/b.txt").display()
+        )
+    );
+
+    fs::write(source.join("SHA256SUMS"), &sums).unwrap();
+    Command::new(cargo_bin!("cp2"))
+        .arg("verify")
+        .arg(&source)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Verified: 2 files"));
+
+    Command::new(cargo_bin!("cp2"))
+        .args(["hash", "--hash", "blake3"])
+        .arg(source.join("missing.txt"))
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains("Cannot read"));
+}
+
+#[test]
+fn test_du_counts_files_and_bytes() {
+    let tmp_dir = TempDir::new().unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"abc"), ("sub/b.txt", b"bbbb"), ("empty/", b"")]);
+
+    let output = Command::new(cargo_bin!("cp2"))
+        .args(["du", "--json"])
+        .arg(&source)
+        .arg(source.join("a.txt"))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let usages: Vec<serde_json::Value> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("every stdout line is JSON"))
+        .collect();
+    assert_eq!(
+        usages,
+        vec![
+            serde_json::json!({"path": source.display().to_string(), "files": 2, "dirs": 3, "bytes": 7}),
+            serde_json::json!({"path": source.join("a.txt").display().to_string(), "files": 1, "dirs": 0, "bytes": 3}),
+        ]
+    );
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("du")
+        .arg(&source)
+        .arg(tmp_dir.path().join("missing"))
+        .assert()
+        .code(2)
+        .stdout(predicate::str::contains("7 B"))
+        .stdout(predicate::str::contains("2 files"))
+        .stderr(predicate::str::contains("Cannot read"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_dedupe_links_duplicates() {
+    use std::os::unix::fs::MetadataExt;
+    let tmp_dir = TempDir::new().unwrap();
+    let source = create_test_src(
+        &tmp_dir,
+        &[("a.txt", b"same"), ("sub/b.txt", b"same"), ("c.txt", b"diff"), ("d.txt", b""), ("e.txt", b"")],
+    );
+    let inode = |name: &str| fs::metadata(source.join(name)).unwrap().ino();
+
+    Command::new(cargo_bin!("cp2"))
+        .args(["dedupe", "--dry-run"])
+        .arg(&source)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(format!(
+            "Would link {} -> {}",
+            source.join("sub/b.txt").display(),
+            source.join("a.txt").display()
+        )))
+        .stderr(predicate::str::contains("1 duplicate found, 4 B could be saved"));
+    assert_ne!(inode("a.txt"), inode("sub/b.txt"));
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("dedupe")
+        .arg(&source)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("1 duplicate replaced, 4 B saved"));
+    assert_eq!(inode("a.txt"), inode("sub/b.txt"));
+    assert_eq!(fs::read(source.join("sub/b.txt")).unwrap(), b"same");
+    assert_ne!(inode("a.txt"), inode("c.txt"));
+    assert_ne!(inode("d.txt"), inode("e.txt"));
+
+    // Files that are already links of each other aren't duplicates.
+    Command::new(cargo_bin!("cp2"))
+        .arg("dedupe")
+        .arg(&source)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("0 duplicates replaced"));
+}
+
+#[test]
+fn test_checksum_skips_identical_destinations() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"abc"), ("b.txt", b"bbb"), ("c.txt", b"ccc")]);
+    Command::new(cargo_bin!("cp2"))
+        .args(["-r", "--yes"])
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success();
+
+    // Same size, different contents: only the digest tells them apart.
+    fs::write(dest.join("source/b.txt"), b"bbx").unwrap();
+    fs::remove_file(dest.join("source/c.txt")).unwrap();
+    Command::new(cargo_bin!("cp2"))
+        .args(["-r", "--yes", "--checksum"])
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Copied:   2 files"))
+        .stderr(predicate::str::contains("Skipped:  1 file"));
+    assert_eq!(fs::read(dest.join("source/b.txt")).unwrap(), b"bbb");
+    assert_eq!(fs::read(dest.join("source/c.txt")).unwrap(), b"ccc");
+}
+
+#[test]
+fn test_skip_identical_copies_only_missing_and_changed_files() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"abc"), ("b.txt", b"bbb"), ("c.txt", b"ccc")]);
+    Command::new(cargo_bin!("cp2"))
+        .args(["-r", "--yes"])
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success();
+
+    fs::write(source.join("b.txt"), b"bbbb").unwrap();
+    fs::remove_file(dest.join("source/c.txt")).unwrap();
+    let output = Command::new(cargo_bin!("cp2"))
+        .args(["-r", "--json", "--skip-identical"])
+        .arg(&source)
+        .arg(&dest)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let events: Vec<serde_json::Value> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("every stdout line is JSON"))
+        .collect();
+    let scan = events.iter().find(|e| e["event"] == "scan_finished").unwrap();
+    assert_eq!(scan["files"], 2);
+    assert_eq!(scan["bytes"], 7);
+    let summary = events.iter().find(|e| e["event"] == "summary").unwrap();
+    assert_eq!(summary["files_copied"], 2);
+    assert_eq!(summary["files_skipped"], 1);
+    assert_eq!(fs::read(dest.join("source/b.txt")).unwrap(), b"bbbb");
+    assert_eq!(fs::read(dest.join("source/c.txt")).unwrap(), b"ccc");
+}
+
+#[test]
+fn test_skip_larger_than_lists_and_counts_skipped_files() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let source = create_test_src(&tmp_dir, &[("small.txt", b"abc"), ("big.bin", &[0u8; 2048])]);
+    Command::new(cargo_bin!("cp2"))
+        .args(["-r", "--summary", "--skip-larger-than", "1K"])
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Skipping 1 file larger than 1.00 KiB"))
+        .stderr(predicate::str::contains("big.bin (2.00 KiB)"))
+        .stderr(predicate::str::contains("Copied:   1 file"))
+        .stderr(predicate::str::contains("Skipped:  1 file"));
+    assert_eq!(fs::read(dest.join("source/small.txt")).unwrap(), b"abc");
+    assert!(!dest.join("source/big.bin").exists());
+}
+
+#[test]
+fn test_hidden_exclude_leaves_out_dotfiles() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let source = create_test_src(
+        &tmp_dir,
+        &[("a.txt", b"a"), (".env", b"secret"), (".git/config", b"git"), ("sub/.cache", b"c"), ("sub/b.txt", b"b")],
+    );
+    Command::new(cargo_bin!("cp2"))
+        .args(["-r", "--summary", "--hidden", "exclude"])
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Copied:   2 files"));
+    assert!(dest.join("source/a.txt").exists());
+    assert!(dest.join("source/sub/b.txt").exists());
+    assert!(!dest.join("source/.env").exists());
+    assert!(!dest.join("source/.git").exists());
+    assert!(!dest.join("source/sub/.cache").exists());
+
+    // A hidden source named on the command line is still copied.
+    Command::new(cargo_bin!("cp2"))
+        .args(["--hidden", "exclude"])
+        .arg(source.join(".env"))
+        .arg(&dest)
+        .assert()
+        .success();
+    assert_eq!(fs::read(dest.join(".env")).unwrap(), b"secret");
+}
+
+#[test]
+fn test_verify_reads_copies_back() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let big: Vec<u8> = (0..3 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"abc"), ("sub/big.bin", &big)]);
+
+    Command::new(cargo_bin!("cp2"))
+        .args(["-r", "--yes", "--verify", "--write-hashes=blake3"])
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success();
+    assert_eq!(fs::read(dest.join("source/sub/big.bin")).unwrap(), big);
+    assert_eq!(fs::read_to_string(dest.join("B3SUMS")).unwrap().lines().count(), 2);
+
+    Command::new(cargo_bin!("cp2"))
+        .args(["--verify", "--delta"])
+        .arg(source.join("a.txt"))
+        .arg(&dest)
+        .assert()
+        .code(3);
+}
+
+#[test]
+fn test_timings_print_breakdown() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"abc"), ("sub/b.txt", b"bbb")]);
+
+    Command::new(cargo_bin!("cp2"))
+        .args(["-r", "--yes", "--sync", "--timings"])
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Timings").and(predicate::str::contains("sync")))
+        .stderr(predicate::str::contains("b.txt"))
+        .stderr(predicate::str::contains("Most time went to"));
+}
+
+#[test]
+fn test_manifest_lists_copied_files_for_verify() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"abc"), ("sub/b.txt", b"bbb")]);
+    let manifest = tmp_dir.path().join("manifest.json");
+    Command::new(cargo_bin!("cp2"))
+        .args(["-r", "--yes", "--manifest"])
+        .arg(&manifest)
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success();
+
+    let json: serde_json::Value = serde_json::from_slice(&fs::read(&manifest).unwrap()).unwrap();
+    assert_eq!(json["version"], 1);
+    assert_eq!(json["algorithm"], "sha256");
+    let files = json["files"].as_array().unwrap();
+    assert_eq!(files.len(), 2);
+    assert_eq!(files[0]["path"], "source/a.txt");
+    assert_eq!(files[0]["size"], 3);
+    assert_eq!(files[0]["hash"], "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    assert!(files[0]["mtime"].as_u64().unwrap() > 0);
+    assert_eq!(files[1]["path"], "source/sub/b.txt");
+
+    Command::new(cargo_bin!("cp2"))
+        .args(["verify", "--manifest"])
+        .arg(&manifest)
+        .arg(&dest)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Verified: 2 files"));
+
+    fs::write(dest.join("source/a.txt"), b"abcd").unwrap();
+    Command::new(cargo_bin!("cp2"))
+        .args(["verify", "--manifest"])
+        .arg(&manifest)
+        .arg(&dest)
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("FAILED source/a.txt (size mismatch: 3 vs 4 bytes)"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_watch_copies_new_files() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"first")]);
+
+    let child = Command::new(cargo_bin!("cp2"))
+        .args(["watch", "--debounce", "100ms"])
+        .arg(&source)
+        .arg(&dest)
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let wait_for = |path: PathBuf, contents: &[u8]| {
+        for _ in 0..100 {
+            if fs::read(&path).is_ok_and(|read| read == contents) {
+                return;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        panic!("{} was never copied", path.display());
+    };
+    wait_for(dest.join("source/a.txt"), b"first");
+    fs::create_dir(source.join("sub")).unwrap();
+    fs::write(source.join("sub/b.txt"), b"second").unwrap();
+    wait_for(dest.join("source/sub/b.txt"), b"second");
+    Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .assert()
+        .success();
+    let output = child.wait_with_output().unwrap();
+
+    assert_eq!(output.status.code(), Some(130));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Synced 1 file"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_daemon_runs_submitted_jobs() {
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::net::UnixStream;
+
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"first"), ("sub/b.txt", b"second")]);
+    let socket = tmp_dir.path().join("cp2.sock");
+
+    let child = Command::new(cargo_bin!("cp2"))
+        .arg("daemon")
+        .arg("--socket")
+        .arg(&socket)
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    let stream = (0..100)
+        .find_map(|_| {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            UnixStream::connect(&socket).ok()
+        })
+        .expect("daemon never listened");
+    use std::os::unix::fs::PermissionsExt;
+    assert_eq!(fs::metadata(&socket).unwrap().permissions().mode() & 0o777, 0o600);
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut request = |line: serde_json::Value| {
+        writeln!(&stream, "{line}").unwrap();
+        let mut response = String::new();
+        reader.read_line(&mut response).unwrap();
+        serde_json::from_str::<serde_json::Value>(&response).unwrap()
+    };
+
+    let submitted = request(serde_json::json!({
+        "op": "submit", "sources": [source], "destination": dest, "recursive": true
+    }));
+    assert_eq!(submitted["ok"], true);
+    assert_eq!(submitted["id"], 1);
+    let job = (0..100)
+        .find_map(|_| {
+            let status = request(serde_json::json!({"op": "status", "id": 1}));
+            let job = status["jobs"][0].clone();
+            if job["state"] == "done" {
+                return Some(job);
+            }
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            None
+        })
+        .expect("job never finished");
+    assert_eq!(job["files_done"], 2);
+    assert_eq!(job["bytes_done"], 11);
+    assert_dirs_equal(&source, &dest.join("source"));
+
+    let cancelled = request(serde_json::json!({"op": "cancel", "id": 1}));
+    assert_eq!(cancelled["error"], "job 1 already finished");
+    let invalid = request(serde_json::json!({"op": "shutdown"}));
+    assert_eq!(invalid["ok"], false);
+
+    Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .assert()
+        .success();
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(130));
+    assert!(!socket.exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_resume_finishes_interrupted_copy() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let state_dir = tmp_dir.path().join("state");
+    let files: Vec<(String, Vec<u8>)> = (0..10).map(|i| (format!("f{i}.txt"), vec![b'x'; 10])).collect();
+    let structure: Vec<(&str, &[u8])> = files.iter().map(|(n, c)| (n.as_str(), c.as_slice())).collect();
+    let source = create_test_src(&tmp_dir, &structure);
+
+    let child = Command::new(cargo_bin!("cp2"))
+        .env("CP2_STATE_DIR", &state_dir)
+        .args(["-r", "-p", "1", "--progress", "plain"])
+        .args(["--pre-file-cmd", "sleep 0.2", "source", "dest"])
+        .current_dir(tmp_dir.path())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(700));
+    Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .assert()
+        .success();
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(130));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let id = stderr
+        .lines()
+        .find_map(|line| line.strip_prefix("Continue with: cp2 resume "))
+        .unwrap_or_else(|| panic!("no job to resume: {stderr}"))
+        .to_string();
+    let copied = fs::read_dir(dest.join("source")).unwrap().count();
+    assert!(copied < 10, "copying stopped early, got {copied} files");
+
+    Command::new(cargo_bin!("cp2"))
+        .env("CP2_STATE_DIR", &state_dir)
+        .arg("resume")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(&id));
+    // Relative paths are resolved where the copy was started.
+    Command::new(cargo_bin!("cp2"))
+        .env("CP2_STATE_DIR", &state_dir)
+        .current_dir(&dest)
+        .args(["resume", &id])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(format!("Resuming: {} already copied, {} left", copied_files(copied), copied_files(10 - copied))));
+    assert_dirs_equal(&source, &dest.join("source"));
+    assert_eq!(fs::read_dir(state_dir.join("jobs")).unwrap().count(), 0);
+
+    Command::new(cargo_bin!("cp2"))
+        .env("CP2_STATE_DIR", &state_dir)
+        .args(["resume", &id])
+        .assert()
+        .code(3)
+        .stderr(predicate::str::contains(format!("no job {id}")));
+}
+
+fn copied_files(n: usize) -> String {
+    match n {
+        1 => "1 file".to_string(),
+        n => format!("{n} files"),
+    }
+}
+
+#[test]
+fn test_undo_reverses_last_copy() {
+    let tmp_dir = TempDir::new().unwrap();
+    let state_dir = tmp_dir.path().join("state");
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"new"), ("sub/b.txt", b"b")]);
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir_all(dest.join("source")).unwrap();
+    fs::write(dest.join("source/a.txt"), "old").unwrap();
+
+    // Copies are only recorded with --undo.
+    Command::new(cargo_bin!("cp2"))
+        .env("CP2_STATE_DIR", &state_dir)
+        .arg("-r")
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success();
+    assert!(!dest.join(".cp2-undo").exists());
+    Command::new(cargo_bin!("cp2"))
+        .env("CP2_STATE_DIR", &state_dir)
+        .arg("undo")
+        .assert()
+        .code(3)
+        .stderr(predicate::str::contains("No copy to undo"));
+
+    fs::write(dest.join("source/a.txt"), "old").unwrap();
+    fs::remove_dir_all(dest.join("source/sub")).unwrap();
+    Command::new(cargo_bin!("cp2"))
+        .env("CP2_STATE_DIR", &state_dir)
+        .args(["-r", "--undo"])
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(dest.join("source/a.txt")).unwrap(), "new");
+
+    Command::new(cargo_bin!("cp2"))
+        .env("CP2_STATE_DIR", &state_dir)
+        .args(["undo", "--dry-run"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(format!("restore {}", dest.join("source/a.txt").display())))
+        .stdout(predicate::str::contains(format!("remove {}", dest.join("source/sub").display())))
+        .stderr(predicate::str::contains("Would remove 2 files and restore 1 file"));
+    assert_eq!(fs::read_to_string(dest.join("source/a.txt")).unwrap(), "new");
+
+    Command::new(cargo_bin!("cp2"))
+        .env("CP2_STATE_DIR", &state_dir)
+        .arg("undo")
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(dest.join("source/a.txt")).unwrap(), "old");
+    assert!(!dest.join("source/sub").exists());
+    assert!(!dest.join(".cp2-undo").exists());
+
+    Command::new(cargo_bin!("cp2"))
+        .env("CP2_STATE_DIR", &state_dir)
+        .arg("undo")
+        .assert()
+        .code(3)
+        .stderr(predicate::str::contains("No copy to undo"));
+}
+
+// Small files aren't delta-updated even with --delta, so they are backed up.
+#[test]
+fn test_undo_restores_small_files_rewritten_under_delta() {
+    let tmp_dir = TempDir::new().unwrap();
+    let state_dir = tmp_dir.path().join("state");
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"new")]);
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir_all(dest.join("source")).unwrap();
+    fs::write(dest.join("source/a.txt"), "old").unwrap();
+
+    Command::new(cargo_bin!("cp2"))
+        .env("CP2_STATE_DIR", &state_dir)
+        .args(["-r", "--delta", "--undo"])
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(dest.join("source/a.txt")).unwrap(), "new");
+
+    Command::new(cargo_bin!("cp2"))
+        .env("CP2_STATE_DIR", &state_dir)
+        .arg("undo")
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(dest.join("source/a.txt")).unwrap(), "old");
+}
+
+// Where the sources can't be snapshotted, they are read live.
+#[test]
+fn test_snapshot_copies_sources() {
+    let tmp_dir = TempDir::new().unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"a"), ("sub/b.txt", b"b")]);
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+
+    Command::new(cargo_bin!("cp2"))
+        .args(["-r", "--snapshot"])
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success();
+    assert_dirs_equal(&source, &dest.join("source"));
+}
+
+#[test]
+fn test_destination_lock() {
+    let tmp_dir = TempDir::new().unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"a")]);
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let lock_path = dest.join(".cp2.lock");
+    let mut lock = File::create(&lock_path).unwrap();
+    lock.lock().unwrap();
+    write!(lock, "4242").unwrap();
+
+    Command::new(cargo_bin!("cp2"))
+        .args(["-r"])
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .code(3)
+        .stderr(predicate::str::contains("Another cp2 run (PID 4242) is copying into"));
+    assert!(!dest.join("source").exists());
+
+    Command::new(cargo_bin!("cp2"))
+        .args(["-r", "--no-lock"])
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success();
+    fs::remove_dir_all(dest.join("source")).unwrap();
+
+    let release = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        drop(lock);
+    });
+    Command::new(cargo_bin!("cp2"))
+        .args(["-r", "--wait-lock"])
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("waiting for it to finish"));
+    release.join().unwrap();
+    assert_dirs_equal(&source, &dest.join("source"));
+    assert!(!lock_path.exists());
+}
+
+#[test]
+fn test_smb_tuning_is_noted_in_report() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"aaa")]);
+
+    let report_path = tmp_dir.path().join("report.json");
+    Command::new(cargo_bin!("cp2"))
+        .args(["-r", "--smb-tuning", "on", "--report"])
+        .arg(&report_path)
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success();
+
+    let report: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&report_path).unwrap()).unwrap();
+    let notes: Vec<&str> = report["notes"].as_array().unwrap().iter().map(|n| n.as_str().unwrap()).collect();
+    assert!(notes[0].starts_with("SMB share detected"));
+    assert!(notes.iter().any(|n| n.contains("buffer size")));
+    assert_eq!(fs::read(dest.join("source/a.txt")).unwrap(), b"aaa");
+
+    // An explicit buffer size is kept, and --smb-tuning off adds no notes.
+    Command::new(cargo_bin!("cp2"))
+        .args(["-r", "--smb-tuning", "on", "--buffer-size", "64K", "--report"])
+        .arg(&report_path)
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success();
+    let report = fs::read_to_string(&report_path).unwrap();
+    assert!(report.contains("SMB share detected") && !report.contains("buffer size"));
+
+    Command::new(cargo_bin!("cp2"))
+        .args(["-r", "--smb-tuning", "off", "--report"])
+        .arg(&report_path)
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success();
+    let report: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&report_path).unwrap()).unwrap();
+    assert!(report.get("notes").is_none());
+}
+
+#[test]
+fn test_summary_flags() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let file = tmp_dir.path().join("s.txt");
+    File::create(&file).unwrap().write_all(b"summary").unwrap();
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg("--summary")
+        .arg(&file)
+        .arg(&dest)
+        .assert()
+        .success()
+        .stdout(predicate::str::is_empty())
+        .stderr(predicate::str::contains("Copied:   1 file"))
+        .stderr(predicate::str::contains("Failed:   0 files"));
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("--no-summary")
+        .arg(&file)
+        .arg(&dest)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Summary:").not());
+}
+
+#[test]
+fn test_log_file_appends_per_file_records() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let source = create_test_src(&tmp_dir, &[("one.txt", b"1"), ("two.txt", b"22")]);
+    let log_path = tmp_dir.path().join("cp2.log");
+
+    for _ in 0..2 {
+        Command::new(cargo_bin!("cp2"))
+            .arg("-q")
+            .arg("-r")
+            .arg("--log-file")
+            .arg(&log_path)
+            .arg(&source)
+            .arg(&dest)
+            .assert()
+            .success();
+    }
+
+    let log = fs::read_to_string(&log_path).unwrap();
+    assert_eq!(log.matches("result=ok").count(), 4, "log is appended to, not truncated");
+    assert_eq!(log.matches("copy started: 2 files").count(), 2);
+    assert!(log.lines().all(|l| l.starts_with("[20") && l.contains("Z] ")));
+    assert!(log.contains("two.txt") && log.contains("bytes=2 "));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_progress_socket_is_cleaned_up_and_never_clobbers_files() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"aaa")]);
+    let socket = tmp_dir.path().join("cp2.sock");
+
+    // A regular file at the socket path must be left alone.
+    fs::write(&socket, b"keep me").unwrap();
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg("--progress-socket")
+        .arg(&socket)
+        .arg(source.join("a.txt"))
+        .arg(&dest)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("not a socket"));
+    assert_eq!(fs::read(&socket).unwrap(), b"keep me");
+    assert!(!dest.join("a.txt").exists());
+
+    fs::remove_file(&socket).unwrap();
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg("--progress-socket")
+        .arg(&socket)
+        .arg(source.join("a.txt"))
+        .arg(&dest)
+        .assert()
+        .success();
+    assert!(dest.join("a.txt").exists());
+    assert!(!socket.exists(), "socket is removed once the run finishes");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_on_complete_cmd_receives_summary_on_stdin() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"aaa"), ("b.txt", b"bb")]);
+    let captured = tmp_dir.path().join("summary.json");
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg("-r")
+        .arg("--on-complete-cmd")
+        .arg(format!("cat > '{}'", captured.display()))
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success();
+
+    let summary: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&captured).unwrap()).unwrap();
+    assert_eq!(summary["event"], "summary");
+    assert_eq!(summary["files_copied"], 2);
+    assert_eq!(summary["bytes_copied"], 5);
+}
+
+#[test]
+fn test_on_complete_url_posts_summary() {
+    use std::io::BufRead;
+    use std::net::TcpListener;
+
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let file = tmp_dir.path().join("hook.txt");
+    fs::write(&file, b"hook").unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/done", listener.local_addr().unwrap());
+    let server = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).unwrap();
+            if header == "\r\n" {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':')
+                && name.eq_ignore_ascii_case("content-length")
+            {
+                content_length = value.trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+        (&stream)
+            .write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        (request_line, body)
+    });
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg("--on-complete-url")
+        .arg(&url)
+        .arg(&file)
+        .arg(&dest)
+        .assert()
+        .success();
+
+    let (request_line, body) = server.join().unwrap();
+    assert!(request_line.starts_with("POST /done "));
+    let summary: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(summary["event"], "summary");
+    assert_eq!(summary["files_copied"], 1);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_post_file_cmd_runs_for_each_copied_file() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"a"), ("it's {dest}.txt", b"b")]);
+    let hook_log = tmp_dir.path().join("hooks.log");
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg("-r")
+        .arg("--post-file-cmd")
+        .arg(format!("cmp {{src}} {{dest}} && echo {{dest}} >> '{}'", hook_log.display()))
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success();
+
+    let mut lines: Vec<String> = fs::read_to_string(&hook_log)
+        .unwrap()
+        .lines()
+        .map(String::from)
+        .collect();
+    lines.sort();
+    let copied = dest.join("source");
+    assert_eq!(
+        lines,
+        vec![
+            copied.join("a.txt").display().to_string(),
+            copied.join("it's {dest}.txt").display().to_string(),
+        ]
+    );
+
+    // A failing hook fails the run, but the file itself was still copied.
+    let dest2 = tmp_dir.path().join("dest2");
+    fs::create_dir(&dest2).unwrap();
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg("--post-file-cmd")
+        .arg("exit 3")
+        .arg(source.join("a.txt"))
+        .arg(&dest2)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("post-file command"));
+    assert!(dest2.join("a.txt").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_pre_file_cmd_skips_rejected_files() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let source = create_test_src(&tmp_dir, &[("keep.txt", b"keep"), ("reject.txt", b"reject")]);
+    let report = tmp_dir.path().join("report.json");
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("-r")
+        .arg("--summary")
+        .arg("--progress")
+        .arg("plain")
+        .arg("--pre-file-cmd")
+        .arg("! grep -q reject {src}")
+        .arg("--report")
+        .arg(&report)
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Skipped:  1 file"));
+
+    assert!(dest.join("source/keep.txt").exists());
+    assert!(!dest.join("source/reject.txt").exists());
+
+    let report: serde_json::Value = serde_json::from_str(&fs::read_to_string(&report).unwrap()).unwrap();
+    assert_eq!(report["totals"]["files_copied"], 1);
+    assert_eq!(report["totals"]["files_skipped"], 1);
+    let skipped = report["files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|f| f["status"] == "skipped")
+        .unwrap();
+    assert!(skipped["source"].as_str().unwrap().ends_with("reject.txt"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_interrupt_stops_scheduling_and_reports() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let files: Vec<(String, Vec<u8>)> = (0..20).map(|i| (format!("f{i}.txt"), vec![b'x'; 10])).collect();
+    let structure: Vec<(&str, &[u8])> = files.iter().map(|(n, c)| (n.as_str(), c.as_slice())).collect();
+    let source = create_test_src(&tmp_dir, &structure);
+
+    let child = Command::new(cargo_bin!("cp2"))
+        .args(["-r", "-p", "1", "--summary", "--progress", "plain"])
+        .args(["--pre-file-cmd", "sleep 0.2"])
+        .arg(&source)
+        .arg(&dest)
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(700));
+    Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .assert()
+        .success();
+    let output = child.wait_with_output().unwrap();
+
+    assert_eq!(output.status.code(), Some(130));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Copy interrupted!"), "{stderr}");
+    assert!(stderr.contains("not copied"), "{stderr}");
+    let copied = fs::read_dir(dest.join("source")).unwrap().count();
+    assert!(copied < 20, "copying stopped early, got {copied} files");
+}
+
+// Reading /proc/self/mem from offset 0 always fails, which makes it a handy
+// source for a copy that breaks after the destination has been created.
+#[cfg(target_os = "linux")]
+#[test]
+fn test_failed_copy_removes_partial_unless_keep_partial() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg("/proc/self/mem")
+        .arg(&dest)
+        .assert()
+        .failure();
+    assert!(!dest.join("mem").exists(), "partial file is removed by default");
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg("--keep-partial")
+        .arg("/proc/self/mem")
+        .arg(&dest)
+        .assert()
+        .failure();
+    assert!(dest.join("mem").exists(), "--keep-partial leaves it in place");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_transactional_copy_rolls_back_on_failure() {
+    let tmp_dir = TempDir::new().unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"new"), ("sub/b.txt", b"b")]);
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir_all(dest.join("source")).unwrap();
+    fs::write(dest.join("source/a.txt"), "old").unwrap();
+    let dest_files = || -> Vec<PathBuf> {
+        walkdir::WalkDir::new(&dest).into_iter().map(|e| e.unwrap().into_path()).collect()
+    };
+
+    Command::new(cargo_bin!("cp2"))
+        .args(["-r", "--transactional"])
+        .arg(&source)
+        .arg("/proc/self/mem")
+        .arg(&dest)
+        .assert()
+        .code(1)
+        .stderr(predicate::str::contains("Rolled back"));
+    assert_eq!(fs::read_to_string(dest.join("source/a.txt")).unwrap(), "old");
+    assert_eq!(dest_files(), [dest.clone(), dest.join("source"), dest.join("source/a.txt")]);
+
+    Command::new(cargo_bin!("cp2"))
+        .args(["-r", "--transactional"])
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(dest.join("source/a.txt")).unwrap(), "new");
+    assert_dirs_equal(&source, &dest.join("source"));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_retries_transient_errors_with_backoff() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+
+    let started = std::time::Instant::now();
+    let output = Command::new(cargo_bin!("cp2"))
+        .arg("-v")
+        .arg("--retries")
+        .arg("2")
+        .arg("--retry-delay")
+        .arg("100ms")
+        .arg("/proc/self/mem")
+        .arg(&dest)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    // Two retries, waiting 100ms and then 200ms.
+    assert!(started.elapsed() >= std::time::Duration::from_millis(300));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("attempt 1/2"), "{stderr}");
+    assert!(stderr.contains("attempt 2/2"), "{stderr}");
+    assert!(!stderr.contains("attempt 3/2"), "{stderr}");
+}
+
+// A missing source is not transient, so it is not retried.
+#[cfg(unix)]
+#[test]
+fn test_retries_skip_permanent_errors() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let source = create_test_src(&tmp_dir, &[("gone.txt", b"data")]);
+
+    let output = Command::new(cargo_bin!("cp2"))
+        .arg("-v")
+        .arg("--retries")
+        .arg("3")
+        .arg("--pre-file-cmd")
+        .arg("rm {src}")
+        .arg(source.join("gone.txt"))
+        .arg(&dest)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("Retrying"), "{stderr}");
+}
+
+// The failing /proc/self/mem copy must abort the throttled copy already in
+// flight and remove its partial destination.
+#[cfg(target_os = "linux")]
+#[test]
+fn test_fail_fast_cancels_copies_in_flight() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let big = tmp_dir.path().join("big.bin");
+    fs::write(&big, vec![1u8; 4 * 1024 * 1024]).unwrap();
+
+    let started = std::time::Instant::now();
+    let output = Command::new(cargo_bin!("cp2"))
+        .arg("--no-summary")
+        .arg("-p")
+        .arg("2")
+        .arg("--bwlimit")
+        .arg("512K")
+        .arg("--fail-fast")
+        .arg("/proc/self/mem")
+        .arg(&big)
+        .arg(&dest)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Aborted after the first error: 1 file not copied"), "{stderr}");
+    assert!(!dest.join("big.bin").exists());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_continue_on_error_overrides_fail_fast() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"a"), ("b.txt", b"b")]);
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg("-p")
+        .arg("1")
+        .arg("--fail-fast")
+        .arg("--continue-on-error")
+        .arg("/proc/self/mem")
+        .arg(source.join("a.txt"))
+        .arg(source.join("b.txt"))
+        .arg(&dest)
+        .assert()
+        .code(2);
+    assert!(dest.join("a.txt").exists());
+    assert!(dest.join("b.txt").exists());
+}
+
+// Failures are grouped by error kind after the run instead of being printed
+// between the progress bars.
+#[cfg(target_os = "linux")]
+#[test]
+fn test_failures_are_reported_grouped_by_kind() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let source = create_test_src(&tmp_dir, &[("gone.txt", b"x"), ("ok.txt", b"y")]);
+
+    let output = Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg("--pre-file-cmd")
+        .arg("case {src} in *gone*) rm {src};; esac")
+        .arg("/proc/self/mem")
+        .arg(source.join("gone.txt"))
+        .arg(source.join("ok.txt"))
+        .arg(&dest)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let report = stderr.split_once("Errors (2):").expect("error report").1;
+    let io_group = report.find("input/output error (1)").expect("EIO group");
+    let missing_group = report.find("no such file or directory (1)").expect("ENOENT group");
+    assert!(io_group < missing_group, "groups are sorted by kind: {stderr}");
+    assert!(report.contains("/proc/self/mem"), "{stderr}");
+    assert!(report.contains("gone.txt"), "{stderr}");
+    assert!(stderr.trim_end().ends_with("Exiting with status 2: 2 errors"), "{stderr}");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_errors_json_prints_one_object_per_failure() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let source = create_test_src(&tmp_dir, &[("ok.txt", b"y")]);
+
+    let output = Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg("--errors")
+        .arg("json")
+        .arg("/proc/self/mem")
+        .arg(source.join("ok.txt"))
+        .arg(&dest)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    // Hints such as how to resume the run stay plain text.
+    let errors: Vec<serde_json::Value> = stderr
+        .lines()
+        .filter(|line| line.starts_with('{'))
+        .map(|line| serde_json::from_str(line).expect("error lines are JSON"))
+        .collect();
+    assert_eq!(errors.len(), 1, "{stderr}");
+    assert_eq!(errors[0]["path"], "/proc/self/mem");
+    assert_eq!(errors[0]["operation"], "copy");
+    assert_eq!(errors[0]["kind"], "input/output error");
+    assert_eq!(errors[0]["os_error"], 5);
+    assert!(errors[0]["message"].as_str().unwrap().contains("/proc/self/mem"), "{stderr}");
+}
+
+#[test]
+fn test_errors_json_covers_argument_errors() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"a")]);
+    let missing = tmp_dir.path().join("nosuch");
+
+    for (args, path) in [
+        (vec![missing.clone(), dest.clone()], missing.clone()),
+        (vec![source.join("a.txt"), missing.clone()], missing.clone()),
+        (vec![source.join("a.txt"), source.join("a.txt")], source.join("a.txt")),
+        (vec![source.clone(), dest.clone()], source.clone()),
+    ] {
+        let output = Command::new(cargo_bin!("cp2"))
+            .arg("--errors=json")
+            .args(&args)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(3));
+        assert!(output.stdout.is_empty(), "{}", String::from_utf8_lossy(&output.stdout));
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        let error: serde_json::Value = serde_json::from_str(stderr.trim()).expect("error lines are JSON");
+        assert_eq!(error["path"], path.to_str().unwrap());
+        assert_eq!(error["operation"], "scan");
+    }
+}
+
+#[test]
+fn test_errors_json_covers_run_setup_errors() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"aaaa"), ("b.txt", b"bbbb")]);
+    let unwritable_log = tmp_dir.path().join("nosuch/cp2.log");
+
+    for (args, operation, errors) in [
+        (vec!["--max-file-size", "2"], "scan", 2),
+        (vec!["--log-file", unwritable_log.to_str().unwrap()], "open log file", 1),
+    ] {
+        let output = Command::new(cargo_bin!("cp2"))
+            .arg("--errors=json")
+            .args(&args)
+            .arg(source.join("a.txt"))
+            .arg(source.join("b.txt"))
+            .arg(&dest)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(3));
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        let lines: Vec<serde_json::Value> = stderr
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap_or_else(|_| panic!("not JSON: {line}")))
+            .collect();
+        assert_eq!(lines.len(), errors, "{stderr}");
+        assert!(lines.iter().all(|error| error["operation"] == operation), "{stderr}");
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_exit_codes() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"a")]);
+
+    // Everything copied.
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg(source.join("a.txt"))
+        .arg(&dest)
+        .assert()
+        .code(0);
+    // Every file failed.
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg("/proc/self/mem")
+        .arg(&dest)
+        .assert()
+        .code(1);
+    // Some files copied, others failed.
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg("/proc/self/mem")
+        .arg(source.join("a.txt"))
+        .arg(&dest)
+        .assert()
+        .code(2);
+    // Usage and preflight errors.
+    Command::new(cargo_bin!("cp2"))
+        .arg("--no-such-flag")
+        .arg(source.join("a.txt"))
+        .arg(&dest)
+        .assert()
+        .code(3);
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg(source.join("a.txt"))
+        .arg(tmp_dir.path().join("missing"))
+        .assert()
+        .code(3);
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("--help")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Exit status:"));
+}
+
+// A sparse file takes no space itself but claims far more than the
+// destination filesystem has free.
+#[cfg(unix)]
+#[test]
+fn test_preflight_refuses_copy_that_does_not_fit() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let huge = tmp_dir.path().join("huge.img");
+    fs::File::create(&huge).unwrap().set_len(1 << 43).ok();
+    if fs::metadata(&huge).map(|m| m.len()).unwrap_or(0) != 1 << 43 {
+        // The filesystem doesn't support a sparse file this large.
+        return;
+    }
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg(&huge)
+        .arg(&dest)
+        .assert()
+        .code(3)
+        .stderr(predicate::str::contains("Not enough free space"));
+    assert!(!dest.join("huge.img").exists(), "nothing is written");
+}
+
+// Files kept for `cp2 undo` keep their space, so overwriting them needs room
+// for the whole copy.
+#[test]
+fn test_preflight_counts_overwritten_files_kept_for_undo() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let huge = tmp_dir.path().join("huge.img");
+    for file in [&huge, &dest.join("huge.img")] {
+        fs::File::create(file).unwrap().set_len(1 << 43).ok();
+        if fs::metadata(file).map(|m| m.len()).unwrap_or(0) != 1 << 43 {
+            return;
+        }
+    }
+
+    Command::new(cargo_bin!("cp2"))
+        .env("CP2_STATE_DIR", tmp_dir.path().join("state"))
+        .args(["-q", "--undo"])
+        .arg(&huge)
+        .arg(&dest)
+        .assert()
+        .code(3)
+        .stderr(predicate::str::contains("Not enough free space"));
+}
+
+#[test]
+fn test_preflight_reports_files_to_overwrite() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"new"), ("b.txt", b"b")]);
+    fs::write(dest.join("a.txt"), b"old").unwrap();
+
+    // Without a terminal there's no prompt; the copy goes ahead.
+    Command::new(cargo_bin!("cp2"))
+        .arg("--no-progress")
+        .arg(source.join("a.txt"))
+        .arg(source.join("b.txt"))
+        .arg(&dest)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Existing files that will be overwritten (1):"))
+        .stderr(predicate::str::contains("a.txt"))
+        .stderr(predicate::str::contains("b.txt").not());
+    assert_eq!(fs::read(dest.join("a.txt")).unwrap(), b"new");
+
+    // Quiet runs and JSON output leave the prose report out.
+    for flag in ["-q", "--json", "--errors=json"] {
+        Command::new(cargo_bin!("cp2"))
+            .args([flag, "--no-progress", "--no-summary"])
+            .arg(source.join("a.txt"))
+            .arg(&dest)
+            .assert()
+            .success()
+            .stderr(predicate::str::contains("Preflight check").not());
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_preflight_refuses_unwritable_destination() {
+    let tmp_dir = TempDir::new().unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"a")]);
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg(source.join("a.txt"))
+        .arg("/proc")
+        .assert()
+        .code(3)
+        .stderr(predicate::str::contains("Destination is not writable"));
+}
+
+#[test]
+fn test_refuses_to_copy_file_onto_itself() {
+    let tmp_dir = TempDir::new().unwrap();
+    let source = create_test_src(&tmp_dir, &[("self.txt", b"precious")]);
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg(source.join("self.txt"))
+        .arg(&source)
+        .assert()
+        .code(3)
+        .stderr(predicate::str::contains("same file"));
+    assert_eq!(fs::read(source.join("self.txt")).unwrap(), b"precious");
+}
+
+// The destination base is close to PATH_MAX, so files below a nested source
+// directory end up over the limit while files at its top still fit.
+#[cfg(target_os = "linux")]
+#[test]
+fn test_reports_destination_paths_over_the_limit() {
+    let tmp_dir = TempDir::new().unwrap();
+    let mut dest = tmp_dir.path().join("dest");
+    while dest.as_os_str().len() + 201 < 4000 {
+        dest.push("d".repeat(200));
+    }
+    fs::create_dir_all(&dest).unwrap();
+    let nested = format!("{}/{}/deep.txt", "n".repeat(250), "m".repeat(250));
+    let source = create_test_src(&tmp_dir, &[("top.txt", b"top"), (nested.as_str(), b"deep")]);
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg("-r")
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("destination path too long"));
+    assert!(dest.join("source").join("top.txt").exists());
+}
+
+#[test]
+fn test_oversize_files_are_refused_or_split() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let content: Vec<u8> = (0..2500u32).map(|i| (i % 251) as u8).collect();
+    let source = create_test_src(&tmp_dir, &[("big.bin", &content), ("small.txt", b"hi")]);
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg("--max-file-size")
+        .arg("1K")
+        .arg(source.join("big.bin"))
+        .arg(source.join("small.txt"))
+        .arg(&dest)
+        .assert()
+        .code(3)
+        .stderr(predicate::str::contains("too large for the destination filesystem"))
+        .stderr(predicate::str::contains("big.bin"));
+    assert!(!dest.join("small.txt").exists(), "nothing is copied");
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg("--max-file-size")
+        .arg("1K")
+        .arg("--split-oversize")
+        .arg(source.join("big.bin"))
+        .arg(source.join("small.txt"))
+        .arg(&dest)
+        .assert()
+        .success();
+    assert!(!dest.join("big.bin").exists());
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&fs::read(dest.join("big.bin.cp2-split.json")).unwrap()).unwrap();
+    assert_eq!(manifest["size"], 2500);
+    let mut joined = Vec::new();
+    for chunk in manifest["chunks"].as_array().unwrap() {
+        let chunk = fs::read(dest.join(chunk.as_str().unwrap())).unwrap();
+        assert!(chunk.len() <= 1024);
+        joined.extend(chunk);
+    }
+    assert_eq!(joined, content);
+    assert_eq!(fs::read(dest.join("small.txt")).unwrap(), b"hi");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_sanitize_names_renames_and_reports() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let source = create_test_src(&tmp_dir, &[("what?/a:b.txt", b"x"), ("plain.txt", b"y")]);
+    let report = tmp_dir.path().join("report.json");
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg("-r")
+        .arg("--sanitize-names")
+        .arg("--sanitize-replacement")
+        .arg("-")
+        .arg("--report")
+        .arg(&report)
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Renamed 2 paths"));
+    assert_eq!(fs::read(dest.join("source/what-/a-b.txt")).unwrap(), b"x");
+    assert!(dest.join("source/plain.txt").exists());
+
+    let report: serde_json::Value = serde_json::from_str(&fs::read_to_string(&report).unwrap()).unwrap();
+    let renamed: Vec<_> = report["files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|f| !f["renamed_from"].is_null())
+        .collect();
+    assert_eq!(renamed.len(), 1);
+    assert!(renamed[0]["renamed_from"].as_str().unwrap().ends_with("what?/a:b.txt"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_normalize_nfc_composes_destination_names() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let source = create_test_src(&tmp_dir, &[("Re\u{301}sume\u{301}/cafe\u{301}.txt", b"x")]);
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg("-r")
+        .arg("--normalize")
+        .arg("nfc")
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success();
+    assert_eq!(fs::read(dest.join("source/R\u{e9}sum\u{e9}/caf\u{e9}.txt")).unwrap(), b"x");
+    assert!(!dest.join("source/Re\u{301}sume\u{301}").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_dereference_follows_links_and_skips_loops() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let source = create_test_src(&tmp_dir, &[("sub/file.txt", b"x")]);
+    let outside = tmp_dir.path().join("outside.txt");
+    fs::write(&outside, b"linked").unwrap();
+    std::os::unix::fs::symlink(&outside, source.join("link.txt")).unwrap();
+    std::os::unix::fs::symlink("..", source.join("sub/up")).unwrap();
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg("-r")
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success();
+    assert!(!dest.join("source/link.txt").exists());
+
+    let dest = tmp_dir.path().join("dest-L");
+    fs::create_dir(&dest).unwrap();
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg("-r")
+        .arg("-L")
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("already visited"));
+    assert_eq!(fs::read(dest.join("source/link.txt")).unwrap(), b"linked");
+    assert_eq!(fs::read(dest.join("source/sub/file.txt")).unwrap(), b"x");
+    assert!(!dest.join("source/sub/up").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_fifo_is_skipped_or_recreated_with_special() {
+    use std::os::unix::fs::FileTypeExt;
+
+    let tmp_dir = TempDir::new().unwrap();
+    let source = create_test_src(&tmp_dir, &[("file.txt", b"x")]);
+    let fifo = std::ffi::CString::new(source.join("pipe").to_str().unwrap()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o640) }, 0);
+
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg("-r")
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Skipped 1 special file"));
+    assert!(dest.join("source/file.txt").exists());
+    assert!(fs::symlink_metadata(dest.join("source/pipe")).is_err());
+
+    let dest = tmp_dir.path().join("dest-special");
+    fs::create_dir(&dest).unwrap();
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg("-r")
+        .arg("--special")
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Skipped").not());
+    let meta = fs::symlink_metadata(dest.join("source/pipe")).unwrap();
+    assert!(meta.file_type().is_fifo());
+}
+
+#[test]
+fn test_preserve_timestamps_of_files_and_directories() {
+    let tmp_dir = TempDir::new().unwrap();
+    let source = create_test_src(&tmp_dir, &[("a/b/file.txt", b"x"), ("a/other.txt", b"y")]);
+    let past = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+    let times = fs::FileTimes::new().set_accessed(past).set_modified(past);
+    for path in ["a/b/file.txt", "a/other.txt", "a/b", "a", ""] {
+        File::open(source.join(path)).unwrap().set_times(times).unwrap();
+    }
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg("-r")
+        .arg("--preserve")
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success();
+    for path in ["a/b/file.txt", "a/other.txt", "a/b", "a", ""] {
+        let modified = fs::metadata(dest.join("source").join(path)).unwrap().modified().unwrap();
+        assert_eq!(modified, past, "{path}");
+    }
+}
+
+/// Reads an extended attribute, or `None` if it isn't set.
+#[cfg(target_os = "linux")]
+fn get_xattr(path: &Path, name: &str) -> Option<Vec<u8>> {
+    let path = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+    let name = std::ffi::CString::new(name).unwrap();
+    let mut value = vec![0u8; 256];
+    let len = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), value.as_mut_ptr().cast(), value.len()) };
+    (len >= 0).then(|| value[..len as usize].to_vec())
+}
+
+/// Sets an extended attribute, returning whether that was allowed.
+#[cfg(target_os = "linux")]
+fn set_xattr(path: &Path, name: &str, value: &[u8]) -> bool {
+    let path = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+    let name = std::ffi::CString::new(name).unwrap();
+    unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), value.as_ptr().cast(), value.len(), 0) == 0 }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_preserve_context_copies_selinux_label() {
+    let tmp_dir = TempDir::new().unwrap();
+    let source = create_test_src(&tmp_dir, &[("bin/tool", b"x")]);
+    let label = b"system_u:object_r:bin_t:s0\0";
+    if !set_xattr(&source.join("bin/tool"), "security.selinux", label) {
+        // Needs root, or a policy that allows relabelling.
+        return;
+    }
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg("-r")
+        .arg("--preserve=context")
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success();
+    assert_eq!(get_xattr(&dest.join("source/bin/tool"), "security.selinux").unwrap(), label);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_preserve_all_copies_file_capabilities() {
+    let tmp_dir = TempDir::new().unwrap();
+    let source = create_test_src(&tmp_dir, &[("ping", b"x")]);
+    // VFS_CAP_REVISION_2 with cap_net_raw (bit 13) permitted and effective.
+    let caps: &[u8] = &[1, 0, 0, 2, 0, 0x20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    if !set_xattr(&source.join("ping"), "security.capability", caps) {
+        // Setting file capabilities needs CAP_SETFCAP.
+        return;
+    }
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg("--preserve=all")
+        .arg(source.join("ping"))
+        .arg(&dest)
+        .assert()
+        .success();
+    assert_eq!(get_xattr(&dest.join("ping"), "security.capability").unwrap(), caps);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_chown_and_chmod_apply_to_files_and_directories() {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let tmp_dir = TempDir::new().unwrap();
+    let source = create_test_src(&tmp_dir, &[("sub/file.txt", b"x")]);
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let is_root = unsafe { libc::geteuid() } == 0;
+
+    let mut cmd = Command::new(cargo_bin!("cp2"));
+    cmd.arg("-q").arg("-r").arg("--chmod").arg("D750,F600");
+    if is_root {
+        cmd.arg("--chown").arg("1234:5678");
+    }
+    cmd.arg(&source).arg(&dest).assert().success();
+
+    for (path, mode) in [("source", 0o750), ("source/sub", 0o750), ("source/sub/file.txt", 0o600)] {
+        let meta = fs::metadata(dest.join(path)).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o7777, mode, "{path}");
+        if is_root {
+            assert_eq!((meta.uid(), meta.gid()), (1234, 5678), "{path}");
+        }
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_uid_and_gid_maps_translate_source_ownership() {
+    use std::os::unix::fs::MetadataExt;
+
+    if unsafe { libc::geteuid() } != 0 {
+        // Giving files to other users needs root.
+        return;
+    }
+    let tmp_dir = TempDir::new().unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"x"), ("b.txt", b"y")]);
+    std::os::unix::fs::chown(source.join("a.txt"), Some(1000), Some(1000)).unwrap();
+    std::os::unix::fs::chown(source.join("b.txt"), Some(42), Some(43)).unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg("-r")
+        .arg("--uid-map")
+        .arg("1000:100000")
+        .arg("--gid-map")
+        .arg("1000:200000:10")
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success();
+    let a = fs::metadata(dest.join("source/a.txt")).unwrap();
+    assert_eq!((a.uid(), a.gid()), (100000, 200000));
+    let b = fs::metadata(dest.join("source/b.txt")).unwrap();
+    assert_eq!((b.uid(), b.gid()), (42, 43));
+}
+
+#[test]
+fn test_environment_variables_set_defaults_below_flags() {
+    let tmp_dir = TempDir::new().unwrap();
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+    let source = create_test_src(&tmp_dir, &[("big.bin", &data)]);
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+
+    Command::new(cargo_bin!("cp2"))
+        .env("CP2_BUFFER_SIZE", "bogus")
+        .arg("-q")
+        .arg(source.join("big.bin"))
+        .arg(&dest)
+        .assert()
+        .code(3)
+        .stderr(predicate::str::contains("--buffer-size"));
+
+    Command::new(cargo_bin!("cp2"))
+        .env("CP2_BUFFER_SIZE", "bogus")
+        .env("CP2_PARALLEL", "2")
+        .arg("-q")
+        .arg("--buffer-size")
+        .arg("4K")
+        .arg(source.join("big.bin"))
+        .arg(&dest)
+        .assert()
+        .success();
+    assert_eq!(fs::read(dest.join("big.bin")).unwrap(), data);
+}
+
+#[test]
+fn test_color_choice_controls_ansi_sequences() {
+    let run = |color: &str, no_color: bool| {
+        let mut cmd = Command::new(cargo_bin!("cp2"));
+        if no_color {
+            cmd.env("NO_COLOR", "1");
+        }
+        let output = cmd.arg("--color").arg(color).arg("/nonexistent/cp2").arg("/tmp").output().unwrap();
+        String::from_utf8(output.stderr).unwrap()
+    };
+    assert!(run("always", false).contains("\x1b["));
+    assert!(run("always", true).contains("\x1b["));
+    assert!(!run("never", false).contains("\x1b["));
+    assert!(!run("auto", true).contains("\x1b["));
+    // stderr is a pipe here, so auto means no color.
+    assert!(!run("auto", false).contains("\x1b["));
+}
+
+#[test]
+fn test_units_si_in_summary() {
+    let tmp_dir = TempDir::new().unwrap();
+    let source = create_test_src(&tmp_dir, &[("two-mb.bin", &vec![0u8; 2_000_000])]);
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("--units")
+        .arg("si")
+        .arg(source.join("two-mb.bin"))
+        .arg(&dest)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Total:    2.00 MB in"));
+
+    Command::new(cargo_bin!("cp2"))
+        .arg(source.join("two-mb.bin"))
+        .arg(&dest)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Total:    1.91 MiB in"));
+}
+
+#[test]
+fn test_bar_templates_are_validated() {
+    let tmp_dir = TempDir::new().unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"x")]);
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("--file-bar-template")
+        .arg("{:}")
+        .arg(source.join("a.txt"))
+        .arg(&dest)
+        .assert()
+        .code(3)
+        .stderr(predicate::str::contains("--file-bar-template"));
+
+    Command::new(cargo_bin!("cp2"))
+        .env("CP2_BAR_TEMPLATE", "{wide_bar} {percent}% {files} ({smooth_eta})")
+        .arg("--file-bar-template")
+        .arg("  {msg} {percent:>3}%")
+        .arg(source.join("a.txt"))
+        .arg(&dest)
+        .assert()
+        .success();
+    assert!(dest.join("a.txt").exists());
+}
+
+#[test]
+fn test_theme_choice() {
+    let tmp_dir = TempDir::new().unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"x")]);
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("--theme")
+        .arg("fancy")
+        .arg(source.join("a.txt"))
+        .arg(&dest)
+        .assert()
+        .code(3);
+
+    for theme in ["classic", "minimal", "blocks", "ascii"] {
+        let dest = dest.join(theme);
+        fs::create_dir(&dest).unwrap();
+        Command::new(cargo_bin!("cp2"))
+            .env("CP2_THEME", theme)
+            .arg(source.join("a.txt"))
+            .arg(&dest)
+            .assert()
+            .success();
+        assert!(dest.join("a.txt").exists());
+    }
+}
+
+// The source disappears between the scan and the copy when a pre-file hook
+// removes it; the untouched destination must survive.
+#[cfg(unix)]
+#[test]
+fn test_unreadable_source_never_deletes_existing_destination() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let source = create_test_src(&tmp_dir, &[("gone.txt", b"new")]);
+    fs::write(dest.join("gone.txt"), b"existing").unwrap();
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg("--pre-file-cmd")
+        .arg("rm {src}")
+        .arg(source.join("gone.txt"))
+        .arg(&dest)
+        .assert()
+        .failure();
+    assert_eq!(fs::read(dest.join("gone.txt")).unwrap(), b"existing");
+}
+
+#[test]
+fn test_bwlimit_caps_transfer_rate() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let file = tmp_dir.path().join("limited.bin");
+    fs::write(&file, vec![7u8; 300 * 1024]).unwrap();
+
+    let started = std::time::Instant::now();
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg("--bwlimit")
+        .arg("300K")
+        .arg(&file)
+        .arg(&dest)
+        .assert()
+        .success();
+
+    assert!(started.elapsed() >= std::time::Duration::from_millis(900));
+    assert_eq!(fs::read(dest.join("limited.bin")).unwrap().len(), 300 * 1024);
+}
+
+// The fake SFTP and WebDAV servers are Python scripts.
+fn has_python3() -> bool {
+    Command::new("python3").arg("--version").output().is_ok_and(|o| o.status.success())
+}
+
+// Stands in for ssh: a small SFTP server that serves the local filesystem.
+fn fake_ssh_command() -> Option<String> {
+    has_python3().then(|| {
+        let server = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fake_sftp_server.py");
+        format!("python3 {}", server.display())
+    })
+}
+
+#[cfg(unix)]
+#[test]
+fn test_sftp_upload_and_download() {
+    let Some(ssh_command) = fake_ssh_command() else {
+        eprintln!("python3 not found; skipping");
+        return;
+    };
+    let tmp_dir = TempDir::new().unwrap();
+    let big = vec![3u8; 1024 * 1024 + 17];
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"hello"), ("sub/big.bin", &big), ("empty/", b"")]);
+    let server = tmp_dir.path().join("server");
+    fs::create_dir(&server).unwrap();
+
+    Command::new(cargo_bin!("cp2"))
+        .env("CP2_SSH_COMMAND", &ssh_command)
+        .arg("-r")
+        .arg(&source)
+        .arg(format!("deploy@example.com:{}", server.display()))
+        .assert()
+        .success();
+    assert_dirs_equal(&source, &server.join("source"));
+
+    let back = tmp_dir.path().join("back");
+    fs::create_dir(&back).unwrap();
+    Command::new(cargo_bin!("cp2"))
+        .env("CP2_SSH_COMMAND", &ssh_command)
+        .arg("-r")
+        .arg(format!("sftp://example.com:2222{}", server.join("source").display()))
+        .arg(&back)
+        .assert()
+        .success();
+    assert_dirs_equal(&source, &back.join("source"));
+
+    // A missing remote destination is a usage error, as it is locally.
+    Command::new(cargo_bin!("cp2"))
+        .env("CP2_SSH_COMMAND", &ssh_command)
+        .arg(source.join("a.txt"))
+        .arg(format!("example.com:{}", tmp_dir.path().join("missing").display()))
+        .assert()
+        .code(3)
+        .stderr(predicate::str::contains("Destination path does not exist"));
+}
+
+// Remote transfers report the bytes they put on the wire and how many of
+// their chunks the server hasn't answered yet, for the per-file bars.
+#[cfg(unix)]
+#[test]
+fn test_sftp_reports_network_bytes_and_chunks_in_flight() {
+    use cp2::transport::Transport;
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct Recorder {
+        network: AtomicU64,
+        most_in_flight: AtomicUsize,
+        last_in_flight: AtomicUsize,
+    }
+    impl cp2::ProgressSink for Recorder {
+        fn on_network_bytes(&self, _from: &Path, bytes: u64) {
+            self.network.fetch_add(bytes, Ordering::Relaxed);
+        }
+        fn on_in_flight(&self, _from: &Path, chunks: usize) {
+            self.most_in_flight.fetch_max(chunks, Ordering::Relaxed);
+            self.last_in_flight.store(chunks, Ordering::Relaxed);
+        }
+    }
+
+    let Some(ssh_command) = fake_ssh_command() else {
+        eprintln!("python3 not found; skipping");
+        return;
+    };
+    let tmp_dir = TempDir::new().unwrap();
+    let data = vec![7u8; 300_000];
+    let source = create_test_src(&tmp_dir, &[("data.bin", &data)]);
+    let server = tmp_dir.path().join("server");
+    fs::create_dir(&server).unwrap();
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let location = cp2::sftp::SftpLocation::parse(&format!("example.com:{}", server.display())).unwrap();
+        let command: Vec<String> = ssh_command.split_whitespace().map(String::from).collect();
+        let session = cp2::sftp::SftpSession::connect(&location, &command).await.unwrap();
+        let recorder = Recorder::default();
+        let remote = format!("{}/data.bin", server.display());
+        session
+            .upload(&source.join("data.bin"), &remote, Some(&recorder), None, None)
+            .await
+            .unwrap();
+        assert!(recorder.network.load(Ordering::Relaxed) >= data.len() as u64);
+        assert!(recorder.most_in_flight.load(Ordering::Relaxed) > 1);
+        assert_eq!(recorder.last_in_flight.load(Ordering::Relaxed), 0);
+    });
+    assert_eq!(fs::read(server.join("data.bin")).unwrap(), data);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_sftp_resumes_interrupted_transfers() {
+    let Some(ssh_command) = fake_ssh_command() else {
+        eprintln!("python3 not found; skipping");
+        return;
+    };
+    let tmp_dir = TempDir::new().unwrap();
+    let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    let source = create_test_src(&tmp_dir, &[("data.bin", &data)]);
+    let server = tmp_dir.path().join("server");
+    fs::create_dir(&server).unwrap();
+    let state_dir = tmp_dir.path().join("state");
+
+    // The first upload breaks off after two 32 KiB writes; their range is
+    // remembered and the remote `.part` file kept.
+    Command::new(cargo_bin!("cp2"))
+        .env("CP2_SSH_COMMAND", &ssh_command)
+        .env("CP2_STATE_DIR", &state_dir)
+        .env("FAKE_SFTP_FAIL_WRITES_AT", "65536")
+        .arg(source.join("data.bin"))
+        .arg(format!("example.com:{}", server.display()))
+        .assert()
+        .failure();
+    let part = server.join("data.bin.part");
+    assert!(part.exists() && !server.join("data.bin").exists());
+    assert_eq!(fs::read_dir(&state_dir).unwrap().count(), 1);
+
+    // The uploaded range is checked against the source on resume, so
+    // corrupting it makes the second upload send it again.
+    let mut uploaded = fs::read(&part).unwrap();
+    uploaded[..65536].fill(0xee);
+    fs::write(&part, &uploaded).unwrap();
+    Command::new(cargo_bin!("cp2"))
+        .env("CP2_SSH_COMMAND", &ssh_command)
+        .env("CP2_STATE_DIR", &state_dir)
+        .arg("-v")
+        .arg(source.join("data.bin"))
+        .arg(format!("example.com:{}", server.display()))
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("doesn't match"));
+    assert_eq!(fs::read(server.join("data.bin")).unwrap(), data);
+    assert!(!part.exists());
+    assert_eq!(fs::read_dir(&state_dir).unwrap().count(), 0);
+
+    // Downloads keep their state next to the `.part` file. A range that
+    // matches the source is kept as it is.
+    let back = tmp_dir.path().join("back");
+    fs::create_dir(&back).unwrap();
+    fs::write(back.join("source.bin.part"), &data[..40_000]).unwrap();
+    fs::write(back.join("source.bin.part.json"), r#"{"size":200000,"ranges":[[0,40000]]}"#).unwrap();
+    fs::rename(source.join("data.bin"), source.join("source.bin")).unwrap();
+    Command::new(cargo_bin!("cp2"))
+        .env("CP2_SSH_COMMAND", &ssh_command)
+        .arg("-v")
+        .arg(format!("example.com:{}", source.join("source.bin").display()))
+        .arg(&back)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("doesn't match").not());
+    assert_eq!(fs::read(back.join("source.bin")).unwrap(), data);
+    assert!(!back.join("source.bin.part").exists() && !back.join("source.bin.part.json").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_sftp_compressed_transfers() {
+    let Some(ssh_command) = fake_ssh_command() else {
+        eprintln!("python3 not found; skipping");
+        return;
+    };
+    if Command::new("zstd").arg("--version").output().is_err() {
+        eprintln!("zstd not found; skipping");
+        return;
+    }
+    let tmp_dir = TempDir::new().unwrap();
+    let text = "compressible text ".repeat(50_000);
+    let source = create_test_src(&tmp_dir, &[("a.txt", text.as_bytes()), ("it's/b.txt", b"quoted")]);
+    let server = tmp_dir.path().join("server");
+    fs::create_dir(&server).unwrap();
+
+    Command::new(cargo_bin!("cp2"))
+        .env("CP2_SSH_COMMAND", &ssh_command)
+        .args(["-r", "--compress-transfer", "-vv"])
+        .arg(&source)
+        .arg(format!("example.com:{}", server.display()))
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Compressing transfers with example.com using zstd"));
+    assert_dirs_equal(&source, &server.join("source"));
+
+    let back = tmp_dir.path().join("back");
+    fs::create_dir(&back).unwrap();
+    Command::new(cargo_bin!("cp2"))
+        .env("CP2_SSH_COMMAND", &ssh_command)
+        .args(["-r", "--compress-transfer"])
+        .arg(format!("example.com:{}", server.join("source").display()))
+        .arg(&back)
+        .assert()
+        .success();
+    assert_dirs_equal(&source, &back.join("source"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_sftp_remote_from_config() {
+    let Some(ssh_command) = fake_ssh_command() else {
+        eprintln!("python3 not found; skipping");
+        return;
+    };
+    let tmp_dir = TempDir::new().unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"hello")]);
+    let server = tmp_dir.path().join("server");
+    fs::create_dir(&server).unwrap();
+    let config = tmp_dir.path().join("config.toml");
+    fs::write(
+        &config,
+        format!(
+            "[remotes.nas]\ntype = \"sftp\"\nhost = \"nas.lan\"\nuser = \"me\"\npath = \"{}\"\nidentity_file = \"/keys/nas\"\nhost_keys = [\"ssh-ed25519 AAAAexample\"]\n",
+            server.display()
+        ),
+    )
+    .unwrap();
+
+    // Relative paths start in the remote's directory.
+    Command::new(cargo_bin!("cp2"))
+        .env("CP2_SSH_COMMAND", &ssh_command)
+        .env("CP2_CONFIG", &config)
+        .arg("-r")
+        .arg(&source)
+        .arg("nas:")
+        .assert()
+        .success();
+    assert_eq!(fs::read(server.join("source/a.txt")).unwrap(), b"hello");
+    let known_hosts = fs::read_to_string(tmp_dir.path().join("known_hosts.nas")).unwrap();
+    assert_eq!(known_hosts, "nas.lan ssh-ed25519 AAAAexample\n");
+
+    let back = tmp_dir.path().join("back");
+    fs::create_dir(&back).unwrap();
+    Command::new(cargo_bin!("cp2"))
+        .env("CP2_SSH_COMMAND", &ssh_command)
+        .env("CP2_CONFIG", &config)
+        .arg("nas:source/a.txt")
+        .arg(&back)
+        .assert()
+        .success();
+    assert_eq!(fs::read(back.join("a.txt")).unwrap(), b"hello");
+
+    // A remote without a host can't be used.
+    fs::write(&config, "[remotes.nas]\ntype = \"sftp\"\n").unwrap();
+    Command::new(cargo_bin!("cp2"))
+        .env("CP2_CONFIG", &config)
+        .arg("nas:a.txt")
+        .arg(&back)
+        .assert()
+        .code(3)
+        .stderr(predicate::str::contains("host is not set"));
+}
+
+#[test]
+fn test_sftp_sources_need_local_destination() {
+    Command::new(cargo_bin!("cp2"))
+        .arg("host.example:/etc/hosts")
+        .arg("other.example:/tmp")
+        .assert()
+        .code(3)
+        .stderr(predicate::str::contains("local destination"));
+}
+
+// Serves `body` over HTTP with range support on a local port until the test
+// ends, recording the `Range` header of every GET request.
+fn serve_http(body: Vec<u8>) -> (String, std::sync::Arc<std::sync::Mutex<Vec<String>>>) {
+    use std::io::{BufRead, BufReader};
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/files/data.bin?token=1", listener.local_addr().unwrap());
+    let ranges = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let seen = std::sync::Arc::clone(&ranges);
+    let body = std::sync::Arc::new(body);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let (body, seen) = (std::sync::Arc::clone(&body), std::sync::Arc::clone(&seen));
+            std::thread::spawn(move || {
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                reader.read_line(&mut request).unwrap();
+                let mut range = None;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.to_ascii_lowercase().strip_prefix("range: bytes=") {
+                        let (start, end) = value.trim().split_once('-').unwrap();
+                        let start: usize = start.parse().unwrap();
+                        let end = end.parse::<usize>().map_or(body.len(), |end| end + 1);
+                        range = Some((start, end));
+                    }
+                }
+                let head = request.starts_with("HEAD");
+                if !head {
+                    seen.lock().unwrap().push(range.map_or("-".to_string(), |(s, e)| format!("{s}-{e}")));
+                }
+                let (status, start, end) = match range {
+                    Some((start, end)) => ("206 Partial Content", start, end),
+                    None => ("200 OK", 0, body.len()),
+                };
+                let header = format!(
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nAccept-Ranges: bytes\r\nConnection: close\r\n\r\n",
+                    end - start
+                );
+                stream.write_all(header.as_bytes()).unwrap();
+                if !head {
+                    let _ = stream.write_all(&body[start..end]);
+                }
+            });
+        }
+    });
+    (url, ranges)
+}
+
+#[test]
+fn test_http_segmented_download() {
+    let body: Vec<u8> = (0..20 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let (url, ranges) = serve_http(body.clone());
+    let tmp_dir = TempDir::new().unwrap();
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg("--segments")
+        .arg("2")
+        .arg(&url)
+        .arg(tmp_dir.path())
+        .assert()
+        .success();
+
+    assert_eq!(fs::read(tmp_dir.path().join("data.bin")).unwrap(), body);
+    assert!(!tmp_dir.path().join("data.bin.part").exists());
+    let mut ranges = ranges.lock().unwrap().clone();
+    ranges.sort();
+    assert_eq!(ranges, vec!["0-10485760".to_string(), "10485760-20971520".to_string()]);
+}
+
+#[test]
+fn test_http_download_resumes_part_file() {
+    let body: Vec<u8> = (0..100_000).map(|i| (i % 253) as u8).collect();
+    let (url, ranges) = serve_http(body.clone());
+    let tmp_dir = TempDir::new().unwrap();
+    fs::write(tmp_dir.path().join("data.bin.part"), &body[..40_000]).unwrap();
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("-q")
+        .arg(&url)
+        .arg(tmp_dir.path())
+        .assert()
+        .success();
+
+    assert_eq!(fs::read(tmp_dir.path().join("data.bin")).unwrap(), body);
+    // The part already there is fetched once more to check it.
+    let mut fetched = ranges.lock().unwrap().clone();
+    fetched.sort();
+    assert_eq!(fetched, vec!["0-40000".to_string(), "40000-100000".to_string()]);
+}
+
+#[test]
+fn test_http_download_refetches_corrupt_part_file() {
+    let body: Vec<u8> = (0..100_000).map(|i| (i % 253) as u8).collect();
+    let (url, _) = serve_http(body.clone());
+    let tmp_dir = TempDir::new().unwrap();
+    fs::write(tmp_dir.path().join("data.bin.part"), vec![0xee; 40_000]).unwrap();
+
+    Command::new(cargo_bin!("cp2"))
+        .arg("-v")
+        .arg(&url)
+        .arg(tmp_dir.path())
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("doesn't match"));
+
+    assert_eq!(fs::read(tmp_dir.path().join("data.bin")).unwrap(), body);
+}
+
+#[test]
+fn test_http_sources_need_local_destination() {
+    Command::new(cargo_bin!("cp2"))
+        .arg("https://example.com/a.iso")
+        .arg("myaws:bucket")
+        .assert()
+        .code(3)
+        .stderr(predicate::str::contains("local destination"));
+}
+
+// Kills the fake server when a test ends, even if it fails.
+struct ServerGuard(std::process::Child);
+
+impl Drop for ServerGuard {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+    }
+}
+
+#[test]
+fn test_webdav_upload_and_download() {
+    if !has_python3() {
+        eprintln!("python3 not found; skipping");
+        return;
+    }
+    let tmp_dir = TempDir::new().unwrap();
+    let root = tmp_dir.path().join("dav");
+    fs::create_dir_all(root.join("files")).unwrap();
+    let mut child = Command::new("python3")
+        .arg(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fake_webdav_server.py"))
+        .arg(&root)
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut port = String::new();
+    std::io::BufRead::read_line(&mut std::io::BufReader::new(child.stdout.take().unwrap()), &mut port).unwrap();
+    let _server = ServerGuard(child);
+    let base = format!("dav://me@127.0.0.1:{}/files", port.trim());
+
+    let source = create_test_src(&tmp_dir, &[("notes 1.txt", b"hello"), ("sub/data.bin", &[9u8; 300_000])]);
+    Command::new(cargo_bin!("cp2"))
+        .env("CP2_DAV_PASSWORD", "secret")
+        .arg("-r")
+        .arg(&source)
+        .arg(&base)
+        .assert()
+        .success();
+    assert_dirs_equal(&source, &root.join("files/source"));
+
+    let back = tmp_dir.path().join("back");
+    fs::create_dir(&back).unwrap();
+    Command::new(cargo_bin!("cp2"))
+        .arg("-r")
+        .arg(format!("{}/source", base))
+        .arg(&back)
+        .assert()
+        .success();
+    assert_dirs_equal(&source, &back.join("source"));
+}
+
+#[test]
+fn test_bench_recommends_defaults() {
+    let tmp_dir = TempDir::new().unwrap();
+    let dest = tmp_dir.path().join("dest");
+    fs::create_dir(&dest).unwrap();
+    let config = tmp_dir.path().join("config.toml");
+
+    let output = Command::new(cargo_bin!("cp2"))
+        .env("CP2_CONFIG", &config)
+        .args(["bench", "--size", "1M"])
+        .arg(&dest)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("buffered"), "{stdout}");
+    let defaults = &stdout[stdout.find("[defaults]").expect("recommends defaults")..];
+    assert!(defaults.contains("buffer_size = "), "{defaults}");
+    assert_eq!(fs::read_dir(&dest).unwrap().count(), 0, "the scratch directory is removed");
+
+    // The recommendation goes into the config file as it is printed.
+    fs::write(&config, format!("{defaults}\n[remotes.nas]\ntype = \"sftp\"\nhost = \"nas.lan\"\n")).unwrap();
+    let source = create_test_src(&tmp_dir, &[("a.txt", b"abc")]);
+    Command::new(cargo_bin!("cp2"))
+        .env("CP2_CONFIG", &config)
+        .arg("-r")
+        .arg(&source)
+        .arg(&dest)
+        .assert()
+        .success();
+    assert_eq!(fs::read(dest.join("source/a.txt")).unwrap(), b"abc");
+    Command::new(cargo_bin!("cp2"))
+        .env("CP2_CONFIG", &config)
+        .args(["config", "list"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("nas"));
+
+    fs::write(&config, "[defaults]\nbuffer_size = \"lots\"\n").unwrap();
+    Command::new(cargo_bin!("cp2"))
+        .env("CP2_CONFIG", &config)
+        .arg("-v")
+        .arg(source.join("a.txt"))
+        .arg(&dest)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Ignoring buffer_size in the config file"));
+}
+
+/// Fraction of the pages of `path` that are in the page cache.
+#[cfg(target_os = "linux")]
+fn cached_fraction(path: &Path) -> f64 {
+    use std::os::fd::AsRawFd;
+    let file = File::open(path).unwrap();
+    let len = file.metadata().unwrap().len() as usize;
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let map = unsafe { libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_SHARED, file.as_raw_fd(), 0) };
+    assert_ne!(map, libc::MAP_FAILED);
+    let mut pages = vec![0u8; len.div_ceil(page)];
+    assert_eq!(unsafe { libc::mincore(map, len, pages.as_mut_ptr()) }, 0);
+    unsafe { libc::munmap(map, len) };
+    pages.iter().filter(|&&page| page & 1 == 1).count() as f64 / pages.len() as f64
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_drop_cache_evicts_large_copies() {
+    let tmp_dir = TempDir::new().unwrap();
+    let file = tmp_dir.path().join("big.bin");
+    File::create(&file).unwrap().set_len(80 * 1024 * 1024).unwrap();
+
+    for (when, dest) in [("auto", "dropped"), ("never", "kept")] {
+        let dest = tmp_dir.path().join(dest);
+        fs::create_dir(&dest).unwrap();
+        Command::new(cargo_bin!("cp2"))
+            .args(["--sync", "--drop-cache", when])
+            .arg(&file)
+            .arg(&dest)
+            .assert()
+            .success();
+    }
+    assert_eq!(fs::metadata(tmp_dir.path().join("dropped/big.bin")).unwrap().len(), 80 * 1024 * 1024);
+    assert!(cached_fraction(&tmp_dir.path().join("dropped/big.bin")) < 0.1);
+    assert!(cached_fraction(&tmp_dir.path().join("kept/big.bin")) > 0.9);
+}
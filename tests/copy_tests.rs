@@ -0,0 +1,249 @@
+/// Tests for the file copy primitives in `cp2::copy`.
+#[cfg(unix)]
+#[tokio::test]
+async fn test_copy_refuses_hard_link_to_source() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let from = tmp_dir.path().join("a.txt");
+    let to = tmp_dir.path().join("b.txt");
+    std::fs::write(&from, b"precious").unwrap();
+    std::fs::hard_link(&from, &to).unwrap();
+
+    let err = cp2::copy::copy_file_with_dual_progress(&from, &to, None, false, None, None)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, cp2::CopyError::SameFile { .. }), "{err}");
+    assert!(err.to_string().contains("are the same file"), "{err}");
+    assert_eq!(std::fs::read(&from).unwrap(), b"precious");
+}
+
+#[tokio::test]
+async fn test_copy_file_split_writes_exact_chunks() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let from = tmp_dir.path().join("disk.img");
+    let to = tmp_dir.path().join("out").join("disk.img");
+    std::fs::create_dir(to.parent().unwrap()).unwrap();
+    std::fs::write(&from, vec![9u8; 4096]).unwrap();
+
+    let copied = cp2::copy::copy_file_split(&from, &to, 1024, None, 100, false, None, None)
+        .await
+        .unwrap();
+    assert_eq!(copied, 4096);
+
+    // An exact multiple of the chunk size leaves no empty trailing chunk.
+    let manifest: cp2::copy::SplitManifest =
+        serde_json::from_slice(&std::fs::read(cp2::copy::split_manifest_path(&to)).unwrap()).unwrap();
+    assert_eq!(manifest.chunks, ["disk.img.000", "disk.img.001", "disk.img.002", "disk.img.003"]);
+    assert_eq!(manifest.chunk_size, 1024);
+    assert!(!to.with_file_name("disk.img.004").exists());
+}
+
+#[tokio::test]
+async fn test_copy_file_to_many_writes_every_destination() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let from = tmp_dir.path().join("a.bin");
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&from, &data).unwrap();
+    let to: Vec<_> = ["b.bin", "c.bin", "d.bin"].iter().map(|name| tmp_dir.path().join(name)).collect();
+
+    let mut buffer = vec![0u8; 4096];
+    let copied = cp2::copy::copy_file_to_many(&from, &to, &mut buffer, None, false, None, None)
+        .await
+        .unwrap();
+    assert_eq!(copied, data.len() as u64);
+    for to in &to {
+        assert_eq!(std::fs::read(to).unwrap(), data);
+    }
+
+    // A destination that is the source itself is refused before anything is
+    // truncated.
+    let err = cp2::copy::copy_file_to_many(&from, &[to[0].clone(), from.clone()], &mut buffer, None, false, None, None)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, cp2::CopyError::SameFile { .. }), "{err}");
+    assert_eq!(std::fs::read(&from).unwrap(), data);
+}
+
+#[test]
+fn test_split_chunk_path_widens_for_many_chunks() {
+    let to = std::path::Path::new("dest/disk.img");
+    assert_eq!(cp2::copy::split_chunk_path(to, 7, 12), std::path::Path::new("dest/disk.img.007"));
+    assert_eq!(cp2::copy::split_chunk_path(to, 42, 5000), std::path::Path::new("dest/disk.img.0042"));
+}
+
+#[tokio::test]
+async fn test_copy_stops_when_cancelled() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let from = tmp_dir.path().join("a.bin");
+    let to = tmp_dir.path().join("b.bin");
+    std::fs::write(&from, vec![1u8; 4096]).unwrap();
+    let cancel = cp2::CancellationToken::new();
+    cancel.cancel();
+
+    let err = cp2::copy::copy_file_with_dual_progress(&from, &to, None, false, None, Some(&cancel))
+        .await
+        .unwrap_err();
+    assert!(err.is_partial());
+    assert!(matches!(err.cause(), cp2::CopyError::Interrupted), "{err}");
+    assert!(std::fs::metadata(&to).unwrap().len() < 4096);
+}
+
+#[tokio::test]
+async fn test_copy_error_names_failed_operation() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let from = tmp_dir.path().join("missing.txt");
+    let to = tmp_dir.path().join("b.txt");
+
+    let err = cp2::copy::copy_file_with_dual_progress(&from, &to, None, false, None, None)
+        .await
+        .unwrap_err();
+    match err {
+        cp2::CopyError::Io { operation, ref path, ref source } => {
+            assert_eq!(operation, "open");
+            assert_eq!(path, &from);
+            assert_eq!(source.kind(), std::io::ErrorKind::NotFound);
+        }
+        other => panic!("unexpected error: {other}"),
+    }
+    assert!(!err.is_partial());
+    assert!(!to.exists());
+}
+
+/// Bytes that don't repeat, so every block of them is distinct.
+fn noise(len: usize) -> Vec<u8> {
+    let mut state = 0x2545_f491_u32;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn test_copy_file_delta_writes_only_changed_blocks() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let from = tmp_dir.path().join("new.bin");
+    let to = tmp_dir.path().join("old.bin");
+    let old = noise(256 * 1024);
+    let mut new = old.clone();
+    new[100_000..100_100].fill(0);
+    std::fs::write(&to, &old).unwrap();
+    std::fs::write(&from, &new).unwrap();
+
+    let stats = cp2::delta::copy_file_delta(&from, &to, None, false, None, None)
+        .await
+        .unwrap();
+    assert_eq!(stats.bytes, new.len() as u64);
+    assert_eq!(stats.written, cp2::delta::block_size(old.len() as u64) as u64);
+    assert_eq!(std::fs::read(&to).unwrap(), new);
+}
+
+#[tokio::test]
+async fn test_copy_file_delta_handles_shifted_data() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let from = tmp_dir.path().join("new.bin");
+    let to = tmp_dir.path().join("old.bin");
+    let old = noise(256 * 1024);
+
+    // Bytes removed near the start: the rest moves up and is reused.
+    let mut new = old.clone();
+    new.drain(10_000..10_123);
+    std::fs::write(&to, &old).unwrap();
+    std::fs::write(&from, &new).unwrap();
+    let stats = cp2::delta::copy_file_delta(&from, &to, None, false, None, None)
+        .await
+        .unwrap();
+    assert_eq!(std::fs::read(&to).unwrap(), new);
+    assert!(stats.written < new.len() as u64, "{stats:?}");
+
+    // Bytes inserted, and a destination that doesn't exist yet.
+    new.splice(50_000..50_000, [7u8; 300]);
+    std::fs::write(&from, &new).unwrap();
+    cp2::delta::copy_file_delta(&from, &to, None, false, None, None)
+        .await
+        .unwrap();
+    assert_eq!(std::fs::read(&to).unwrap(), new);
+    let fresh = tmp_dir.path().join("fresh.bin");
+    let stats = cp2::delta::copy_file_delta(&from, &fresh, None, false, None, None)
+        .await
+        .unwrap();
+    assert_eq!(stats.written, new.len() as u64);
+    assert_eq!(std::fs::read(&fresh).unwrap(), new);
+}
+
+#[tokio::test]
+async fn test_copy_file_direct_keeps_exact_size() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    // Sizes that end mid-block, on a block boundary, and an empty file.
+    for size in [3 * 4096 + 123, 5 * 4096, 100, 0] {
+        let from = tmp_dir.path().join(format!("{size}.bin"));
+        let to = tmp_dir.path().join(format!("{size}.copy"));
+        let data: Vec<u8> = (0..size as u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&from, &data).unwrap();
+
+        let copied = cp2::copy::copy_file_direct(&from, &to, None, 8192, true, None, None)
+            .await
+            .unwrap();
+        assert_eq!(copied, size as u64);
+        assert_eq!(std::fs::read(&to).unwrap(), data);
+    }
+}
+
+// A short read before the end of the source, here because it shrinks after
+// the first block, fails the copy instead of truncating the destination.
+#[tokio::test]
+async fn test_copy_file_direct_fails_on_short_read() {
+    struct Shrink(std::path::PathBuf);
+    impl cp2::ProgressSink for Shrink {
+        fn on_bytes(&self, _from: &std::path::Path, _bytes: u64) {
+            let file = std::fs::OpenOptions::new().write(true).open(&self.0).unwrap();
+            file.set_len(8192 + 100).unwrap();
+        }
+    }
+
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let from = tmp_dir.path().join("shrinking.bin");
+    let to = tmp_dir.path().join("shrinking.copy");
+    let size = 3 * 4096 + 123;
+    std::fs::write(&from, vec![7u8; size]).unwrap();
+
+    // tmpfs and other filesystems without O_DIRECT take the buffered path.
+    use std::os::unix::fs::OpenOptionsExt;
+    if std::fs::File::options().read(true).custom_flags(libc::O_DIRECT).open(&from).is_err() {
+        return;
+    }
+    let sink = Shrink(from.clone());
+    let err = cp2::copy::copy_file_direct(&from, &to, Some(&sink), 8192, false, None, None)
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err.cause(), cp2::CopyError::VerificationFailed { expected, written: 8292, .. } if *expected == size as u64),
+        "{err}"
+    );
+}
+
+#[tokio::test]
+async fn test_copy_file_kernel_copies_contents_only() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let from = tmp_dir.path().join("run.sh");
+    let to = tmp_dir.path().join("copy.sh");
+    let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&from, &data).unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&from, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    let copied = cp2::copy::copy_file_kernel(&from, &to, None, true, None, None).await.unwrap();
+    assert_eq!(copied, data.len() as u64);
+    assert_eq!(std::fs::read(&to).unwrap(), data);
+    // Unlike std::fs::copy, the mode isn't carried over.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(std::fs::metadata(&to).unwrap().permissions().mode() & 0o111, 0);
+    }
+}
@@ -0,0 +1,195 @@
+/// Tests for the library entry point `cp2::copy`.
+use cp2::report::FileStatus;
+use cp2::{CopyOptions, Overwrite};
+
+#[tokio::test]
+async fn test_copy_tree_reports_every_file() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let source = tmp_dir.path().join("src");
+    std::fs::create_dir_all(source.join("nested")).unwrap();
+    std::fs::write(source.join("a.txt"), b"hello").unwrap();
+    std::fs::write(source.join("nested").join("b.txt"), b"world!").unwrap();
+    let dest = tmp_dir.path().join("dest");
+    std::fs::create_dir(&dest).unwrap();
+
+    let options = CopyOptions::new().recursive(true).parallel(2).buffer_size(4);
+    let report = cp2::copy(&[&source], &dest, &options).await.unwrap();
+
+    assert!(report.is_success());
+    assert_eq!(report.totals.files_copied, 2);
+    assert_eq!(report.totals.bytes_copied, 11);
+    assert_eq!(std::fs::read(dest.join("src").join("nested").join("b.txt")).unwrap(), b"world!");
+}
+
+#[tokio::test]
+async fn test_copy_records_unusable_sources_as_failed() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let dir = tmp_dir.path().join("dir");
+    std::fs::create_dir(&dir).unwrap();
+    let dest = tmp_dir.path().join("dest");
+    std::fs::create_dir(&dest).unwrap();
+
+    let missing = tmp_dir.path().join("missing.txt");
+    let report = cp2::copy(&[&missing, &dir], &dest, &CopyOptions::new()).await.unwrap();
+    assert!(!report.is_success());
+    assert_eq!(report.totals.files_failed, 2);
+
+    let err = cp2::copy(&[&dir], tmp_dir.path().join("nowhere"), &CopyOptions::new()).await.unwrap_err();
+    assert!(matches!(err, cp2::CopyError::Io { .. }));
+}
+
+#[tokio::test]
+async fn test_copy_records_failed_directories_and_same_file_copies() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let source = tmp_dir.path().join("src");
+    std::fs::create_dir_all(source.join("nested")).unwrap();
+    std::fs::write(source.join("nested").join("a.txt"), b"a").unwrap();
+    let dest = tmp_dir.path().join("dest");
+    std::fs::create_dir(&dest).unwrap();
+    // A file where the copied directory should go.
+    std::fs::write(dest.join("src"), b"in the way").unwrap();
+
+    let options = CopyOptions::new().recursive(true);
+    let report = cp2::copy(&[&source], &dest, &options).await.unwrap();
+    assert_eq!(report.totals.files_failed, 1);
+    assert!(report.files[0].error.as_deref().unwrap().contains("cannot create directory"));
+
+    // Copying a file into its own directory would truncate it.
+    let file = source.join("nested").join("a.txt");
+    let report = cp2::copy(&[&file], source.join("nested"), &CopyOptions::new()).await.unwrap();
+    assert_eq!(report.totals.files_failed, 1);
+    assert_eq!(std::fs::read(&file).unwrap(), b"a");
+}
+
+#[tokio::test]
+async fn test_copy_overwrite_never_keeps_existing_files() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let source = tmp_dir.path().join("a.txt");
+    std::fs::write(&source, b"new").unwrap();
+    let dest = tmp_dir.path().join("dest");
+    std::fs::create_dir(&dest).unwrap();
+    std::fs::write(dest.join("a.txt"), b"old").unwrap();
+
+    let options = CopyOptions::new().overwrite(Overwrite::Never);
+    let report = cp2::copy(&[&source], &dest, &options).await.unwrap();
+    assert_eq!(report.files[0].status, FileStatus::Skipped);
+    assert_eq!(std::fs::read(dest.join("a.txt")).unwrap(), b"old");
+
+    let report = cp2::copy(&[&source], &dest, &CopyOptions::new()).await.unwrap();
+    assert_eq!(report.files[0].status, FileStatus::Copied);
+    assert_eq!(std::fs::read(dest.join("a.txt")).unwrap(), b"new");
+}
+
+#[tokio::test]
+async fn test_copy_skip_hidden_leaves_out_dotfiles() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let source = tmp_dir.path().join("src");
+    std::fs::create_dir_all(source.join(".git")).unwrap();
+    std::fs::write(source.join("a.txt"), b"a").unwrap();
+    std::fs::write(source.join(".env"), b"secret").unwrap();
+    std::fs::write(source.join(".git").join("HEAD"), b"ref").unwrap();
+    let dest = tmp_dir.path().join("dest");
+    std::fs::create_dir(&dest).unwrap();
+
+    let options = CopyOptions::new().recursive(true).skip_hidden(true);
+    let report = cp2::copy(&[&source], &dest, &options).await.unwrap();
+    assert_eq!(report.totals.files_copied, 1);
+    assert!(!dest.join("src").join(".env").exists());
+    assert!(!dest.join("src").join(".git").exists());
+}
+
+/// Remembers every call it gets.
+#[derive(Default)]
+struct RecordingSink {
+    bytes: std::sync::atomic::AtomicU64,
+    calls: std::sync::Mutex<Vec<String>>,
+}
+
+impl cp2::ProgressSink for RecordingSink {
+    fn on_bytes(&self, _from: &std::path::Path, bytes: u64) {
+        self.bytes.fetch_add(bytes, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    fn on_file_start(&self, from: &std::path::Path, _to: &std::path::Path, size: u64) {
+        let name = from.file_name().unwrap().to_string_lossy();
+        self.calls.lock().unwrap().push(format!("start {name} {size}"));
+    }
+
+    fn on_file_done(&self, from: &std::path::Path, _to: &std::path::Path, bytes: u64) {
+        let name = from.file_name().unwrap().to_string_lossy();
+        self.calls.lock().unwrap().push(format!("done {name} {bytes}"));
+    }
+
+    fn on_error(&self, path: &std::path::Path, _message: &str) {
+        let name = path.file_name().unwrap().to_string_lossy();
+        self.calls.lock().unwrap().push(format!("error {name}"));
+    }
+}
+
+#[tokio::test]
+async fn test_copy_reports_progress_to_sink() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let source = tmp_dir.path().join("a.txt");
+    std::fs::write(&source, vec![1u8; 10]).unwrap();
+    let dest = tmp_dir.path().join("dest");
+    std::fs::create_dir(&dest).unwrap();
+
+    let sink = std::sync::Arc::new(RecordingSink::default());
+    let options = CopyOptions::new().buffer_size(3).progress(sink.clone());
+    let missing = tmp_dir.path().join("missing.txt");
+    cp2::copy(&[&source, &missing], &dest, &options).await.unwrap();
+
+    assert_eq!(sink.bytes.load(std::sync::atomic::Ordering::Relaxed), 10);
+    assert_eq!(*sink.calls.lock().unwrap(), ["error missing.txt", "start a.txt 10", "done a.txt 10"]);
+}
+
+#[tokio::test]
+async fn test_cancelled_copy_skips_remaining_files() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let source = tmp_dir.path().join("a.txt");
+    std::fs::write(&source, b"hello").unwrap();
+    let dest = tmp_dir.path().join("dest");
+    std::fs::create_dir(&dest).unwrap();
+
+    let cancel = cp2::CancellationToken::new();
+    cancel.cancel();
+    let report = cp2::copy(&[&source], &dest, &CopyOptions::new().cancel(cancel)).await.unwrap();
+    assert!(report.cancelled);
+    assert!(!report.is_success());
+    assert_eq!(report.totals.files_skipped, 1);
+    assert!(!dest.join("a.txt").exists());
+}
+
+#[tokio::test]
+async fn test_copy_with_events_streams_json_events() {
+    let tmp_dir = tempfile::TempDir::new().unwrap();
+    let source = tmp_dir.path().join("a.txt");
+    std::fs::write(&source, b"hello").unwrap();
+    let dest = tmp_dir.path().join("dest");
+    std::fs::create_dir(&dest).unwrap();
+
+    let mut events = cp2::copy_with_events(&[&source], &dest, &CopyOptions::new());
+    let mut names = Vec::new();
+    while let Some(event) = events.next().await {
+        let json = serde_json::to_value(&event).unwrap();
+        names.push(json["event"].as_str().unwrap().to_string());
+        if let cp2::CopyEvent::Summary { files_copied, bytes_copied, .. } = event {
+            assert_eq!((files_copied, bytes_copied), (1, 5));
+        }
+    }
+    // Progress events are timed, so only the one after each file is certain.
+    let done = names.iter().position(|n| n == "file_done").unwrap();
+    assert_eq!(names[done + 1], "progress");
+    names.retain(|n| n != "progress");
+    assert_eq!(names, ["scan_started", "scan_finished", "file_started", "file_done", "summary"]);
+    assert_eq!(std::fs::read(dest.join("a.txt")).unwrap(), b"hello");
+
+    let mut events = cp2::copy_with_events(&[&source], tmp_dir.path().join("nowhere"), &CopyOptions::new());
+    let mut last_error = None;
+    while let Some(event) = events.next().await {
+        if let cp2::CopyEvent::Error { path: None, message } = event {
+            last_error = Some(message);
+        }
+    }
+    assert!(last_error.unwrap().contains("cannot copy into"));
+}
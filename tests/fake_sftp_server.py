@@ -0,0 +1,147 @@
+#!/usr/bin/env python3
+# A minimal SFTP v3 server on stdin/stdout, serving the local filesystem.
+# The SFTP tests use it as `--ssh-command` in place of ssh; the ssh
+# arguments cp2 appends (`-s host sftp`) are ignored. With
+# FAKE_SFTP_FAIL_WRITES_AT=N set, writes at offset N or later fail, as if the
+# connection broke there. Called without `-s`, like ssh it runs its last
+# argument as a shell command instead.
+import os
+import struct
+import sys
+
+if "-s" not in sys.argv:
+    os.execvp("sh", ["sh", "-c", sys.argv[-1]])
+
+inp = sys.stdin.buffer
+out = sys.stdout.buffer
+handles = {}
+fail_writes_at = int(os.environ.get("FAKE_SFTP_FAIL_WRITES_AT", -1))
+next_handle = 0
+
+
+def read_exact(n):
+    data = b""
+    while len(data) < n:
+        chunk = inp.read(n - len(data))
+        if not chunk:
+            sys.exit(0)
+        data += chunk
+    return data
+
+
+def send(kind, payload):
+    out.write(struct.pack(">IB", len(payload) + 1, kind) + payload)
+    out.flush()
+
+
+def string(b):
+    return struct.pack(">I", len(b)) + b
+
+
+def attrs(st):
+    return struct.pack(">IQI", 0x01 | 0x04, st.st_size, st.st_mode)
+
+
+def status(rid, code, message=b""):
+    send(101, struct.pack(">II", rid, code) + string(message) + string(b""))
+
+
+def error(rid, e):
+    code = {2: 2, 13: 3, 1: 3}.get(e.errno, 4)
+    status(rid, code, e.strerror.encode())
+
+
+class Reader:
+    def __init__(self, data):
+        self.data = data
+
+    def u32(self):
+        (n,) = struct.unpack(">I", self.data[:4])
+        self.data = self.data[4:]
+        return n
+
+    def u64(self):
+        (n,) = struct.unpack(">Q", self.data[:8])
+        self.data = self.data[8:]
+        return n
+
+    def string(self):
+        n = self.u32()
+        s = self.data[:n]
+        self.data = self.data[n:]
+        return s
+
+
+while True:
+    (length,) = struct.unpack(">I", read_exact(4))
+    packet = read_exact(length)
+    kind, r = packet[0], Reader(packet[1:])
+    if kind == 1:
+        send(2, struct.pack(">I", 3))
+        continue
+    rid = r.u32()
+    try:
+        if kind == 3:  # OPEN
+            path, pflags = r.string(), r.u32()
+            flags = os.O_RDWR if pflags & 3 == 3 else os.O_WRONLY if pflags & 2 else os.O_RDONLY
+            if pflags & 0x08:
+                flags |= os.O_CREAT
+            if pflags & 0x10:
+                flags |= os.O_TRUNC
+            next_handle += 1
+            handles[next_handle] = os.open(path, flags, 0o644)
+            send(102, struct.pack(">I", rid) + string(str(next_handle).encode()))
+        elif kind == 4:  # CLOSE
+            h = handles.pop(int(r.string()))
+            if isinstance(h, int):
+                os.close(h)
+            status(rid, 0)
+        elif kind == 5:  # READ
+            h, offset, n = handles[int(r.string())], r.u64(), r.u32()
+            data = os.pread(h, n, offset)
+            if data:
+                send(103, struct.pack(">I", rid) + string(data))
+            else:
+                status(rid, 1)
+        elif kind == 6:  # WRITE
+            h, offset, data = handles[int(r.string())], r.u64(), r.string()
+            if 0 <= fail_writes_at <= offset:
+                status(rid, 4, b"connection broke")
+                continue
+            os.pwrite(h, data, offset)
+            status(rid, 0)
+        elif kind in (7, 17):  # LSTAT, STAT
+            send(105, struct.pack(">I", rid) + attrs(os.stat(r.string())))
+        elif kind == 11:  # OPENDIR
+            path = r.string()
+            names = os.listdir(path)
+            next_handle += 1
+            handles[next_handle] = [(n, os.lstat(os.path.join(path, n))) for n in names]
+            send(102, struct.pack(">I", rid) + string(str(next_handle).encode()))
+        elif kind == 12:  # READDIR
+            entries = handles[int(r.string())]
+            if not entries:
+                status(rid, 1)
+                continue
+            batch, entries[:] = entries[:], []
+            payload = struct.pack(">II", rid, len(batch))
+            for name, st in batch:
+                payload += string(name) + string(name) + attrs(st)
+            send(104, payload)
+        elif kind == 13:  # REMOVE
+            os.remove(r.string())
+            status(rid, 0)
+        elif kind == 18:  # RENAME, which like OpenSSH won't replace a file
+            old, new = r.string(), r.string()
+            if os.path.exists(new):
+                status(rid, 4, b"file exists")
+                continue
+            os.rename(old, new)
+            status(rid, 0)
+        elif kind == 14:  # MKDIR
+            os.mkdir(r.string())
+            status(rid, 0)
+        else:
+            status(rid, 8)
+    except OSError as e:
+        error(rid, e)
@@ -0,0 +1,93 @@
+#!/usr/bin/env python3
+# A minimal WebDAV server serving the directory given as its argument. It
+# prints the port it listens on, then serves until killed. The WebDAV tests
+# use it in place of Nextcloud.
+import os
+import shutil
+import sys
+from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+from urllib.parse import quote, unquote, urlparse
+
+ROOT = sys.argv[1]
+
+
+def response(href, st):
+    is_dir = os.path.isdir(href[1])
+    kind = "<d:resourcetype><d:collection/></d:resourcetype>" if is_dir else "<d:resourcetype/>"
+    length = "" if is_dir else "<d:getcontentlength>%d</d:getcontentlength>" % st.st_size
+    return (
+        "<d:response><d:href>%s</d:href><d:propstat><d:prop>%s%s</d:prop>"
+        "<d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response>" % (href[0], kind, length)
+    )
+
+
+class Handler(BaseHTTPRequestHandler):
+    def log_message(self, *args):
+        pass
+
+    def local(self):
+        path = unquote(urlparse(self.path).path)
+        return path, os.path.join(ROOT, path.lstrip("/"))
+
+    def reply(self, code, body=b"", content_type="text/plain"):
+        self.send_response(code)
+        self.send_header("Content-Length", str(len(body)))
+        self.send_header("Content-Type", content_type)
+        self.end_headers()
+        self.wfile.write(body)
+
+    def do_PROPFIND(self):
+        self.rfile.read(int(self.headers.get("Content-Length", 0)))
+        path, local = self.local()
+        if not os.path.exists(local):
+            return self.reply(404)
+        hrefs = [(quote(path), local)]
+        if os.path.isdir(local) and self.headers.get("Depth") == "1":
+            base = path.rstrip("/") + "/"
+            for name in sorted(os.listdir(local)):
+                child = os.path.join(local, name)
+                suffix = "/" if os.path.isdir(child) else ""
+                hrefs.append((quote(base + name) + suffix, child))
+        body = '<?xml version="1.0"?><d:multistatus xmlns:d="DAV:">%s</d:multistatus>' % "".join(
+            response(href, os.stat(href[1])) for href in hrefs
+        )
+        self.reply(207, body.encode(), "application/xml")
+
+    def do_MKCOL(self):
+        _, local = self.local()
+        if os.path.exists(local):
+            return self.reply(405)
+        os.mkdir(local)
+        self.reply(201)
+
+    def do_PUT(self):
+        _, local = self.local()
+        remaining = int(self.headers["Content-Length"])
+        with open(local, "wb") as f:
+            while remaining:
+                chunk = self.rfile.read(min(remaining, 65536))
+                f.write(chunk)
+                remaining -= len(chunk)
+        self.reply(201)
+
+    def do_GET(self):
+        _, local = self.local()
+        if not os.path.isfile(local):
+            return self.reply(404)
+        with open(local, "rb") as f:
+            self.reply(200, f.read(), "application/octet-stream")
+
+    def do_DELETE(self):
+        _, local = self.local()
+        if os.path.isdir(local):
+            shutil.rmtree(local)
+        elif os.path.exists(local):
+            os.remove(local)
+        else:
+            return self.reply(404)
+        self.reply(204)
+
+
+server = ThreadingHTTPServer(("127.0.0.1", 0), Handler)
+print(server.server_address[1], flush=True)
+server.serve_forever()
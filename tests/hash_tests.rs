@@ -0,0 +1,76 @@
+/// Tests for the digests in `cp2::hash` and `cp2::blake3`.
+use cp2::blake3;
+use cp2::hash::{parse_sums, HashAlgorithm};
+use cp2::manifest::{Manifest, ManifestEntry, ManifestFormat};
+use std::path::Path;
+
+fn blake3_hex(input: &[u8], piece: usize) -> String {
+    let mut hasher = blake3::Hasher::new();
+    for chunk in input.chunks(piece.max(1)) {
+        hasher.update(chunk);
+    }
+    hasher.finalize().iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[test]
+fn test_blake3_matches_reference_vectors() {
+    assert_eq!(blake3_hex(b"", 1), "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262");
+    assert_eq!(blake3_hex(b"abc", 1), "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85");
+    // The official test vectors hash the bytes 0, 1, ..., 250, 0, 1, ...
+    let vectors = [
+        (1024, "42214739f095a406f3fc83deb889744ac00df831c10daa55189b5d121c855af7"),
+        (1025, "d00278ae47eb27b34faecf67b4fe263f82d5412916c1ffd97c8cb7fb814b8444"),
+        (102400, "bc3e3d41a1146b069abffad3c0d44860cf664390afce4d9661f7902e7943e085"),
+    ];
+    for (len, expected) in vectors {
+        let input: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        for piece in [1, 333, 1024, len] {
+            assert_eq!(blake3_hex(&input, piece), expected, "{len} bytes fed {piece} at a time");
+        }
+    }
+}
+
+#[test]
+fn test_sums_round_trip() {
+    let mut hasher = HashAlgorithm::Sha256.hasher();
+    hasher.update(b"hello\n");
+    let digest = hasher.finish();
+    assert_eq!(digest, "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03");
+
+    let sums = parse_sums(&format!("{digest}  dir/a b.txt\n{digest} *bin.dat\nnot a checksum line\n"));
+    assert_eq!(sums.len(), 2);
+    assert_eq!(sums["dir/a b.txt"], digest);
+    assert_eq!(sums["bin.dat"], digest);
+}
+
+#[test]
+fn test_manifest_formats() {
+    let digest = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    let manifest = Manifest::new(
+        HashAlgorithm::Sha256,
+        vec![ManifestEntry {
+            path: "dir/a.txt".to_string(),
+            size: 3,
+            mtime: Some(1_714_570_620),
+            hash: digest.to_string(),
+        }],
+    );
+    let root = Path::new("/mnt/archive");
+
+    let gnu = manifest.render(ManifestFormat::Gnu, root).unwrap();
+    assert_eq!(gnu, format!("{digest}  dir/a.txt\n"));
+    let bsd = manifest.render(ManifestFormat::Bsd, root).unwrap();
+    assert_eq!(bsd, format!("SHA256 (dir/a.txt) = {digest}\n"));
+    // Both read back the same way.
+    assert_eq!(parse_sums(&gnu), parse_sums(&bsd));
+
+    let hashdeep = manifest.render(ManifestFormat::Hashdeep, root).unwrap();
+    let lines: Vec<&str> = hashdeep.lines().collect();
+    assert_eq!(lines[0], "%%%% HASHDEEP-1.0");
+    assert_eq!(lines[1], "%%%% size,sha256,filename");
+    assert_eq!(lines[2], "## Invoked from: /mnt/archive");
+    assert_eq!(lines.last().unwrap(), &format!("3,{digest},dir/a.txt"));
+
+    let json = manifest.render(ManifestFormat::Json, root).unwrap();
+    assert_eq!(serde_json::from_str::<Manifest>(&json).unwrap(), manifest);
+}
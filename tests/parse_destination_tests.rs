@@ -1,5 +1,6 @@
 /// Tests for `cp2::cli::parse_destination`.
 use cp2::cli::{parse_destination, Destination};
+use cp2::sftp::SftpLocation;
 use std::path::PathBuf;
 
 #[test]
@@ -83,3 +84,63 @@ fn remote_name_with_slash_falls_back_to_local() {
         Destination::Local(PathBuf::from("foo/bar:baz")),
     );
 }
+
+#[test]
+fn sftp_host_with_absolute_path() {
+    assert_eq!(
+        parse_destination("server:/srv/app"),
+        Destination::Sftp(SftpLocation {
+            user: None,
+            host: "server".to_string(),
+            port: None,
+            path: "/srv/app".to_string(),
+        }),
+    );
+}
+
+#[test]
+fn sftp_user_at_host_with_relative_path() {
+    assert_eq!(
+        parse_destination("deploy@server:releases"),
+        Destination::Sftp(SftpLocation {
+            user: Some("deploy".to_string()),
+            host: "server".to_string(),
+            port: None,
+            path: "releases".to_string(),
+        }),
+    );
+}
+
+#[test]
+fn sftp_url_with_port() {
+    assert_eq!(
+        parse_destination("sftp://deploy@server:2222/srv/app"),
+        Destination::Sftp(SftpLocation {
+            user: Some("deploy".to_string()),
+            host: "server".to_string(),
+            port: Some(2222),
+            path: "/srv/app".to_string(),
+        }),
+    );
+}
+
+#[test]
+fn sftp_user_at_host_without_path_is_home() {
+    assert_eq!(
+        parse_destination("deploy@server:"),
+        Destination::Sftp(SftpLocation {
+            user: Some("deploy".to_string()),
+            host: "server".to_string(),
+            port: None,
+            path: ".".to_string(),
+        }),
+    );
+}
+
+#[test]
+fn webdav_url() {
+    assert_eq!(
+        parse_destination("davs://me@cloud.example.com/remote.php/dav/files/me"),
+        Destination::WebDav("https://me@cloud.example.com/remote.php/dav/files/me".parse().unwrap()),
+    );
+}
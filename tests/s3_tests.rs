@@ -57,6 +57,7 @@ fn test_config_save_and_load_roundtrip() {
             secret_access_key: Some("SECRET".to_string()),
             region: Some("us-east-1".to_string()),
             endpoint: None,
+            ..Default::default()
         },
     );
 
@@ -98,6 +99,7 @@ fn test_config_with_endpoint() {
             secret_access_key: Some("miniosecret".to_string()),
             region: Some("us-east-1".to_string()),
             endpoint: Some("http://localhost:9000".to_string()),
+            ..Default::default()
         },
     );
 
@@ -107,6 +109,51 @@ fn test_config_with_endpoint() {
     assert_eq!(r.endpoint.as_deref(), Some("http://localhost:9000"));
 }
 
+#[test]
+fn test_config_reads_remotes_section_and_legacy_tables() {
+    let tmp = TempDir::new().unwrap();
+    let config_path = tmp.path().join("config.toml");
+    let _guard = ConfigEnvGuard::set(&config_path);
+    fs::write(
+        &config_path,
+        r#"
+[old]
+type = "s3"
+region = "eu-west-1"
+
+[remotes.nas]
+type = "sftp"
+host = "nas.lan"
+port = 2222
+user = "me"
+path = "/volume1"
+host_keys = ["ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIExample"]
+
+[remotes.cloud]
+type = "webdav"
+url = "davs://cloud.example.com/remote.php/dav/files/me"
+user = "me"
+"#,
+    )
+    .unwrap();
+
+    let loaded = cp2::config::load_config().expect("load");
+    assert_eq!(loaded["old"].region.as_deref(), Some("eu-west-1"));
+    let nas = cp2::config::get_remote(&loaded, "nas").expect("nas");
+    let location = nas.sftp_location("share/dir").unwrap();
+    assert_eq!(location.to_string(), "sftp://me@nas.lan:2222/volume1/share/dir");
+    assert_eq!(nas.sftp_location("/tmp").unwrap().path, "/tmp");
+    let url = loaded["cloud"].webdav_url("/Documents/a b").unwrap();
+    assert_eq!(url.as_str(), "https://me@cloud.example.com/remote.php/dav/files/me/Documents/a%20b");
+
+    // Saving moves every remote into the [remotes] section.
+    cp2::config::save_config(&loaded).expect("save");
+    let saved = fs::read_to_string(&config_path).unwrap();
+    assert!(saved.contains("[remotes.old]") && saved.contains("[remotes.nas]"));
+    assert!(!saved.contains("\n[old]"));
+    assert_eq!(cp2::config::load_config().unwrap().len(), 3);
+}
+
 #[cfg(unix)]
 #[test]
 fn test_config_file_is_chmod_600() {
@@ -126,6 +173,7 @@ fn test_config_file_is_chmod_600() {
             secret_access_key: None,
             region: None,
             endpoint: None,
+            ..Default::default()
         },
     );
     cp2::config::save_config(&cfg).expect("save");
@@ -146,6 +194,7 @@ fn test_config_get_remote_present() {
             secret_access_key: None,
             region: None,
             endpoint: None,
+            ..Default::default()
         },
     );
     assert!(cp2::config::get_remote(&cfg, "aws").is_some());
@@ -160,7 +209,7 @@ async fn test_s3_entries_single_file_no_prefix() {
     let file = tmp.path().join("data.txt");
     File::create(&file).unwrap().write_all(b"hello").unwrap();
 
-    let (entries, count, size) = cp2::s3::collect_s3_upload_entries(&file, "").await.unwrap();
+    let (entries, count, size) = cp2::s3::collect_s3_upload_entries(&file, "", false, false).await.unwrap();
 
     assert_eq!(count, 1);
     assert_eq!(size, 5);
@@ -175,7 +224,7 @@ async fn test_s3_entries_single_file_with_prefix() {
     File::create(&file).unwrap().write_all(b"a,b").unwrap();
 
     let (entries, _count, _size) =
-        cp2::s3::collect_s3_upload_entries(&file, "uploads/2024").await.unwrap();
+        cp2::s3::collect_s3_upload_entries(&file, "uploads/2024", false, false).await.unwrap();
 
     assert_eq!(entries[0].key, "uploads/2024/report.csv");
 }
@@ -188,7 +237,7 @@ async fn test_s3_entries_single_file_prefix_trailing_slash() {
 
     // Trailing slash on prefix should be normalised.
     let (entries, _count, _size) =
-        cp2::s3::collect_s3_upload_entries(&file, "media/images/").await.unwrap();
+        cp2::s3::collect_s3_upload_entries(&file, "media/images/", false, false).await.unwrap();
 
     assert_eq!(entries[0].key, "media/images/img.png");
 }
@@ -203,7 +252,7 @@ async fn test_s3_entries_directory() {
     File::create(src.join("sub").join("b.txt")).unwrap().write_all(b"22").unwrap();
 
     let (entries, count, size) =
-        cp2::s3::collect_s3_upload_entries(&src, "backup").await.unwrap();
+        cp2::s3::collect_s3_upload_entries(&src, "backup", false, false).await.unwrap();
 
     assert_eq!(count, 2);
     assert_eq!(size, 3);
@@ -221,7 +270,7 @@ async fn test_s3_entries_directory_no_prefix() {
     File::create(src.join("logo.svg")).unwrap().write_all(b"svg").unwrap();
 
     let (entries, _count, _size) =
-        cp2::s3::collect_s3_upload_entries(&src, "").await.unwrap();
+        cp2::s3::collect_s3_upload_entries(&src, "", false, false).await.unwrap();
 
     assert_eq!(entries[0].key, "assets/logo.svg");
 }
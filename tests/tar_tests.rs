@@ -0,0 +1,71 @@
+/// Tests for the tar reader and writer in `cp2::tar`.
+use cp2::tar::{EntryKind, Header, TarReader, TarWriter};
+use std::path::Path;
+
+fn header(path: &str, kind: EntryKind, size: u64) -> Header {
+    Header {
+        path: path.to_string(),
+        kind,
+        size,
+        mode: 0o640,
+        mtime: 1_700_000_000,
+        uid: 1000,
+        gid: 1000,
+        link: None,
+    }
+}
+
+#[tokio::test]
+async fn test_tar_round_trip_with_pax_headers() {
+    let long_path = format!("dir/{}/file.txt", "d".repeat(150));
+    let mut big_owner = header(&long_path, EntryKind::File, 5);
+    big_owner.uid = 1 << 30;
+    let mut link = header("dir/link", EntryKind::Symlink, 0);
+    link.link = Some("file.txt".to_string());
+    let mut dir = header("dir/", EntryKind::Dir, 0);
+    dir.mode = 0o755;
+
+    let mut writer = TarWriter::new(Vec::new());
+    writer.append(&dir).await.unwrap();
+    writer
+        .append_file(&big_owner, Path::new("in"), Path::new("out.tar"), &mut &b"hello"[..], None, None, None)
+        .await
+        .unwrap();
+    writer.append(&link).await.unwrap();
+    let archive = writer.finish().await.unwrap();
+    assert_eq!(archive.len() % 512, 0);
+
+    let mut reader = TarReader::new(&archive[..]);
+    assert_eq!(reader.next().await.unwrap().unwrap(), dir);
+    let read = reader.next().await.unwrap().unwrap();
+    assert_eq!(read, big_owner);
+    let mut data = Vec::new();
+    reader
+        .copy_data(&read, Path::new("out.tar"), Path::new("file"), &mut data, None, None, None)
+        .await
+        .unwrap();
+    assert_eq!(data, b"hello");
+    assert_eq!(reader.next().await.unwrap().unwrap(), link);
+    assert_eq!(reader.next().await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_tar_reader_skips_unread_data_and_rejects_garbage() {
+    let mut writer = TarWriter::new(Vec::new());
+    for name in ["a.bin", "b.bin"] {
+        let data = vec![7u8; 1000];
+        writer
+            .append_file(&header(name, EntryKind::File, 1000), Path::new(name), Path::new("x.tar"), &mut &data[..], None, None, None)
+            .await
+            .unwrap();
+    }
+    let archive = writer.finish().await.unwrap();
+    let mut reader = TarReader::new(&archive[..]);
+    assert_eq!(reader.next().await.unwrap().unwrap().path, "a.bin");
+    assert_eq!(reader.next().await.unwrap().unwrap().path, "b.bin");
+    assert_eq!(reader.next().await.unwrap(), None);
+
+    let garbage = vec![b'x'; 1024];
+    let err = TarReader::new(&garbage[..]).next().await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
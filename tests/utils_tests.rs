@@ -8,3 +8,195 @@ fn test_trim_filename() {
     assert_eq!(cp2::utils::trim_filename("medium_length_name.txt", 10), "medi...txt");
     assert_eq!(cp2::utils::trim_filename("tiny", 3), "...");
 }
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(cp2::utils::parse_size("512").unwrap(), 512);
+    assert_eq!(cp2::utils::parse_size("64K").unwrap(), 64 * 1024);
+    assert_eq!(cp2::utils::parse_size("1.5g").unwrap(), 3 * 512 * 1024 * 1024);
+    assert_eq!(cp2::utils::parse_size("2TiB").unwrap(), 2 << 40);
+    assert!(cp2::utils::parse_size("abc").is_err());
+    assert!(cp2::utils::parse_size("10X").is_err());
+}
+
+#[test]
+fn test_parse_duration() {
+    use std::time::Duration;
+    assert_eq!(cp2::utils::parse_duration("2").unwrap(), Duration::from_secs(2));
+    assert_eq!(cp2::utils::parse_duration("500ms").unwrap(), Duration::from_millis(500));
+    assert_eq!(cp2::utils::parse_duration("1.5m").unwrap(), Duration::from_secs(90));
+    assert_eq!(cp2::utils::parse_duration("1H").unwrap(), Duration::from_secs(3600));
+    assert!(cp2::utils::parse_duration("soon").is_err());
+    assert!(cp2::utils::parse_duration("3d").is_err());
+}
+
+#[test]
+fn test_parse_locked_policy() {
+    use cp2::utils::{parse_locked_policy, LockedPolicy, DEFAULT_LOCK_WAIT};
+    use std::time::Duration;
+    assert_eq!(parse_locked_policy("skip").unwrap(), LockedPolicy::Skip);
+    assert_eq!(parse_locked_policy("fail").unwrap(), LockedPolicy::Fail);
+    assert_eq!(parse_locked_policy("wait").unwrap(), LockedPolicy::Wait(DEFAULT_LOCK_WAIT));
+    assert_eq!(
+        parse_locked_policy("wait=2m").unwrap(),
+        LockedPolicy::Wait(Duration::from_secs(120))
+    );
+    assert!(parse_locked_policy("wait=soon").is_err());
+    assert!(parse_locked_policy("skip=1s").is_err());
+    assert!(parse_locked_policy("ignore").is_err());
+}
+
+#[test]
+fn test_case_collisions() {
+    use cp2::utils::{case_collisions, case_fold, case_unique_path, CopyEntry};
+    use std::path::{Path, PathBuf};
+    let entry = |to: &str| CopyEntry {
+        from: PathBuf::from("src").join(to),
+        to: PathBuf::from("dest").join(to),
+        size: 1,
+    };
+    let entries = vec![entry("Readme.md"), entry("README.md"), entry("other.txt"), entry("readme.MD")];
+    assert_eq!(case_collisions(&entries), vec![1, 3]);
+
+    let taken = entries.iter().map(|e| case_fold(&e.to)).collect();
+    assert_eq!(
+        case_unique_path(Path::new("dest/README.md"), &taken),
+        PathBuf::from("dest/README (1).md")
+    );
+    let mut taken = taken;
+    taken.insert(case_fold(Path::new("dest/readme (1).md")));
+    assert_eq!(
+        case_unique_path(Path::new("dest/README.md"), &taken),
+        PathBuf::from("dest/README (2).md")
+    );
+    assert_eq!(
+        case_unique_path(Path::new("dest/Makefile"), &taken),
+        PathBuf::from("dest/Makefile (1)")
+    );
+}
+
+#[test]
+fn test_sanitize_name() {
+    use cp2::utils::sanitize_name;
+    assert_eq!(sanitize_name("notes.txt", "_"), None);
+    assert_eq!(sanitize_name("a:b*c?.txt", "_").as_deref(), Some("a_b_c_.txt"));
+    assert_eq!(sanitize_name("say \"hi\" <now>|", "-").as_deref(), Some("say -hi- -now--"));
+    assert_eq!(sanitize_name("tab\there", "_").as_deref(), Some("tab_here"));
+    assert_eq!(sanitize_name("ends with dot.", "_").as_deref(), Some("ends with dot_"));
+    assert_eq!(sanitize_name("CON", "_").as_deref(), Some("CON_"));
+    assert_eq!(sanitize_name("aux.tar.gz", "_").as_deref(), Some("aux_.tar.gz"));
+    assert_eq!(sanitize_name("console.log", "_"), None);
+    assert_eq!(sanitize_name("?", "").as_deref(), Some("_"));
+}
+
+#[test]
+fn test_parse_preserve() {
+    use cp2::utils::{Preserve, parse_preserve};
+    assert_eq!(
+        parse_preserve("timestamps").unwrap(),
+        Preserve {
+            timestamps: true,
+            ..Preserve::default()
+        }
+    );
+    assert_eq!(
+        parse_preserve("context,timestamps").unwrap(),
+        Preserve {
+            timestamps: true,
+            context: true,
+            ..Preserve::default()
+        }
+    );
+    assert_eq!(parse_preserve("all").unwrap(), Preserve::ALL);
+    assert!(parse_preserve("timestamps,bogus").is_err());
+}
+
+#[test]
+fn test_parse_owner() {
+    use cp2::utils::{Owner, parse_owner};
+    assert_eq!(parse_owner("1000").unwrap(), Owner { uid: Some(1000), gid: None });
+    assert_eq!(parse_owner("1000:100").unwrap(), Owner { uid: Some(1000), gid: Some(100) });
+    assert_eq!(parse_owner(":100").unwrap(), Owner { uid: None, gid: Some(100) });
+    #[cfg(unix)]
+    assert_eq!(parse_owner("root").unwrap(), Owner { uid: Some(0), gid: None });
+    assert!(parse_owner(":").is_err());
+    assert!(parse_owner("no-such-user-cp2").is_err());
+}
+
+#[test]
+fn test_parse_id_map_and_map_id() {
+    use cp2::utils::{IdMap, map_id, parse_id_map};
+    assert_eq!(parse_id_map("1000:100000").unwrap(), IdMap { from: 1000, to: 100000, count: 1 });
+    let range = parse_id_map("0:100000:65536").unwrap();
+    assert_eq!(range, IdMap { from: 0, to: 100000, count: 65536 });
+    assert!(parse_id_map("1000").is_err());
+    assert!(parse_id_map("1:2:0").is_err());
+    assert!(parse_id_map("a:b").is_err());
+
+    let maps = [parse_id_map("1000:5000").unwrap(), range];
+    assert_eq!(map_id(&maps, 1000), 5000);
+    assert_eq!(map_id(&maps, 33), 100033);
+    assert_eq!(map_id(&maps, 70000), 70000);
+}
+
+#[test]
+fn test_parse_modes() {
+    use cp2::utils::{Modes, parse_modes};
+    assert_eq!(parse_modes("644").unwrap(), Modes { file: Some(0o644), dir: Some(0o644) });
+    assert_eq!(parse_modes("D755,F0640").unwrap(), Modes { file: Some(0o640), dir: Some(0o755) });
+    assert!(parse_modes("rwx").is_err());
+    assert!(parse_modes("F99").is_err());
+}
+
+#[test]
+fn test_normalize_name() {
+    use cp2::utils::{NormalForm, normalize_name};
+    let nfc = "caf\u{e9}.txt";
+    let nfd = "cafe\u{301}.txt";
+    assert_eq!(normalize_name(nfd, NormalForm::Nfc).as_deref(), Some(nfc));
+    assert_eq!(normalize_name(nfc, NormalForm::Nfd).as_deref(), Some(nfd));
+    assert_eq!(normalize_name(nfc, NormalForm::Nfc), None);
+    assert_eq!(normalize_name(nfd, NormalForm::Nfd), None);
+    assert_eq!(normalize_name("plain.txt", NormalForm::Nfd), None);
+}
+
+#[test]
+fn test_sanitize_path_only_touches_components_below_base() {
+    use cp2::utils::sanitize_path;
+    use std::path::Path;
+    let base = Path::new("/mnt/usb:stick");
+    assert_eq!(sanitize_path(base, &base.join("ok/fine.txt"), "_"), None);
+    assert_eq!(
+        sanitize_path(base, &base.join("a|b/c?.txt"), "_").unwrap(),
+        base.join("a_b/c_.txt")
+    );
+}
+
+#[tokio::test]
+async fn test_collect_copy_entries_scans_tree_in_parallel() {
+    let tmp = tempfile::TempDir::new().unwrap();
+    let src = tmp.path().join("tree");
+    for d in 0..5 {
+        let sub = src.join(format!("d{d}")).join("nested");
+        std::fs::create_dir_all(&sub).unwrap();
+        std::fs::write(sub.join("f.txt"), b"1234").unwrap();
+    }
+    std::fs::write(src.join("top.txt"), b"12").unwrap();
+    let dest = tmp.path().join("dest");
+    std::fs::create_dir(&dest).unwrap();
+
+    let pb = indicatif::ProgressBar::with_draw_target(Some(0), indicatif::ProgressDrawTarget::hidden());
+    let (entries, dirs, count, size, specials, _) = cp2::utils::collect_copy_entries(&src, &dest, 3, false, false, Some(&pb))
+        .await
+        .unwrap();
+
+    assert_eq!(count, 6);
+    assert_eq!(size, 22);
+    assert!(specials.is_empty());
+    assert_eq!(entries.len(), 6);
+    // tree itself, five d{n} dirs and five nested dirs
+    assert_eq!(dirs.len(), 11);
+    assert!(dirs.contains(&(src.join("d3").join("nested"), dest.join("tree").join("d3").join("nested"))));
+    assert_eq!(pb.position(), 6);
+    assert_eq!(pb.length(), Some(22));
+}